@@ -11,7 +11,9 @@ use ruma::{
 		uiaa::{AuthFlow, AuthType, UiaaInfo},
 	},
 	events::{room::message::RoomMessageEventContent, GlobalAccountDataEventType},
-	push, UserId,
+	push,
+	thirdparty::{Medium, ThirdPartyIdentifierInit},
+	MilliSecondsSinceUnixEpoch, UInt, UserId,
 };
 use tracing::{error, info, warn};
 
@@ -56,7 +58,12 @@ pub async fn get_register_available_route(
 		return Err(Error::BadRequest(ErrorKind::Unknown, "Username is forbidden."));
 	}
 
-	// TODO add check for appservice namespaces
+	if services().appservice.is_exclusive_user_id(&user_id).await {
+		return Err(Error::BadRequest(
+			ErrorKind::Exclusive,
+			"Username is reserved by an appservice.",
+		));
+	}
 
 	// If no if check is true we have an username that's available to be used.
 	Ok(get_username_availability::v3::Response {
@@ -100,7 +107,7 @@ pub async fn register_route(body: Ruma<register::v3::Request>) -> Result<registe
 
 	if is_guest
 		&& (!services().globals.allow_guest_registration()
-			|| (services().globals.allow_registration() && services().globals.config.registration_token.is_some()))
+			|| (services().globals.allow_registration() && services().globals.registration_token().is_some()))
 	{
 		info!(
 			"Guest registration disabled / registration enabled with token configured, rejecting guest registration, \
@@ -146,6 +153,26 @@ pub async fn register_route(body: Ruma<register::v3::Request>) -> Result<registe
 				return Err(Error::BadRequest(ErrorKind::Unknown, "Username is forbidden."));
 			}
 
+			match &body.appservice_info {
+				Some(info) if !info.is_user_match(&proposed_user_id) => {
+					return Err(Error::BadRequest(
+						ErrorKind::Exclusive,
+						"Username is not in an appservice namespace.",
+					));
+				},
+				None if services()
+					.appservice
+					.is_exclusive_user_id(&proposed_user_id)
+					.await =>
+				{
+					return Err(Error::BadRequest(
+						ErrorKind::Exclusive,
+						"Username is reserved by an appservice.",
+					));
+				},
+				_ => {},
+			}
+
 			proposed_user_id
 		},
 		_ => loop {
@@ -163,7 +190,7 @@ pub async fn register_route(body: Ruma<register::v3::Request>) -> Result<registe
 	// UIAA
 	let mut uiaainfo;
 	let skip_auth;
-	if services().globals.config.registration_token.is_some() {
+	if services().globals.registration_token().is_some() {
 		// Registration token required
 		uiaainfo = UiaaInfo {
 			flows: vec![AuthFlow {
@@ -517,11 +544,27 @@ pub async fn deactivate_route(body: Ruma<deactivate::v3::Request>) -> Result<dea
 ///
 /// Get a list of third party identifiers associated with this account.
 ///
-/// - Currently always returns empty list
+/// - Only returns email addresses admins bound to the account
 pub async fn third_party_route(body: Ruma<get_3pids::v3::Request>) -> Result<get_3pids::v3::Response> {
-	let _sender_user = body.sender_user.as_ref().expect("user is authenticated");
+	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+	let threepids = services()
+		.users
+		.emails(sender_user)
+		.filter_map(Result::ok)
+		.map(|(address, added_at)| {
+			let added_at = MilliSecondsSinceUnixEpoch(UInt::new_saturating(added_at));
+			ThirdPartyIdentifierInit {
+				address,
+				medium: Medium::Email,
+				validated_at: added_at,
+				added_at,
+			}
+			.into()
+		})
+		.collect();
 
-	Ok(get_3pids::v3::Response::new(Vec::new()))
+	Ok(get_3pids::v3::Response::new(threepids))
 }
 
 /// # `POST /_matrix/client/v3/account/3pid/email/requestToken`
@@ -1,17 +1,16 @@
 use rand::seq::SliceRandom;
 use ruma::{
 	api::{
-		appservice,
 		client::{
 			alias::{create_alias, delete_alias, get_alias},
 			error::ErrorKind,
 		},
 		federation,
 	},
-	OwnedRoomAliasId, OwnedServerName,
+	OwnedRoomAliasId, OwnedServerName, RoomAliasId,
 };
 
-use crate::{services, Error, Result, Ruma};
+use crate::{service::appservice::RegistrationInfo, services, Error, Result, Ruma};
 
 /// # `PUT /_matrix/client/v3/directory/room/{roomAlias}`
 ///
@@ -29,6 +28,8 @@ pub async fn create_alias_route(body: Ruma<create_alias::v3::Request>) -> Result
 		return Err(Error::BadRequest(ErrorKind::Unknown, "Room alias is forbidden."));
 	}
 
+	check_alias_namespace(&body.room_alias, body.appservice_info.as_ref()).await?;
+
 	if services()
 		.rooms
 		.alias
@@ -53,6 +54,22 @@ pub async fn create_alias_route(body: Ruma<create_alias::v3::Request>) -> Result
 	Ok(create_alias::v3::Response::new())
 }
 
+/// Appservices may only manage aliases in their namespace, and nobody else may
+/// touch aliases an appservice claims exclusively.
+pub(crate) async fn check_alias_namespace(
+	alias: &RoomAliasId, appservice_info: Option<&RegistrationInfo>,
+) -> Result<()> {
+	match appservice_info {
+		Some(info) if !info.aliases.is_match(alias.as_str()) => {
+			Err(Error::BadRequest(ErrorKind::Exclusive, "Room alias is not in namespace."))
+		},
+		None if services().appservice.is_exclusive_alias(alias).await => {
+			Err(Error::BadRequest(ErrorKind::Exclusive, "Room alias is reserved by appservice."))
+		},
+		_ => Ok(()),
+	}
+}
+
 /// # `DELETE /_matrix/client/v3/directory/room/{roomAlias}`
 ///
 /// Deletes a room alias from this server.
@@ -64,6 +81,8 @@ pub async fn delete_alias_route(body: Ruma<delete_alias::v3::Request>) -> Result
 		return Err(Error::BadRequest(ErrorKind::InvalidParam, "Alias is from another server."));
 	}
 
+	check_alias_namespace(&body.room_alias, body.appservice_info.as_ref()).await?;
+
 	if services()
 		.rooms
 		.alias
@@ -151,35 +170,9 @@ pub(crate) async fn get_alias_helper(room_alias: OwnedRoomAliasId) -> Result<get
 		return Ok(get_alias::v3::Response::new(room_id, servers));
 	}
 
-	let mut room_id = None;
-	match services().rooms.alias.resolve_local_alias(&room_alias)? {
-		Some(r) => room_id = Some(r),
-		None => {
-			for appservice in services().appservice.read().await.values() {
-				if appservice.aliases.is_match(room_alias.as_str())
-					&& matches!(
-						services()
-							.sending
-							.send_appservice_request(
-								appservice.registration.clone(),
-								appservice::query::query_room_alias::v1::Request {
-									room_alias: room_alias.clone(),
-								},
-							)
-							.await,
-						Ok(Some(_opt_result))
-					) {
-					room_id = Some(
-						services()
-							.rooms
-							.alias
-							.resolve_local_alias(&room_alias)?
-							.ok_or_else(|| Error::bad_config("Room does not exist."))?,
-					);
-					break;
-				}
-			}
-		},
+	let room_id = match services().rooms.alias.resolve_local_alias(&room_alias)? {
+		Some(room_id) => Some(room_id),
+		None => services().appservice.query_room_alias(&room_alias).await?,
 	};
 
 	let Some(room_id) = room_id else {
@@ -0,0 +1,32 @@
+use ruma::api::client::{appservice::request_ping, error::ErrorKind};
+
+use crate::{services, Error, Result, Ruma};
+
+/// # `POST /_matrix/client/v1/appservice/{appserviceId}/ping`
+///
+/// Asks the homeserver to ping the appservice, so it can check that both can
+/// reach each other.
+///
+/// - Appservices can only ping themselves
+pub async fn appservice_ping_route(body: Ruma<request_ping::v1::Request>) -> Result<request_ping::v1::Response> {
+	let Some(info) = &body.appservice_info else {
+		return Err(Error::BadRequest(
+			ErrorKind::forbidden(),
+			"This endpoint can only be used by appservices.",
+		));
+	};
+
+	if info.registration.id != body.appservice_id {
+		return Err(Error::BadRequest(
+			ErrorKind::forbidden(),
+			"Appservices can only ping themselves.",
+		));
+	}
+
+	let duration = services()
+		.appservice
+		.ping(&body.appservice_id, body.transaction_id.clone())
+		.await?;
+
+	Ok(request_ping::v1::Response::new(duration))
+}
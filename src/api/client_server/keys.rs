@@ -27,6 +27,8 @@ use crate::{services, utils, Error, Result, Ruma};
 /// Publish end-to-end encryption keys for the sender device.
 ///
 /// - Adds one time keys
+/// - Adds fallback keys, replacing the previous fallback key of the same
+///   algorithm
 /// - If there are no device keys yet: Adds device keys (TODO: merge with
 ///   existing keys?)
 pub async fn upload_keys_route(body: Ruma<upload_keys::v3::Request>) -> Result<upload_keys::v3::Response> {
@@ -39,6 +41,12 @@ pub async fn upload_keys_route(body: Ruma<upload_keys::v3::Request>) -> Result<u
 			.add_one_time_key(sender_user, sender_device, key_key, key_value)?;
 	}
 
+	for (key_key, key_value) in &body.fallback_keys {
+		services()
+			.users
+			.add_fallback_key(sender_user, sender_device, key_key, key_value)?;
+	}
+
 	if let Some(device_keys) = &body.device_keys {
 		// TODO: merge this and the existing event?
 		// This check is needed to assure that signatures are kept
@@ -85,6 +93,8 @@ pub async fn get_keys_route(body: Ruma<get_keys::v3::Request>) -> Result<get_key
 /// # `POST /_matrix/client/r0/keys/claim`
 ///
 /// Claims one-time keys
+///
+/// - Returns the fallback key of a device once it ran out of one-time keys
 pub async fn claim_keys_route(body: Ruma<claim_keys::v3::Request>) -> Result<claim_keys::v3::Response> {
 	let response = claim_keys_helper(&body.one_time_keys).await?;
 
@@ -468,10 +478,17 @@ pub(crate) async fn claim_keys_helper(
 
 		let mut container = BTreeMap::new();
 		for (device_id, key_algorithm) in map {
-			if let Some(one_time_keys) = services()
+			let one_time_key = match services()
 				.users
 				.take_one_time_key(user_id, device_id, key_algorithm)?
 			{
+				Some(one_time_key) => Some(one_time_key),
+				None => services()
+					.users
+					.take_fallback_key(user_id, device_id, key_algorithm)?,
+			};
+
+			if let Some(one_time_keys) = one_time_key {
 				let mut c = BTreeMap::new();
 				c.insert(one_time_keys.0, one_time_keys.1);
 				container.insert(device_id.clone(), c);
@@ -10,7 +10,7 @@ use ruma::{
 			error::ErrorKind,
 			membership::{
 				ban_user, forget_room, get_member_events, invite_user, join_room_by_id, join_room_by_id_or_alias,
-				joined_members, joined_rooms, kick_user, leave_room, unban_user, ThirdPartySigned,
+				joined_members, joined_rooms, kick_user, leave_room, unban_user, Invite3pid, ThirdPartySigned,
 			},
 		},
 		federation::{self, membership::create_invite},
@@ -19,7 +19,7 @@ use ruma::{
 	events::{
 		room::{
 			join_rules::{AllowRule, JoinRule, RoomJoinRulesEventContent},
-			member::{MembershipState, RoomMemberEventContent},
+			member::{MembershipState, RoomMemberEventContent, ThirdPartyInvite},
 		},
 		StateEventType, TimelineEventType,
 	},
@@ -207,15 +207,19 @@ pub async fn invite_user_route(body: Ruma<invite_user::v3::Request>) -> Result<i
 		));
 	}
 
-	if let invite_user::v3::InvitationRecipient::UserId {
-		user_id,
-	} = &body.recipient
-	{
-		invite_helper(sender_user, user_id, &body.room_id, body.reason.clone(), false).await?;
-		Ok(invite_user::v3::Response {})
-	} else {
-		Err(Error::BadRequest(ErrorKind::NotFound, "User not found."))
+	match &body.recipient {
+		invite_user::v3::InvitationRecipient::UserId {
+			user_id,
+		} => {
+			invite_helper(sender_user, user_id, &body.room_id, body.reason.clone(), false).await?;
+		},
+		invite_user::v3::InvitationRecipient::ThirdPartyId(invite) => {
+			invite_3pid_helper(sender_user, &body.room_id, invite).await?;
+		},
+		_ => return Err(Error::BadRequest(ErrorKind::NotFound, "User not found.")),
 	}
+
+	Ok(invite_user::v3::Response {})
 }
 
 /// # `POST /_matrix/client/r0/rooms/{roomId}/kick`
@@ -551,7 +555,7 @@ pub async fn joined_members_route(body: Ruma<joined_members::v3::Request>) -> Re
 
 pub(crate) async fn join_room_by_id_helper(
 	sender_user: Option<&UserId>, room_id: &RoomId, reason: Option<String>, servers: &[OwnedServerName],
-	_third_party_signed: Option<&ThirdPartySigned>,
+	third_party_signed: Option<&ThirdPartySigned>,
 ) -> Result<join_room_by_id::v3::Response> {
 	let sender_user = sender_user.expect("user is authenticated");
 
@@ -562,6 +566,29 @@ pub(crate) async fn join_room_by_id_helper(
 		});
 	}
 
+	if let Some(third_party_signed) = third_party_signed {
+		if !services()
+			.rooms
+			.state_cache
+			.is_invited(sender_user, room_id)?
+		{
+			if third_party_signed.mxid != sender_user {
+				return Err(Error::BadRequest(
+					ErrorKind::forbidden(),
+					"Third party invite was signed for a different user.",
+				));
+			}
+
+			let mut signed = utils::to_canonical_object(third_party_signed).map_err(|e| {
+				warn!("Failed to convert third_party_signed to canonical JSON: {e}");
+				Error::BadRequest(ErrorKind::InvalidParam, "Invalid third_party_signed.")
+			})?;
+			signed.remove("sender");
+
+			bind_third_party_invite_helper(&third_party_signed.sender, sender_user, room_id, signed).await?;
+		}
+	}
+
 	let mutex_state = Arc::clone(
 		services()
 			.globals
@@ -831,25 +858,29 @@ pub(crate) async fn join_room_by_id_helper(
 
 		info!("Running send_join auth check");
 
+		let state_fetch = |k: &StateEventType, s: &str| {
+			services()
+				.rooms
+				.timeline
+				.get_pdu(
+					state.get(
+						&services()
+							.rooms
+							.short
+							.get_or_create_shortstatekey(&k.to_string().into(), s)
+							.ok()?,
+					)?,
+				)
+				.ok()?
+		};
 		let auth_check = state_res::event_auth::auth_check(
 			&state_res::RoomVersion::new(&room_version_id).expect("room version is supported"),
 			&parsed_join_pdu,
-			None::<PduEvent>, // TODO: third party invite
-			|k, s| {
-				services()
-					.rooms
-					.timeline
-					.get_pdu(
-						state.get(
-							&services()
-								.rooms
-								.short
-								.get_or_create_shortstatekey(&k.to_string().into(), s)
-								.ok()?,
-						)?,
-					)
-					.ok()?
-			},
+			services()
+				.rooms
+				.third_party_invite
+				.auth_event(&parsed_join_pdu, state_fetch),
+			state_fetch,
 		)
 		.map_err(|e| {
 			warn!("Auth check failed: {e}");
@@ -1272,6 +1303,138 @@ async fn validate_and_add_event_id(
 
 pub(crate) async fn invite_helper(
 	sender_user: &UserId, user_id: &UserId, room_id: &RoomId, reason: Option<String>, is_direct: bool,
+) -> Result<()> {
+	send_invite(sender_user, user_id, room_id, reason, is_direct, None).await
+}
+
+/// Invites a third party identifier (e.g. an email address) to a room.
+///
+/// - If the identity server knows a Matrix ID for it: invites that user
+/// - Otherwise: stores the invite on the identity server and sends a
+///   `m.room.third_party_invite` event that the user can later claim
+pub(crate) async fn invite_3pid_helper(sender_user: &UserId, room_id: &RoomId, invite: &Invite3pid) -> Result<()> {
+	// Checked before asking the identity server, so the server can't be used
+	// to look up third party identifiers for rooms the sender isn't in
+	if !services()
+		.rooms
+		.state_cache
+		.is_joined(sender_user, room_id)?
+	{
+		return Err(Error::BadRequest(
+			ErrorKind::forbidden(),
+			"You don't have permission to view this room.",
+		));
+	}
+
+	if let Some(user_id) = services().rooms.third_party_invite.lookup(invite).await? {
+		debug!("Third party identifier is bound to {user_id}, sending a regular invite");
+		return invite_helper(sender_user, &user_id, room_id, None, false).await;
+	}
+
+	let (token, content) = services()
+		.rooms
+		.third_party_invite
+		.store_invite(sender_user, room_id, invite)
+		.await?;
+
+	let mutex_state = Arc::clone(
+		services()
+			.globals
+			.roomid_mutex_state
+			.write()
+			.await
+			.entry(room_id.to_owned())
+			.or_default(),
+	);
+	let state_lock = mutex_state.lock().await;
+
+	services()
+		.rooms
+		.timeline
+		.build_and_append_pdu(
+			PduBuilder {
+				event_type: TimelineEventType::RoomThirdPartyInvite,
+				content: to_raw_value(&content).expect("event is valid, we just created it"),
+				unsigned: None,
+				state_key: Some(token),
+				redacts: None,
+			},
+			sender_user,
+			room_id,
+			&state_lock,
+		)
+		.await?;
+
+	drop(state_lock);
+
+	Ok(())
+}
+
+/// Turns a signed third party invite into an `m.room.member` invite event sent
+/// by `sender_user`, who must be one of our users.
+pub(crate) async fn exchange_third_party_invite_helper(
+	sender_user: &UserId, user_id: &UserId, room_id: &RoomId, third_party_invite: ThirdPartyInvite,
+) -> Result<()> {
+	if third_party_invite.signed.mxid != user_id {
+		return Err(Error::BadRequest(
+			ErrorKind::InvalidParam,
+			"Third party invite was signed for a different user.",
+		));
+	}
+
+	let display_name = services()
+		.rooms
+		.third_party_invite
+		.display_name(room_id, &third_party_invite.signed.token)?
+		.ok_or(Error::BadRequest(
+			ErrorKind::NotFound,
+			"No pending third party invite with this token in the room.",
+		))?;
+
+	let third_party_invite = ThirdPartyInvite {
+		display_name,
+		..third_party_invite
+	};
+
+	send_invite(sender_user, user_id, room_id, None, false, Some(third_party_invite)).await
+}
+
+/// Claims a third party invite for one of our users, either directly if the
+/// inviter is local, or by asking the inviter's server to exchange it.
+pub(crate) async fn bind_third_party_invite_helper(
+	sender: &UserId, user_id: &UserId, room_id: &RoomId, signed: CanonicalJsonObject,
+) -> Result<()> {
+	// The room's server replaces this with the display name of the
+	// m.room.third_party_invite event
+	let third_party_invite = services()
+		.rooms
+		.third_party_invite
+		.from_signed(user_id.to_string(), signed)?;
+
+	if sender.server_name() == services().globals.server_name() {
+		return exchange_third_party_invite_helper(sender, user_id, room_id, third_party_invite).await;
+	}
+
+	services()
+		.sending
+		.send_federation_request(
+			sender.server_name(),
+			federation::thirdparty::exchange_invite::v1::Request {
+				room_id: room_id.to_owned(),
+				kind: StateEventType::RoomMember,
+				sender: sender.to_owned(),
+				state_key: user_id.to_owned(),
+				content: third_party_invite,
+			},
+		)
+		.await?;
+
+	Ok(())
+}
+
+async fn send_invite(
+	sender_user: &UserId, user_id: &UserId, room_id: &RoomId, reason: Option<String>, is_direct: bool,
+	third_party_invite: Option<ThirdPartyInvite>,
 ) -> Result<()> {
 	if !services().users.is_admin(user_id)? && services().globals.block_non_admin_invites() {
 		info!("User {sender_user} is not an admin and attempted to send an invite to room {room_id}");
@@ -1299,7 +1462,7 @@ pub(crate) async fn invite_helper(
 				displayname: None,
 				is_direct: Some(is_direct),
 				membership: MembershipState::Invite,
-				third_party_invite: None,
+				third_party_invite: third_party_invite.clone(),
 				blurhash: None,
 				reason,
 				join_authorized_via_users_server: None,
@@ -1404,6 +1567,13 @@ pub(crate) async fn invite_helper(
 		));
 	}
 
+	// Appservices may create their users only once they are needed. Only asked
+	// after the permission check, so users can't make the server query
+	// appservices for rooms they aren't in.
+	if !services().users.exists(user_id)? && !services().appservice.query_user_id(user_id).await? {
+		return Err(Error::BadRequest(ErrorKind::NotFound, "User not found."));
+	}
+
 	let mutex_state = Arc::clone(
 		services()
 			.globals
@@ -1426,7 +1596,7 @@ pub(crate) async fn invite_helper(
 					displayname: services().users.displayname(user_id)?,
 					avatar_url: services().users.avatar_url(user_id)?,
 					is_direct: Some(is_direct),
-					third_party_invite: None,
+					third_party_invite,
 					blurhash: services().users.blurhash(user_id)?,
 					reason,
 					join_authorized_via_users_server: None,
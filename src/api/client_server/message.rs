@@ -8,9 +8,10 @@ use ruma::{
 		error::ErrorKind,
 		filter::{RoomEventFilter, UrlFilter},
 		message::{get_message_events, send_message_event},
+		room::get_event_by_timestamp,
 	},
 	events::{MessageLikeEventType, StateEventType},
-	RoomId, UserId,
+	MilliSecondsSinceUnixEpoch, RoomId, UserId,
 };
 use serde_json::{from_str, Value};
 
@@ -19,6 +20,10 @@ use crate::{
 	services, utils, Error, PduEvent, Result, Ruma,
 };
 
+/// Most events looked at when the event closest to a timestamp is hidden from
+/// the user
+const MAX_TIMESTAMP_WALK: usize = 1000;
+
 /// # `PUT /_matrix/client/v3/rooms/{roomId}/send/{eventType}/{txnId}`
 ///
 /// Send a message event into the room.
@@ -267,6 +272,57 @@ pub async fn get_message_events_route(
 	Ok(resp)
 }
 
+/// # `GET /_matrix/client/v1/rooms/{roomId}/timestamp_to_event`
+///
+/// Returns the event closest to the given timestamp in the given direction.
+///
+/// - Asks other servers in the room if we don't know of any such event or the
+///   closest one borders a gap in our history
+/// - Only returns events the user is allowed to see
+pub async fn get_event_by_timestamp_route(
+	body: Ruma<get_event_by_timestamp::v1::Request>,
+) -> Result<get_event_by_timestamp::v1::Response> {
+	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+	if !services()
+		.rooms
+		.state_accessor
+		.user_can_see_state_events(sender_user, &body.room_id)?
+	{
+		return Err(Error::BadRequest(
+			ErrorKind::forbidden(),
+			"You don't have permission to view this room.",
+		));
+	}
+
+	let pdu = match services()
+		.rooms
+		.timeline
+		.fetch_pdu_by_timestamp(&body.room_id, body.ts, body.dir)
+		.await?
+	{
+		Some(pdu) if visibility_filter(&pdu, sender_user, &body.room_id) => Some(pdu),
+		// Walk on to the closest event the user is allowed to see
+		Some(pdu) => services()
+			.rooms
+			.timeline
+			.pdus_by_timestamp(&body.room_id, MilliSecondsSinceUnixEpoch(pdu.origin_server_ts), body.dir)?
+			.filter_map(Result::ok)
+			.take(MAX_TIMESTAMP_WALK)
+			.find(|pdu| visibility_filter(pdu, sender_user, &body.room_id)),
+		None => None,
+	}
+	.ok_or(Error::BadRequest(
+		ErrorKind::NotFound,
+		"Unable to find an event in the given direction.",
+	))?;
+
+	Ok(get_event_by_timestamp::v1::Response {
+		event_id: pdu.event_id.as_ref().to_owned(),
+		origin_server_ts: MilliSecondsSinceUnixEpoch(pdu.origin_server_ts),
+	})
+}
+
 fn visibility_filter(pdu: &PduEvent, user_id: &UserId, room_id: &RoomId) -> bool {
 	services()
 		.rooms
@@ -1,5 +1,6 @@
 mod account;
 mod alias;
+mod appservice;
 mod backup;
 mod capabilities;
 mod config;
@@ -11,6 +12,7 @@ mod keys;
 mod media;
 mod membership;
 mod message;
+mod openid;
 mod presence;
 mod profile;
 mod push;
@@ -35,6 +37,7 @@ mod voip;
 
 pub use account::*;
 pub use alias::*;
+pub use appservice::*;
 pub use backup::*;
 pub use capabilities::*;
 pub use config::*;
@@ -46,6 +49,7 @@ pub use keys::*;
 pub use media::*;
 pub use membership::*;
 pub use message::*;
+pub use openid::*;
 pub use presence::*;
 pub use profile::*;
 pub use push::*;
@@ -90,7 +90,8 @@ pub async fn set_displayname_route(
 		// Presence update
 		services()
 			.presence
-			.ping_presence(sender_user, &PresenceState::Online)?;
+			.ping_presence(sender_user, &PresenceState::Online)
+			.await?;
 	}
 
 	Ok(set_display_name::v3::Response {})
@@ -141,9 +142,9 @@ pub async fn get_displayname_route(
 		}
 	}
 
-	if !services().users.exists(&body.user_id)? {
+	if !services().users.exists(&body.user_id)? && !services().appservice.query_user_id(&body.user_id).await? {
 		// Return 404 if this user doesn't exist and we couldn't fetch it over
-		// federation
+		// federation or from an appservice
 		return Err(Error::BadRequest(ErrorKind::NotFound, "Profile was not found."));
 	}
 
@@ -230,7 +231,8 @@ pub async fn set_avatar_url_route(body: Ruma<set_avatar_url::v3::Request>) -> Re
 		// Presence update
 		services()
 			.presence
-			.ping_presence(sender_user, &PresenceState::Online)?;
+			.ping_presence(sender_user, &PresenceState::Online)
+			.await?;
 	}
 
 	Ok(set_avatar_url::v3::Response {})
@@ -280,9 +282,9 @@ pub async fn get_avatar_url_route(body: Ruma<get_avatar_url::v3::Request>) -> Re
 		}
 	}
 
-	if !services().users.exists(&body.user_id)? {
+	if !services().users.exists(&body.user_id)? && !services().appservice.query_user_id(&body.user_id).await? {
 		// Return 404 if this user doesn't exist and we couldn't fetch it over
-		// federation
+		// federation or from an appservice
 		return Err(Error::BadRequest(ErrorKind::NotFound, "Profile was not found."));
 	}
 
@@ -337,9 +339,9 @@ pub async fn get_profile_route(body: Ruma<get_profile::v3::Request>) -> Result<g
 		}
 	}
 
-	if !services().users.exists(&body.user_id)? {
+	if !services().users.exists(&body.user_id)? && !services().appservice.query_user_id(&body.user_id).await? {
 		// Return 404 if this user doesn't exist and we couldn't fetch it over
-		// federation
+		// federation or from an appservice
 		return Err(Error::BadRequest(ErrorKind::NotFound, "Profile was not found."));
 	}
 
@@ -1,16 +1,23 @@
+use axum::{
+	extract::Query,
+	response::{Html, IntoResponse},
+};
 use ruma::{
 	api::client::{
 		error::ErrorKind,
 		push::{
-			delete_pushrule, get_pushers, get_pushrule, get_pushrule_actions, get_pushrule_enabled, get_pushrules_all,
-			set_pusher, set_pushrule, set_pushrule_actions, set_pushrule_enabled, RuleScope,
+			delete_pushrule, get_notifications, get_pushers, get_pushrule, get_pushrule_actions, get_pushrule_enabled,
+			get_pushrules_all, set_pusher, set_pushrule, set_pushrule_actions, set_pushrule_enabled, RuleScope,
 		},
 	},
 	events::{push_rules::PushRulesEvent, GlobalAccountDataEventType},
 	push::{InsertPushRuleError, RemovePushRuleError, Ruleset},
+	MilliSecondsSinceUnixEpoch, UInt,
 };
+use serde::Deserialize;
+use tracing::info;
 
-use crate::{services, Error, Result, Ruma};
+use crate::{services, utils::HtmlEscape, Error, Result, Ruma};
 
 /// # `GET /_matrix/client/r0/pushrules/`
 ///
@@ -369,3 +376,107 @@ pub async fn set_pushers_route(body: Ruma<set_pusher::v3::Request>) -> Result<se
 
 	Ok(set_pusher::v3::Response::default())
 }
+
+/// # `GET /_matrix/client/v3/notifications`
+///
+/// Paginates over the events that notified the sender user, newest first.
+///
+/// - `only=highlight` only returns notifications that highlight the user
+/// - A notification is read once the user sent a read receipt in the room after
+///   it arrived
+pub async fn get_notifications_route(
+	body: Ruma<get_notifications::v3::Request>,
+) -> Result<get_notifications::v3::Response> {
+	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+	let until = match body.from.as_ref().map(|from| from.parse()) {
+		Some(Ok(from)) => from,
+		Some(Err(_)) => return Err(Error::BadRequest(ErrorKind::InvalidParam, "Invalid from token.")),
+		None => u64::MAX,
+	};
+
+	// Use limit or else 10, with maximum 100
+	let limit = body.limit.map_or(10, u64::from).min(100) as usize;
+	let only_highlight = body.only.as_deref() == Some("highlight");
+
+	let mut notifications = Vec::new();
+	let mut next_token = None;
+
+	for result in services().pusher.notifications_until(sender_user, until) {
+		let (count, notification) = result?;
+
+		if only_highlight && !notification.highlight {
+			continue;
+		}
+
+		if notifications.len() == limit {
+			next_token = Some((count + 1).to_string());
+			break;
+		}
+
+		// The event may have been purged since
+		let Some(pdu) = services().rooms.timeline.get_pdu(&notification.event_id)? else {
+			continue;
+		};
+
+		let read = services()
+			.rooms
+			.user
+			.last_notification_read(sender_user, &notification.room_id)?
+			> count;
+
+		notifications.push(get_notifications::v3::Notification {
+			actions: notification.actions,
+			event: pdu.to_sync_room_event(),
+			profile_tag: None,
+			read,
+			room_id: notification.room_id,
+			ts: MilliSecondsSinceUnixEpoch(UInt::new_saturating(notification.ts)),
+		});
+	}
+
+	Ok(get_notifications::v3::Response {
+		next_token,
+		notifications,
+	})
+}
+
+#[derive(Deserialize)]
+pub struct EmailUnsubscribeParams {
+	token: String,
+}
+
+/// # `GET /_conduwuit/email/unsubscribe`
+///
+/// Asks to confirm removing the email pusher the unsubscribe link was sent
+/// for. Link scanners of mail providers open every link, so this doesn't
+/// change anything.
+pub async fn email_unsubscribe_page_route(Query(params): Query<EmailUnsubscribeParams>) -> Result<impl IntoResponse> {
+	if services()
+		.pusher
+		.find_from_unsubscribe_token(&params.token)?
+		.is_none()
+	{
+		return Err(Error::BadRequest(ErrorKind::NotFound, "Unknown or expired unsubscribe link."));
+	}
+
+	Ok(Html(format!(
+		"<p>Do you want to stop receiving email notifications?</p>\n<form method=\"post\" \
+		 action=\"?token={}\"><button type=\"submit\">Unsubscribe</button></form>",
+		HtmlEscape(&params.token)
+	)))
+}
+
+/// # `POST /_conduwuit/email/unsubscribe`
+///
+/// Removes the email pusher the unsubscribe link was sent for. Mail clients
+/// supporting one-click unsubscribe (RFC 8058) post here directly.
+pub async fn email_unsubscribe_route(Query(params): Query<EmailUnsubscribeParams>) -> Result<impl IntoResponse> {
+	let Some(user) = services().pusher.unsubscribe_email(&params.token)? else {
+		return Err(Error::BadRequest(ErrorKind::NotFound, "Unknown or expired unsubscribe link."));
+	};
+
+	info!("{user} unsubscribed from email notifications");
+
+	Ok(Html("<p>You will no longer receive email notifications.</p>"))
+}
@@ -79,14 +79,18 @@ pub async fn set_read_marker_route(body: Ruma<set_read_marker::v3::Request>) ->
 		let mut receipt_content = BTreeMap::new();
 		receipt_content.insert(event.to_owned(), receipts);
 
-		services().rooms.read_receipt.readreceipt_update(
-			sender_user,
-			&body.room_id,
-			ruma::events::receipt::ReceiptEvent {
-				content: ruma::events::receipt::ReceiptEventContent(receipt_content),
-				room_id: body.room_id.clone(),
-			},
-		)?;
+		services()
+			.rooms
+			.read_receipt
+			.readreceipt_update(
+				sender_user,
+				&body.room_id,
+				ruma::events::receipt::ReceiptEvent {
+					content: ruma::events::receipt::ReceiptEventContent(receipt_content),
+					room_id: body.room_id.clone(),
+				},
+			)
+			.await?;
 	}
 
 	Ok(set_read_marker::v3::Response {})
@@ -95,6 +99,8 @@ pub async fn set_read_marker_route(body: Ruma<set_read_marker::v3::Request>) ->
 /// # `POST /_matrix/client/r0/rooms/{roomId}/receipt/{receiptType}/{eventId}`
 ///
 /// Sets private read marker and public read receipt EDU.
+///
+/// - Threaded receipts only mark the thread (or the main timeline) as read
 pub async fn create_receipt_route(body: Ruma<create_receipt::v3::Request>) -> Result<create_receipt::v3::Response> {
 	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
@@ -102,10 +108,24 @@ pub async fn create_receipt_route(body: Ruma<create_receipt::v3::Request>) -> Re
 		&body.receipt_type,
 		create_receipt::v3::ReceiptType::Read | create_receipt::v3::ReceiptType::ReadPrivate
 	) {
-		services()
-			.rooms
-			.user
-			.reset_notification_counts(sender_user, &body.room_id)?;
+		match &body.thread {
+			ReceiptThread::Main => {
+				services()
+					.rooms
+					.user
+					.reset_thread_notification_counts(sender_user, &body.room_id, None)?
+			},
+			ReceiptThread::Thread(thread_id) => {
+				services()
+					.rooms
+					.user
+					.reset_thread_notification_counts(sender_user, &body.room_id, Some(thread_id))?
+			},
+			_ => services()
+				.rooms
+				.user
+				.reset_notification_counts(sender_user, &body.room_id)?,
+		}
 	}
 
 	match body.receipt_type {
@@ -128,7 +148,7 @@ pub async fn create_receipt_route(body: Ruma<create_receipt::v3::Request>) -> Re
 				sender_user.clone(),
 				ruma::events::receipt::Receipt {
 					ts: Some(MilliSecondsSinceUnixEpoch::now()),
-					thread: ReceiptThread::Unthreaded,
+					thread: body.thread.clone(),
 				},
 			);
 			let mut receipts = BTreeMap::new();
@@ -137,14 +157,18 @@ pub async fn create_receipt_route(body: Ruma<create_receipt::v3::Request>) -> Re
 			let mut receipt_content = BTreeMap::new();
 			receipt_content.insert(body.event_id.clone(), receipts);
 
-			services().rooms.read_receipt.readreceipt_update(
-				sender_user,
-				&body.room_id,
-				ruma::events::receipt::ReceiptEvent {
-					content: ruma::events::receipt::ReceiptEventContent(receipt_content),
-					room_id: body.room_id.clone(),
-				},
-			)?;
+			services()
+				.rooms
+				.read_receipt
+				.readreceipt_update(
+					sender_user,
+					&body.room_id,
+					ruma::events::receipt::ReceiptEvent {
+						content: ruma::events::receipt::ReceiptEventContent(receipt_content),
+						room_id: body.room_id.clone(),
+					},
+				)
+				.await?;
 		},
 		create_receipt::v3::ReceiptType::ReadPrivate => {
 			let count = services()
@@ -27,7 +27,11 @@ use ruma::{
 use serde_json::{json, value::to_raw_value};
 use tracing::{debug, error, info, warn};
 
-use crate::{api::client_server::invite_helper, service::pdu::PduBuilder, services, Error, Result, Ruma};
+use crate::{
+	api::client_server::{check_alias_namespace, invite_3pid_helper, invite_helper},
+	service::pdu::PduBuilder,
+	services, Error, Result, Ruma,
+};
 
 /// # `POST /_matrix/client/v3/createRoom`
 ///
@@ -184,6 +188,10 @@ pub async fn create_room_route(body: Ruma<create_room::v3::Request>) -> Result<c
 			}
 		})?;
 
+	if let Some(alias) = &alias {
+		check_alias_namespace(alias, body.appservice_info.as_ref()).await?;
+	}
+
 	let room_version = match body.room_version.clone() {
 		Some(room_version) => {
 			if services()
@@ -553,12 +561,18 @@ pub async fn create_room_route(body: Ruma<create_room::v3::Request>) -> Result<c
 			.await?;
 	}
 
-	// 8. Events implied by invite (and TODO: invite_3pid)
+	// 8. Events implied by invite and invite_3pid
 	drop(state_lock);
 	for user_id in &body.invite {
 		_ = invite_helper(sender_user, user_id, &room_id, None, body.is_direct).await;
 	}
 
+	for invite in &body.invite_3pid {
+		if let Err(e) = invite_3pid_helper(sender_user, &room_id, invite).await {
+			warn!("Failed to send third party invite to {} in {room_id}: {e}", invite.address);
+		}
+	}
+
 	// Homeserver specific stuff
 	if let Some(alias) = alias {
 		services().rooms.alias.set_alias(&alias, &room_id)?;
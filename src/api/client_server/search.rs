@@ -5,12 +5,15 @@ use ruma::{
 		error::ErrorKind,
 		search::search_events::{
 			self,
-			v3::{EventContextResult, ResultCategories, ResultRoomEvents, SearchResult},
+			v3::{
+				EventContextResult, GroupingKey, OrderBy, ResultCategories, ResultGroup, ResultRoomEvents,
+				RoomIdOrUserId, SearchResult,
+			},
 		},
 	},
 	events::AnyStateEvent,
 	serde::Raw,
-	OwnedRoomId,
+	OwnedRoomId, UInt,
 };
 use tracing::debug;
 
@@ -20,8 +23,10 @@ use crate::{services, Error, Result, Ruma};
 ///
 /// Searches rooms for messages.
 ///
-/// - Only works if the user is currently joined to the room (TODO: Respect
-///   history visibility)
+/// - Searches all rooms the user is or was joined to unless the filter lists
+///   rooms, only returning events the user is allowed to see
+/// - Results are ranked by relevance (BM25) unless `order_by` is `recent`
+/// - Supports `"phrase queries"` and `prefix*` queries
 pub async fn search_events_route(body: Ruma<search_events::v3::Request>) -> Result<search_events::v3::Response> {
 	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
@@ -29,154 +34,190 @@ pub async fn search_events_route(body: Ruma<search_events::v3::Request>) -> Resu
 	let filter = &search_criteria.filter;
 	let include_state = &search_criteria.include_state;
 
-	let room_ids = filter.rooms.clone().unwrap_or_else(|| {
-		services()
-			.rooms
-			.state_cache
-			.rooms_joined(sender_user)
-			.filter_map(Result::ok)
-			.collect()
-	});
+	let room_ids: Vec<OwnedRoomId> = filter
+		.rooms
+		.clone()
+		.unwrap_or_else(|| {
+			services()
+				.rooms
+				.state_cache
+				.rooms_joined(sender_user)
+				.filter_map(Result::ok)
+				.chain(
+					services()
+						.rooms
+						.state_cache
+						.rooms_left(sender_user)
+						.filter_map(Result::ok)
+						.map(|(room_id, _)| room_id),
+				)
+				.collect()
+		})
+		.into_iter()
+		.filter(|room_id| !filter.not_rooms.contains(room_id))
+		.collect();
 
 	// Use limit or else 10, with maximum 100
 	let limit = filter.limit.map_or(10, u64::from).min(100) as usize;
 
+	for room_id in &room_ids {
+		if !services()
+			.rooms
+			.state_cache
+			.once_joined(sender_user, room_id)?
+			&& !services()
+				.rooms
+				.state_accessor
+				.user_can_see_state_events(sender_user, room_id)?
+		{
+			return Err(Error::BadRequest(
+				ErrorKind::forbidden(),
+				"You don't have permission to view this room.",
+			));
+		}
+	}
+
 	let mut room_states: BTreeMap<OwnedRoomId, Vec<Raw<AnyStateEvent>>> = BTreeMap::new();
 
 	if include_state.is_some_and(|include_state| include_state) {
 		for room_id in &room_ids {
+			// Rooms the user left are still searched, but their current state is not
+			// theirs to see
 			if !services()
 				.rooms
-				.state_cache
-				.is_joined(sender_user, room_id)?
+				.state_accessor
+				.user_can_see_state_events(sender_user, room_id)?
 			{
-				return Err(Error::BadRequest(
-					ErrorKind::forbidden(),
-					"You don't have permission to view this room.",
-				));
+				continue;
 			}
 
-			// check if sender_user can see state events
-			if services()
+			let room_state = services()
 				.rooms
 				.state_accessor
-				.user_can_see_state_events(sender_user, room_id)?
-			{
-				let room_state = services()
-					.rooms
-					.state_accessor
-					.room_state_full(room_id)
-					.await?
-					.values()
-					.map(|pdu| pdu.to_state_event())
-					.collect::<Vec<_>>();
-
-				debug!("Room state: {:?}", room_state);
-
-				room_states.insert(room_id.clone(), room_state);
-			} else {
-				return Err(Error::BadRequest(
-					ErrorKind::forbidden(),
-					"You don't have permission to view this room.",
-				));
-			}
+				.room_state_full(room_id)
+				.await?
+				.values()
+				.map(|pdu| pdu.to_state_event())
+				.collect::<Vec<_>>();
+
+			debug!("Room state: {:?}", room_state);
+
+			room_states.insert(room_id.clone(), room_state);
 		}
 	}
 
-	let mut searches = Vec::new();
+	let mut hits = Vec::new();
+	let mut highlights = Vec::new();
 
 	for room_id in &room_ids {
-		if !services()
-			.rooms
-			.state_cache
-			.is_joined(sender_user, room_id)?
-		{
-			return Err(Error::BadRequest(
-				ErrorKind::forbidden(),
-				"You don't have permission to view this room.",
-			));
-		}
-
-		if let Some(search) = services()
+		if let Some((room_hits, room_highlights)) = services()
 			.rooms
 			.search
 			.search_pdus(room_id, &search_criteria.search_term)?
 		{
-			searches.push(search.0.peekable());
+			hits.extend(room_hits);
+			highlights = room_highlights;
 		}
 	}
 
+	match search_criteria.order_by {
+		Some(OrderBy::Recent) => hits.sort_unstable_by(|a, b| b.count.cmp(&a.count)),
+		_ => hits.sort_unstable_by(|a, b| b.rank.total_cmp(&a.rank).then(b.count.cmp(&a.count))),
+	}
+
 	let skip = match body.next_batch.as_ref().map(|s| s.parse()) {
 		Some(Ok(s)) => s,
 		Some(Err(_)) => return Err(Error::BadRequest(ErrorKind::InvalidParam, "Invalid next_batch token.")),
 		None => 0, // Default to the start
 	};
 
-	let mut results = Vec::new();
-	for _ in 0..skip + limit {
-		if let Some(s) = searches
-			.iter_mut()
-			.map(|s| (s.peek().cloned(), s))
-			.max_by_key(|(peek, _)| peek.clone())
-			.and_then(|(_, i)| i.next())
-		{
-			results.push(s);
-		}
-	}
-
-	let results: Vec<_> = results
+	let visible: Vec<_> = hits
 		.iter()
-		.filter_map(|result| {
-			services()
+		.filter_map(|hit| {
+			let pdu = services()
 				.rooms
 				.timeline
-				.get_pdu_from_id(result)
-				.ok()?
-				.filter(|pdu| {
-					services()
-						.rooms
-						.state_accessor
-						.user_can_see_event(sender_user, &pdu.room_id, &pdu.event_id)
-						.unwrap_or(false)
-				})
-				.map(|pdu| pdu.to_room_event())
-		})
-		.map(|result| {
-			Ok::<_, Error>(SearchResult {
-				context: EventContextResult {
-					end: None,
-					events_after: Vec::new(),
-					events_before: Vec::new(),
-					profile_info: BTreeMap::new(),
-					start: None,
-				},
-				rank: None,
-				result: Some(result),
-			})
+				.get_pdu_from_id(&hit.pdu_id)
+				.ok()??;
+
+			if filter
+				.senders
+				.as_ref()
+				.is_some_and(|senders| !senders.contains(&pdu.sender))
+				|| filter.not_senders.contains(&pdu.sender)
+			{
+				return None;
+			}
+
+			services()
+				.rooms
+				.state_accessor
+				.user_can_see_event(sender_user, &pdu.room_id, &pdu.event_id)
+				.unwrap_or(false)
+				.then_some((hit.rank, pdu))
 		})
-		.filter_map(Result::ok)
-		.skip(skip)
-		.take(limit)
 		.collect();
 
+	let count = visible.len();
+
+	let results: Vec<_> = visible.into_iter().skip(skip).take(limit).collect();
+
+	let mut groups: BTreeMap<GroupingKey, BTreeMap<RoomIdOrUserId, ResultGroup>> = BTreeMap::new();
+	for grouping in &search_criteria.groupings.group_by {
+		let Some(key) = &grouping.key else {
+			continue;
+		};
+
+		let group = groups.entry(key.clone()).or_default();
+		for (_, pdu) in &results {
+			let id = match key {
+				GroupingKey::RoomId => RoomIdOrUserId::RoomId(pdu.room_id.clone()),
+				GroupingKey::Sender => RoomIdOrUserId::UserId(pdu.sender.clone()),
+				_ => continue,
+			};
+
+			let order = UInt::try_from(group.len() + 1).ok();
+			group
+				.entry(id)
+				.or_insert_with(|| ResultGroup {
+					next_batch: None,
+					order,
+					results: Vec::new(),
+				})
+				.results
+				.push(pdu.event_id.as_ref().to_owned());
+		}
+	}
+
 	let next_batch = if results.len() < limit {
 		None
 	} else {
 		Some((skip + limit).to_string())
 	};
 
+	let results = results
+		.into_iter()
+		.map(|(rank, pdu)| SearchResult {
+			context: EventContextResult {
+				end: None,
+				events_after: Vec::new(),
+				events_before: Vec::new(),
+				profile_info: BTreeMap::new(),
+				start: None,
+			},
+			rank: Some(rank),
+			result: Some(pdu.to_room_event()),
+		})
+		.collect();
+
 	Ok(search_events::v3::Response::new(ResultCategories {
 		room_events: ResultRoomEvents {
-			count: Some((results.len() as u32).into()),
-			groups: BTreeMap::new(), // TODO
+			count: Some((count as u32).into()),
+			groups,
 			next_batch,
 			results,
 			state: room_states,
-			highlights: search_criteria
-				.search_term
-				.split_terminator(|c: char| !c.is_alphanumeric())
-				.map(str::to_lowercase)
-				.collect(),
+			highlights,
 		},
 	}))
 }
@@ -144,7 +144,7 @@ pub async fn login_route(body: Ruma<login::v3::Request>) -> Result<login::v3::Re
 			user,
 		}) => {
 			debug!("Got appservice login type");
-			if !body.from_appservice {
+			let Some(info) = &body.appservice_info else {
 				return Err(Error::BadRequest(ErrorKind::MissingToken, "Missing Appservice token."));
 			};
 			let username = if let Some(UserIdentifier::UserIdOrLocalpart(user_id)) = identifier {
@@ -161,10 +161,16 @@ pub async fn login_route(body: Ruma<login::v3::Request>) -> Result<login::v3::Re
 				return Err(Error::BadRequest(ErrorKind::forbidden(), "Bad login type."));
 			};
 
-			UserId::parse_with_server_name(username, services().globals.server_name()).map_err(|e| {
+			let user_id = UserId::parse_with_server_name(username, services().globals.server_name()).map_err(|e| {
 				warn!("Failed to parse username from appservice logging in: {}", e);
 				Error::BadRequest(ErrorKind::InvalidUsername, "Username is invalid.")
-			})?
+			})?;
+
+			if !info.is_user_match(&user_id) {
+				return Err(Error::BadRequest(ErrorKind::Exclusive, "User is not in namespace."));
+			}
+
+			user_id
 		},
 		_ => {
 			warn!("Unsupported or unknown login type: {:?}", &body.login_info);
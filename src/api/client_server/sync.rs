@@ -5,6 +5,7 @@ use std::{
 	time::Duration,
 };
 
+use axum::extract::Query;
 use ruma::{
 	api::client::{
 		filter::{FilterDefinition, LazyLoadOptions},
@@ -14,24 +15,35 @@ use ruma::{
 				Ephemeral, Filter, GlobalAccountData, InviteState, InvitedRoom, JoinedRoom, LeftRoom, Presence,
 				RoomAccountData, RoomSummary, Rooms, State, Timeline, ToDevice,
 			},
-			v4::SlidingOp,
+			v4::{RoomReceiptConfig, SlidingOp},
 			DeviceLists, UnreadNotificationsCount,
 		},
 		uiaa::UiaaResponse,
 	},
 	events::{
 		presence::PresenceEvent,
+		receipt::{ReceiptEventContent, SyncReceiptEvent},
 		room::member::{MembershipState, RoomMemberEventContent},
 		StateEventType, TimelineEventType,
 	},
 	serde::Raw,
-	uint, DeviceId, OwnedDeviceId, OwnedUserId, RoomId, UInt, UserId,
+	uint, DeviceId, OwnedDeviceId, OwnedRoomId, OwnedUserId, RoomId, UInt, UserId,
 };
+use serde::Deserialize;
 use tokio::sync::watch::Sender;
-use tracing::{debug, error};
+use tracing::{debug, error, warn};
 
 use crate::{service::rooms::timeline::PduCount, services, Error, PduEvent, Result, Ruma, RumaResponse};
 
+/// Query parameters of `/sync` that are not part of the specification.
+#[derive(Deserialize)]
+pub struct SyncStreamParams {
+	/// Identifies an additional consumer of the device. Every stream keeps its
+	/// own to-device position, the default stream is the one without an id.
+	#[serde(rename = "org.conduwuit.sync_stream")]
+	stream: Option<String>,
+}
+
 /// # `GET /_matrix/client/r0/sync`
 ///
 /// Synchronize the client's state with the latest state on the server.
@@ -61,7 +73,9 @@ use crate::{service::rooms::timeline::PduCount, services, Error, PduEvent, Resul
 /// - If there are events in the timeline we send or the user send updated his
 ///   read mark: Notification counts
 /// - EDUs that are active now (read receipts, typing updates, presence)
-/// - TODO: Allow multiple sync streams to support Pantalaimon
+/// - Consumers sharing a device (e.g. Pantalaimon and the client behind it) can
+///   pass `org.conduwuit.sync_stream` to receive to-device events independently
+///   of each other
 ///
 /// For invited rooms:
 /// - If the user was invited after `since`: A subset of the state of the room
@@ -75,25 +89,30 @@ use crate::{service::rooms::timeline::PduCount, services, Error, PduEvent, Resul
 ///   with the same
 /// `since` will be cached
 pub async fn sync_events_route(
-	body: Ruma<sync_events::v3::Request>,
+	Query(params): Query<SyncStreamParams>, body: Ruma<sync_events::v3::Request>,
 ) -> Result<sync_events::v3::Response, RumaResponse<UiaaResponse>> {
 	let sender_user = body.sender_user.expect("user is authenticated");
 	let sender_device = body.sender_device.expect("user is authenticated");
 	let body = body.body;
+	let stream = params.stream.filter(|stream| !stream.is_empty());
 
-	let mut rx = match services()
-		.globals
-		.sync_receivers
-		.write()
-		.await
-		.entry((sender_user.clone(), sender_device.clone()))
-	{
+	let mut rx = match services().globals.sync_receivers.write().await.entry((
+		sender_user.clone(),
+		sender_device.clone(),
+		stream.clone(),
+	)) {
 		Entry::Vacant(v) => {
 			let (tx, rx) = tokio::sync::watch::channel(None);
 
 			v.insert((body.since.clone(), rx.clone()));
 
-			tokio::spawn(sync_helper_wrapper(sender_user.clone(), sender_device.clone(), body, tx));
+			tokio::spawn(sync_helper_wrapper(
+				sender_user.clone(),
+				sender_device.clone(),
+				stream.clone(),
+				body,
+				tx,
+			));
 
 			rx
 		},
@@ -105,7 +124,13 @@ pub async fn sync_events_route(
 
 				debug!("Sync started for {sender_user}");
 
-				tokio::spawn(sync_helper_wrapper(sender_user.clone(), sender_device.clone(), body, tx));
+				tokio::spawn(sync_helper_wrapper(
+					sender_user.clone(),
+					sender_device.clone(),
+					stream.clone(),
+					body,
+					tx,
+				));
 
 				rx
 			} else {
@@ -134,12 +159,12 @@ pub async fn sync_events_route(
 }
 
 async fn sync_helper_wrapper(
-	sender_user: OwnedUserId, sender_device: OwnedDeviceId, body: sync_events::v3::Request,
+	sender_user: OwnedUserId, sender_device: OwnedDeviceId, stream: Option<String>, body: sync_events::v3::Request,
 	tx: Sender<Option<Result<sync_events::v3::Response>>>,
 ) {
 	let since = body.since.clone();
 
-	let r = sync_helper(sender_user.clone(), sender_device.clone(), body).await;
+	let r = sync_helper(sender_user.clone(), sender_device.clone(), stream.clone(), body).await;
 
 	if let Ok((_, caching_allowed)) = r {
 		if !caching_allowed {
@@ -148,7 +173,7 @@ async fn sync_helper_wrapper(
 				.sync_receivers
 				.write()
 				.await
-				.entry((sender_user, sender_device))
+				.entry((sender_user, sender_device, stream))
 			{
 				Entry::Occupied(o) => {
 					// Only remove if the device didn't start a different /sync already
@@ -167,6 +192,7 @@ async fn sync_helper_wrapper(
 async fn sync_helper(
 	sender_user: OwnedUserId,
 	sender_device: OwnedDeviceId,
+	stream: Option<String>,
 	body: sync_events::v3::Request,
 	// bool = caching allowed
 ) -> Result<(sync_events::v3::Response, bool), Error> {
@@ -174,7 +200,8 @@ async fn sync_helper(
 	if services().globals.allow_local_presence() {
 		services()
 			.presence
-			.ping_presence(&sender_user, &body.set_presence)?;
+			.ping_presence(&sender_user, &body.set_presence)
+			.await?;
 	}
 
 	// Setup watchers, so if there's no response, we can wait for them
@@ -201,6 +228,14 @@ async fn sync_helper(
 		LazyLoadOptions::Disabled => (false, false),
 	};
 
+	// Which members were sent is remembered per device, so only the default stream
+	// may rely on it and additional streams always get the members they need
+	let lazy_load_track = stream.is_none();
+	let lazy_load_send_redundant = lazy_load_send_redundant || !lazy_load_track;
+
+	// Clients that understand threads get separate counts for them (MSC3773)
+	let thread_notifications = filter.room.timeline.unread_thread_notifications;
+
 	let full_state = body.full_state;
 
 	let mut joined_rooms = BTreeMap::new();
@@ -249,6 +284,8 @@ async fn sync_helper(
 			next_batchcount,
 			lazy_load_enabled,
 			lazy_load_send_redundant,
+			lazy_load_track,
+			thread_notifications,
 			full_state,
 			&mut device_list_updates,
 			&mut left_encrypted_users,
@@ -459,7 +496,7 @@ async fn sync_helper(
 	// Remove all to-device events the device received *last time*
 	services()
 		.users
-		.remove_to_device_events(&sender_user, &sender_device, since)?;
+		.remove_to_device_events(&sender_user, &sender_device, stream.as_deref(), since)?;
 
 	let response = sync_events::v3::Response {
 		next_batch: next_batch_string,
@@ -497,10 +534,13 @@ async fn sync_helper(
 		to_device: ToDevice {
 			events: services()
 				.users
-				.get_to_device_events(&sender_user, &sender_device)?,
+				.get_to_device_events(&sender_user, &sender_device, stream.as_deref())?,
 		},
-		// Fallback keys are not yet supported
-		device_unused_fallback_key_types: None,
+		device_unused_fallback_key_types: Some(
+			services()
+				.users
+				.unused_fallback_key_types(&sender_user, &sender_device)?,
+		),
 	};
 
 	// TODO: Retry the endpoint instead of returning
@@ -572,7 +612,8 @@ async fn process_presence_updates(
 async fn load_joined_room(
 	sender_user: &UserId, sender_device: &DeviceId, room_id: &RoomId, since: u64, sincecount: PduCount,
 	next_batch: u64, next_batchcount: PduCount, lazy_load_enabled: bool, lazy_load_send_redundant: bool,
-	full_state: bool, device_list_updates: &mut HashSet<OwnedUserId>, left_encrypted_users: &mut HashSet<OwnedUserId>,
+	lazy_load_track: bool, thread_notifications: bool, full_state: bool,
+	device_list_updates: &mut HashSet<OwnedUserId>, left_encrypted_users: &mut HashSet<OwnedUserId>,
 ) -> Result<JoinedRoom> {
 	{
 		// Get and drop the lock to wait for remaining operations to finish
@@ -604,11 +645,13 @@ async fn load_joined_room(
 		timeline_users.insert(event.sender.as_str().to_owned());
 	}
 
-	services()
-		.rooms
-		.lazy_loading
-		.lazy_load_confirm_delivery(sender_user, sender_device, room_id, sincecount)
-		.await?;
+	if lazy_load_track {
+		services()
+			.rooms
+			.lazy_loading
+			.lazy_load_confirm_delivery(sender_user, sender_device, room_id, sincecount)
+			.await?;
+	}
 
 	// Database queries:
 
@@ -764,19 +807,21 @@ async fn load_joined_room(
 					}
 				}
 
-				// Reset lazy loading because this is an initial sync
-				services()
-					.rooms
-					.lazy_loading
-					.lazy_load_reset(sender_user, sender_device, room_id)?;
+				if lazy_load_track {
+					// Reset lazy loading because this is an initial sync
+					services()
+						.rooms
+						.lazy_loading
+						.lazy_load_reset(sender_user, sender_device, room_id)?;
 
-				// The state_events above should contain all timeline_users, let's mark them as
-				// lazy loaded.
-				services()
-					.rooms
-					.lazy_loading
-					.lazy_load_mark_sent(sender_user, sender_device, room_id, lazy_loaded, next_batchcount)
-					.await;
+					// The state_events above should contain all timeline_users, let's mark them as
+					// lazy loaded.
+					services()
+						.rooms
+						.lazy_loading
+						.lazy_load_mark_sent(sender_user, sender_device, room_id, lazy_loaded, next_batchcount)
+						.await;
+				}
 
 				(heroes, joined_member_count, invited_member_count, true, state_events)
 			} else {
@@ -830,13 +875,13 @@ async fn load_joined_room(
 						continue;
 					}
 
-					if !services().rooms.lazy_loading.lazy_load_was_sent_before(
-						sender_user,
-						sender_device,
-						room_id,
-						&event.sender,
-					)? || lazy_load_send_redundant
-					{
+					if lazy_load_send_redundant
+						|| !services().rooms.lazy_loading.lazy_load_was_sent_before(
+							sender_user,
+							sender_device,
+							room_id,
+							&event.sender,
+						)? {
 						if let Some(member_event) = services().rooms.state_accessor.room_state_get(
 							room_id,
 							&StateEventType::RoomMember,
@@ -848,11 +893,13 @@ async fn load_joined_room(
 					}
 				}
 
-				services()
-					.rooms
-					.lazy_loading
-					.lazy_load_mark_sent(sender_user, sender_device, room_id, lazy_loaded, next_batchcount)
-					.await;
+				if lazy_load_track {
+					services()
+						.rooms
+						.lazy_loading
+						.lazy_load_mark_sent(sender_user, sender_device, room_id, lazy_loaded, next_batchcount)
+						.await;
+				}
 
 				let encrypted_room = services()
 					.rooms
@@ -953,12 +1000,31 @@ async fn load_joined_room(
 			.filter_map(Result::ok),
 	);
 
+	let thread_counts = if send_notification_counts && thread_notifications {
+		services()
+			.rooms
+			.user
+			.thread_notification_counts(sender_user, room_id)?
+	} else {
+		BTreeMap::new()
+	};
+
+	// The room counts include all threads, but the client expects them to only
+	// cover the main timeline if it gets the thread counts separately
+	let (thread_notification_count, thread_highlight_count) = thread_counts.values().fold(
+		(0, 0),
+		|(notifications, highlights), (thread_notifications, thread_highlights)| {
+			(notifications + thread_notifications, highlights + thread_highlights)
+		},
+	);
+
 	let notification_count = if send_notification_counts {
 		Some(
 			services()
 				.rooms
 				.user
 				.notification_count(sender_user, room_id)?
+				.saturating_sub(thread_notification_count)
 				.try_into()
 				.expect("notification count can't go that high"),
 		)
@@ -972,6 +1038,7 @@ async fn load_joined_room(
 				.rooms
 				.user
 				.highlight_count(sender_user, room_id)?
+				.saturating_sub(thread_highlight_count)
 				.try_into()
 				.expect("highlight count can't go that high"),
 		)
@@ -979,6 +1046,27 @@ async fn load_joined_room(
 		None
 	};
 
+	let unread_thread_notifications = thread_counts
+		.into_iter()
+		.map(|(thread_id, (notification_count, highlight_count))| {
+			(
+				thread_id,
+				UnreadNotificationsCount {
+					highlight_count: Some(
+						highlight_count
+							.try_into()
+							.expect("highlight count can't go that high"),
+					),
+					notification_count: Some(
+						notification_count
+							.try_into()
+							.expect("notification count can't go that high"),
+					),
+				},
+			)
+		})
+		.collect();
+
 	let prev_batch = timeline_pdus
 		.first()
 		.map_or(Ok::<_, Error>(None), |(pdu_count, _)| {
@@ -1057,7 +1145,7 @@ async fn load_joined_room(
 		ephemeral: Ephemeral {
 			events: edus,
 		},
-		unread_thread_notifications: BTreeMap::new(),
+		unread_thread_notifications,
 	})
 }
 
@@ -1170,7 +1258,7 @@ pub async fn sync_events_v4_route(
 	if body.extensions.to_device.enabled.unwrap_or(false) {
 		services()
 			.users
-			.remove_to_device_events(&sender_user, &sender_device, globalsince)?;
+			.remove_to_device_events(&sender_user, &sender_device, None, globalsince)?;
 	}
 
 	let mut left_encrypted_users = HashSet::new(); // Users that have left any encrypted rooms the sender was in
@@ -1339,6 +1427,7 @@ pub async fn sync_events_v4_route(
 
 	let mut lists = BTreeMap::new();
 	let mut todo_rooms = BTreeMap::new(); // and required state
+	let mut list_rooms = BTreeMap::new(); // for the extensions' list filters
 
 	for (list_id, list) in body.lists {
 		if list.filters.and_then(|f| f.is_invite).unwrap_or(false) {
@@ -1395,6 +1484,8 @@ pub async fn sync_events_v4_route(
 			},
 		);
 
+		list_rooms.insert(list_id.clone(), new_known_rooms.clone());
+
 		if let Some(conn_id) = &body.conn_id {
 			services().users.update_sync_known_rooms(
 				sender_user.clone(),
@@ -1434,6 +1525,8 @@ pub async fn sync_events_v4_route(
 		body.room_subscriptions.remove(&r);
 	}
 
+	let subscription_rooms = known_subscription_rooms.clone();
+
 	if let Some(conn_id) = &body.conn_id {
 		services().users.update_sync_known_rooms(
 			sender_user.clone(),
@@ -1452,6 +1545,15 @@ pub async fn sync_events_v4_route(
 			conn_id.clone(),
 			body.room_subscriptions,
 		);
+
+		// Persist the sticky parameters so the connection survives restarts
+		if let Err(e) =
+			services()
+				.users
+				.save_sync_request_connection(sender_user.clone(), sender_device.clone(), conn_id.clone())
+		{
+			warn!("Failed to persist sliding sync connection {conn_id}: {e}");
+		}
 	}
 
 	let mut rooms = BTreeMap::new();
@@ -1460,7 +1562,15 @@ pub async fn sync_events_v4_route(
 
 		let (timeline_pdus, limited) = load_timeline(&sender_user, room_id, roomsincecount, *timeline_limit)?;
 
-		if roomsince != &0 && timeline_pdus.is_empty() {
+		// Still send rooms whose notification counts were reset by a read receipt
+		if roomsince != &0
+			&& timeline_pdus.is_empty()
+			&& services()
+				.rooms
+				.user
+				.last_notification_read(&sender_user, room_id)?
+				<= *roomsince
+		{
 			continue;
 		}
 
@@ -1609,9 +1719,109 @@ pub async fn sync_events_v4_route(
 		);
 	}
 
+	let response_rooms = todo_rooms.keys().cloned().collect::<BTreeSet<_>>();
+
+	let mut account_data_rooms = BTreeMap::new();
+	if body.extensions.account_data.enabled.unwrap_or(false) {
+		for room_id in extension_rooms(
+			&response_rooms,
+			&list_rooms,
+			body.extensions.account_data.lists.as_deref(),
+			body.extensions.account_data.rooms.as_deref(),
+		) {
+			let events: Vec<_> = services()
+				.account_data
+				.changes_since(Some(&room_id), &sender_user, globalsince)?
+				.into_iter()
+				.filter_map(|(_, v)| {
+					serde_json::from_str(v.json().get())
+						.map_err(|_| Error::bad_database("Invalid account event in database."))
+						.ok()
+				})
+				.collect();
+
+			if !events.is_empty() {
+				account_data_rooms.insert(room_id, events);
+			}
+		}
+	}
+
+	let mut receipt_rooms = BTreeMap::new();
+	if body.extensions.receipts.enabled.unwrap_or(false) {
+		let requested_rooms = body.extensions.receipts.rooms.as_ref().map(|rooms| {
+			rooms
+				.iter()
+				.flat_map(|room| match room {
+					RoomReceiptConfig::AllSubscribed => subscription_rooms.iter().cloned().collect(),
+					RoomReceiptConfig::Room(room_id) => vec![room_id.clone()],
+				})
+				.collect::<Vec<_>>()
+		});
+
+		for room_id in extension_rooms(
+			&response_rooms,
+			&list_rooms,
+			body.extensions.receipts.lists.as_deref(),
+			requested_rooms.as_deref(),
+		) {
+			let mut content = ReceiptEventContent(BTreeMap::new());
+			for (_, _, event) in services()
+				.rooms
+				.read_receipt
+				.readreceipts_since(&room_id, globalsince)
+				.filter_map(Result::ok)
+			{
+				let Ok(event) = event.deserialize_as::<SyncReceiptEvent>() else {
+					continue;
+				};
+
+				for (event_id, receipts) in event.content.0 {
+					let event_receipts = content.0.entry(event_id).or_default();
+					for (receipt_type, user_receipts) in receipts {
+						event_receipts
+							.entry(receipt_type)
+							.or_default()
+							.extend(user_receipts);
+					}
+				}
+			}
+
+			if !content.0.is_empty() {
+				receipt_rooms.insert(
+					room_id,
+					Raw::new(&SyncReceiptEvent {
+						content,
+					})
+					.expect("receipt event is valid json"),
+				);
+			}
+		}
+	}
+
+	let mut typing_rooms = BTreeMap::new();
+	if body.extensions.typing.enabled.unwrap_or(false) {
+		for room_id in extension_rooms(
+			&response_rooms,
+			&list_rooms,
+			body.extensions.typing.lists.as_deref(),
+			body.extensions.typing.rooms.as_deref(),
+		) {
+			if services().rooms.typing.last_typing_update(&room_id).await? > globalsince {
+				typing_rooms.insert(
+					room_id.clone(),
+					Raw::new(&services().rooms.typing.typings_all(&room_id).await?)
+						.expect("typing event is valid json"),
+				);
+			}
+		}
+	}
+
 	if rooms
 		.iter()
 		.all(|(_, r)| r.timeline.is_empty() && r.required_state.is_empty())
+		&& account_data_rooms.is_empty()
+		&& receipt_rooms.is_empty()
+		&& typing_rooms.is_empty()
 	{
 		// Hang a few seconds so requests are not spammed
 		// Stop hanging if new info arrives
@@ -1633,7 +1843,7 @@ pub async fn sync_events_v4_route(
 				Some(sync_events::v4::ToDevice {
 					events: services()
 						.users
-						.get_to_device_events(&sender_user, &sender_device)?,
+						.get_to_device_events(&sender_user, &sender_device, None)?,
 					next_batch: next_batch.to_string(),
 				})
 			} else {
@@ -1647,8 +1857,11 @@ pub async fn sync_events_v4_route(
 				device_one_time_keys_count: services()
 					.users
 					.count_one_time_keys(&sender_user, &sender_device)?,
-				// Fallback keys are not yet supported
-				device_unused_fallback_key_types: None,
+				device_unused_fallback_key_types: Some(
+					services()
+						.users
+						.unused_fallback_key_types(&sender_user, &sender_device)?,
+				),
 			},
 			account_data: sync_events::v4::AccountData {
 				global: if body.extensions.account_data.enabled.unwrap_or(false) {
@@ -1665,15 +1878,46 @@ pub async fn sync_events_v4_route(
 				} else {
 					Vec::new()
 				},
-				rooms: BTreeMap::new(),
+				rooms: account_data_rooms,
 			},
 			receipts: sync_events::v4::Receipts {
-				rooms: BTreeMap::new(),
+				rooms: receipt_rooms,
 			},
 			typing: sync_events::v4::Typing {
-				rooms: BTreeMap::new(),
+				rooms: typing_rooms,
 			},
 		},
 		delta_token: None,
 	})
 }
+
+/// Returns the rooms a sliding sync extension should be computed for: the
+/// rooms of the requested lists plus the requested rooms, limited to the rooms
+/// in this response. Without any filter, every room in the response is used.
+fn extension_rooms(
+	response_rooms: &BTreeSet<OwnedRoomId>, list_rooms: &BTreeMap<String, BTreeSet<OwnedRoomId>>,
+	lists: Option<&[String]>, rooms: Option<&[OwnedRoomId]>,
+) -> BTreeSet<OwnedRoomId> {
+	if lists.is_none() && rooms.is_none() {
+		return response_rooms.clone();
+	}
+
+	let mut result = BTreeSet::new();
+	for list_id in lists.unwrap_or_default() {
+		if list_id == "*" {
+			result.extend(list_rooms.values().flatten().cloned());
+		} else if let Some(room_ids) = list_rooms.get(list_id) {
+			result.extend(room_ids.iter().cloned());
+		}
+	}
+
+	result.extend(
+		rooms
+			.unwrap_or_default()
+			.iter()
+			.filter(|room_id| response_rooms.contains(*room_id))
+			.cloned(),
+	);
+
+	result
+}
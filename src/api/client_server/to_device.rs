@@ -53,28 +53,38 @@ pub async fn send_event_to_device_route(
 
 			match target_device_id_maybe {
 				DeviceIdOrAllDevices::DeviceId(target_device_id) => {
-					services().users.add_to_device_event(
-						sender_user,
-						target_user_id,
-						target_device_id,
-						&body.event_type.to_string(),
-						event
-							.deserialize_as()
-							.map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Event is invalid"))?,
-					)?;
-				},
-
-				DeviceIdOrAllDevices::AllDevices => {
-					for target_device_id in services().users.all_device_ids(target_user_id) {
-						services().users.add_to_device_event(
+					services()
+						.users
+						.add_to_device_event(
 							sender_user,
 							target_user_id,
-							&target_device_id?,
+							target_device_id,
 							&body.event_type.to_string(),
 							event
 								.deserialize_as()
 								.map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Event is invalid"))?,
-						)?;
+						)
+						.await?;
+				},
+
+				DeviceIdOrAllDevices::AllDevices => {
+					for target_device_id in services()
+						.users
+						.all_device_ids(target_user_id)
+						.collect::<Vec<_>>()
+					{
+						services()
+							.users
+							.add_to_device_event(
+								sender_user,
+								target_user_id,
+								&target_device_id?,
+								&body.event_type.to_string(),
+								event
+									.deserialize_as()
+									.map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Event is invalid"))?,
+							)
+							.await?;
 					}
 				},
 			}
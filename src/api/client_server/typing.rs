@@ -23,8 +23,8 @@ pub async fn create_typing_event_route(
 	if let Typing::Yes(duration) = body.state {
 		let duration = utils::clamp(
 			duration.as_millis() as u64,
-			services().globals.config.typing_client_timeout_min_s * 1000,
-			services().globals.config.typing_client_timeout_max_s * 1000,
+			services().globals.typing_client_timeout_min_s() * 1000,
+			services().globals.typing_client_timeout_max_s() * 1000,
 		);
 		services()
 			.rooms
@@ -15,13 +15,13 @@ use bytes::{Buf, BufMut, Bytes, BytesMut};
 use http::{Request, StatusCode};
 use ruma::{
 	api::{client::error::ErrorKind, AuthScheme, IncomingRequest, OutgoingResponse},
-	CanonicalJsonValue, OwnedDeviceId, OwnedServerName, UserId,
+	CanonicalJsonValue, OwnedDeviceId, OwnedServerName, OwnedUserId, UserId,
 };
 use serde::Deserialize;
 use tracing::{debug, error, trace, warn};
 
 use super::{Ruma, RumaResponse};
-use crate::{services, Error, Result};
+use crate::{service::appservice::RegistrationInfo, services, Error, Result};
 
 #[derive(Deserialize)]
 struct QueryParams {
@@ -85,19 +85,10 @@ where
 		};
 
 		let (sender_user, sender_device, sender_servername, from_appservice) =
-			if let Some(info) = appservice_registration {
+			if let Some(info) = &appservice_registration {
 				match metadata.authentication {
 					AuthScheme::AccessToken => {
-						let user_id = query_params.user_id.map_or_else(
-							|| {
-								UserId::parse_with_server_name(
-									info.registration.sender_localpart.as_str(),
-									services().globals.server_name(),
-								)
-								.unwrap()
-							},
-							|s| UserId::parse(s).unwrap(),
-						);
+						let user_id = appservice_user_id(info, query_params.user_id.as_deref())?;
 
 						debug!("User ID: {:?}", user_id);
 
@@ -105,27 +96,16 @@ where
 							return Err(Error::BadRequest(ErrorKind::forbidden(), "User does not exist."));
 						}
 
-						// TODO: Check if appservice is allowed to be that user
 						(Some(user_id), None, None, true)
 					},
 					AuthScheme::AccessTokenOptional | AuthScheme::AppserviceToken => {
-						let user_id = query_params.user_id.map_or_else(
-							|| {
-								UserId::parse_with_server_name(
-									info.registration.sender_localpart.as_str(),
-									services().globals.server_name(),
-								)
-								.unwrap()
-							},
-							|s| UserId::parse(s).unwrap(),
-						);
+						let user_id = appservice_user_id(info, query_params.user_id.as_deref())?;
 
 						debug!("User ID: {:?}", user_id);
 
 						if !services().users.exists(&user_id)? {
 							(None, None, None, true)
 						} else {
-							// TODO: Check if appservice is allowed to be that user
 							(Some(user_id), None, None, true)
 						}
 					},
@@ -352,10 +332,32 @@ where
 			sender_servername,
 			json_body,
 			from_appservice,
+			appservice_info: appservice_registration,
 		})
 	}
 }
 
+/// Returns the user an appservice acts as: the one in the `user_id` query
+/// parameter, which has to be in its namespace, or its sender user.
+fn appservice_user_id(info: &RegistrationInfo, user_id: Option<&str>) -> Result<OwnedUserId> {
+	let Some(user_id) = user_id else {
+		return Ok(UserId::parse_with_server_name(
+			info.registration.sender_localpart.as_str(),
+			services().globals.server_name(),
+		)
+		.unwrap());
+	};
+
+	let user_id =
+		UserId::parse(user_id).map_err(|_| Error::BadRequest(ErrorKind::InvalidUsername, "Username is invalid."))?;
+
+	if !info.is_user_match(&user_id) {
+		return Err(Error::BadRequest(ErrorKind::Exclusive, "User is not in namespace."));
+	}
+
+	Ok(user_id)
+}
+
 struct XMatrix {
 	origin: OwnedServerName,
 	destination: Option<String>,
@@ -2,7 +2,7 @@ use std::ops::Deref;
 
 use ruma::{api::client::uiaa::UiaaResponse, CanonicalJsonValue, OwnedDeviceId, OwnedServerName, OwnedUserId};
 
-use crate::Error;
+use crate::{service::appservice::RegistrationInfo, Error};
 
 mod axum;
 
@@ -15,6 +15,7 @@ pub struct Ruma<T> {
 	// This is None when body is not a valid string
 	pub json_body: Option<CanonicalJsonValue>,
 	pub from_appservice: bool,
+	pub appservice_info: Option<RegistrationInfo>,
 }
 
 impl<T> Deref for Ruma<T> {
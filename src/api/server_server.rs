@@ -18,11 +18,13 @@ use ruma::{
 			device::get_devices::{self, v1::UserDevice},
 			directory::{get_public_rooms, get_public_rooms_filtered},
 			discovery::{get_server_keys, get_server_version, ServerSigningKeys, VerifyKey},
-			event::{get_event, get_missing_events, get_room_state, get_room_state_ids},
+			event::{get_event, get_event_by_timestamp, get_missing_events, get_room_state, get_room_state_ids},
 			keys::{claim_keys, get_keys},
 			membership::{create_invite, create_join_event, prepare_join_event},
+			openid::get_openid_userinfo,
 			query::{get_profile_information, get_room_information},
 			space::get_hierarchy,
+			thirdparty::exchange_invite,
 			transactions::{
 				edu::{DeviceListUpdateContent, DirectDeviceContent, Edu, SigningKeyUpdateContent},
 				send_transaction_message,
@@ -44,6 +46,7 @@ use ruma::{
 	uint, user_id, CanonicalJsonObject, CanonicalJsonValue, EventId, MilliSecondsSinceUnixEpoch, OwnedEventId,
 	OwnedRoomId, OwnedServerName, OwnedServerSigningKeyId, OwnedUserId, RoomId, RoomVersionId, ServerName,
 };
+use serde::Deserialize;
 use serde_json::value::{to_raw_value, RawValue as RawJsonValue};
 use tokio::sync::RwLock;
 use tracing::{debug, error, info, warn};
@@ -338,13 +341,16 @@ pub async fn send_transaction_message_route(
 				}
 
 				for update in presence.push {
-					services().presence.set_presence(
-						&update.user_id,
-						&update.presence,
-						Some(update.currently_active),
-						Some(update.last_active_ago),
-						update.status_msg.clone(),
-					)?;
+					services()
+						.presence
+						.set_presence(
+							&update.user_id,
+							&update.presence,
+							Some(update.currently_active),
+							Some(update.last_active_ago),
+							update.status_msg.clone(),
+						)
+						.await?;
 				}
 			},
 			Edu::Receipt(receipt) => {
@@ -384,7 +390,8 @@ pub async fn send_transaction_message_route(
 							services()
 								.rooms
 								.read_receipt
-								.readreceipt_update(&user_id, &room_id, event)?;
+								.readreceipt_update(&user_id, &room_id, event)
+								.await?;
 						} else {
 							// TODO fetch missing events
 							debug!("No known event ids in read receipt: {:?}", user_updates);
@@ -393,7 +400,7 @@ pub async fn send_transaction_message_route(
 				}
 			},
 			Edu::Typing(typing) => {
-				if !services().globals.config.allow_incoming_typing {
+				if !services().globals.allow_incoming_typing() {
 					continue;
 				}
 
@@ -403,8 +410,8 @@ pub async fn send_transaction_message_route(
 					.is_joined(&typing.user_id, &typing.room_id)?
 				{
 					if typing.typing {
-						let timeout = utils::millis_since_unix_epoch()
-							+ services().globals.config.typing_federation_timeout_s * 1000;
+						let timeout =
+							utils::millis_since_unix_epoch() + services().globals.typing_federation_timeout_s() * 1000;
 						services()
 							.rooms
 							.typing
@@ -444,29 +451,39 @@ pub async fn send_transaction_message_route(
 					for (target_device_id_maybe, event) in map {
 						match target_device_id_maybe {
 							DeviceIdOrAllDevices::DeviceId(target_device_id) => {
-								services().users.add_to_device_event(
-									&sender,
-									target_user_id,
-									target_device_id,
-									&ev_type.to_string(),
-									event.deserialize_as().map_err(|e| {
-										warn!("To-Device event is invalid: {event:?} {e}");
-										Error::BadRequest(ErrorKind::InvalidParam, "Event is invalid")
-									})?,
-								)?;
-							},
-
-							DeviceIdOrAllDevices::AllDevices => {
-								for target_device_id in services().users.all_device_ids(target_user_id) {
-									services().users.add_to_device_event(
+								services()
+									.users
+									.add_to_device_event(
 										&sender,
 										target_user_id,
-										&target_device_id?,
+										target_device_id,
 										&ev_type.to_string(),
-										event.deserialize_as().map_err(|_| {
+										event.deserialize_as().map_err(|e| {
+											warn!("To-Device event is invalid: {event:?} {e}");
 											Error::BadRequest(ErrorKind::InvalidParam, "Event is invalid")
 										})?,
-									)?;
+									)
+									.await?;
+							},
+
+							DeviceIdOrAllDevices::AllDevices => {
+								for target_device_id in services()
+									.users
+									.all_device_ids(target_user_id)
+									.collect::<Vec<_>>()
+								{
+									services()
+										.users
+										.add_to_device_event(
+											&sender,
+											target_user_id,
+											&target_device_id?,
+											&ev_type.to_string(),
+											event.deserialize_as().map_err(|_| {
+												Error::BadRequest(ErrorKind::InvalidParam, "Event is invalid")
+											})?,
+										)
+										.await?;
 								}
 							},
 						}
@@ -556,6 +573,56 @@ pub async fn get_event_route(body: Ruma<get_event::v1::Request>) -> Result<get_e
 	})
 }
 
+/// # `GET /_matrix/federation/v1/timestamp_to_event/{roomId}`
+///
+/// Returns the event closest to the given timestamp in the given direction.
+///
+/// - Only looks at our own history, we never ask other servers on behalf of a
+///   federation request
+pub async fn get_event_by_timestamp_route(
+	body: Ruma<get_event_by_timestamp::v1::Request>,
+) -> Result<get_event_by_timestamp::v1::Response> {
+	let sender_servername = body
+		.sender_servername
+		.as_ref()
+		.expect("server is authenticated");
+
+	if !services()
+		.rooms
+		.state_cache
+		.server_in_room(sender_servername, &body.room_id)?
+	{
+		return Err(Error::BadRequest(ErrorKind::forbidden(), "Server is not in room."));
+	}
+
+	services()
+		.rooms
+		.event_handler
+		.acl_check(sender_servername, &body.room_id)?;
+
+	let pdu = services()
+		.rooms
+		.timeline
+		.pdu_by_timestamp(&body.room_id, body.ts, body.dir)?
+		.ok_or(Error::BadRequest(
+			ErrorKind::NotFound,
+			"Unable to find an event in the given direction.",
+		))?;
+
+	if !services()
+		.rooms
+		.state_accessor
+		.server_can_see_event(sender_servername, &body.room_id, &pdu.event_id)?
+	{
+		return Err(Error::BadRequest(ErrorKind::forbidden(), "Server is not allowed to see event."));
+	}
+
+	Ok(get_event_by_timestamp::v1::Response {
+		event_id: pdu.event_id.as_ref().to_owned(),
+		origin_server_ts: MilliSecondsSinceUnixEpoch(pdu.origin_server_ts),
+	})
+}
+
 /// # `GET /_matrix/federation/v1/backfill/<room_id>`
 ///
 /// Retrieves events from before the sender joined the room, if the room's
@@ -1317,6 +1384,104 @@ pub async fn create_invite_route(body: Ruma<create_invite::v2::Request>) -> Resu
 	})
 }
 
+/// # `PUT /_matrix/federation/v1/exchange_third_party_invite/{roomId}`
+///
+/// Turns a signed third party invite into an invite event sent by one of our
+/// users.
+pub async fn exchange_third_party_invite_route(
+	body: Ruma<exchange_invite::v1::Request>,
+) -> Result<exchange_invite::v1::Response> {
+	if let Some(sender_servername) = body.sender_servername.as_ref() {
+		services()
+			.rooms
+			.event_handler
+			.acl_check(sender_servername, &body.room_id)?;
+	}
+
+	if body.kind != StateEventType::RoomMember {
+		return Err(Error::BadRequest(
+			ErrorKind::InvalidParam,
+			"Third party invites can only be exchanged for m.room.member events.",
+		));
+	}
+
+	if body.sender.server_name() != services().globals.server_name() {
+		return Err(Error::BadRequest(
+			ErrorKind::InvalidParam,
+			"Inviting user does not belong to this homeserver.",
+		));
+	}
+
+	if !services().rooms.metadata.exists(&body.room_id)? {
+		return Err(Error::BadRequest(ErrorKind::NotFound, "Room is unknown to this server."));
+	}
+
+	client_server::exchange_third_party_invite_helper(
+		&body.sender,
+		&body.state_key,
+		&body.room_id,
+		body.content.clone(),
+	)
+	.await?;
+
+	Ok(exchange_invite::v1::Response {})
+}
+
+#[derive(Deserialize)]
+pub struct ThirdPartyBindRequest {
+	mxid: OwnedUserId,
+	invites: Vec<ThirdPartyBindInvite>,
+}
+
+#[derive(Deserialize)]
+struct ThirdPartyBindInvite {
+	mxid: OwnedUserId,
+	room_id: OwnedRoomId,
+	sender: OwnedUserId,
+	signed: CanonicalJsonObject,
+}
+
+/// # `PUT /_matrix/federation/v1/3pid/onbind`
+///
+/// Called by identity servers when a third party identifier with pending
+/// invites gets bound to one of our users.
+///
+/// - Every invite is exchanged for a proper invite event by the inviting user's
+///   server
+// Request type for this endpoint is Json because the identity server does not
+// sign it and the invites carry their own signatures
+pub async fn third_party_invite_bind_route(Json(body): Json<ThirdPartyBindRequest>) -> Result<impl IntoResponse> {
+	if !services().globals.allow_federation() {
+		return Err(Error::bad_config("Federation is disabled."));
+	}
+
+	if body.mxid.server_name() != services().globals.server_name() {
+		return Err(Error::BadRequest(
+			ErrorKind::InvalidParam,
+			"User does not belong to this homeserver.",
+		));
+	}
+
+	for invite in body.invites {
+		if invite.mxid != body.mxid {
+			warn!("Ignoring third party invite for {} in bind for {}", invite.mxid, body.mxid);
+			continue;
+		}
+
+		if let Err(e) =
+			client_server::bind_third_party_invite_helper(&invite.sender, &invite.mxid, &invite.room_id, invite.signed)
+				.await
+		{
+			warn!(
+				"Failed to exchange third party invite from {} in {}: {e}",
+				invite.sender, invite.room_id
+			);
+		}
+	}
+
+	Ok(Json(serde_json::json!({})))
+}
+
 /// # `GET /_matrix/federation/v1/user/devices/{userId}`
 ///
 /// Gets information on all devices of the user.
@@ -1377,11 +1542,18 @@ pub async fn get_devices_route(body: Ruma<get_devices::v1::Request>) -> Result<g
 pub async fn get_room_information_route(
 	body: Ruma<get_room_information::v1::Request>,
 ) -> Result<get_room_information::v1::Response> {
-	let room_id = services()
+	let room_id = match services()
 		.rooms
 		.alias
 		.resolve_local_alias(&body.room_alias)?
-		.ok_or(Error::BadRequest(ErrorKind::NotFound, "Room alias not found."))?;
+	{
+		Some(room_id) => room_id,
+		None => services()
+			.appservice
+			.query_room_alias(&body.room_alias)
+			.await?
+			.ok_or(Error::BadRequest(ErrorKind::NotFound, "Room alias not found."))?,
+	};
 
 	Ok(get_room_information::v1::Response {
 		room_id,
@@ -1430,6 +1602,28 @@ pub async fn get_profile_information_route(
 	})
 }
 
+/// # `GET /_matrix/federation/v1/openid/userinfo`
+///
+/// Exchanges an OpenID token handed out to one of our users for their user
+/// ID, so third parties can verify who they are talking to.
+pub async fn get_openid_userinfo_route(
+	body: Ruma<get_openid_userinfo::v1::Request>,
+) -> Result<get_openid_userinfo::v1::Response> {
+	let sub = services()
+		.users
+		.find_from_openid_token(&body.access_token)?
+		.ok_or(Error::BadRequest(
+			ErrorKind::UnknownToken {
+				soft_logout: false,
+			},
+			"OpenID token is unknown or expired.",
+		))?;
+
+	Ok(get_openid_userinfo::v1::Response {
+		sub,
+	})
+}
+
 /// # `POST /_matrix/federation/v1/user/keys/query`
 ///
 /// Gets devices and identity keys for the given users.
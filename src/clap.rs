@@ -2,7 +2,7 @@
 
 use std::path::PathBuf;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
 /// Returns the current version of the crate with extra info if supplied
 ///
@@ -26,6 +26,89 @@ pub struct Args {
 	#[arg(short, long)]
 	/// Optional argument to the path of a conduwuit config TOML file
 	pub config: Option<PathBuf>,
+
+	#[arg(long, value_name = "FILE", conflicts_with = "import")]
+	/// Export the database to a file and exit, the server must not be running
+	///
+	/// Media files are not exported, copy the media directory in
+	/// database_path separately.
+	pub export: Option<PathBuf>,
+
+	#[arg(long, value_name = "FILE")]
+	/// Import a database export into a new, empty database and exit, e.g. to
+	/// switch database backends
+	pub import: Option<PathBuf>,
+
+	#[command(subcommand)]
+	pub command: Option<Commands>,
+}
+
+/// Subcommands that work on the database without starting the server, which
+/// must not be running
+#[derive(Subcommand, Clone, Debug)]
+pub enum Commands {
+	/// Check the database for inconsistencies between its trees and exit
+	CheckDb {
+		#[arg(long)]
+		/// Repair the inconsistencies that can be fixed without losing data
+		repair: bool,
+	},
+
+	/// Run pending database migrations, including rebuilding the search index,
+	/// and exit
+	Migrate,
+
+	/// Replace the database with a verified backup from database_backup_path
+	RestoreBackup {
+		#[arg(long)]
+		/// ID of the backup to restore, the newest one if not given
+		id: Option<u64>,
+
+		#[arg(long)]
+		/// Also copy media files missing from the media directory back from the
+		/// backup path
+		media: bool,
+	},
+
+	/// Create a local user, printing the generated password if none is given
+	CreateUser {
+		/// Username of the new user
+		username: String,
+		/// Password of the new user
+		password: Option<String>,
+	},
+
+	/// Reset the password of a local user to a generated one and print it
+	ResetPassword {
+		/// Username of the user whose password is reset
+		username: String,
+	},
+
+	/// Grant a local user admin privileges
+	MakeAdmin {
+		/// The full user ID, e.g. @alice:example.com
+		user_id: String,
+	},
+
+	/// List the rooms the server knows about
+	ListRooms {
+		/// Page of the list, 100 rooms each
+		page: Option<usize>,
+	},
+
+	/// Run any admin room command and print its output, e.g. `admin users
+	/// list`
+	Admin {
+		#[arg(long, value_name = "FILE")]
+		/// File with the code block the command expects below it, such as an
+		/// appservice registration
+		body: Option<PathBuf>,
+
+		#[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+		/// The command, as it would be sent to the admin room without the
+		/// server user prefix
+		command: Vec<String>,
+	},
 }
 
 /// Parse commandline arguments into structured data
@@ -3,7 +3,11 @@ use std::path::Path; // not unix specific, just only for UNIX sockets stuff and
 
 use tracing::{debug, error, info, warn};
 
-use crate::{utils::error::Error, Config};
+use crate::{
+	database::backup::{backup_dir, Schedule},
+	utils::{self, error::Error},
+	Config,
+};
 
 pub fn check(config: &Config) -> Result<(), Error> {
 	config.warn_deprecated();
@@ -64,6 +68,8 @@ pub fn check(config: &Config) -> Result<(), Error> {
 		));
 	}
 
+	check_backup_schedule(config)?;
+
 	// yeah, unless the user built a debug build hopefully for local testing only
 	if config.server_name == "your.server.name" && !cfg!(debug_assertions) {
 		return Err(Error::bad_config(
@@ -157,3 +163,32 @@ For security and safety reasons, conduwuit will shut down. If you are extra sure
 
 	Ok(())
 }
+
+fn check_backup_schedule(config: &Config) -> Result<(), Error> {
+	let Some(schedule) = &config.database_backup_schedule else {
+		return Ok(());
+	};
+
+	let schedule = schedule
+		.parse::<Schedule>()
+		.map_err(|e| Error::BadConfig(format!("database_backup_schedule is not a valid schedule: {e}")))?;
+
+	if schedule
+		.next_after(utils::millis_since_unix_epoch() / 1000)
+		.is_none()
+	{
+		return Err(Error::bad_config("database_backup_schedule never runs."));
+	}
+
+	if backup_dir(config).is_none() {
+		return Err(Error::bad_config(
+			"database_backup_schedule needs database_backup_path to be set.",
+		));
+	}
+
+	if config.database_backend == "memory" {
+		return Err(Error::bad_config("In-memory databases can't be backed up."));
+	}
+
+	Ok(())
+}
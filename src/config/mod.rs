@@ -1,5 +1,5 @@
 use std::{
-	collections::BTreeMap,
+	collections::{BTreeMap, BTreeSet},
 	fmt::{self, Write as _},
 	net::{IpAddr, Ipv4Addr, SocketAddr},
 	path::PathBuf,
@@ -11,6 +11,7 @@ use either::{
 };
 use figment::{
 	providers::{Env, Format, Toml},
+	value::Dict,
 	Figment,
 };
 use itertools::Itertools;
@@ -53,6 +54,9 @@ pub struct Config {
 	pub database_backup_path: Option<PathBuf>,
 	#[serde(default = "default_database_backups_to_keep")]
 	pub database_backups_to_keep: i16,
+	pub database_backup_schedule: Option<String>,
+	#[serde(default)]
+	pub database_backup_media: bool,
 	#[serde(default = "default_db_cache_capacity_mb")]
 	pub db_cache_capacity_mb: f64,
 	#[serde(default = "default_new_user_displayname_suffix")]
@@ -132,6 +136,8 @@ pub struct Config {
 	#[serde(default = "default_pusher_idle_timeout")]
 	pub pusher_idle_timeout: u64,
 	#[serde(default)]
+	pub appservice_registrations: Vec<PathBuf>,
+	#[serde(default)]
 	pub allow_registration: bool,
 	#[serde(default)]
 	pub yes_i_am_very_very_sure_i_want_an_open_registration_server_prone_to_abuse: bool,
@@ -180,6 +186,16 @@ pub struct Config {
 	#[serde(default = "default_turn_ttl")]
 	pub turn_ttl: u64,
 
+	#[serde(default = "default_openid_token_ttl")]
+	pub openid_token_ttl: u64,
+
+	#[serde(default)]
+	pub allow_email_pushers: bool,
+	pub smtp_url: Option<String>,
+	pub email_from: Option<String>,
+	#[serde(default = "default_email_notification_delay")]
+	pub email_notification_delay: u64,
+
 	#[serde(default = "Vec::new")]
 	pub auto_join_rooms: Vec<OwnedRoomId>,
 
@@ -291,6 +307,14 @@ pub struct Config {
 	#[serde(flatten)]
 	#[allow(clippy::zero_sized_map_values)] // this is a catchall, the map shouldn't be zero at runtime
 	pub catchall: BTreeMap<String, IgnoredAny>,
+
+	/// The config file given on the command line, to read it again on reload
+	#[serde(skip)]
+	pub config_path: Option<PathBuf>,
+	/// The keys and values as they were read, to find which keys changed on
+	/// reload
+	#[serde(skip)]
+	raw: Dict,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -306,6 +330,36 @@ pub struct TlsConfig {
 
 const DEPRECATED_KEYS: &[&str] = &["cache_capacity"];
 
+/// Keys that take effect when the config is reloaded at runtime, all others
+/// need a restart
+pub const RELOADABLE_KEYS: &[&str] = &[
+	"allow_registration",
+	"yes_i_am_very_very_sure_i_want_an_open_registration_server_prone_to_abuse",
+	"registration_token",
+	"allow_guest_registration",
+	"forbidden_usernames",
+	"forbidden_alias_names",
+	"url_preview_domain_contains_allowlist",
+	"url_preview_domain_explicit_allowlist",
+	"url_preview_url_contains_allowlist",
+	"url_preview_max_spider_size",
+	"url_preview_check_root_domain",
+	"allow_incoming_presence",
+	"allow_outgoing_presence",
+	"presence_idle_timeout_s",
+	"presence_offline_timeout_s",
+	"allow_incoming_read_receipts",
+	"allow_incoming_typing",
+	"allow_outgoing_typing",
+	"typing_federation_timeout_s",
+	"typing_client_timeout_min_s",
+	"typing_client_timeout_max_s",
+	"log",
+	"trusted_servers",
+	"prevent_media_downloads_from",
+	"appservice_registrations",
+];
+
 impl Config {
 	/// Initialize config
 	pub fn new(path: Option<PathBuf>) -> Result<Self, Error> {
@@ -313,7 +367,7 @@ impl Config {
 			Figment::new()
 				.merge(Toml::file(config_file_env).nested())
 				.merge(Env::prefixed("CONDUIT_").global())
-		} else if let Some(config_file_arg) = path {
+		} else if let Some(config_file_arg) = &path {
 			Figment::new()
 				.merge(Toml::file(config_file_arg).nested())
 				.merge(Env::prefixed("CONDUIT_").global())
@@ -321,11 +375,16 @@ impl Config {
 			Figment::new().merge(Env::prefixed("CONDUIT_").global())
 		};
 
-		let config = match raw_config.extract::<Config>() {
+		let mut config = match raw_config.extract::<Config>() {
 			Err(e) => return Err(Error::BadConfig(format!("{e}"))),
 			Ok(config) => config,
 		};
 
+		config.config_path = path;
+		config.raw = raw_config
+			.extract::<Dict>()
+			.map_err(|e| Error::BadConfig(format!("{e}")))?;
+
 		check::check(&config)?;
 
 		// don't start if we're listening on both UNIX sockets and TCP at same time
@@ -387,6 +446,20 @@ impl Config {
 		false
 	}
 
+	/// The keys that are set to different values in `other`, including keys
+	/// only set in one of them
+	#[must_use]
+	pub fn changed_keys(&self, other: &Self) -> Vec<String> {
+		self.raw
+			.keys()
+			.chain(other.raw.keys())
+			.collect::<BTreeSet<_>>()
+			.into_iter()
+			.filter(|key| self.raw.get(*key) != other.raw.get(*key))
+			.cloned()
+			.collect()
+	}
+
 	#[must_use]
 	pub fn get_bind_addrs(&self) -> Vec<SocketAddr> {
 		match &self.port.ports {
@@ -424,6 +497,13 @@ impl fmt::Display for Config {
 				},
 			),
 			("Database backups to keep", &self.database_backups_to_keep.to_string()),
+			(
+				"Database backup schedule",
+				self.database_backup_schedule
+					.as_deref()
+					.unwrap_or("disabled"),
+			),
+			("Back up media", &self.database_backup_media.to_string()),
 			("Database cache capacity (MB)", &self.db_cache_capacity_mb.to_string()),
 			("Cache capacity modifier", &self.conduit_cache_capacity_modifier.to_string()),
 			("PDU cache capacity", &self.pdu_cache_capacity.to_string()),
@@ -468,6 +548,14 @@ impl fmt::Display for Config {
 			("Appservice timeout", &self.appservice_timeout.to_string()),
 			("Appservice pool idle timeout", &self.appservice_idle_timeout.to_string()),
 			("Pusher pool idle timeout", &self.pusher_idle_timeout.to_string()),
+			(
+				"Appservice registration files",
+				&self
+					.appservice_registrations
+					.iter()
+					.map(|path| path.display())
+					.join(", "),
+			),
 			("Allow registration", &self.allow_registration.to_string()),
 			(
 				"Registration token",
@@ -567,6 +655,16 @@ impl fmt::Display for Config {
 				}
 			}),
 			("Turn TTL", &self.turn_ttl.to_string()),
+			("OpenID token TTL", &self.openid_token_ttl.to_string()),
+			("Allow email pushers", &self.allow_email_pushers.to_string()),
+			("SMTP server", {
+				match self.smtp_url {
+					Some(_) => "set",
+					None => "not set",
+				}
+			}),
+			("Email notification sender", self.email_from.as_deref().unwrap_or("not set")),
+			("Email notification delay", &self.email_notification_delay.to_string()),
 			("Turn URIs", {
 				let mut lst = vec![];
 				for item in self.turn_uris.iter().cloned().enumerate() {
@@ -778,6 +876,10 @@ fn default_notification_push_path() -> String { "/_matrix/push/v1/notify".to_own
 
 fn default_turn_ttl() -> u64 { 60 * 60 * 24 }
 
+fn default_openid_token_ttl() -> u64 { 60 * 60 }
+
+fn default_email_notification_delay() -> u64 { 10 * 60 }
+
 fn default_presence_idle_timeout_s() -> u64 { 5 * 60 }
 
 fn default_presence_offline_timeout_s() -> u64 { 30 * 60 }
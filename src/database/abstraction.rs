@@ -1,15 +1,16 @@
-use std::{error::Error, future::Future, pin::Pin, sync::Arc};
+use std::{error::Error, future::Future, path::Path, pin::Pin, sync::Arc};
 
 use super::Config;
 use crate::Result;
 
+pub(crate) mod memory;
+
 #[cfg(feature = "sqlite")]
 pub mod sqlite;
 
 #[cfg(feature = "rocksdb")]
 pub(crate) mod rocksdb;
 
-#[cfg(any(feature = "sqlite", feature = "rocksdb"))]
 pub(crate) mod watchers;
 
 pub(crate) trait KeyValueDatabaseEngine: Send + Sync {
@@ -17,6 +18,9 @@ pub(crate) trait KeyValueDatabaseEngine: Send + Sync {
 	where
 		Self: Sized;
 	fn open_tree(&self, name: &'static str) -> Result<Arc<dyn KvTree>>;
+	/// Names of all trees in the database, including ones that are no longer
+	/// opened by this version.
+	fn tree_names(&self) -> Result<Vec<String>>;
 	fn flush(&self) -> Result<()>;
 	#[allow(dead_code)]
 	fn sync(&self) -> Result<()> { Ok(()) }
@@ -31,8 +35,20 @@ pub(crate) trait KeyValueDatabaseEngine: Send + Sync {
 	#[allow(dead_code)]
 	fn clear_caches(&self) {}
 
+	/// Creates a backup in `database_backup_path` and verifies it, then
+	/// removes the backups beyond `database_backups_to_keep`
 	fn backup(&self) -> Result<(), Box<dyn Error>> { unimplemented!() }
 
+	/// Replaces the database in the config with the backup `id` in
+	/// `backup_dir`, or the newest one. The database must not be open. Returns
+	/// the ID of the restored backup.
+	fn restore_backup(_config: &Config, _backup_dir: &Path, _id: Option<u64>) -> Result<u64>
+	where
+		Self: Sized,
+	{
+		Err(crate::Error::bad_config("This database backend does not support backups."))
+	}
+
 	fn backup_list(&self) -> Result<String> { Ok(String::new()) }
 
 	fn file_list(&self) -> Result<String> { Ok(String::new()) }
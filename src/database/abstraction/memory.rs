@@ -0,0 +1,315 @@
+use std::{
+	collections::{BTreeMap, HashMap, VecDeque},
+	error::Error,
+	future::Future,
+	ops::Bound,
+	pin::Pin,
+	sync::{Arc, RwLock},
+};
+
+use super::{watchers::Watchers, KeyValueDatabaseEngine, KvTree};
+use crate::{database::Config, Result};
+
+/// How many entries iterators copy out of a tree at once, so the tree is not
+/// locked while the caller handles them.
+const ITER_CHUNK_SIZE: usize = 256;
+
+type TupleOfBytes = (Vec<u8>, Vec<u8>);
+
+/// Keeps every tree in memory. Everything is lost when the server stops, which
+/// is useful for tests and throwaway servers.
+#[derive(Default)]
+pub(crate) struct Engine {
+	trees: RwLock<HashMap<String, Arc<MemoryTree>>>,
+}
+
+impl KeyValueDatabaseEngine for Arc<Engine> {
+	fn open(_config: &Config) -> Result<Self> { Ok(Arc::new(Engine::default())) }
+
+	fn open_tree(&self, name: &'static str) -> Result<Arc<dyn KvTree>> {
+		// Opening a tree twice has to return the same data, like the other engines
+		let tree = Arc::clone(
+			self.trees
+				.write()
+				.unwrap()
+				.entry(name.to_owned())
+				.or_default(),
+		);
+
+		Ok(tree)
+	}
+
+	fn tree_names(&self) -> Result<Vec<String>> { Ok(self.trees.read().unwrap().keys().cloned().collect()) }
+
+	fn flush(&self) -> Result<()> { Ok(()) }
+
+	fn memory_usage(&self) -> Result<String> {
+		let trees = self.trees.read().unwrap();
+
+		let (mut entries, mut bytes) = (0, 0);
+		for tree in trees.values() {
+			let map = tree.map.read().unwrap();
+			entries += map.len();
+			bytes += map
+				.iter()
+				.map(|(key, value)| key.len() + value.len())
+				.sum::<usize>();
+		}
+
+		Ok(format!(
+			"In-memory database: {} trees, {entries} entries, {:.2} MiB of keys and values",
+			trees.len(),
+			bytes as f64 / 1024.0 / 1024.0
+		))
+	}
+
+	fn backup(&self) -> Result<(), Box<dyn Error>> { Err("In-memory databases can't be backed up.".into()) }
+}
+
+#[derive(Default)]
+pub(crate) struct MemoryTree {
+	map: RwLock<BTreeMap<Vec<u8>, Vec<u8>>>,
+	watchers: Watchers,
+}
+
+impl MemoryTree {
+	/// Copies the next entries after `bound` out of the tree, in iteration
+	/// order.
+	fn chunk(&self, bound: &Bound<Vec<u8>>, backwards: bool) -> VecDeque<TupleOfBytes> {
+		let map = self.map.read().unwrap();
+
+		if backwards {
+			map.range::<Vec<u8>, _>((Bound::Unbounded, bound.clone()))
+				.rev()
+				.take(ITER_CHUNK_SIZE)
+				.map(|(key, value)| (key.clone(), value.clone()))
+				.collect()
+		} else {
+			map.range::<Vec<u8>, _>((bound.clone(), Bound::Unbounded))
+				.take(ITER_CHUNK_SIZE)
+				.map(|(key, value)| (key.clone(), value.clone()))
+				.collect()
+		}
+	}
+}
+
+impl KvTree for MemoryTree {
+	fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> { Ok(self.map.read().unwrap().get(key).cloned()) }
+
+	fn insert(&self, key: &[u8], value: &[u8]) -> Result<()> {
+		self.map
+			.write()
+			.unwrap()
+			.insert(key.to_vec(), value.to_vec());
+
+		self.watchers.wake(key);
+
+		Ok(())
+	}
+
+	fn insert_batch(&self, iter: &mut dyn Iterator<Item = (Vec<u8>, Vec<u8>)>) -> Result<()> {
+		let mut keys = Vec::new();
+
+		let mut map = self.map.write().unwrap();
+		for (key, value) in iter {
+			map.insert(key.clone(), value);
+			keys.push(key);
+		}
+		drop(map);
+
+		for key in keys {
+			self.watchers.wake(&key);
+		}
+
+		Ok(())
+	}
+
+	fn remove(&self, key: &[u8]) -> Result<()> {
+		self.map.write().unwrap().remove(key);
+
+		Ok(())
+	}
+
+	fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = TupleOfBytes> + 'a> {
+		Box::new(MemoryTreeIter {
+			tree: self,
+			bound: Some(Bound::Unbounded),
+			backwards: false,
+			buffer: VecDeque::new(),
+		})
+	}
+
+	fn iter_from<'a>(&'a self, from: &[u8], backwards: bool) -> Box<dyn Iterator<Item = TupleOfBytes> + 'a> {
+		Box::new(MemoryTreeIter {
+			tree: self,
+			bound: Some(Bound::Included(from.to_vec())),
+			backwards,
+			buffer: VecDeque::new(),
+		})
+	}
+
+	fn increment(&self, key: &[u8]) -> Result<Vec<u8>> {
+		let mut map = self.map.write().unwrap();
+
+		let new = crate::utils::increment(map.get(key).map(Vec::as_slice));
+		map.insert(key.to_vec(), new.clone());
+		drop(map);
+
+		self.watchers.wake(key);
+
+		Ok(new)
+	}
+
+	fn increment_batch(&self, iter: &mut dyn Iterator<Item = Vec<u8>>) -> Result<()> {
+		let mut keys = Vec::new();
+
+		let mut map = self.map.write().unwrap();
+		for key in iter {
+			let new = crate::utils::increment(map.get(&key).map(Vec::as_slice));
+			map.insert(key.clone(), new);
+			keys.push(key);
+		}
+		drop(map);
+
+		for key in keys {
+			self.watchers.wake(&key);
+		}
+
+		Ok(())
+	}
+
+	fn scan_prefix<'a>(&'a self, prefix: Vec<u8>) -> Box<dyn Iterator<Item = TupleOfBytes> + 'a> {
+		Box::new(
+			self.iter_from(&prefix, false)
+				.take_while(move |(key, _)| key.starts_with(&prefix)),
+		)
+	}
+
+	fn watch_prefix<'a>(&'a self, prefix: &[u8]) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>> {
+		self.watchers.watch(prefix)
+	}
+
+	fn clear(&self) -> Result<()> {
+		self.map.write().unwrap().clear();
+
+		Ok(())
+	}
+}
+
+/// Iterates over a tree in chunks. Like a database cursor it sees changes made
+/// to the part of the tree it did not reach yet.
+struct MemoryTreeIter<'a> {
+	tree: &'a MemoryTree,
+	/// Where the next chunk starts, None once the end of the tree was reached
+	bound: Option<Bound<Vec<u8>>>,
+	backwards: bool,
+	buffer: VecDeque<TupleOfBytes>,
+}
+
+impl Iterator for MemoryTreeIter<'_> {
+	type Item = TupleOfBytes;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		if self.buffer.is_empty() {
+			let bound = self.bound.take()?;
+			self.buffer = self.tree.chunk(&bound, self.backwards);
+
+			if self.buffer.len() == ITER_CHUNK_SIZE {
+				self.bound = self
+					.buffer
+					.back()
+					.map(|(key, _)| Bound::Excluded(key.clone()));
+			}
+		}
+
+		self.buffer.pop_front()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn tree(entries: &[(&[u8], &[u8])]) -> MemoryTree {
+		let tree = MemoryTree::default();
+		for (key, value) in entries {
+			tree.insert(key, value).unwrap();
+		}
+		tree
+	}
+
+	#[test]
+	fn iter_from_is_inclusive_in_both_directions() {
+		let tree = tree(&[(b"a", b"1"), (b"b", b"2"), (b"c", b"3")]);
+
+		let forwards = tree
+			.iter_from(b"b", false)
+			.map(|(key, _)| key)
+			.collect::<Vec<_>>();
+		assert_eq!(forwards, vec![b"b".to_vec(), b"c".to_vec()]);
+
+		let backwards = tree
+			.iter_from(b"b", true)
+			.map(|(key, _)| key)
+			.collect::<Vec<_>>();
+		assert_eq!(backwards, vec![b"b".to_vec(), b"a".to_vec()]);
+
+		// Keys that don't exist still position the iterator
+		let backwards = tree
+			.iter_from(b"bb", true)
+			.map(|(key, _)| key)
+			.collect::<Vec<_>>();
+		assert_eq!(backwards, vec![b"b".to_vec(), b"a".to_vec()]);
+	}
+
+	#[test]
+	fn iterators_cross_chunks() {
+		let tree = MemoryTree::default();
+		let count = ITER_CHUNK_SIZE as u64 * 2 + 1;
+		for i in 0..count {
+			tree.insert(&i.to_be_bytes(), &[]).unwrap();
+		}
+
+		assert_eq!(tree.iter().count() as u64, count);
+		assert_eq!(tree.iter_from(&u64::MAX.to_be_bytes(), true).count() as u64, count);
+
+		let keys = tree
+			.iter_from(&u64::MAX.to_be_bytes(), true)
+			.map(|(key, _)| u64::from_be_bytes(key.try_into().unwrap()))
+			.collect::<Vec<_>>();
+		assert!(keys.windows(2).all(|pair| pair[0] == pair[1] + 1));
+	}
+
+	#[test]
+	fn scan_prefix_stops_at_prefix_end() {
+		let tree = tree(&[(b"ab", b""), (b"abc", b""), (b"ac", b""), (b"b", b"")]);
+
+		let keys = tree
+			.scan_prefix(b"ab".to_vec())
+			.map(|(key, _)| key)
+			.collect::<Vec<_>>();
+		assert_eq!(keys, vec![b"ab".to_vec(), b"abc".to_vec()]);
+	}
+
+	#[test]
+	fn increment_counts_up() {
+		let tree = MemoryTree::default();
+
+		assert_eq!(tree.increment(b"counter").unwrap(), 1_u64.to_be_bytes().to_vec());
+		assert_eq!(tree.increment(b"counter").unwrap(), 2_u64.to_be_bytes().to_vec());
+		assert_eq!(tree.get(b"counter").unwrap(), Some(2_u64.to_be_bytes().to_vec()));
+	}
+
+	#[tokio::test]
+	async fn watch_prefix_wakes_on_insert() {
+		let tree = MemoryTree::default();
+
+		let watch = tree.watch_prefix(b"user");
+		tree.insert(b"other", b"").unwrap();
+		tree.insert(b"user1", b"").unwrap();
+
+		tokio::time::timeout(std::time::Duration::from_secs(1), watch)
+			.await
+			.expect("watcher was woken up");
+	}
+}
@@ -1,12 +1,13 @@
 use std::{
 	future::Future,
+	path::Path,
 	pin::Pin,
 	sync::{atomic::AtomicU32, Arc},
 };
 
 use chrono::{DateTime, Utc};
 use rust_rocksdb::{
-	backup::{BackupEngine, BackupEngineOptions},
+	backup::{BackupEngine, BackupEngineOptions, RestoreOptions},
 	LogLevel::{Debug, Error, Fatal, Info, Warn},
 	WriteBatchWithTransaction,
 };
@@ -184,6 +185,20 @@ impl KeyValueDatabaseEngine for Arc<Engine> {
 		}))
 	}
 
+	fn tree_names(&self) -> Result<Vec<String>> {
+		let names = rust_rocksdb::DBWithThreadMode::<rust_rocksdb::MultiThreaded>::list_cf(
+			&self.opts,
+			&self.config.database_path,
+		)?;
+
+		// Every RocksDB database has a default column family that conduwuit does not
+		// use
+		Ok(names
+			.into_iter()
+			.filter(|name| name != rust_rocksdb::DEFAULT_COLUMN_FAMILY_NAME)
+			.collect())
+	}
+
 	fn flush(&self) -> Result<()> {
 		rust_rocksdb::DBCommon::flush_wal(&self.rocks, false)?;
 
@@ -257,8 +272,13 @@ impl KeyValueDatabaseEngine for Arc<Engine> {
 
 			let engine_info = engine.get_backup_info();
 			let info = &engine_info.last().unwrap();
+			if let Err(e) = engine.verify_backup(info.backup_id) {
+				error!("Database backup #{} failed verification: {e}", info.backup_id);
+				return Err(Box::new(e));
+			}
+
 			info!(
-				"Created database backup #{} using {} bytes in {} files",
+				"Created and verified database backup #{} using {} bytes in {} files",
 				info.backup_id, info.size, info.num_files,
 			);
 			Ok(())
@@ -276,6 +296,40 @@ impl KeyValueDatabaseEngine for Arc<Engine> {
 		ret
 	}
 
+	fn restore_backup(config: &Config, backup_dir: &Path, id: Option<u64>) -> Result<u64> {
+		let options = BackupEngineOptions::new(backup_dir)?;
+		let mut engine = BackupEngine::open(&options, &rust_rocksdb::Env::new()?)?;
+
+		let id = match id {
+			Some(id) => u32::try_from(id).map_err(|_| crate::Error::bad_config("Invalid backup ID."))?,
+			None => engine
+				.get_backup_info()
+				.last()
+				.map(|info| info.backup_id)
+				.ok_or_else(|| crate::Error::bad_config("There are no backups to restore."))?,
+		};
+
+		engine.verify_backup(id)?;
+
+		// RocksDB locks the database while it is open, so opening it fails while the
+		// server is running. It's closed again because restoring replaces its files.
+		if Path::new(&config.database_path).join("CURRENT").exists() {
+			type Db = rust_rocksdb::DBWithThreadMode<rust_rocksdb::MultiThreaded>;
+
+			let opts = rust_rocksdb::Options::default();
+			let cfs = Db::list_cf(&opts, &config.database_path)?;
+			Db::open_cf(&opts, &config.database_path, cfs).map_err(|e| {
+				crate::Error::Error(format!(
+					"Failed to open the database, stop the server before restoring a backup: {e}"
+				))
+			})?;
+		}
+
+		engine.restore_from_backup(&config.database_path, &config.database_path, &RestoreOptions::default(), id)?;
+
+		Ok(id.into())
+	}
+
 	fn backup_list(&self) -> Result<String> {
 		let path = self.config.database_backup_path.as_ref();
 		if path.is_none() || path.is_some_and(|path| path.as_os_str().is_empty()) {
@@ -1,18 +1,29 @@
 use std::{
 	cell::RefCell,
+	fmt::Write as _,
+	fs,
 	future::Future,
+	io,
 	path::{Path, PathBuf},
 	pin::Pin,
 	sync::Arc,
 };
 
+use chrono::{DateTime, Utc};
 use parking_lot::{Mutex, MutexGuard};
-use rusqlite::{Connection, DatabaseName::Main, OptionalExtension};
+use rusqlite::{Connection, DatabaseName::Main, ErrorCode, OpenFlags, OptionalExtension};
 use thread_local::ThreadLocal;
-use tracing::debug;
+use tracing::{debug, error, info};
 
 use super::{watchers::Watchers, KeyValueDatabaseEngine, KvTree};
-use crate::{database::Config, Result};
+use crate::{
+	database::{backup::backup_dir, Config},
+	utils, Error, Result,
+};
+
+/// Backups are named with this prefix, the unix timestamp they were made at
+/// as their ID, and `.db`
+const BACKUP_PREFIX: &str = "conduit-";
 
 thread_local! {
 	static READ_CONNECTION: RefCell<Option<&'static Connection>> = const { RefCell::new(None) };
@@ -50,6 +61,9 @@ pub(crate) struct Engine {
 
 	path: PathBuf,
 	cache_size_per_thread: u32,
+
+	backup_path: Option<PathBuf>,
+	backups_to_keep: i16,
 }
 
 impl Engine {
@@ -82,6 +96,95 @@ impl Engine {
 			.pragma_update(Some(Main), "wal_checkpoint", "RESTART")?;
 		Ok(())
 	}
+
+	/// Copies the database into the backup directory with the online backup
+	/// API, which does not block writers for long
+	fn create_backup(&self, dir: &Path) -> Result<()> {
+		fs::create_dir_all(dir)?;
+
+		let id = utils::millis_since_unix_epoch() / 1000;
+		let path = dir.join(format!("{BACKUP_PREFIX}{id}.db"));
+		// Only complete and verified backups get their final name
+		let partial = path.with_extension("db.partial");
+
+		self.read_lock().backup(Main, &partial, None)?;
+
+		if let Err(e) = verify_backup(&partial) {
+			_ = fs::remove_file(&partial);
+			return Err(e);
+		}
+
+		fs::rename(&partial, &path)?;
+
+		info!(
+			"Created and verified database backup #{id} using {} bytes",
+			fs::metadata(&path)?.len()
+		);
+
+		Ok(())
+	}
+}
+
+/// The backups in `dir` with their IDs, oldest first
+fn list_backups(dir: &Path) -> Result<Vec<(u64, PathBuf)>> {
+	let entries = match fs::read_dir(dir) {
+		Ok(entries) => entries,
+		Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+		Err(e) => return Err(e.into()),
+	};
+
+	let mut backups = Vec::new();
+	for entry in entries {
+		let path = entry?.path();
+		let id = path
+			.file_name()
+			.and_then(|name| name.to_str())
+			.and_then(|name| name.strip_prefix(BACKUP_PREFIX))
+			.and_then(|name| name.strip_suffix(".db"))
+			.and_then(|id| id.parse().ok());
+
+		if let Some(id) = id {
+			backups.push((id, path));
+		}
+	}
+
+	backups.sort_unstable();
+
+	Ok(backups)
+}
+
+fn verify_backup(path: &Path) -> Result<()> {
+	let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+	let result: String = conn.query_row("PRAGMA integrity_check", [], |row| row.get(0))?;
+
+	if result != "ok" {
+		return Err(Error::Error(format!(
+			"Database backup {} failed the integrity check: {result}",
+			path.display()
+		)));
+	}
+
+	Ok(())
+}
+
+/// Takes an exclusive lock on the database at `path`, if it exists. Open
+/// connections in WAL mode hold a shared lock, so this fails while the server
+/// is running.
+fn lock_database(path: &Path) -> Result<Option<Connection>> {
+	if !path.exists() {
+		return Ok(None);
+	}
+
+	let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_WRITE)?;
+	conn.execute_batch("PRAGMA locking_mode = EXCLUSIVE; BEGIN EXCLUSIVE; COMMIT;")
+		.map_err(|e| match e.sqlite_error_code() {
+			Some(ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked) => {
+				Error::Error("The database is in use. Stop the server before restoring a backup.".to_owned())
+			},
+			_ => e.into(),
+		})?;
+
+	Ok(Some(conn))
 }
 
 impl KeyValueDatabaseEngine for Arc<Engine> {
@@ -104,6 +207,8 @@ impl KeyValueDatabaseEngine for Arc<Engine> {
 			read_iterator_conn_tls: ThreadLocal::new(),
 			path,
 			cache_size_per_thread,
+			backup_path: backup_dir(config).map(Path::to_path_buf),
+			backups_to_keep: config.database_backups_to_keep,
 		});
 
 		Ok(arc)
@@ -122,12 +227,109 @@ impl KeyValueDatabaseEngine for Arc<Engine> {
 		}))
 	}
 
+	fn tree_names(&self) -> Result<Vec<String>> {
+		let guard = self.write_lock();
+		let mut statement = guard.prepare("SELECT name FROM sqlite_master WHERE type = 'table'")?;
+		let names = statement
+			.query_map([], |row| row.get(0))?
+			.collect::<Result<Vec<String>, _>>()?;
+
+		Ok(names)
+	}
+
 	fn flush(&self) -> Result<()> {
 		// we enabled PRAGMA synchronous=normal, so this should not be necessary
 		Ok(())
 	}
 
 	fn cleanup(&self) -> Result<()> { self.flush_wal() }
+
+	fn backup(&self) -> Result<(), Box<dyn std::error::Error>> {
+		let Some(dir) = &self.backup_path else {
+			return Ok(());
+		};
+
+		if self.backups_to_keep > 0 {
+			self.create_backup(dir)?;
+		}
+
+		if self.backups_to_keep >= 0 {
+			let keep = usize::try_from(self.backups_to_keep)?;
+			let backups = list_backups(dir)?;
+			for (_, path) in &backups[..backups.len().saturating_sub(keep)] {
+				if let Err(e) = fs::remove_file(path) {
+					error!("Failed to purge old backup {}: {e}", path.display());
+				}
+			}
+		}
+
+		Ok(())
+	}
+
+	fn restore_backup(config: &Config, backup_dir: &Path, id: Option<u64>) -> Result<u64> {
+		let mut backups = list_backups(backup_dir)?;
+		let backup = match id {
+			Some(id) => backups.into_iter().find(|(backup_id, _)| *backup_id == id),
+			None => backups.pop(),
+		};
+		let Some((id, backup)) = backup else {
+			return Err(Error::bad_config("The backup to restore does not exist."));
+		};
+
+		verify_backup(&backup)?;
+
+		fs::create_dir_all(&config.database_path)?;
+		let path = Path::new(&config.database_path).join("conduit.db");
+
+		// Held until the backup is in place, so the server can't open the database
+		// in between
+		let lock = lock_database(&path)?;
+
+		// Copied next to the database first, so a failed copy leaves it intact and
+		// the rename replaces it at once
+		let mut temp = path.clone().into_os_string();
+		temp.push(".restore");
+		fs::copy(&backup, &temp)?;
+
+		// The write-ahead log of the old database must not be applied to the backup
+		for suffix in ["-wal", "-shm"] {
+			let mut file = path.clone().into_os_string();
+			file.push(suffix);
+
+			if let Err(e) = fs::remove_file(file) {
+				if e.kind() != io::ErrorKind::NotFound {
+					return Err(e.into());
+				}
+			}
+		}
+
+		fs::rename(&temp, &path)?;
+		drop(lock);
+
+		Ok(id)
+	}
+
+	fn backup_list(&self) -> Result<String> {
+		let Some(dir) = &self.backup_path else {
+			return Ok(
+				"Configure database_backup_path to enable backups, or the path specified is not valid".to_owned(),
+			);
+		};
+
+		let mut res = String::new();
+		for (id, path) in list_backups(dir)? {
+			_ = writeln!(
+				res,
+				"#{id} {}: {} bytes",
+				DateTime::<Utc>::from_timestamp(i64::try_from(id).unwrap_or_default(), 0)
+					.unwrap_or_default()
+					.to_rfc2822(),
+				fs::metadata(&path)?.len(),
+			);
+		}
+
+		Ok(res)
+	}
 }
 
 pub struct SqliteTable {
@@ -0,0 +1,301 @@
+//! Exporting and importing the whole database, independent of the backend.
+//!
+//! An archive starts with [`MAGIC`] and the format version, followed by a zstd
+//! stream of records: a tree name starts a tree, every entry after it belongs
+//! to that tree, and the archive ends with an end record so truncated files are
+//! noticed.
+
+use std::{
+	fs::File,
+	io::{self, BufReader, BufWriter, Read, Write},
+	path::Path,
+	sync::Arc,
+	time::Instant,
+};
+
+use tracing::info;
+
+use super::{
+	abstraction::{KeyValueDatabaseEngine, KvTree},
+	KeyValueDatabase,
+};
+use crate::{Config, Error, Result};
+
+const MAGIC: &[u8] = b"conduwuit-export";
+const FORMAT_VERSION: u32 = 1;
+
+const RECORD_TREE: u8 = 1;
+const RECORD_ENTRY: u8 = 2;
+const RECORD_END: u8 = 3;
+
+/// zstd level, a good tradeoff between speed and size for large databases
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// How many entries are inserted into a tree at once on import
+const IMPORT_BATCH_SIZE: usize = 1000;
+
+impl KeyValueDatabase {
+	/// Writes every tree of the database in the config to an archive at
+	/// `path`. The server must not be running. Media files are not part of the
+	/// archive.
+	pub fn export(config: &Config, path: &Path) -> Result<()> {
+		let start = Instant::now();
+
+		// Opening the engine would create an empty database to export instead
+		let database_path = Path::new(&config.database_path);
+		let exists = match &*config.database_backend {
+			"sqlite" => database_path.join("conduit.db").exists(),
+			"rocksdb" => database_path.join("IDENTITY").exists(),
+			"memory" => return Err(Error::bad_config("In-memory databases can't be exported.")),
+			_ => false,
+		};
+		if !exists {
+			return Err(Error::bad_config(&format!(
+				"No {} database found at {}.",
+				config.database_backend,
+				database_path.display()
+			)));
+		}
+
+		let engine = Self::open_engine(config)?;
+
+		let total = write_archive(&*engine, BufWriter::new(File::create(path)?))?;
+
+		info!("Exported {total} entries to {} in {:?}", path.display(), start.elapsed());
+
+		Ok(())
+	}
+
+	/// Reads an archive written by [`KeyValueDatabase::export`] into the
+	/// database in the config, which may use a different backend. The database
+	/// must be empty and the server must not be running.
+	pub fn import(config: &Config, path: &Path) -> Result<()> {
+		let start = Instant::now();
+		let engine = Self::open_engine(config)?;
+
+		let total = read_archive(&*engine, BufReader::new(File::open(path)?))?;
+
+		info!("Imported {total} entries from {} in {:?}", path.display(), start.elapsed());
+
+		Ok(())
+	}
+}
+
+/// Writes every tree of `engine` to `file`. Returns the number of entries.
+fn write_archive(engine: &dyn KeyValueDatabaseEngine, mut file: impl Write) -> Result<u64> {
+	file.write_all(MAGIC)?;
+	file.write_all(&FORMAT_VERSION.to_be_bytes())?;
+
+	let mut writer = zstd::stream::write::Encoder::new(file, COMPRESSION_LEVEL)?;
+	// Lets the import notice corruption inside the compressed stream
+	writer.include_checksum(true)?;
+
+	let mut names = engine.tree_names()?;
+	names.sort_unstable();
+
+	let mut total = 0_u64;
+	for name in names {
+		let tree = engine.open_tree(leak_name(name.clone()))?;
+
+		writer.write_all(&[RECORD_TREE])?;
+		write_bytes(&mut writer, name.as_bytes())?;
+
+		let mut entries = 0_u64;
+		for (key, value) in tree.iter() {
+			writer.write_all(&[RECORD_ENTRY])?;
+			write_bytes(&mut writer, &key)?;
+			write_bytes(&mut writer, &value)?;
+			entries += 1;
+		}
+
+		info!("Exported {entries} entries of {name}");
+		total += entries;
+	}
+
+	writer.write_all(&[RECORD_END])?;
+	writer.finish()?.flush()?;
+
+	Ok(total)
+}
+
+/// Reads an archive from `file` into `engine`, which must be empty. Returns
+/// the number of entries.
+fn read_archive(engine: &dyn KeyValueDatabaseEngine, mut file: impl Read) -> Result<u64> {
+	for name in engine.tree_names()? {
+		if engine.open_tree(leak_name(name))?.iter().next().is_some() {
+			return Err(Error::bad_config(
+				"The database to import into is not empty. Point database_path to a new directory.",
+			));
+		}
+	}
+
+	let mut magic = [0; MAGIC.len()];
+	file.read_exact(&mut magic)?;
+	if magic != MAGIC {
+		return Err(invalid("not a conduwuit database export"));
+	}
+
+	let mut version = [0; 4];
+	file.read_exact(&mut version)?;
+	if u32::from_be_bytes(version) != FORMAT_VERSION {
+		return Err(invalid("exported by an incompatible version of conduwuit"));
+	}
+
+	let mut reader = zstd::stream::read::Decoder::new(file)?;
+
+	let mut tree: Option<(String, Arc<dyn KvTree>)> = None;
+	let mut batch = Vec::with_capacity(IMPORT_BATCH_SIZE);
+	let mut total = 0_u64;
+
+	loop {
+		let mut record = [0];
+		reader.read_exact(&mut record).map_err(truncated)?;
+
+		match record[0] {
+			RECORD_ENTRY => {
+				let Some((_, tree)) = &tree else {
+					return Err(invalid("entry outside of a tree"));
+				};
+
+				let key = read_bytes(&mut reader)?;
+				let value = read_bytes(&mut reader)?;
+				batch.push((key, value));
+				total += 1;
+
+				if batch.len() == IMPORT_BATCH_SIZE {
+					tree.insert_batch(&mut batch.drain(..))?;
+				}
+			},
+			RECORD_TREE | RECORD_END => {
+				if let Some((name, tree)) = tree.take() {
+					tree.insert_batch(&mut batch.drain(..))?;
+					info!("Imported {name}");
+				}
+
+				if record[0] == RECORD_END {
+					break;
+				}
+
+				let name = String::from_utf8(read_bytes(&mut reader)?).map_err(|_| invalid("invalid tree name"))?;
+				tree = Some((name.clone(), engine.open_tree(leak_name(name))?));
+			},
+			_ => return Err(invalid("unknown record")),
+		}
+	}
+
+	engine.flush()?;
+
+	Ok(total)
+}
+
+/// Trees are opened with static names, which the ones read from the database
+/// or an archive are not. Exports and imports run once before the process
+/// exits, so leaking the few names is fine.
+fn leak_name(name: String) -> &'static str { Box::leak(name.into_boxed_str()) }
+
+fn write_bytes(writer: &mut impl Write, bytes: &[u8]) -> io::Result<()> {
+	let len = u32::try_from(bytes.len()).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "entry too large"))?;
+	writer.write_all(&len.to_be_bytes())?;
+	writer.write_all(bytes)
+}
+
+fn read_bytes(reader: &mut impl Read) -> Result<Vec<u8>> {
+	let mut len = [0; 4];
+	reader.read_exact(&mut len).map_err(truncated)?;
+
+	// The length comes from the file, so the buffer only grows with the data
+	// actually read instead of being allocated up front
+	let len = u64::from(u32::from_be_bytes(len));
+	let mut bytes = Vec::new();
+	reader.by_ref().take(len).read_to_end(&mut bytes)?;
+	if bytes.len() as u64 != len {
+		return Err(invalid("file is truncated"));
+	}
+
+	Ok(bytes)
+}
+
+fn truncated(e: io::Error) -> Error {
+	if e.kind() == io::ErrorKind::UnexpectedEof {
+		return invalid("file is truncated");
+	}
+
+	e.into()
+}
+
+fn invalid(reason: &str) -> Error { Error::Error(format!("Invalid database export: {reason}")) }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::database::abstraction::memory;
+
+	fn archive(engine: &Arc<memory::Engine>) -> Vec<u8> {
+		let mut file = Vec::new();
+		write_archive(engine, &mut file).unwrap();
+		file
+	}
+
+	#[test]
+	fn round_trip() {
+		let source = Arc::new(memory::Engine::default());
+		let users = source.open_tree("userid_password").unwrap();
+		users.insert(b"@alice:example.com", b"hash").unwrap();
+		users.insert(b"@bob:example.com", b"").unwrap();
+		let pdus = source.open_tree("pduid_pdu").unwrap();
+		for i in 0..IMPORT_BATCH_SIZE as u64 + 1 {
+			pdus.insert(&i.to_be_bytes(), &[0xFF; 100]).unwrap();
+		}
+		source.open_tree("empty").unwrap();
+
+		let file = archive(&source);
+
+		let target = Arc::new(memory::Engine::default());
+		assert_eq!(read_archive(&target, &file[..]).unwrap(), IMPORT_BATCH_SIZE as u64 + 3);
+
+		let mut names = target.tree_names().unwrap();
+		names.sort_unstable();
+		assert_eq!(names, vec!["empty", "pduid_pdu", "userid_password"]);
+
+		for name in ["empty", "pduid_pdu", "userid_password"] {
+			let expected = source.open_tree(name).unwrap().iter().collect::<Vec<_>>();
+			let imported = target.open_tree(name).unwrap().iter().collect::<Vec<_>>();
+			assert_eq!(imported, expected);
+		}
+	}
+
+	#[test]
+	fn refuses_non_empty_database() {
+		let source = Arc::new(memory::Engine::default());
+		source
+			.open_tree("global")
+			.unwrap()
+			.insert(b"key", b"value")
+			.unwrap();
+		let file = archive(&source);
+
+		assert!(read_archive(&source, &file[..]).is_err());
+	}
+
+	#[test]
+	fn rejects_truncated_archive() {
+		let source = Arc::new(memory::Engine::default());
+		let tree = source.open_tree("global").unwrap();
+		for i in 0_u64..100 {
+			tree.insert(&i.to_be_bytes(), &i.to_be_bytes()).unwrap();
+		}
+		let file = archive(&source);
+
+		let target = Arc::new(memory::Engine::default());
+		assert!(read_archive(&target, &file[..file.len() - 10]).is_err());
+	}
+
+	#[test]
+	fn huge_length_is_not_allocated() {
+		let mut file = Vec::new();
+		file.extend_from_slice(&u32::MAX.to_be_bytes());
+		file.extend_from_slice(b"short");
+
+		assert!(read_bytes(&mut &file[..]).is_err());
+	}
+}
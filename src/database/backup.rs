@@ -0,0 +1,403 @@
+//! Scheduled database backups, copying the media directory into the backup
+//! path and restoring backups offline.
+
+#[cfg(any(feature = "sqlite", feature = "rocksdb"))]
+use std::sync::Arc;
+use std::{
+	fmt::Write as _,
+	fs, io,
+	path::{Path, PathBuf},
+	str::FromStr,
+	sync::Mutex,
+	time::Duration,
+};
+
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use ruma::events::room::message::RoomMessageEventContent;
+use tokio::time::sleep;
+use tracing::{debug, error, info};
+
+#[cfg(any(feature = "sqlite", feature = "rocksdb"))]
+use super::abstraction::{self, KeyValueDatabaseEngine};
+use super::KeyValueDatabase;
+use crate::{services, utils, Config, Error, Result};
+
+/// How far ahead a schedule is searched for its next run, long enough to find
+/// schedules that only run on leap days
+const SCHEDULE_SEARCH_DAYS: u64 = 5 * 366;
+
+const MINUTES_PER_DAY: u64 = 24 * 60;
+
+/// Keeps a scheduled backup and one started from the admin room from running
+/// at the same time
+static BACKUP_LOCK: Mutex<()> = Mutex::new(());
+
+/// When backups run, parsed from a cron expression with the fields minute,
+/// hour, day of month, month and day of week, in UTC.
+///
+/// Fields take `*`, numbers, ranges like `1-5`, steps like `*/15` or `0-30/10`
+/// and lists of these separated by commas. Days of week count from 0 for
+/// Sunday, 7 is Sunday too. Like in cron, a day matches either day field if
+/// both are restricted. `@hourly`, `@daily`, `@weekly` and `@monthly` can be
+/// used as shortcuts.
+#[derive(Debug)]
+pub(crate) struct Schedule {
+	minutes: u64,
+	hours: u64,
+	days: u64,
+	months: u64,
+	weekdays: u64,
+	/// Either day field is `*`, so a day has to match both of them
+	match_both_days: bool,
+}
+
+impl FromStr for Schedule {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let expression = match s.trim() {
+			"@hourly" => "0 * * * *",
+			"@daily" => "0 0 * * *",
+			"@weekly" => "0 0 * * 0",
+			"@monthly" => "0 0 1 * *",
+			expression => expression,
+		};
+
+		let fields = expression.split_whitespace().collect::<Vec<_>>();
+		let [minutes, hours, days, months, weekdays] = fields[..] else {
+			return Err(format!("expected 5 fields, found {}", fields.len()));
+		};
+
+		let mut weekdays_parsed = parse_field(weekdays, 0, 7)?;
+		// 7 is another name for Sunday
+		if weekdays_parsed & (1 << 7) != 0 {
+			weekdays_parsed = (weekdays_parsed | 1) & !(1 << 7);
+		}
+
+		Ok(Self {
+			minutes: parse_field(minutes, 0, 59)?,
+			hours: parse_field(hours, 0, 23)?,
+			days: parse_field(days, 1, 31)?,
+			months: parse_field(months, 1, 12)?,
+			weekdays: weekdays_parsed,
+			match_both_days: days == "*" || weekdays == "*",
+		})
+	}
+}
+
+/// Parses one field of a cron expression into a bit set of the values it
+/// matches
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+	let number = |value: &str| {
+		value
+			.parse::<u32>()
+			.map_err(|_| format!("invalid number {value:?} in {field:?}"))
+	};
+
+	let mut values = 0_u64;
+	for part in field.split(',') {
+		let (range, step) = match part.split_once('/') {
+			Some((range, step)) => (range, number(step)?),
+			None => (part, 1),
+		};
+
+		let (start, end) = if range == "*" {
+			(min, max)
+		} else if let Some((start, end)) = range.split_once('-') {
+			(number(start)?, number(end)?)
+		} else {
+			let start = number(range)?;
+			// A single value with a step, like `5/10`, runs from there to the end
+			(
+				start,
+				if step > 1 {
+					max
+				} else {
+					start
+				},
+			)
+		};
+
+		if step == 0 || start < min || end > max || start > end {
+			return Err(format!("{part:?} is out of range, {field:?} takes {min} to {max}"));
+		}
+
+		for value in (start..=end).step_by(step as usize) {
+			values |= 1 << value;
+		}
+	}
+
+	Ok(values)
+}
+
+impl Schedule {
+	/// The first time this schedule runs after `secs`, in seconds since the
+	/// unix epoch. None if it never runs.
+	pub(crate) fn next_after(&self, secs: u64) -> Option<u64> {
+		let mut minute = secs / 60 + 1;
+		let end = minute + SCHEDULE_SEARCH_DAYS * MINUTES_PER_DAY;
+
+		while minute < end {
+			let time = DateTime::<Utc>::from_timestamp(i64::try_from(minute * 60).ok()?, 0)?;
+
+			if !self.runs_on(&time) {
+				// Days since the epoch are whole days in UTC
+				minute = (minute / MINUTES_PER_DAY + 1) * MINUTES_PER_DAY;
+				continue;
+			}
+
+			if has(self.hours, time.hour()) && has(self.minutes, time.minute()) {
+				return Some(minute * 60);
+			}
+
+			minute += 1;
+		}
+
+		None
+	}
+
+	fn runs_on<T: Datelike>(&self, date: &T) -> bool {
+		let day = has(self.days, date.day());
+		let weekday = has(self.weekdays, date.weekday().num_days_from_sunday());
+
+		has(self.months, date.month())
+			&& if self.match_both_days {
+				day && weekday
+			} else {
+				day || weekday
+			}
+	}
+}
+
+fn has(values: u64, value: u32) -> bool { values & (1 << value) != 0 }
+
+/// The configured backup path, None if backups are disabled
+pub(crate) fn backup_dir(config: &Config) -> Option<&Path> {
+	config
+		.database_backup_path
+		.as_deref()
+		.filter(|path| !path.as_os_str().is_empty())
+}
+
+/// Backs up the database and, if `database_backup_media` is set, copies new
+/// media into the backup path. Blocks until both are done and
+/// returns a summary of the backups.
+///
+/// The backed up media is shared by all backups and never pruned, it only
+/// grows.
+pub(crate) fn create_backup() -> Result<String> {
+	let config = &services().globals.config;
+	let Some(dir) = backup_dir(config) else {
+		return Err(Error::bad_config("Configure database_backup_path to enable backups."));
+	};
+
+	let _lock = BACKUP_LOCK.lock().unwrap();
+
+	services()
+		.globals
+		.db
+		.backup()
+		.map_err(|e| Error::Error(format!("Failed to back up the database: {e}")))?;
+
+	let mut summary = services().globals.db.backup_list()?;
+
+	if config.database_backup_media {
+		let from = media_dir(config);
+		let to = dir.join("media");
+
+		// Media deleted since is kept, older backups may still refer to it. Nothing
+		// tracks which backups refer to a file, so it is never removed.
+		let copied = copy_media(&from, &to, true)?;
+
+		info!("Copied {copied} media files into {}", to.display());
+		_ = write!(summary, "\nMedia: {copied} files copied");
+	}
+
+	Ok(summary)
+}
+
+fn media_dir(config: &Config) -> PathBuf { config.database_path.join("media") }
+
+/// The regular files in `dir`, which may not exist yet
+fn media_files(dir: &Path) -> io::Result<Vec<(PathBuf, u64)>> {
+	let entries = match fs::read_dir(dir) {
+		Ok(entries) => entries,
+		Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+		Err(e) => return Err(e),
+	};
+
+	let mut files = Vec::new();
+	for entry in entries {
+		let entry = entry?;
+		let metadata = entry.metadata()?;
+		if metadata.is_file() {
+			files.push((entry.path(), metadata.len()));
+		}
+	}
+
+	Ok(files)
+}
+
+/// Copies the media files in `from` that are missing in `to`, and with
+/// `replace_changed` the ones that have a different size there. Returns how
+/// many were copied.
+fn copy_media(from: &Path, to: &Path, replace_changed: bool) -> io::Result<usize> {
+	fs::create_dir_all(to)?;
+
+	let mut copied = 0;
+	for (path, len) in media_files(from)? {
+		let Some(name) = path.file_name() else {
+			continue;
+		};
+
+		let target = to.join(name);
+		let unchanged = match fs::metadata(&target) {
+			Ok(metadata) => !replace_changed || metadata.len() == len,
+			Err(e) if e.kind() == io::ErrorKind::NotFound => false,
+			Err(e) => return Err(e),
+		};
+
+		if !unchanged {
+			fs::copy(&path, &target)?;
+			copied += 1;
+		}
+	}
+
+	Ok(copied)
+}
+
+impl KeyValueDatabase {
+	/// Runs [`create_backup`] on the `database_backup_schedule`
+	pub(super) fn start_backup_task() {
+		let Some(schedule) = &services().globals.config.database_backup_schedule else {
+			return;
+		};
+
+		let schedule = match schedule.parse::<Schedule>() {
+			Ok(schedule) => schedule,
+			Err(e) => {
+				error!("Invalid database_backup_schedule, no backups will be made: {e}");
+				return;
+			},
+		};
+
+		tokio::spawn(async move {
+			loop {
+				let now = utils::millis_since_unix_epoch() / 1000;
+				let Some(next) = schedule.next_after(now) else {
+					error!("database_backup_schedule never runs, no backups will be made");
+					return;
+				};
+
+				debug!("Next scheduled database backup in {} seconds", next - now);
+				sleep(Duration::from_secs(next - now)).await;
+
+				let result = tokio::task::spawn_blocking(create_backup)
+					.await
+					.unwrap_or_else(|e| Err(Error::Error(format!("Backup task failed: {e}"))));
+
+				match result {
+					Ok(_) => info!("Scheduled database backup finished"),
+					Err(e) => {
+						error!("Scheduled database backup failed: {e}");
+						services()
+							.admin
+							.send_message(RoomMessageEventContent::text_plain(format!(
+								"Scheduled database backup failed: {e}"
+							)));
+					},
+				}
+			}
+		});
+	}
+
+	/// Replaces the database in the config with a backup from
+	/// `database_backup_path`, the newest one if `id` is None. The backup is
+	/// verified first. With `media`, media files missing from the media
+	/// directory are copied back from the backup path. The server must not be
+	/// running.
+	pub fn restore_backup(config: &Config, id: Option<u64>, media: bool) -> Result<()> {
+		let Some(dir) = backup_dir(config) else {
+			return Err(Error::bad_config("Configure database_backup_path to restore a backup."));
+		};
+
+		let restored = match &*config.database_backend {
+			"sqlite" => {
+				#[cfg(not(feature = "sqlite"))]
+				return Err(Error::bad_config("Database backend not found."));
+				#[cfg(feature = "sqlite")]
+				Arc::<abstraction::sqlite::Engine>::restore_backup(config, dir, id)?
+			},
+			"rocksdb" => {
+				#[cfg(not(feature = "rocksdb"))]
+				return Err(Error::bad_config("Database backend not found."));
+				#[cfg(feature = "rocksdb")]
+				Arc::<abstraction::rocksdb::Engine>::restore_backup(config, dir, id)?
+			},
+			_ => {
+				return Err(Error::bad_config(
+					"Only rocksdb and sqlite databases can be restored from backups.",
+				));
+			},
+		};
+
+		info!("Restored backup #{restored} into {}", config.database_path.display());
+
+		if media {
+			let copied = copy_media(&dir.join("media"), &media_dir(config), false)?;
+			info!("Restored {copied} media files");
+		}
+
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::Schedule;
+
+	/// 2024-01-01T00:00:00Z, a Monday
+	const NEW_YEAR: u64 = 1_704_067_200;
+	const HOUR: u64 = 60 * 60;
+	const DAY: u64 = 24 * HOUR;
+
+	fn next(schedule: &str, after: u64) -> Option<u64> { schedule.parse::<Schedule>().unwrap().next_after(after) }
+
+	#[test]
+	fn runs_strictly_after_the_given_time() {
+		assert_eq!(next("0 4 * * *", NEW_YEAR), Some(NEW_YEAR + 4 * HOUR));
+		assert_eq!(next("@daily", NEW_YEAR), Some(NEW_YEAR + DAY));
+		assert_eq!(next("*/15 * * * *", NEW_YEAR), Some(NEW_YEAR + 15 * 60));
+		assert_eq!(next("*/15 * * * *", NEW_YEAR + 60), Some(NEW_YEAR + 15 * 60));
+	}
+
+	#[test]
+	fn day_fields() {
+		// Sundays, written both ways
+		assert_eq!(next("0 0 * * 0", NEW_YEAR), Some(NEW_YEAR + 6 * DAY));
+		assert_eq!(next("0 0 * * 7", NEW_YEAR), Some(NEW_YEAR + 6 * DAY));
+
+		// Either the 13th or a Friday, and Friday the 5th comes first
+		assert_eq!(next("0 0 13 * 5", NEW_YEAR), Some(NEW_YEAR + 4 * DAY));
+
+		// The next leap day
+		assert_eq!(next("0 0 29 2 *", NEW_YEAR), Some(NEW_YEAR + 59 * DAY));
+
+		assert_eq!(next("0 0 31 2 *", NEW_YEAR), None);
+	}
+
+	#[test]
+	fn invalid_expressions() {
+		for expression in [
+			"",
+			"* * * *",
+			"60 * * * *",
+			"*/0 * * * *",
+			"5-1 * * * *",
+			"0 0 0 * *",
+			"a * * * *",
+		] {
+			assert!(expression.parse::<Schedule>().is_err(), "{expression:?} parsed");
+		}
+	}
+}
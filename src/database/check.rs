@@ -0,0 +1,469 @@
+//! Checking invariants between trees that the rest of the code relies on, see
+//! [`KeyValueDatabase::check_consistency`].
+
+use std::{
+	collections::{HashMap, HashSet},
+	fmt,
+	sync::Arc,
+};
+
+use ruma::{
+	events::{
+		room::member::{MembershipState, RoomMemberEventContent},
+		StateEventType,
+	},
+	OwnedDeviceId, OwnedUserId, RoomId, UserId,
+};
+
+use super::{abstraction::KvTree, KeyValueDatabase};
+use crate::{services, utils, Result};
+
+/// How many problems of each check are described in the report
+const MAX_EXAMPLES: usize = 10;
+
+/// The outcome of [`KeyValueDatabase::check_consistency`]
+#[derive(Default)]
+pub struct CheckReport {
+	checks: Vec<Check>,
+}
+
+impl CheckReport {
+	/// Whether every problem that was found has been repaired
+	#[must_use]
+	pub fn is_consistent(&self) -> bool {
+		self.checks
+			.iter()
+			.all(|check| check.problems == check.repaired)
+	}
+}
+
+impl fmt::Display for CheckReport {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		for check in &self.checks {
+			writeln!(
+				f,
+				"{}: {} checked, {} problems, {} repaired",
+				check.description, check.checked, check.problems, check.repaired
+			)?;
+
+			for example in &check.examples {
+				writeln!(f, "  - {example}")?;
+			}
+
+			if check.problems > check.examples.len() as u64 {
+				writeln!(f, "  - and {} more", check.problems - check.examples.len() as u64)?;
+			}
+		}
+
+		Ok(())
+	}
+}
+
+/// One invariant and how often it did not hold
+struct Check {
+	description: &'static str,
+	checked: u64,
+	problems: u64,
+	repaired: u64,
+	examples: Vec<String>,
+}
+
+impl Check {
+	fn new(description: &'static str) -> Self {
+		Self {
+			description,
+			checked: 0,
+			problems: 0,
+			repaired: 0,
+			examples: Vec::new(),
+		}
+	}
+
+	/// Counts a problem, only describing the first few to keep the report short
+	fn problem<F: FnOnce() -> String>(&mut self, describe: F) {
+		self.problems += 1;
+		if self.examples.len() < MAX_EXAMPLES {
+			self.examples.push(describe());
+		}
+	}
+}
+
+impl KeyValueDatabase {
+	/// Checks the invariants between trees that can't be enforced by the
+	/// database itself. With `repair`, the problems that can be fixed without
+	/// losing data are fixed.
+	pub(crate) async fn check_consistency(&self, repair: bool) -> Result<CheckReport> {
+		let mut report = CheckReport::default();
+
+		report.checks.push(self.check_eventid_pduid(repair)?);
+		report.checks.push(check_inverse(
+			"Every shorteventid_eventid entry has a matching eventid_shorteventid entry",
+			&*self.shorteventid_eventid,
+			&*self.eventid_shorteventid,
+			(show_short, show_string),
+			repair,
+		)?);
+		report.checks.push(check_inverse(
+			"Every eventid_shorteventid entry has a matching shorteventid_eventid entry",
+			&*self.eventid_shorteventid,
+			&*self.shorteventid_eventid,
+			(show_string, show_short),
+			repair,
+		)?);
+		report.checks.push(check_memberships(repair).await?);
+		report.checks.push(self.check_device_tokens(repair)?);
+		report.checks.push(self.check_media_files(repair)?);
+
+		Ok(report)
+	}
+
+	/// Mappings to PDUs that don't exist are removed, the event is then handled
+	/// like any other unknown event.
+	fn check_eventid_pduid(&self, repair: bool) -> Result<Check> {
+		let mut check = Check::new("Every eventid_pduid entry points to an existing PDU");
+		let mut dangling = Vec::new();
+
+		for (event_id, pdu_id) in self.eventid_pduid.iter() {
+			check.checked += 1;
+
+			if self.pduid_pdu.get(&pdu_id)?.is_none() {
+				check.problem(|| format!("{} points to the missing PDU {pdu_id:?}", show_string(&event_id)));
+				dangling.push(event_id);
+			}
+		}
+
+		if repair {
+			for event_id in dangling {
+				self.eventid_pduid.remove(&event_id)?;
+				check.repaired += 1;
+			}
+		}
+
+		Ok(check)
+	}
+
+	/// Devices need an access token, and `token_userdeviceid` may only contain
+	/// the current token of each device because it authenticates requests.
+	/// Old tokens are revoked and current tokens that were not accepted are
+	/// restored. Devices without a token are only reported: removing them would
+	/// also remove their keys and to-device messages.
+	fn check_device_tokens(&self, repair: bool) -> Result<Check> {
+		let mut check = Check::new("Every device has exactly one working access token");
+		let mut unaccepted = Vec::new();
+		let mut stale = Vec::new();
+
+		for (userdeviceid, _) in self.userdeviceid_metadata.iter() {
+			check.checked += 1;
+
+			match self.userdeviceid_token.get(&userdeviceid)? {
+				None => {
+					check.problem(|| {
+						format!(
+							"{} has no access token, remove the device if it is no longer used",
+							show_userdeviceid(&userdeviceid)
+						)
+					});
+				},
+				Some(token) => {
+					if self.token_userdeviceid.get(&token)?.as_ref() != Some(&userdeviceid) {
+						check.problem(|| {
+							format!("The access token of {} is not accepted", show_userdeviceid(&userdeviceid))
+						});
+						unaccepted.push((token, userdeviceid));
+					}
+				},
+			}
+		}
+
+		for (token, userdeviceid) in self.token_userdeviceid.iter() {
+			check.checked += 1;
+
+			if self.userdeviceid_token.get(&userdeviceid)?.as_ref() != Some(&token) {
+				check.problem(|| {
+					format!("An old access token of {} is still accepted", show_userdeviceid(&userdeviceid))
+				});
+				stale.push(token);
+			}
+		}
+
+		if repair {
+			for token in stale {
+				self.token_userdeviceid.remove(&token)?;
+				check.repaired += 1;
+			}
+
+			for (token, userdeviceid) in unaccepted {
+				// Never take over a token that authenticates another device
+				if self.token_userdeviceid.get(&token)?.is_none() {
+					self.token_userdeviceid.insert(&token, &userdeviceid)?;
+					check.repaired += 1;
+				}
+			}
+		}
+
+		Ok(check)
+	}
+
+	/// Media without a file can't be served, removing the entry allows remote
+	/// media to be fetched again.
+	fn check_media_files(&self, repair: bool) -> Result<Check> {
+		let mut check = Check::new("Every media entry has a file on disk");
+		let mut missing = Vec::new();
+
+		for (key, _) in self.mediaid_file.iter() {
+			check.checked += 1;
+
+			#[cfg(feature = "sha256_media")]
+			let path = services().globals.get_media_file_new(&key);
+
+			#[cfg(not(feature = "sha256_media"))]
+			let path = services().globals.get_media_file(&key);
+
+			if !path.exists() {
+				check.problem(|| format!("{} has no file at {}", show_mxc(&key), path.display()));
+				missing.push(key);
+			}
+		}
+
+		if repair {
+			for key in missing {
+				self.mediaid_file.remove(&key)?;
+
+				// Forget the uploader once no file of the media is left
+				let mxc = key
+					.split(|&b| b == 0xFF)
+					.next()
+					.expect("split always returns one element");
+				let mut prefix = mxc.to_vec();
+				prefix.push(0xFF);
+				if self.mediaid_file.scan_prefix(prefix).next().is_none() {
+					self.mediaid_user.remove(mxc)?;
+				}
+
+				check.repaired += 1;
+			}
+		}
+
+		Ok(check)
+	}
+}
+
+/// Checks that every entry of `tree` is mapped back by `inverse`. Missing
+/// entries are added to `inverse`, but conflicting ones are only reported
+/// because other trees may refer to either of them.
+fn check_inverse(
+	description: &'static str, tree: &dyn KvTree, inverse: &dyn KvTree,
+	(show_key, show_value): (fn(&[u8]) -> String, fn(&[u8]) -> String), repair: bool,
+) -> Result<Check> {
+	let mut check = Check::new(description);
+	let mut missing = Vec::new();
+
+	for (key, value) in tree.iter() {
+		check.checked += 1;
+
+		match inverse.get(&value)? {
+			Some(inverse_key) if inverse_key == key => {},
+			Some(inverse_key) => check.problem(|| {
+				format!(
+					"{} maps to {}, which maps back to {}",
+					show_key(&key),
+					show_value(&value),
+					show_key(&inverse_key)
+				)
+			}),
+			None => {
+				check
+					.problem(|| format!("{} maps to {}, which is not mapped back", show_key(&key), show_value(&value)));
+				missing.push((value, key));
+			},
+		}
+	}
+
+	if repair {
+		for (value, key) in missing {
+			inverse.insert(&value, &key)?;
+			check.repaired += 1;
+		}
+	}
+
+	Ok(check)
+}
+
+/// Compares the membership caches of every room with its current state. Wrong
+/// entries are fixed the same way as when the state of a room is replaced.
+async fn check_memberships(repair: bool) -> Result<Check> {
+	let mut check = Check::new("The membership caches match the current room state");
+
+	let room_ids = services()
+		.rooms
+		.metadata
+		.iter_ids()
+		.filter_map(Result::ok)
+		.collect::<Vec<_>>();
+
+	for room_id in room_ids {
+		let mutex_state = Arc::clone(
+			services()
+				.globals
+				.roomid_mutex_state
+				.write()
+				.await
+				.entry(room_id.clone())
+				.or_default(),
+		);
+		let _state_lock = mutex_state.lock().await;
+
+		check_room_memberships(&mut check, &room_id, repair).await?;
+	}
+
+	Ok(check)
+}
+
+async fn check_room_memberships(check: &mut Check, room_id: &RoomId, repair: bool) -> Result<()> {
+	let Some(shortstatehash) = services().rooms.state.get_room_shortstatehash(room_id)? else {
+		return Ok(());
+	};
+
+	let state = match services()
+		.rooms
+		.state_accessor
+		.state_full(shortstatehash)
+		.await
+	{
+		Ok(state) => state,
+		Err(e) => {
+			check.problem(|| format!("The state of {room_id} could not be loaded: {e}"));
+			return Ok(());
+		},
+	};
+
+	let mut members = HashMap::new();
+	for ((event_type, state_key), pdu) in state {
+		if event_type != StateEventType::RoomMember {
+			continue;
+		}
+
+		let (Ok(user_id), Ok(content)) = (
+			UserId::parse(state_key),
+			serde_json::from_str::<RoomMemberEventContent>(pdu.content.get()),
+		) else {
+			continue;
+		};
+
+		members.insert(user_id, (content, pdu.sender.clone()));
+	}
+
+	let joined = services()
+		.rooms
+		.state_cache
+		.room_members(room_id)
+		.filter_map(Result::ok)
+		.collect::<HashSet<_>>();
+	let invited = services()
+		.rooms
+		.state_cache
+		.room_members_invited(room_id)
+		.filter_map(Result::ok)
+		.collect::<HashSet<_>>();
+
+	// The users the caches are wrong about, with the membership they should have
+	let mut wrong = Vec::new();
+	for (user_id, (content, sender)) in &members {
+		check.checked += 1;
+
+		if (content.membership == MembershipState::Join) != joined.contains(user_id)
+			|| (content.membership == MembershipState::Invite) != invited.contains(user_id)
+		{
+			wrong.push((user_id.clone(), content.clone(), sender.clone()));
+		}
+	}
+	for user_id in joined.union(&invited) {
+		if !members.contains_key(user_id) {
+			check.checked += 1;
+			wrong.push((
+				user_id.clone(),
+				RoomMemberEventContent::new(MembershipState::Leave),
+				user_id.clone(),
+			));
+		}
+	}
+
+	for (user_id, content, _) in &wrong {
+		check.problem(|| {
+			format!(
+				"{user_id} is cached with the wrong membership in {room_id}, the state says {}",
+				content.membership
+			)
+		});
+	}
+
+	let counts_wrong = !counts_match(room_id, &members)?;
+	if counts_wrong {
+		check.problem(|| format!("The member counts of {room_id} are wrong"));
+	}
+
+	if repair {
+		for (user_id, content, sender) in wrong {
+			services()
+				.rooms
+				.state_cache
+				.update_membership(room_id, &user_id, content, &sender, None, false)
+				.await?;
+			check.repaired += 1;
+		}
+
+		services().rooms.state_cache.update_joined_count(room_id)?;
+		if counts_wrong {
+			check.repaired += 1;
+		}
+	}
+
+	Ok(())
+}
+
+/// Whether the cached joined and invited counts of a room match its state
+fn counts_match(
+	room_id: &RoomId, members: &HashMap<OwnedUserId, (RoomMemberEventContent, OwnedUserId)>,
+) -> Result<bool> {
+	let count = |membership: MembershipState| {
+		members
+			.values()
+			.filter(|(content, _)| content.membership == membership)
+			.count() as u64
+	};
+
+	Ok(
+		services().rooms.state_cache.room_joined_count(room_id)? == Some(count(MembershipState::Join))
+			&& services().rooms.state_cache.room_invited_count(room_id)? == Some(count(MembershipState::Invite)),
+	)
+}
+
+/// Splits a `userdeviceid` key into the user and device ID
+fn parse_userdeviceid(userdeviceid: &[u8]) -> Option<(OwnedUserId, OwnedDeviceId)> {
+	let mut parts = userdeviceid.split(|&b| b == 0xFF);
+	let user_id = UserId::parse(utils::string_from_bytes(parts.next()?).ok()?).ok()?;
+	let device_id = utils::string_from_bytes(parts.next()?).ok()?.into();
+
+	Some((user_id, device_id))
+}
+
+fn show_string(bytes: &[u8]) -> String { String::from_utf8_lossy(bytes).into_owned() }
+
+fn show_short(bytes: &[u8]) -> String {
+	utils::u64_from_bytes(bytes).map_or_else(|_| format!("{bytes:?}"), |short| format!("short ID {short}"))
+}
+
+fn show_userdeviceid(userdeviceid: &[u8]) -> String {
+	parse_userdeviceid(userdeviceid).map_or_else(
+		|| format!("{userdeviceid:?}"),
+		|(user_id, device_id)| format!("device {device_id} of {user_id}"),
+	)
+}
+
+fn show_mxc(key: &[u8]) -> String {
+	show_string(
+		key.split(|&b| b == 0xFF)
+			.next()
+			.expect("split always returns one element"),
+	)
+}
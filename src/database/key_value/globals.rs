@@ -10,7 +10,7 @@ use ruma::{
 };
 
 use crate::{
-	database::{abstraction::Cork, KeyValueDatabase},
+	database::{abstraction::Cork, check::CheckReport, KeyValueDatabase},
 	service, services, utils, Error, Result,
 };
 
@@ -312,6 +312,10 @@ lasttimelinecount_cache: {lasttimelinecount_cache}\n"
 		Ok(())
 	}
 
+	async fn check_consistency(&self, repair: bool) -> Result<CheckReport> {
+		KeyValueDatabase::check_consistency(self, repair).await
+	}
+
 	fn backup(&self) -> Result<(), Box<dyn std::error::Error>> { self.db.backup() }
 
 	fn backup_list(&self) -> Result<String> { self.db.backup_list() }
@@ -7,7 +7,7 @@ mod media;
 //mod pdu;
 mod presence;
 mod pusher;
-mod rooms;
+pub(crate) mod rooms;
 mod sending;
 mod transaction_ids;
 mod uiaa;
@@ -1,9 +1,18 @@
+use std::mem::size_of;
+
 use ruma::{
 	api::client::push::{set_pusher, Pusher},
-	UserId,
+	OwnedUserId, UserId,
 };
 
-use crate::{database::KeyValueDatabase, service, utils, Error, Result};
+use crate::{
+	database::KeyValueDatabase,
+	service::{
+		self,
+		pusher::{PusherStatus, StoredNotification},
+	},
+	utils, Error, Result,
+};
 
 impl service::pusher::Data for KeyValueDatabase {
 	fn set_pusher(&self, sender: &UserId, pusher: set_pusher::v3::PusherAction) -> Result<()> {
@@ -14,12 +23,23 @@ impl service::pusher::Data for KeyValueDatabase {
 				key.extend_from_slice(data.pusher.ids.pushkey.as_bytes());
 				self.senderkey_pusher
 					.insert(&key, &serde_json::to_vec(&pusher).expect("Pusher is valid JSON value"))?;
+
+				// The pusher may point somewhere else now, so give it a fresh start
+				self.senderkey_pusherstatus.remove(&key)?;
 				Ok(())
 			},
 			set_pusher::v3::PusherAction::Delete(ids) => {
 				let mut key = sender.as_bytes().to_vec();
 				key.push(0xFF);
 				key.extend_from_slice(ids.pushkey.as_bytes());
+
+				if let Some(token) = self.senderkey_unsubscribetoken.get(&key)? {
+					self.senderkey_unsubscribetoken.remove(&key)?;
+					self.unsubscribetoken_senderkey.remove(&token)?;
+				}
+				self.senderkey_pusherstatus.remove(&key)?;
+				self.senderkey_emaildigest.remove(&key)?;
+
 				self.senderkey_pusher.remove(&key).map_err(Into::into)
 			},
 		}
@@ -62,4 +82,188 @@ impl service::pusher::Data for KeyValueDatabase {
 			Ok(push_key_string)
 		}))
 	}
+
+	fn add_notification(&self, user_id: &UserId, count: u64, notification: &StoredNotification) -> Result<()> {
+		let mut key = user_id.as_bytes().to_vec();
+		key.push(0xFF);
+		key.extend_from_slice(&count.to_be_bytes());
+
+		// The creation time comes first so old notifications can be pruned without
+		// parsing them
+		let mut value = notification.ts.to_be_bytes().to_vec();
+		value.extend_from_slice(&serde_json::to_vec(notification).expect("StoredNotification can be serialized"));
+
+		self.useridcount_notification.insert(&key, &value)
+	}
+
+	fn notifications_until<'a>(
+		&'a self, user_id: &UserId, until: u64,
+	) -> Box<dyn Iterator<Item = Result<(u64, StoredNotification)>> + 'a> {
+		let mut prefix = user_id.as_bytes().to_vec();
+		prefix.push(0xFF);
+
+		let mut current = prefix.clone();
+		current.extend_from_slice(&until.saturating_sub(1).to_be_bytes());
+
+		Box::new(
+			self.useridcount_notification
+				.iter_from(&current, true)
+				.take_while(move |(key, _)| key.starts_with(&prefix))
+				.map(|(key, value)| {
+					let count = utils::u64_from_bytes(&key[key.len() - size_of::<u64>()..])
+						.map_err(|_| Error::bad_database("Invalid count in useridcount_notification."))?;
+
+					if value.len() < size_of::<u64>() {
+						return Err(Error::bad_database("Invalid notification in db."));
+					}
+
+					let (ts, notification) = value.split_at(size_of::<u64>());
+					let mut notification: StoredNotification = serde_json::from_slice(notification)
+						.map_err(|_| Error::bad_database("Invalid notification in db."))?;
+					notification.ts =
+						utils::u64_from_bytes(ts).map_err(|_| Error::bad_database("Invalid notification in db."))?;
+
+					Ok((count, notification))
+				}),
+		)
+	}
+
+	fn remove_notifications_before(&self, until: u64) -> Result<()> {
+		let expired = self
+			.useridcount_notification
+			.iter()
+			.filter(|(_, value)| {
+				value
+					.get(..size_of::<u64>())
+					.and_then(|ts| utils::u64_from_bytes(ts).ok())
+					.map_or(true, |ts| ts < until)
+			})
+			.map(|(key, _)| key)
+			.collect::<Vec<_>>();
+
+		self.useridcount_notification
+			.remove_batch(&mut expired.into_iter())
+	}
+
+	fn get_unsubscribe_token(&self, sender: &UserId, pushkey: &str) -> Result<Option<String>> {
+		let mut senderkey = sender.as_bytes().to_vec();
+		senderkey.push(0xFF);
+		senderkey.extend_from_slice(pushkey.as_bytes());
+
+		self.senderkey_unsubscribetoken
+			.get(&senderkey)?
+			.map(|token| {
+				utils::string_from_bytes(&token)
+					.map_err(|_| Error::bad_database("Invalid token in senderkey_unsubscribetoken."))
+			})
+			.transpose()
+	}
+
+	fn set_unsubscribe_token(&self, sender: &UserId, pushkey: &str, token: &str) -> Result<()> {
+		let mut senderkey = sender.as_bytes().to_vec();
+		senderkey.push(0xFF);
+		senderkey.extend_from_slice(pushkey.as_bytes());
+
+		self.senderkey_unsubscribetoken
+			.insert(&senderkey, token.as_bytes())?;
+		self.unsubscribetoken_senderkey
+			.insert(token.as_bytes(), &senderkey)
+	}
+
+	fn find_from_unsubscribe_token(&self, token: &str) -> Result<Option<(OwnedUserId, String)>> {
+		self.unsubscribetoken_senderkey
+			.get(token.as_bytes())?
+			.map(|senderkey| {
+				let mut parts = senderkey.splitn(2, |&b| b == 0xFF);
+				let sender = parts
+					.next()
+					.ok_or_else(|| Error::bad_database("Invalid unsubscribetoken_senderkey in db"))?;
+				let pushkey = parts
+					.next()
+					.ok_or_else(|| Error::bad_database("Invalid unsubscribetoken_senderkey in db"))?;
+
+				Ok((
+					UserId::parse(
+						utils::string_from_bytes(sender)
+							.map_err(|_| Error::bad_database("Invalid user ID in unsubscribetoken_senderkey."))?,
+					)
+					.map_err(|_| Error::bad_database("Invalid user ID in unsubscribetoken_senderkey."))?,
+					utils::string_from_bytes(pushkey)
+						.map_err(|_| Error::bad_database("Invalid pushkey in unsubscribetoken_senderkey."))?,
+				))
+			})
+			.transpose()
+	}
+
+	fn set_email_digest(&self, sender: &UserId, pushkey: &str, since: u64, due_at: u64) -> Result<()> {
+		let mut senderkey = sender.as_bytes().to_vec();
+		senderkey.push(0xFF);
+		senderkey.extend_from_slice(pushkey.as_bytes());
+
+		let mut value = since.to_be_bytes().to_vec();
+		value.extend_from_slice(&due_at.to_be_bytes());
+
+		self.senderkey_emaildigest.insert(&senderkey, &value)
+	}
+
+	fn remove_email_digest(&self, sender: &UserId, pushkey: &str) -> Result<()> {
+		let mut senderkey = sender.as_bytes().to_vec();
+		senderkey.push(0xFF);
+		senderkey.extend_from_slice(pushkey.as_bytes());
+
+		self.senderkey_emaildigest.remove(&senderkey)
+	}
+
+	fn email_digests<'a>(&'a self) -> Box<dyn Iterator<Item = Result<(OwnedUserId, String, u64, u64)>> + 'a> {
+		Box::new(self.senderkey_emaildigest.iter().map(|(senderkey, value)| {
+			let mut parts = senderkey.splitn(2, |&b| b == 0xFF);
+			let sender = parts
+				.next()
+				.ok_or_else(|| Error::bad_database("Invalid senderkey_emaildigest in db"))?;
+			let pushkey = parts
+				.next()
+				.ok_or_else(|| Error::bad_database("Invalid senderkey_emaildigest in db"))?;
+
+			if value.len() != 2 * size_of::<u64>() {
+				return Err(Error::bad_database("Invalid email digest in db."));
+			}
+			let (since, due_at) = value.split_at(size_of::<u64>());
+
+			Ok((
+				UserId::parse(
+					utils::string_from_bytes(sender)
+						.map_err(|_| Error::bad_database("Invalid user ID in senderkey_emaildigest."))?,
+				)
+				.map_err(|_| Error::bad_database("Invalid user ID in senderkey_emaildigest."))?,
+				utils::string_from_bytes(pushkey)
+					.map_err(|_| Error::bad_database("Invalid pushkey in senderkey_emaildigest."))?,
+				utils::u64_from_bytes(since).map_err(|_| Error::bad_database("Invalid email digest in db."))?,
+				utils::u64_from_bytes(due_at).map_err(|_| Error::bad_database("Invalid email digest in db."))?,
+			))
+		}))
+	}
+
+	fn get_pusher_status(&self, sender: &UserId, pushkey: &str) -> Result<Option<PusherStatus>> {
+		let mut senderkey = sender.as_bytes().to_vec();
+		senderkey.push(0xFF);
+		senderkey.extend_from_slice(pushkey.as_bytes());
+
+		self.senderkey_pusherstatus
+			.get(&senderkey)?
+			.map(|status| {
+				serde_json::from_slice(&status).map_err(|_| Error::bad_database("Invalid PusherStatus in db."))
+			})
+			.transpose()
+	}
+
+	fn set_pusher_status(&self, sender: &UserId, pushkey: &str, status: &PusherStatus) -> Result<()> {
+		let mut senderkey = sender.as_bytes().to_vec();
+		senderkey.push(0xFF);
+		senderkey.extend_from_slice(pushkey.as_bytes());
+
+		self.senderkey_pusherstatus.insert(
+			&senderkey,
+			&serde_json::to_vec(status).expect("PusherStatus is valid JSON value"),
+		)
+	}
 }
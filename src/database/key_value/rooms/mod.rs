@@ -13,7 +13,7 @@ mod state_accessor;
 mod state_cache;
 mod state_compressor;
 mod threads;
-mod timeline;
+pub(crate) mod timeline;
 mod user;
 
 use crate::{database::KeyValueDatabase, service};
@@ -1,64 +1,147 @@
-use ruma::RoomId;
+use std::mem::size_of;
 
-use crate::{database::KeyValueDatabase, service, services, utils, Result};
+use crate::{
+	database::KeyValueDatabase,
+	service::{self, rooms::search::Posting},
+	utils, Error, Result,
+};
 
-type SearchPdusResult<'a> = Result<Option<(Box<dyn Iterator<Item = Vec<u8>> + 'a>, Vec<String>)>>;
+/// Key in `global` that is set while the index of every room has to be rebuilt
+const REBUILD_PENDING_KEY: &[u8] = b"search_index_rebuild_pending";
 
 impl service::rooms::search::Data for KeyValueDatabase {
-	fn index_pdu(&self, shortroomid: u64, pdu_id: &[u8], message_body: &str) -> Result<()> {
-		let mut batch = message_body
-			.split_terminator(|c: char| !c.is_alphanumeric())
-			.filter(|s| !s.is_empty())
-			.filter(|word| word.len() <= 50)
-			.map(str::to_lowercase)
-			.map(|word| {
+	fn index_pdu(&self, shortroomid: u64, pdu_id: &[u8], tokens: &[(String, u32)], length: u32) -> Result<()> {
+		let batch = tokens
+			.iter()
+			.map(|(token, frequency)| {
 				let mut key = shortroomid.to_be_bytes().to_vec();
-				key.extend_from_slice(word.as_bytes());
+				key.extend_from_slice(token.as_bytes());
 				key.push(0xFF);
 				key.extend_from_slice(pdu_id); // TODO: currently we save the room id a second time here
-				(key, Vec::new())
-			});
 
-		self.tokenids.insert_batch(&mut batch)
+				let mut value = frequency.to_be_bytes().to_vec();
+				value.extend_from_slice(&length.to_be_bytes());
+
+				(key, value)
+			})
+			.collect::<Vec<_>>();
+
+		// Don't count the pdu twice if it is already indexed
+		match batch.first() {
+			Some((key, _)) if self.tokenids.get(key)?.is_none() => {},
+			_ => return Ok(()),
+		}
+
+		self.tokenids.insert_batch(&mut batch.into_iter())?;
+
+		let (count, total_length) = self.room_stats(shortroomid)?;
+		let mut stats = (count + 1).to_be_bytes().to_vec();
+		stats.extend_from_slice(&(total_length + u64::from(length)).to_be_bytes());
+
+		self.shortroomid_searchstats
+			.insert(&shortroomid.to_be_bytes(), &stats)
+	}
+
+	fn deindex_pdu(&self, shortroomid: u64, pdu_id: &[u8], tokens: &[String], length: u32) -> Result<()> {
+		let keys = tokens
+			.iter()
+			.map(|token| {
+				let mut key = shortroomid.to_be_bytes().to_vec();
+				key.extend_from_slice(token.as_bytes());
+				key.push(0xFF);
+				key.extend_from_slice(pdu_id);
+				key
+			})
+			.collect::<Vec<_>>();
+
+		// Don't touch the stats if the pdu was never indexed
+		match keys.first() {
+			Some(key) if self.tokenids.get(key)?.is_some() => {},
+			_ => return Ok(()),
+		}
+
+		self.tokenids.remove_batch(&mut keys.into_iter())?;
+
+		let (count, total_length) = self.room_stats(shortroomid)?;
+		let mut stats = count.saturating_sub(1).to_be_bytes().to_vec();
+		stats.extend_from_slice(&total_length.saturating_sub(u64::from(length)).to_be_bytes());
+
+		self.shortroomid_searchstats
+			.insert(&shortroomid.to_be_bytes(), &stats)
 	}
 
-	fn search_pdus<'a>(&'a self, room_id: &RoomId, search_string: &str) -> SearchPdusResult<'a> {
-		let prefix = services()
-			.rooms
-			.short
-			.get_shortroomid(room_id)?
-			.expect("room exists")
-			.to_be_bytes()
-			.to_vec();
-
-		let words: Vec<_> = search_string
-			.split_terminator(|c: char| !c.is_alphanumeric())
-			.filter(|s| !s.is_empty())
-			.map(str::to_lowercase)
-			.collect();
-
-		let iterators = words.clone().into_iter().map(move |word| {
-			let mut prefix2 = prefix.clone();
-			prefix2.extend_from_slice(word.as_bytes());
-			prefix2.push(0xFF);
-			let prefix3 = prefix2.clone();
-
-			let mut last_possible_id = prefix2.clone();
-			last_possible_id.extend_from_slice(&u64::MAX.to_be_bytes());
+	fn postings<'a>(&'a self, shortroomid: u64, token: &str, prefix: bool) -> Box<dyn Iterator<Item = Posting> + 'a> {
+		let mut key_prefix = shortroomid.to_be_bytes().to_vec();
+		key_prefix.extend_from_slice(token.as_bytes());
+		if !prefix {
+			key_prefix.push(0xFF);
+		}
 
+		Box::new(
 			self.tokenids
-				.iter_from(&last_possible_id, true) // Newest pdus first
-				.take_while(move |(k, _)| k.starts_with(&prefix2))
-				.map(move |(key, _)| key[prefix3.len()..].to_vec())
-		});
-
-		let Some(common_elements) = utils::common_elements(iterators, |a, b| {
-			// We compare b with a because we reversed the iterator earlier
-			b.cmp(a)
-		}) else {
-			return Ok(None);
+				.scan_prefix(key_prefix)
+				.filter_map(|(key, value)| {
+					// Tokens are valid UTF-8 and can never contain 0xFF
+					let separator = key.iter().skip(size_of::<u64>()).position(|&b| b == 0xFF)? + size_of::<u64>();
+
+					// Entries written before term frequencies were stored have no value
+					let (frequency, length) = if value.len() == 2 * size_of::<u32>() {
+						(
+							u32::from_be_bytes(value[..size_of::<u32>()].try_into().ok()?),
+							u32::from_be_bytes(value[size_of::<u32>()..].try_into().ok()?),
+						)
+					} else {
+						(1, 0)
+					};
+
+					Some(Posting {
+						pdu_id: key[separator + 1..].to_vec(),
+						frequency,
+						length,
+					})
+				}),
+		)
+	}
+
+	fn room_stats(&self, shortroomid: u64) -> Result<(u64, u64)> {
+		let Some(stats) = self
+			.shortroomid_searchstats
+			.get(&shortroomid.to_be_bytes())?
+		else {
+			return Ok((0, 0));
 		};
 
-		Ok(Some((Box::new(common_elements), words)))
+		if stats.len() != 2 * size_of::<u64>() {
+			return Err(Error::bad_database("Invalid search stats in db."));
+		}
+
+		let (count, total_length) = stats.split_at(size_of::<u64>());
+
+		Ok((
+			utils::u64_from_bytes(count).map_err(|_| Error::bad_database("Invalid search stats in db."))?,
+			utils::u64_from_bytes(total_length).map_err(|_| Error::bad_database("Invalid search stats in db."))?,
+		))
+	}
+
+	fn clear_room(&self, shortroomid: u64) -> Result<()> {
+		let keys = self
+			.tokenids
+			.scan_prefix(shortroomid.to_be_bytes().to_vec())
+			.map(|(key, _)| key)
+			.collect::<Vec<_>>();
+
+		self.tokenids.remove_batch(&mut keys.into_iter())?;
+		self.shortroomid_searchstats
+			.remove(&shortroomid.to_be_bytes())
+	}
+
+	fn rebuild_pending(&self) -> Result<bool> { Ok(self.global.get(REBUILD_PENDING_KEY)?.is_some()) }
+
+	fn set_rebuild_pending(&self, pending: bool) -> Result<()> {
+		if pending {
+			self.global.insert(REBUILD_PENDING_KEY, &[])
+		} else {
+			self.global.remove(REBUILD_PENDING_KEY)
+		}
 	}
 }
@@ -1,6 +1,8 @@
 use std::{collections::hash_map, mem::size_of, sync::Arc};
 
-use ruma::{api::client::error::ErrorKind, CanonicalJsonObject, EventId, OwnedUserId, RoomId, UserId};
+use ruma::{
+	api::client::error::ErrorKind, CanonicalJsonObject, CanonicalJsonValue, EventId, OwnedUserId, RoomId, UserId,
+};
 use service::rooms::timeline::PduCount;
 use tracing::error;
 
@@ -149,6 +151,8 @@ impl service::rooms::timeline::Data for KeyValueDatabase {
 
 		self.eventid_pduid.insert(pdu.event_id.as_bytes(), pdu_id)?;
 		self.eventid_outlierpdu.remove(pdu.event_id.as_bytes())?;
+		self.tsid_pduid
+			.insert(&pdu_tsid(pdu_id, pdu.origin_server_ts.into()), pdu_id)?;
 
 		Ok(())
 	}
@@ -162,6 +166,12 @@ impl service::rooms::timeline::Data for KeyValueDatabase {
 		self.eventid_pduid.insert(event_id.as_bytes(), pdu_id)?;
 		self.eventid_outlierpdu.remove(event_id.as_bytes())?;
 
+		if let Some(CanonicalJsonValue::Integer(ts)) = json.get("origin_server_ts") {
+			if let Ok(ts) = u64::try_from(i64::from(*ts)) {
+				self.tsid_pduid.insert(&pdu_tsid(pdu_id, ts), pdu_id)?;
+			}
+		}
+
 		Ok(())
 	}
 
@@ -235,6 +245,29 @@ impl service::rooms::timeline::Data for KeyValueDatabase {
 		))
 	}
 
+	fn pdu_ids_by_timestamp<'a>(
+		&'a self, room_id: &RoomId, ts: u64, backwards: bool,
+	) -> Result<Box<dyn Iterator<Item = Vec<u8>> + 'a>> {
+		let Some(shortroomid) = services().rooms.short.get_shortroomid(room_id)? else {
+			return Ok(Box::new(std::iter::empty()));
+		};
+		let prefix = shortroomid.to_be_bytes().to_vec();
+
+		let mut current = prefix.clone();
+		current.extend_from_slice(&ts.to_be_bytes());
+		if backwards {
+			// Sorts after every pdu id with the same timestamp
+			current.extend_from_slice(&[0xFF; 2 * size_of::<u64>()]);
+		}
+
+		Ok(Box::new(
+			self.tsid_pduid
+				.iter_from(&current, backwards)
+				.take_while(move |(k, _)| k.starts_with(&prefix))
+				.map(|(_, pdu_id)| pdu_id),
+		))
+	}
+
 	fn increment_notification_counts(
 		&self, room_id: &RoomId, notifies: Vec<OwnedUserId>, highlights: Vec<OwnedUserId>,
 	) -> Result<()> {
@@ -259,10 +292,52 @@ impl service::rooms::timeline::Data for KeyValueDatabase {
 			.increment_batch(&mut highlights_batch.into_iter())?;
 		Ok(())
 	}
+
+	fn increment_thread_notification_counts(
+		&self, room_id: &RoomId, thread_id: &EventId, notifies: &[OwnedUserId], highlights: &[OwnedUserId],
+	) -> Result<()> {
+		let userroomthread_id = |user: &OwnedUserId| {
+			let mut key = user.as_bytes().to_vec();
+			key.push(0xFF);
+			key.extend_from_slice(room_id.as_bytes());
+			key.push(0xFF);
+			key.extend_from_slice(thread_id.as_bytes());
+			key
+		};
+
+		self.userroomthreadid_notificationcount
+			.increment_batch(&mut notifies.iter().map(userroomthread_id))?;
+		self.userroomthreadid_highlightcount
+			.increment_batch(&mut highlights.iter().map(userroomthread_id))?;
+		Ok(())
+	}
+
+	fn increment_missed_call_counts(&self, room_id: &RoomId, users: &[OwnedUserId]) -> Result<()> {
+		self.userroomid_missedcallcount
+			.increment_batch(&mut users.iter().map(|user| {
+				let mut userroom_id = user.as_bytes().to_vec();
+				userroom_id.push(0xFF);
+				userroom_id.extend_from_slice(room_id.as_bytes());
+				userroom_id
+			}))
+	}
+}
+
+/// Returns the `tsid_pduid` key of a pdu: its short room id, the timestamp
+/// and the remaining part of its pdu id.
+fn pdu_tsid(pdu_id: &[u8], origin_server_ts: u64) -> Vec<u8> {
+	let mut tsid = pdu_id[..size_of::<u64>()].to_vec();
+	tsid.extend_from_slice(&origin_server_ts.to_be_bytes());
+	tsid.extend_from_slice(&pdu_id[size_of::<u64>()..]);
+	tsid
 }
 
 /// Returns the `count` of this pdu's id.
-fn pdu_count(pdu_id: &[u8]) -> Result<PduCount> {
+pub(crate) fn pdu_count(pdu_id: &[u8]) -> Result<PduCount> {
+	if pdu_id.len() < 2 * size_of::<u64>() {
+		return Err(Error::bad_database("PDU has invalid count bytes."));
+	}
+
 	let last_u64 = utils::u64_from_bytes(&pdu_id[pdu_id.len() - size_of::<u64>()..])
 		.map_err(|_| Error::bad_database("PDU has invalid count bytes."))?;
 	let second_last_u64 =
@@ -1,4 +1,6 @@
-use ruma::{OwnedRoomId, OwnedUserId, RoomId, UserId};
+use std::collections::BTreeMap;
+
+use ruma::{EventId, OwnedEventId, OwnedRoomId, OwnedUserId, RoomId, UserId};
 
 use crate::{database::KeyValueDatabase, service, services, utils, Error, Result};
 
@@ -15,6 +17,19 @@ impl service::rooms::user::Data for KeyValueDatabase {
 			.insert(&userroom_id, &0_u64.to_be_bytes())?;
 		self.userroomid_highlightcount
 			.insert(&userroom_id, &0_u64.to_be_bytes())?;
+		self.userroomid_missedcallcount
+			.insert(&userroom_id, &0_u64.to_be_bytes())?;
+
+		let mut prefix = userroom_id;
+		prefix.push(0xFF);
+		for (key, _) in self
+			.userroomthreadid_notificationcount
+			.scan_prefix(prefix.clone())
+			.chain(self.userroomthreadid_highlightcount.scan_prefix(prefix))
+		{
+			self.userroomthreadid_notificationcount.remove(&key)?;
+			self.userroomthreadid_highlightcount.remove(&key)?;
+		}
 
 		self.roomuserid_lastnotificationread
 			.insert(&roomuser_id, &services().globals.next_count()?.to_be_bytes())?;
@@ -46,6 +61,104 @@ impl service::rooms::user::Data for KeyValueDatabase {
 			})
 	}
 
+	fn missed_call_count(&self, user_id: &UserId, room_id: &RoomId) -> Result<u64> {
+		let mut userroom_id = user_id.as_bytes().to_vec();
+		userroom_id.push(0xFF);
+		userroom_id.extend_from_slice(room_id.as_bytes());
+
+		self.userroomid_missedcallcount
+			.get(&userroom_id)?
+			.map_or(Ok(0), |bytes| {
+				utils::u64_from_bytes(&bytes).map_err(|_| Error::bad_database("Invalid missed call count in db."))
+			})
+	}
+
+	fn thread_notification_counts(
+		&self, user_id: &UserId, room_id: &RoomId,
+	) -> Result<BTreeMap<OwnedEventId, (u64, u64)>> {
+		let mut prefix = user_id.as_bytes().to_vec();
+		prefix.push(0xFF);
+		prefix.extend_from_slice(room_id.as_bytes());
+		prefix.push(0xFF);
+
+		let thread_id = |key: &[u8]| {
+			utils::string_from_bytes(&key[prefix.len()..])
+				.ok()
+				.and_then(|thread_id| OwnedEventId::try_from(thread_id).ok())
+				.ok_or_else(|| Error::bad_database("Invalid thread id in thread notification counts."))
+		};
+
+		let mut counts = BTreeMap::<OwnedEventId, (u64, u64)>::new();
+		for (key, value) in self
+			.userroomthreadid_notificationcount
+			.scan_prefix(prefix.clone())
+		{
+			counts.entry(thread_id(&key)?).or_default().0 =
+				utils::u64_from_bytes(&value).map_err(|_| Error::bad_database("Invalid notification count in db."))?;
+		}
+		for (key, value) in self
+			.userroomthreadid_highlightcount
+			.scan_prefix(prefix.clone())
+		{
+			counts.entry(thread_id(&key)?).or_default().1 =
+				utils::u64_from_bytes(&value).map_err(|_| Error::bad_database("Invalid highlight count in db."))?;
+		}
+
+		Ok(counts)
+	}
+
+	fn reset_thread_notification_counts(
+		&self, user_id: &UserId, room_id: &RoomId, thread_id: Option<&EventId>,
+	) -> Result<()> {
+		let mut userroom_id = user_id.as_bytes().to_vec();
+		userroom_id.push(0xFF);
+		userroom_id.extend_from_slice(room_id.as_bytes());
+
+		let threads = self.thread_notification_counts(user_id, room_id)?;
+
+		let (notifications, highlights) = if let Some(thread_id) = thread_id {
+			// Only this thread was read, the others stay unread in the room counts
+			let mut userroomthread_id = userroom_id.clone();
+			userroomthread_id.push(0xFF);
+			userroomthread_id.extend_from_slice(thread_id.as_bytes());
+			self.userroomthreadid_notificationcount
+				.remove(&userroomthread_id)?;
+			self.userroomthreadid_highlightcount
+				.remove(&userroomthread_id)?;
+
+			let (thread_notifications, thread_highlights) = threads.get(thread_id).copied().unwrap_or_default();
+			(
+				self.notification_count(user_id, room_id)?
+					.saturating_sub(thread_notifications),
+				self.highlight_count(user_id, room_id)?
+					.saturating_sub(thread_highlights),
+			)
+		} else {
+			// The main timeline was read, only the threads stay unread
+			let mut roomuser_id = room_id.as_bytes().to_vec();
+			roomuser_id.push(0xFF);
+			roomuser_id.extend_from_slice(user_id.as_bytes());
+			self.roomuserid_lastnotificationread
+				.insert(&roomuser_id, &services().globals.next_count()?.to_be_bytes())?;
+			self.userroomid_missedcallcount
+				.insert(&userroom_id, &0_u64.to_be_bytes())?;
+
+			threads.values().fold(
+				(0, 0),
+				|(notifications, highlights), (thread_notifications, thread_highlights)| {
+					(notifications + thread_notifications, highlights + thread_highlights)
+				},
+			)
+		};
+
+		self.userroomid_notificationcount
+			.insert(&userroom_id, &notifications.to_be_bytes())?;
+		self.userroomid_highlightcount
+			.insert(&userroom_id, &highlights.to_be_bytes())?;
+
+		Ok(())
+	}
+
 	fn last_notification_read(&self, user_id: &UserId, room_id: &RoomId) -> Result<u64> {
 		let mut key = room_id.as_bytes().to_vec();
 		key.push(0xFF);
@@ -8,11 +8,14 @@ use ruma::{
 	DeviceId, DeviceKeyAlgorithm, DeviceKeyId, MilliSecondsSinceUnixEpoch, OwnedDeviceId, OwnedDeviceKeyId,
 	OwnedMxcUri, OwnedUserId, UInt, UserId,
 };
-use tracing::warn;
+use tracing::{debug, warn};
 
 use crate::{
 	database::KeyValueDatabase,
-	service::{self, users::clean_signatures},
+	service::{
+		self,
+		users::{clean_signatures, SlidingSyncCache},
+	},
 	services, utils, Error, Result,
 };
 
@@ -242,6 +245,30 @@ impl service::users::Data for KeyValueDatabase {
 			self.todeviceid_events.remove(&key)?;
 		}
 
+		// Remove sync stream positions
+		let mut prefix = userdeviceid.clone();
+		prefix.push(0xFF);
+
+		for (key, _) in self.userdevicestreamid_todevicecount.scan_prefix(prefix) {
+			self.userdevicestreamid_todevicecount.remove(&key)?;
+		}
+
+		// Remove fallback keys
+		let mut prefix = userdeviceid.clone();
+		prefix.push(0xFF);
+
+		for (key, _) in self.userdevicealgorithm_fallbackkey.scan_prefix(prefix) {
+			self.userdevicealgorithm_fallbackkey.remove(&key)?;
+		}
+
+		// Remove sliding sync connections
+		let mut prefix = userdeviceid.clone();
+		prefix.push(0xFF);
+
+		for (key, _) in self.userdeviceconnid_slidingsync.scan_prefix(prefix) {
+			self.userdeviceconnid_slidingsync.remove(&key)?;
+		}
+
 		// TODO: Remove onetimekeys
 
 		self.userid_devicelistversion
@@ -420,6 +447,84 @@ impl service::users::Data for KeyValueDatabase {
 		Ok(counts)
 	}
 
+	fn add_fallback_key(
+		&self, user_id: &UserId, device_id: &DeviceId, fallback_key_key: &DeviceKeyId,
+		fallback_key_value: &Raw<OneTimeKey>,
+	) -> Result<()> {
+		let mut key = user_id.as_bytes().to_vec();
+		key.push(0xFF);
+		key.extend_from_slice(device_id.as_bytes());
+
+		if self.userdeviceid_metadata.get(&key)?.is_none() {
+			return Err(Error::bad_database(
+				"User does not exist or device ID has no metadata in database.",
+			));
+		}
+
+		key.push(0xFF);
+		key.extend_from_slice(fallback_key_key.algorithm().as_ref().as_bytes());
+
+		// The first byte is set once the key was claimed
+		let mut value = vec![0];
+		value.extend_from_slice(
+			&serde_json::to_vec(&(fallback_key_key, fallback_key_value)).expect("fallback key can be serialized"),
+		);
+
+		self.userdevicealgorithm_fallbackkey.insert(&key, &value)?;
+
+		self.userid_lastonetimekeyupdate
+			.insert(user_id.as_bytes(), &services().globals.next_count()?.to_be_bytes())?;
+
+		Ok(())
+	}
+
+	fn take_fallback_key(
+		&self, user_id: &UserId, device_id: &DeviceId, key_algorithm: &DeviceKeyAlgorithm,
+	) -> Result<Option<(OwnedDeviceKeyId, Raw<OneTimeKey>)>> {
+		let mut key = user_id.as_bytes().to_vec();
+		key.push(0xFF);
+		key.extend_from_slice(device_id.as_bytes());
+		key.push(0xFF);
+		key.extend_from_slice(key_algorithm.as_ref().as_bytes());
+
+		let Some(mut value) = self.userdevicealgorithm_fallbackkey.get(&key)? else {
+			return Ok(None);
+		};
+
+		let fallback_key = serde_json::from_slice(value.get(1..).unwrap_or_default())
+			.map_err(|_| Error::bad_database("Fallback key in db is invalid."))?;
+
+		if value[0] == 0 {
+			value[0] = 1;
+			self.userdevicealgorithm_fallbackkey.insert(&key, &value)?;
+
+			// Let the device know it should upload a new fallback key
+			self.userid_lastonetimekeyupdate
+				.insert(user_id.as_bytes(), &services().globals.next_count()?.to_be_bytes())?;
+		}
+
+		Ok(Some(fallback_key))
+	}
+
+	fn unused_fallback_key_types(&self, user_id: &UserId, device_id: &DeviceId) -> Result<Vec<DeviceKeyAlgorithm>> {
+		let mut prefix = user_id.as_bytes().to_vec();
+		prefix.push(0xFF);
+		prefix.extend_from_slice(device_id.as_bytes());
+		prefix.push(0xFF);
+
+		self.userdevicealgorithm_fallbackkey
+			.scan_prefix(prefix.clone())
+			.filter(|(_, value)| value.first() == Some(&0))
+			.map(|(key, _)| {
+				Ok::<_, Error>(
+					utils::string_from_bytes(&key[prefix.len()..])
+						.map_err(|_| Error::bad_database("Fallback key algorithm in db is invalid."))?
+						.into(),
+				)
+			})
+			.collect()
+	}
+
 	fn add_device_keys(&self, user_id: &UserId, device_id: &DeviceId, device_keys: &Raw<DeviceKeys>) -> Result<()> {
 		let mut userdeviceid = user_id.as_bytes().to_vec();
 		userdeviceid.push(0xFF);
@@ -727,7 +832,9 @@ impl service::users::Data for KeyValueDatabase {
 		Ok(())
 	}
 
-	fn get_to_device_events(&self, user_id: &UserId, device_id: &DeviceId) -> Result<Vec<Raw<AnyToDeviceEvent>>> {
+	fn get_to_device_events(
+		&self, user_id: &UserId, device_id: &DeviceId, stream: Option<&str>,
+	) -> Result<Vec<Raw<AnyToDeviceEvent>>> {
 		let mut events = Vec::new();
 
 		let mut prefix = user_id.as_bytes().to_vec();
@@ -735,7 +842,22 @@ impl service::users::Data for KeyValueDatabase {
 		prefix.extend_from_slice(device_id.as_bytes());
 		prefix.push(0xFF);
 
-		for (_, value) in self.todeviceid_events.scan_prefix(prefix) {
+		// Other streams may still need events this stream already acknowledged
+		let acknowledged = self
+			.userdevicestreamid_todevicecount
+			.get(&userdevicestreamid(user_id, device_id, stream))?
+			.map(|value| parse_stream_position(&value))
+			.transpose()?
+			.map_or(0, |(count, _)| count);
+
+		let mut first = prefix.clone();
+		first.extend_from_slice(&acknowledged.saturating_add(1).to_be_bytes());
+
+		for (_, value) in self
+			.todeviceid_events
+			.iter_from(&first, false)
+			.take_while(|(key, _)| key.starts_with(&prefix))
+		{
 			events.push(
 				serde_json::from_slice(&value)
 					.map_err(|_| Error::bad_database("Event in todeviceid_events is invalid."))?,
@@ -745,12 +867,47 @@ impl service::users::Data for KeyValueDatabase {
 		Ok(events)
 	}
 
-	fn remove_to_device_events(&self, user_id: &UserId, device_id: &DeviceId, until: u64) -> Result<()> {
+	fn remove_to_device_events(
+		&self, user_id: &UserId, device_id: &DeviceId, stream: Option<&str>, until: u64, expired_before: u64,
+	) -> Result<()> {
 		let mut prefix = user_id.as_bytes().to_vec();
 		prefix.push(0xFF);
 		prefix.extend_from_slice(device_id.as_bytes());
 		prefix.push(0xFF);
 
+		let mut streams = Vec::new();
+		for (key, value) in self
+			.userdevicestreamid_todevicecount
+			.scan_prefix(prefix.clone())
+		{
+			let (count, last_used) = parse_stream_position(&value)?;
+			if last_used < expired_before {
+				self.userdevicestreamid_todevicecount.remove(&key)?;
+			} else {
+				streams.push((key, count));
+			}
+		}
+
+		let streamid = userdevicestreamid(user_id, device_id, stream);
+		streams.retain(|(key, _)| *key != streamid);
+
+		// Without additional streams the default stream doesn't need to remember its
+		// position, everything it acknowledged can be removed right away
+		let until = if stream.is_none() && streams.is_empty() {
+			self.userdevicestreamid_todevicecount.remove(&streamid)?;
+			until
+		} else {
+			let mut value = until.to_be_bytes().to_vec();
+			value.extend_from_slice(&utils::millis_since_unix_epoch().to_be_bytes());
+			self.userdevicestreamid_todevicecount
+				.insert(&streamid, &value)?;
+
+			streams
+				.iter()
+				.map(|&(_, count)| count)
+				.fold(until, u64::min)
+		};
+
 		let mut last = prefix.clone();
 		last.extend_from_slice(&until.to_be_bytes());
 
@@ -869,10 +1026,193 @@ impl service::users::Data for KeyValueDatabase {
 			Ok(None)
 		}
 	}
+
+	fn create_openid_token(&self, user_id: &UserId, token: &str, expires_at: u64) -> Result<()> {
+		let mut value = expires_at.to_be_bytes().to_vec();
+		value.extend_from_slice(user_id.as_bytes());
+
+		self.openidtoken_expiresatuserid
+			.insert(token.as_bytes(), &value)
+	}
+
+	fn remove_expired_openid_tokens(&self) -> Result<()> {
+		let now = utils::millis_since_unix_epoch();
+		let expired = self
+			.openidtoken_expiresatuserid
+			.iter()
+			.filter(|(_, value)| {
+				value
+					.get(..size_of::<u64>())
+					.and_then(|expires_at| utils::u64_from_bytes(expires_at).ok())
+					.map_or(true, |expires_at| expires_at < now)
+			})
+			.map(|(key, _)| key)
+			.collect::<Vec<_>>();
+
+		self.openidtoken_expiresatuserid
+			.remove_batch(&mut expired.into_iter())
+	}
+
+	fn find_from_openid_token(&self, token: &str) -> Result<Option<OwnedUserId>> {
+		let Some(value) = self.openidtoken_expiresatuserid.get(token.as_bytes())? else {
+			return Ok(None);
+		};
+
+		if value.len() < size_of::<u64>() {
+			return Err(Error::bad_database("OpenID token in db is invalid."));
+		}
+
+		let (expires_at, user_id) = value.split_at(size_of::<u64>());
+		let expires_at = utils::u64_from_bytes(expires_at)
+			.map_err(|_| Error::bad_database("OpenID token expiry in db is invalid."))?;
+
+		if expires_at < utils::millis_since_unix_epoch() {
+			debug!("OpenID token is expired, removing");
+			self.openidtoken_expiresatuserid.remove(token.as_bytes())?;
+			return Ok(None);
+		}
+
+		UserId::parse(
+			utils::string_from_bytes(user_id)
+				.map_err(|_| Error::bad_database("User ID in openidtoken_expiresatuserid is invalid unicode."))?,
+		)
+		.map(Some)
+		.map_err(|_| Error::bad_database("User ID in openidtoken_expiresatuserid is invalid."))
+	}
+
+	fn add_email(&self, user_id: &UserId, address: &str) -> Result<()> {
+		let mut key = user_id.as_bytes().to_vec();
+		key.push(0xFF);
+		key.extend_from_slice(address.as_bytes());
+
+		self.useridemail_addedat
+			.insert(&key, &utils::millis_since_unix_epoch().to_be_bytes())
+	}
+
+	fn remove_email(&self, user_id: &UserId, address: &str) -> Result<()> {
+		let mut key = user_id.as_bytes().to_vec();
+		key.push(0xFF);
+		key.extend_from_slice(address.as_bytes());
+
+		self.useridemail_addedat.remove(&key)
+	}
+
+	fn emails<'a>(&'a self, user_id: &UserId) -> Box<dyn Iterator<Item = Result<(String, u64)>> + 'a> {
+		let mut prefix = user_id.as_bytes().to_vec();
+		prefix.push(0xFF);
+
+		Box::new(
+			self.useridemail_addedat
+				.scan_prefix(prefix)
+				.map(|(key, value)| {
+					let address = key
+						.rsplit(|&b| b == 0xFF)
+						.next()
+						.ok_or_else(|| Error::bad_database("Key in useridemail_addedat is invalid."))?;
+					let address = utils::string_from_bytes(address)
+						.map_err(|_| Error::bad_database("Email address in useridemail_addedat is invalid unicode."))?;
+					let added_at = utils::u64_from_bytes(&value)
+						.map_err(|_| Error::bad_database("Timestamp in useridemail_addedat is invalid."))?;
+
+					Ok((address, added_at))
+				}),
+		)
+	}
+
+	fn get_sync_connection(
+		&self, user_id: &UserId, device_id: &DeviceId, conn_id: &str,
+	) -> Result<Option<SlidingSyncCache>> {
+		let Some(value) = self
+			.userdeviceconnid_slidingsync
+			.get(&userdeviceconnid(user_id, device_id, conn_id))?
+		else {
+			return Ok(None);
+		};
+
+		if value.len() < size_of::<u64>() {
+			return Err(Error::bad_database("Sliding sync connection in db is invalid."));
+		}
+
+		let (last_used, cache) = value.split_at(size_of::<u64>());
+		let mut cache: SlidingSyncCache = serde_json::from_slice(cache)
+			.map_err(|_| Error::bad_database("Sliding sync connection in db is invalid."))?;
+		cache.set_last_used(
+			utils::u64_from_bytes(last_used)
+				.map_err(|_| Error::bad_database("Sliding sync connection in db is invalid."))?,
+		);
+
+		Ok(Some(cache))
+	}
+
+	fn set_sync_connection(
+		&self, user_id: &UserId, device_id: &DeviceId, conn_id: &str, cache: &SlidingSyncCache,
+	) -> Result<()> {
+		// The timestamp comes first so expired connections can be found without
+		// parsing them
+		let mut value = cache.last_used().to_be_bytes().to_vec();
+		value.extend_from_slice(&serde_json::to_vec(cache).expect("SlidingSyncCache can be serialized"));
+
+		self.userdeviceconnid_slidingsync
+			.insert(&userdeviceconnid(user_id, device_id, conn_id), &value)
+	}
+
+	fn remove_sync_connection(&self, user_id: &UserId, device_id: &DeviceId, conn_id: &str) -> Result<()> {
+		self.userdeviceconnid_slidingsync
+			.remove(&userdeviceconnid(user_id, device_id, conn_id))
+	}
+
+	fn remove_sync_connections_before(&self, until: u64) -> Result<()> {
+		let expired = self
+			.userdeviceconnid_slidingsync
+			.iter()
+			.filter(|(_, value)| {
+				value
+					.get(..size_of::<u64>())
+					.and_then(|last_used| utils::u64_from_bytes(last_used).ok())
+					.map_or(true, |last_used| last_used < until)
+			})
+			.map(|(key, _)| key)
+			.collect::<Vec<_>>();
+
+		self.userdeviceconnid_slidingsync
+			.remove_batch(&mut expired.into_iter())
+	}
 }
 
 impl KeyValueDatabase {}
 
+fn userdevicestreamid(user_id: &UserId, device_id: &DeviceId, stream: Option<&str>) -> Vec<u8> {
+	let mut key = user_id.as_bytes().to_vec();
+	key.push(0xFF);
+	key.extend_from_slice(device_id.as_bytes());
+	key.push(0xFF);
+	key.extend_from_slice(stream.unwrap_or_default().as_bytes());
+	key
+}
+
+/// Returns the acknowledged to-device count and the last use of a sync stream.
+fn parse_stream_position(value: &[u8]) -> Result<(u64, u64)> {
+	if value.len() != 2 * size_of::<u64>() {
+		return Err(Error::bad_database("Sync stream position in db is invalid."));
+	}
+
+	let (count, last_used) = value.split_at(size_of::<u64>());
+
+	Ok((
+		utils::u64_from_bytes(count).map_err(|_| Error::bad_database("Sync stream position in db is invalid."))?,
+		utils::u64_from_bytes(last_used).map_err(|_| Error::bad_database("Sync stream position in db is invalid."))?,
+	))
+}
+
+fn userdeviceconnid(user_id: &UserId, device_id: &DeviceId, conn_id: &str) -> Vec<u8> {
+	let mut key = user_id.as_bytes().to_vec();
+	key.push(0xFF);
+	key.extend_from_slice(device_id.as_bytes());
+	key.push(0xFF);
+	key.extend_from_slice(conn_id.as_bytes());
+	key
+}
+
 /// Will only return with Some(username) if the password was not empty and the
 /// username could be successfully parsed.
 /// If `utils::string_from_bytes`(...) returns an error that username will be
@@ -1,4 +1,7 @@
 pub(crate) mod abstraction;
+mod archive;
+pub(crate) mod backup;
+pub(crate) mod check;
 pub(crate) mod key_value;
 
 use std::{
@@ -23,7 +26,8 @@ use ruma::{
 		GlobalAccountDataEvent, GlobalAccountDataEventType, StateEventType,
 	},
 	push::Ruleset,
-	CanonicalJsonValue, EventId, OwnedDeviceId, OwnedEventId, OwnedRoomId, OwnedUserId, RoomId, UserId,
+	CanonicalJsonObject, CanonicalJsonValue, EventId, OwnedDeviceId, OwnedEventId, OwnedRoomId, OwnedUserId, RoomId,
+	UserId,
 };
 use serde::Deserialize;
 #[cfg(unix)]
@@ -33,6 +37,17 @@ use tracing::{debug, error, info, warn};
 
 use crate::{service::rooms::timeline::PduCount, services, utils, Config, Error, PduEvent, Result, Services, SERVICES};
 
+/// Database version whose migration rebuilds `tokenids` with term frequencies
+/// and the unicode aware tokenizer. The rebuild runs in the background, see
+/// [`KeyValueDatabase::start_search_index_rebuild`].
+const SEARCH_INDEX_VERSION: u64 = 16;
+
+/// Key in `global` that is set once media file names were migrated to sha256.
+/// The database version can't tell, because builds without `sha256_media` skip
+/// version 14.
+#[cfg(feature = "sha256_media")]
+const SHA256_MEDIA_KEY: &[u8] = b"sha256_media_migrated";
+
 pub struct KeyValueDatabase {
 	db: Arc<dyn KeyValueDatabaseEngine>,
 
@@ -49,9 +64,12 @@ pub struct KeyValueDatabase {
 	pub(super) userdeviceid_metadata: Arc<dyn KvTree>, // This is also used to check if a device exists
 	pub(super) userid_devicelistversion: Arc<dyn KvTree>, // DevicelistVersion = u64
 	pub(super) token_userdeviceid: Arc<dyn KvTree>,
+	pub(super) openidtoken_expiresatuserid: Arc<dyn KvTree>, // ExpiresAtUserId = ExpiresAt + UserId
+	pub(super) useridemail_addedat: Arc<dyn KvTree>,         // AddedAt = u64
 
 	pub(super) onetimekeyid_onetimekeys: Arc<dyn KvTree>, // OneTimeKeyId = UserId + DeviceKeyId
 	pub(super) userid_lastonetimekeyupdate: Arc<dyn KvTree>, // LastOneTimeKeyUpdate = Count
+	pub(super) userdevicealgorithm_fallbackkey: Arc<dyn KvTree>, // UserDeviceAlgorithm = UserId + DeviceId + Algorithm
 	pub(super) keychangeid_userid: Arc<dyn KvTree>,       // KeyChangeId = UserId/RoomId + Count
 	pub(super) keyid_key: Arc<dyn KvTree>,                // KeyId = UserId + KeyId (depends on key type)
 	pub(super) userid_masterkeyid: Arc<dyn KvTree>,
@@ -60,6 +78,8 @@ pub struct KeyValueDatabase {
 
 	pub(super) userfilterid_filter: Arc<dyn KvTree>, // UserFilterId = UserId + FilterId
 	pub(super) todeviceid_events: Arc<dyn KvTree>,   // ToDeviceId = UserId + DeviceId + Count
+	pub(super) userdevicestreamid_todevicecount: Arc<dyn KvTree>, // UserDeviceStreamId = UserId + DeviceId + StreamId
+	pub(super) userdeviceconnid_slidingsync: Arc<dyn KvTree>, // UserDeviceConnId = UserId + DeviceId + ConnId
 	pub(super) userid_presenceid: Arc<dyn KvTree>,   // UserId => Count
 	pub(super) presenceid_presence: Arc<dyn KvTree>, // Count + UserId => Presence
 
@@ -83,7 +103,9 @@ pub struct KeyValueDatabase {
 
 	pub(super) threadid_userids: Arc<dyn KvTree>, // ThreadId = RoomId + Count
 
-	pub(super) tokenids: Arc<dyn KvTree>, // TokenId = ShortRoomId + Token + PduIdCount
+	pub(super) tokenids: Arc<dyn KvTree>, // TokenId = ShortRoomId + Token + PduIdCount, Frequency + Length
+	pub(super) shortroomid_searchstats: Arc<dyn KvTree>, // SearchStats = PduCount + TotalLength
+	pub(super) tsid_pduid: Arc<dyn KvTree>, // TsId = ShortRoomId + OriginServerTs + PduIdCount
 
 	/// Participating servers in a room.
 	pub(super) roomserverids: Arc<dyn KvTree>, // RoomServerId = RoomId + ServerName
@@ -108,6 +130,9 @@ pub struct KeyValueDatabase {
 	pub(super) userroomid_notificationcount: Arc<dyn KvTree>, // NotifyCount = u64
 	pub(super) userroomid_highlightcount: Arc<dyn KvTree>,    // HightlightCount = u64
 	pub(super) roomuserid_lastnotificationread: Arc<dyn KvTree>, // LastNotificationRead = u64
+	pub(super) userroomthreadid_notificationcount: Arc<dyn KvTree>, // ThreadId = ThreadRootEventId
+	pub(super) userroomthreadid_highlightcount: Arc<dyn KvTree>,
+	pub(super) userroomid_missedcallcount: Arc<dyn KvTree>, // MissedCallCount = u64
 
 	/// Remember the current state hash of a room.
 	pub(super) roomid_shortstatehash: Arc<dyn KvTree>,
@@ -169,6 +194,11 @@ pub struct KeyValueDatabase {
 
 	//pub pusher: pusher::PushData,
 	pub(super) senderkey_pusher: Arc<dyn KvTree>,
+	pub(super) useridcount_notification: Arc<dyn KvTree>, // UserIdCount = UserId + PduCount
+	pub(super) senderkey_unsubscribetoken: Arc<dyn KvTree>,
+	pub(super) unsubscribetoken_senderkey: Arc<dyn KvTree>,
+	pub(super) senderkey_emaildigest: Arc<dyn KvTree>, // EmailDigest = Since + DueAt
+	pub(super) senderkey_pusherstatus: Arc<dyn KvTree>, // PusherStatus = JSON
 
 	pub(super) pdu_cache: Mutex<LruCache<OwnedEventId, Arc<PduEvent>>>,
 	pub(super) shorteventid_cache: Mutex<LruCache<u64, Arc<EventId>>>,
@@ -194,6 +224,12 @@ struct CheckForUpdatesResponse {
 
 impl KeyValueDatabase {
 	fn check_db_setup(config: &Config) -> Result<()> {
+		// The memory backend keeps no database files in the database path, only
+		// the uploaded media
+		if config.database_backend == "memory" {
+			return Ok(());
+		}
+
 		let path = Path::new(&config.database_path);
 
 		let sqlite_exists = path.join("conduit.db").exists();
@@ -229,12 +265,12 @@ impl KeyValueDatabase {
 		Ok(())
 	}
 
-	/// Load an existing database or create a new one.
-	#[allow(clippy::too_many_lines)]
-	pub async fn load_or_create(config: Config) -> Result<()> {
-		Self::check_db_setup(&config)?;
+	/// Opens the database engine selected in the config, creating the database
+	/// if it does not exist yet.
+	fn open_engine(config: &Config) -> Result<Arc<dyn KeyValueDatabaseEngine>> {
+		Self::check_db_setup(config)?;
 
-		if !Path::new(&config.database_path).exists() {
+		if config.database_backend != "memory" && !Path::new(&config.database_path).exists() {
 			debug!("Database path does not exist, assuming this is a new setup and creating it");
 			fs::create_dir_all(&config.database_path).map_err(|e| {
 				error!("Failed to create database path: {e}");
@@ -252,22 +288,40 @@ impl KeyValueDatabase {
 				#[cfg(not(feature = "sqlite"))]
 				return Err(Error::bad_config("Database backend not found."));
 				#[cfg(feature = "sqlite")]
-				Arc::new(Arc::<abstraction::sqlite::Engine>::open(&config)?)
+				Arc::new(Arc::<abstraction::sqlite::Engine>::open(config)?)
 			},
 			"rocksdb" => {
 				debug!("Got rocksdb database backend");
 				#[cfg(not(feature = "rocksdb"))]
 				return Err(Error::bad_config("Database backend not found."));
 				#[cfg(feature = "rocksdb")]
-				Arc::new(Arc::<abstraction::rocksdb::Engine>::open(&config)?)
+				Arc::new(Arc::<abstraction::rocksdb::Engine>::open(config)?)
+			},
+			"memory" => {
+				warn!(
+					"Using the in-memory database backend, the database is lost when the server stops. Media is still \
+					 stored in database_path"
+				);
+				Arc::new(Arc::<abstraction::memory::Engine>::open(config)?)
 			},
 			_ => {
 				return Err(Error::bad_config(
-					"Database backend not found. sqlite (not recommended) and rocksdb are the only supported backends.",
+					"Database backend not found. rocksdb, sqlite (not recommended) and memory (for tests) are the \
+					 only supported backends.",
 				));
 			},
 		};
 
+		Ok(builder)
+	}
+
+	/// Load an existing database or create a new one and run pending
+	/// migrations. The server additionally needs
+	/// [`KeyValueDatabase::start_background_tasks`].
+	#[allow(clippy::too_many_lines)]
+	pub async fn load_or_create(config: Config) -> Result<()> {
+		let builder = Self::open_engine(&config)?;
+
 		let db_raw = Box::new(Self {
 			db: builder.clone(),
 			userid_password: builder.open_tree("userid_password")?,
@@ -278,8 +332,11 @@ impl KeyValueDatabase {
 			userdeviceid_metadata: builder.open_tree("userdeviceid_metadata")?,
 			userid_devicelistversion: builder.open_tree("userid_devicelistversion")?,
 			token_userdeviceid: builder.open_tree("token_userdeviceid")?,
+			openidtoken_expiresatuserid: builder.open_tree("openidtoken_expiresatuserid")?,
+			useridemail_addedat: builder.open_tree("useridemail_addedat")?,
 			onetimekeyid_onetimekeys: builder.open_tree("onetimekeyid_onetimekeys")?,
 			userid_lastonetimekeyupdate: builder.open_tree("userid_lastonetimekeyupdate")?,
+			userdevicealgorithm_fallbackkey: builder.open_tree("userdevicealgorithm_fallbackkey")?,
 			keychangeid_userid: builder.open_tree("keychangeid_userid")?,
 			keyid_key: builder.open_tree("keyid_key")?,
 			userid_masterkeyid: builder.open_tree("userid_masterkeyid")?,
@@ -287,6 +344,8 @@ impl KeyValueDatabase {
 			userid_usersigningkeyid: builder.open_tree("userid_usersigningkeyid")?,
 			userfilterid_filter: builder.open_tree("userfilterid_filter")?,
 			todeviceid_events: builder.open_tree("todeviceid_events")?,
+			userdevicestreamid_todevicecount: builder.open_tree("userdevicestreamid_todevicecount")?,
+			userdeviceconnid_slidingsync: builder.open_tree("userdeviceconnid_slidingsync")?,
 			userid_presenceid: builder.open_tree("userid_presenceid")?,
 			presenceid_presence: builder.open_tree("presenceid_presence")?,
 
@@ -306,6 +365,8 @@ impl KeyValueDatabase {
 			threadid_userids: builder.open_tree("threadid_userids")?,
 
 			tokenids: builder.open_tree("tokenids")?,
+			shortroomid_searchstats: builder.open_tree("shortroomid_searchstats")?,
+			tsid_pduid: builder.open_tree("tsid_pduid")?,
 
 			roomserverids: builder.open_tree("roomserverids")?,
 			serverroomids: builder.open_tree("serverroomids")?,
@@ -328,6 +389,9 @@ impl KeyValueDatabase {
 			userroomid_notificationcount: builder.open_tree("userroomid_notificationcount")?,
 			userroomid_highlightcount: builder.open_tree("userroomid_highlightcount")?,
 			roomuserid_lastnotificationread: builder.open_tree("userroomid_highlightcount")?,
+			userroomthreadid_notificationcount: builder.open_tree("userroomthreadid_notificationcount")?,
+			userroomthreadid_highlightcount: builder.open_tree("userroomthreadid_highlightcount")?,
+			userroomid_missedcallcount: builder.open_tree("userroomid_missedcallcount")?,
 
 			statekey_shortstatekey: builder.open_tree("statekey_shortstatekey")?,
 			shortstatekey_statekey: builder.open_tree("shortstatekey_statekey")?,
@@ -363,6 +427,11 @@ impl KeyValueDatabase {
 			servercurrentevent_data: builder.open_tree("servercurrentevent_data")?,
 			id_appserviceregistrations: builder.open_tree("id_appserviceregistrations")?,
 			senderkey_pusher: builder.open_tree("senderkey_pusher")?,
+			useridcount_notification: builder.open_tree("useridcount_notification")?,
+			senderkey_unsubscribetoken: builder.open_tree("senderkey_unsubscribetoken")?,
+			unsubscribetoken_senderkey: builder.open_tree("unsubscribetoken_senderkey")?,
+			senderkey_emaildigest: builder.open_tree("senderkey_emaildigest")?,
+			senderkey_pusherstatus: builder.open_tree("senderkey_pusherstatus")?,
 			global: builder.open_tree("global")?,
 			server_signingkeys: builder.open_tree("server_signingkeys")?,
 
@@ -415,12 +484,10 @@ impl KeyValueDatabase {
 		}
 
 		// If the database has any data, perform data migrations before starting
-		// do not increment the db version if the user is not using sha256_media
-		let latest_database_version = if cfg!(feature = "sha256_media") {
-			14
-		} else {
-			13
-		};
+		// Version 14 is only used with sha256_media, builds without it migrate from
+		// 13 to 15. The media file names are migrated whenever sha256_media is
+		// enabled, see SHA256_MEDIA_KEY.
+		let latest_database_version = SEARCH_INDEX_VERSION;
 
 		if services().users.count()? > 0 {
 			// MIGRATIONS
@@ -921,7 +988,7 @@ impl KeyValueDatabase {
 
 			#[cfg(feature = "sha256_media")]
 			{
-				if services().globals.database_version()? < 14 && cfg!(feature = "sha256_media") {
+				if db.global.get(SHA256_MEDIA_KEY)?.is_none() {
 					warn!(
 						"sha256_media feature flag is enabled, migrating legacy base64 file names to sha256 file names"
 					);
@@ -937,12 +1004,62 @@ impl KeyValueDatabase {
 						}
 					}
 
-					services().globals.bump_database_version(14)?;
+					db.global.insert(SHA256_MEDIA_KEY, &[])?;
+					if services().globals.database_version()? < 14 {
+						services().globals.bump_database_version(14)?;
+					}
 
-					warn!("Migration: 13 -> 14 finished");
+					warn!("Migration of media file names to sha256 finished");
 				}
 			}
 
+			let database_version = services().globals.database_version()?;
+			if database_version < 15 {
+				let mut iter = db
+					.pduid_pdu
+					.iter()
+					.filter_map(|(pdu_id, v)| {
+						let json = serde_json::from_slice::<CanonicalJsonObject>(&v).ok()?;
+						let Some(CanonicalJsonValue::Integer(ts)) = json.get("origin_server_ts") else {
+							return None;
+						};
+						let ts = u64::try_from(i64::from(*ts)).ok()?;
+
+						let mut tsid = pdu_id[..size_of::<u64>()].to_vec();
+						tsid.extend_from_slice(&ts.to_be_bytes());
+						tsid.extend_from_slice(&pdu_id[size_of::<u64>()..]);
+
+						Some((tsid, pdu_id))
+					})
+					.peekable();
+
+				// Written in chunks so large databases don't build one huge write batch
+				while iter.peek().is_some() {
+					db.tsid_pduid
+						.insert_batch(&mut iter.by_ref().take(10_000))?;
+				}
+
+				services().globals.bump_database_version(15)?;
+
+				warn!("Migration: {database_version} -> 15 finished");
+			}
+
+			let database_version = services().globals.database_version()?;
+			if database_version < SEARCH_INDEX_VERSION {
+				// Rebuilding takes long on large servers, so it continues in the background
+				// after startup
+				services().rooms.search.set_rebuild_pending(true)?;
+
+				services()
+					.globals
+					.bump_database_version(SEARCH_INDEX_VERSION)?;
+
+				warn!(
+					"Migration: {database_version} -> {SEARCH_INDEX_VERSION} started, the search index is rebuilt in \
+					 the background"
+				);
+			}
+
 			assert_eq!(
 				services().globals.database_version().unwrap(),
 				latest_database_version,
@@ -1010,6 +1127,10 @@ impl KeyValueDatabase {
 				.globals
 				.bump_database_version(latest_database_version)?;
 
+			// There are no legacy media file names in a new database
+			#[cfg(feature = "sha256_media")]
+			db.global.insert(SHA256_MEDIA_KEY, &[])?;
+
 			// Create the admin room and server user on first run
 			services().admin.create_admin_room().await?;
 
@@ -1019,6 +1140,12 @@ impl KeyValueDatabase {
 			);
 		}
 
+		Ok(())
+	}
+
+	/// Starts the admin room handler, the sending queue and the other tasks the
+	/// running server needs, after the database has been loaded.
+	pub async fn start_background_tasks() {
 		services().admin.start_handler();
 
 		// Set emergency access for the conduit user
@@ -1044,16 +1171,20 @@ impl KeyValueDatabase {
 
 		services().sending.start_handler();
 
-		if config.allow_local_presence {
+		#[cfg(unix)]
+		services().globals.start_reload_handler();
+
+		if services().globals.config.allow_local_presence {
 			services().presence.start_handler();
 		}
 
 		Self::start_cleanup_task().await;
+		Self::start_backup_task();
+		Self::start_search_index_rebuild();
+		services().pusher.start_email_digests();
 		if services().globals.allow_check_for_updates() {
 			Self::start_check_for_updates_task().await;
 		}
-
-		Ok(())
 	}
 
 	pub fn flush(&self) -> Result<()> {
@@ -1066,6 +1197,61 @@ impl KeyValueDatabase {
 		res
 	}
 
+	/// Rebuilds the search index of every room in the background if the
+	/// migration to [`SEARCH_INDEX_VERSION`] left that pending. Rooms whose
+	/// index wasn't rebuilt yet can still be searched with the old entries.
+	fn start_search_index_rebuild() {
+		tokio::spawn(async {
+			if let Err(e) = Self::rebuild_search_index().await {
+				error!("Failed to rebuild the search index: {e}");
+			}
+		});
+	}
+
+	/// Rebuilds the search index of every room if the migration to
+	/// [`SEARCH_INDEX_VERSION`] left that pending, and returns once it is done.
+	pub async fn rebuild_search_index() -> Result<()> {
+		if !services().rooms.search.rebuild_pending()? {
+			return Ok(());
+		}
+
+		warn!("Rebuilding the search index");
+
+		let room_ids = services()
+			.rooms
+			.metadata
+			.iter_ids()
+			.filter_map(Result::ok)
+			.collect::<Vec<_>>();
+
+		for room_id in room_ids {
+			// New pdus are indexed while the state lock is held
+			let mutex_state = Arc::clone(
+				services()
+					.globals
+					.roomid_mutex_state
+					.write()
+					.await
+					.entry(room_id.clone())
+					.or_default(),
+			);
+			let state_lock = mutex_state.lock().await;
+
+			// Reindexing scans the whole timeline of the room
+			tokio::task::spawn_blocking(move || services().rooms.search.reindex_room(&room_id))
+				.await
+				.map_err(|e| Error::Error(format!("Reindexing a room panicked: {e}")))??;
+
+			drop(state_lock);
+		}
+
+		services().rooms.search.set_rebuild_pending(false)?;
+
+		warn!("Finished rebuilding the search index");
+
+		Ok(())
+	}
+
 	#[tracing::instrument]
 	async fn start_check_for_updates_task() {
 		let timer_interval = Duration::from_secs(7200); // 2 hours
@@ -1126,6 +1312,18 @@ impl KeyValueDatabase {
 		} else {
 			debug!(target: "database-cleanup", "Finished cleanup in {:#?}.", start.elapsed());
 		}
+
+		if let Err(e) = services().users.remove_expired_sync_connections() {
+			error!(target: "database-cleanup", "Failed to remove expired sliding sync connections: {}", e);
+		}
+
+		if let Err(e) = services().pusher.remove_old_notifications() {
+			error!(target: "database-cleanup", "Failed to remove old notifications: {}", e);
+		}
+
+		if let Err(e) = services().users.remove_expired_openid_tokens() {
+			error!(target: "database-cleanup", "Failed to remove expired OpenID tokens: {}", e);
+		}
 	}
 
 	#[tracing::instrument]
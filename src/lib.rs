@@ -15,7 +15,10 @@ pub use api::ruma_wrapper::{Ruma, RumaResponse};
 pub use config::Config;
 pub use database::KeyValueDatabase;
 pub use service::{pdu::PduEvent, Services};
-pub use utils::error::{Error, Result};
+pub use utils::{
+	error::{Error, Result},
+	log::{set_filter_handle as set_log_filter_handle, CaptureLayer},
+};
 
 pub static SERVICES: RwLock<Option<&'static Services<'static>>> = RwLock::new(None);
 
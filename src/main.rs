@@ -38,7 +38,7 @@ use tower_http::{
 	ServiceBuilderExt as _,
 };
 use tracing::{debug, error, info, warn, Level};
-use tracing_subscriber::{prelude::*, EnvFilter};
+use tracing_subscriber::{prelude::*, reload, EnvFilter};
 
 mod routes;
 
@@ -57,13 +57,105 @@ struct Server {
 
 fn main() -> Result<(), Error> {
 	let args = clap::parse();
+	let (export, import, command) = (args.export.clone(), args.import.clone(), args.command.clone());
 	let conduwuit: Server = init(args)?;
 
+	if let Some(path) = export {
+		return KeyValueDatabase::export(&conduwuit.config, &path);
+	}
+
+	if let Some(path) = import {
+		return KeyValueDatabase::import(&conduwuit.config, &path);
+	}
+
+	if let Some(command) = command {
+		return conduwuit
+			.runtime
+			.block_on(async { run_command(&conduwuit, command).await });
+	}
+
 	conduwuit
 		.runtime
 		.block_on(async { async_main(&conduwuit).await })
 }
 
+/// Runs a subcommand against the database instead of starting the server
+async fn run_command(server: &Server, command: clap::Commands) -> Result<(), Error> {
+	// Restoring replaces the database, so it must not be opened first
+	if let clap::Commands::RestoreBackup {
+		id,
+		media,
+	} = command
+	{
+		return KeyValueDatabase::restore_backup(&server.config, id, media);
+	}
+
+	KeyValueDatabase::load_or_create(server.config.clone()).await?;
+
+	// Most subcommands are shortcuts for admin room commands
+	let mut body_file = None;
+	let admin_command = match command {
+		clap::Commands::CheckDb {
+			repair,
+		} => {
+			let report = services().globals.db.check_consistency(repair).await?;
+			print!("{report}");
+
+			if !report.is_consistent() {
+				return Err(Error::bad_database("The database has inconsistencies that were not repaired."));
+			}
+
+			None
+		},
+		clap::Commands::Migrate => {
+			// The running server rebuilds the search index in the background, but
+			// there is no running server after this command
+			KeyValueDatabase::rebuild_search_index().await?;
+			None
+		},
+		clap::Commands::RestoreBackup {
+			..
+		} => unreachable!("backups are restored before the database is opened"),
+		clap::Commands::CreateUser {
+			username,
+			password,
+		} => Some(
+			["users".to_owned(), "create".to_owned(), username]
+				.into_iter()
+				.chain(password)
+				.collect(),
+		),
+		clap::Commands::ResetPassword {
+			username,
+		} => Some(vec!["users".to_owned(), "reset-password".to_owned(), username]),
+		clap::Commands::MakeAdmin {
+			user_id,
+		} => Some(vec!["users".to_owned(), "make-admin".to_owned(), user_id]),
+		clap::Commands::ListRooms {
+			page,
+		} => Some(vec!["rooms".to_owned(), "list".to_owned(), page.unwrap_or(1).to_string()]),
+		clap::Commands::Admin {
+			body,
+			command,
+		} => {
+			body_file = body;
+			Some(command)
+		},
+	};
+
+	if let Some(args) = admin_command {
+		let body = match body_file {
+			Some(path) => format!("```\n{}\n```", std::fs::read_to_string(path)?),
+			None => String::new(),
+		};
+
+		let reply = services().admin.run_command(args, &body).await?;
+		println!("{}", reply.body());
+	}
+
+	services().globals.db.flush()
+}
+
 async fn async_main(server: &Server) -> Result<(), Error> {
 	if let Err(error) = start(server).await {
 		error!("Critical error starting server: {error}");
@@ -270,6 +362,8 @@ async fn start(server: &Server) -> Result<(), Error> {
 	KeyValueDatabase::load_or_create(server.config.clone()).await?;
 	info!("Database took {:?} to load", db_load_time.elapsed());
 
+	KeyValueDatabase::start_background_tasks().await;
+
 	Ok(())
 }
 
@@ -491,6 +585,9 @@ fn init_tracing_sub(config: &Config) {
 			EnvFilter::try_new("warn").unwrap()
 		},
 	};
+	let filter = filter_layer.to_string();
+	let (filter_layer, filter_handle) = reload::Layer::new(filter_layer);
+	set_log_filter_handle(filter_handle, filter);
 
 	#[cfg(feature = "sentry_telemetry")]
 	let sentry_layer = sentry_tracing::layer();
@@ -503,13 +600,17 @@ fn init_tracing_sub(config: &Config) {
 		subscriber = registry
 			.with(filter_layer)
 			.with(fmt_layer)
+			.with(CaptureLayer)
 			.with(sentry_layer);
 	};
 
 	#[allow(clippy::unnecessary_operation)] // error[E0658]: attributes on expressions are experimental
 	#[cfg(not(feature = "sentry_telemetry"))]
 	{
-		subscriber = registry.with(filter_layer).with(fmt_layer);
+		subscriber = registry
+			.with(filter_layer)
+			.with(fmt_layer)
+			.with(CaptureLayer);
 	};
 
 	tracing::subscriber::set_global_default(subscriber).unwrap();
@@ -532,10 +633,14 @@ fn init_tracing_jaeger(config: &Config) {
 			EnvFilter::try_new("warn").unwrap()
 		},
 	};
+	let filter = filter_layer.to_string();
+	let (filter_layer, filter_handle) = reload::Layer::new(filter_layer);
+	set_log_filter_handle(filter_handle, filter);
 
 	let subscriber = tracing_subscriber::Registry::default()
 		.with(filter_layer)
-		.with(telemetry);
+		.with(telemetry)
+		.with(CaptureLayer);
 	tracing::subscriber::set_global_default(subscriber).unwrap();
 }
 
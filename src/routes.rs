@@ -3,7 +3,7 @@ use std::future::Future;
 use axum::{
 	extract::FromRequestParts,
 	response::IntoResponse,
-	routing::{get, on, post, MethodFilter},
+	routing::{get, on, post, put, MethodFilter},
 	Router,
 };
 use conduit::{
@@ -22,6 +22,7 @@ pub fn routes() -> Router {
 		.ruma_route(client_server::get_login_types_route)
 		.ruma_route(client_server::login_route)
 		.ruma_route(client_server::whoami_route)
+		.ruma_route(client_server::create_openid_token_route)
 		.ruma_route(client_server::logout_route)
 		.ruma_route(client_server::logout_all_route)
 		.ruma_route(client_server::change_password_route)
@@ -79,6 +80,7 @@ pub fn routes() -> Router {
 		.ruma_route(client_server::create_alias_route)
 		.ruma_route(client_server::delete_alias_route)
 		.ruma_route(client_server::get_alias_route)
+		.ruma_route(client_server::appservice_ping_route)
 		.ruma_route(client_server::join_room_by_id_route)
 		.ruma_route(client_server::join_room_by_id_or_alias_route)
 		.ruma_route(client_server::joined_members_route)
@@ -127,6 +129,7 @@ pub fn routes() -> Router {
 		.ruma_route(client_server::sync_events_v4_route)
 		.ruma_route(client_server::get_context_route)
 		.ruma_route(client_server::get_message_events_route)
+		.ruma_route(client_server::get_event_by_timestamp_route)
 		.ruma_route(client_server::search_events_route)
 		.ruma_route(client_server::turn_server_route)
 		.ruma_route(client_server::send_event_to_device_route)
@@ -174,6 +177,7 @@ pub fn routes() -> Router {
 		.ruma_route(client_server::get_key_changes_route)
 		.ruma_route(client_server::get_pushers_route)
 		.ruma_route(client_server::set_pushers_route)
+		.ruma_route(client_server::get_notifications_route)
 		// .ruma_route(client_server::third_party_route)
 		.ruma_route(client_server::upgrade_room_route)
 		.ruma_route(client_server::get_threads_route)
@@ -191,6 +195,7 @@ pub fn routes() -> Router {
 		.ruma_route(server_server::get_public_rooms_filtered_route)
 		.ruma_route(server_server::send_transaction_message_route)
 		.ruma_route(server_server::get_event_route)
+		.ruma_route(server_server::get_event_by_timestamp_route)
 		.ruma_route(server_server::get_backfill_route)
 		.ruma_route(server_server::get_missing_events_route)
 		.ruma_route(server_server::get_event_authorization_route)
@@ -200,13 +205,20 @@ pub fn routes() -> Router {
 		.ruma_route(server_server::create_join_event_v1_route)
 		.ruma_route(server_server::create_join_event_v2_route)
 		.ruma_route(server_server::create_invite_route)
+		.ruma_route(server_server::exchange_third_party_invite_route)
+		.route("/_matrix/federation/v1/3pid/onbind", put(server_server::third_party_invite_bind_route))
 		.ruma_route(server_server::get_devices_route)
 		.ruma_route(server_server::get_room_information_route)
 		.ruma_route(server_server::get_profile_information_route)
+		.ruma_route(server_server::get_openid_userinfo_route)
 		.ruma_route(server_server::get_keys_route)
 		.ruma_route(server_server::claim_keys_route)
         .ruma_route(server_server::get_hierarchy_route)
         .route("/_conduwuit/server_version", get(client_server::conduwuit_server_version))
+		.route(
+			"/_conduwuit/email/unsubscribe",
+			get(client_server::email_unsubscribe_page_route).post(client_server::email_unsubscribe_route),
+		)
 		.route("/_matrix/client/r0/rooms/:room_id/initialSync", get(initial_sync))
 		.route("/_matrix/client/v3/rooms/:room_id/initialSync", get(initial_sync))
 		.route("/client/server.json", get(client_server::syncv3_client_server_json))
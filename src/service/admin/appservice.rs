@@ -1,7 +1,12 @@
+use std::fmt::Write as _;
+
 use clap::Subcommand;
 use ruma::{api::appservice::Registration, events::room::message::RoomMessageEventContent};
 
-use crate::{service::admin::escape_html, services, Result};
+use crate::{
+	service::admin::{escape_html, format_millis},
+	services, Result,
+};
 
 #[cfg_attr(test, derive(Debug))]
 #[derive(Subcommand)]
@@ -33,6 +38,29 @@ pub(crate) enum AppserviceCommand {
 
 	/// - List all the currently registered appservices
 	List,
+
+	/// - Re-read the appservice registration files from the config
+	Reload,
+
+	/// - Show how deliveries to the appservices went since the server started
+	Status,
+
+	/// - Retry the failed transaction of an appservice now instead of waiting
+	///   for its backoff
+	///
+	/// You can find the ID using the `list-appservices` command.
+	Retry {
+		/// The appservice to retry
+		appservice_identifier: String,
+	},
+
+	/// - Ping an appservice to check that it can be reached
+	///
+	/// You can find the ID using the `list-appservices` command.
+	Ping {
+		/// The appservice to ping
+		appservice_identifier: String,
+	},
 }
 
 pub(crate) async fn process(command: AppserviceCommand, body: Vec<&str>) -> Result<RoomMessageEventContent> {
@@ -96,5 +124,79 @@ pub(crate) async fn process(command: AppserviceCommand, body: Vec<&str>) -> Resu
 			let output = format!("Appservices ({}): {}", appservices.len(), appservices.join(", "));
 			Ok(RoomMessageEventContent::text_plain(output))
 		},
+		AppserviceCommand::Reload => match services().appservice.reload_registration_files().await {
+			Ok(loaded) => Ok(RoomMessageEventContent::text_plain(format!(
+				"Loaded {loaded} appservices from registration files."
+			))),
+			Err(e) => Ok(RoomMessageEventContent::text_plain(format!(
+				"Failed to reload appservice registration files: {e}"
+			))),
+		},
+		AppserviceCommand::Status => {
+			let mut output = String::new();
+			for id in services().appservice.iter_ids().await {
+				let status = services().sending.appservice_status(&id);
+				let queued = services().sending.appservice_queued_count(&id);
+
+				_ = writeln!(output, "{id}:");
+				_ = writeln!(output, "  queued events: {queued}");
+				_ = writeln!(
+					output,
+					"  last success: {}",
+					status
+						.last_success
+						.map_or_else(|| "never".to_owned(), format_millis)
+				);
+				let last_error = status.last_error.map_or_else(
+					|| "none".to_owned(),
+					|(time, error)| format!("{} ({error})", format_millis(time)),
+				);
+				_ = writeln!(output, "  last error: {last_error}");
+				if let Some(retry_at) = status.retry_at {
+					_ = writeln!(
+						output,
+						"  backing off after {} failures until {}",
+						status.failures,
+						format_millis(retry_at)
+					);
+				}
+			}
+
+			if output.is_empty() {
+				output = "No appservices are registered.".to_owned();
+			}
+
+			Ok(RoomMessageEventContent::text_plain(output))
+		},
+		AppserviceCommand::Retry {
+			appservice_identifier,
+		} => {
+			if services()
+				.appservice
+				.get_registration(&appservice_identifier)
+				.await
+				.is_none()
+			{
+				return Ok(RoomMessageEventContent::text_plain("Appservice does not exist."));
+			}
+
+			if services().sending.retry_appservice(&appservice_identifier) {
+				Ok(RoomMessageEventContent::text_plain("Retrying the failed transaction now."))
+			} else {
+				Ok(RoomMessageEventContent::text_plain("No transaction to this appservice failed."))
+			}
+		},
+		AppserviceCommand::Ping {
+			appservice_identifier,
+		} => match services()
+			.appservice
+			.ping(&appservice_identifier, None)
+			.await
+		{
+			Ok(duration) => Ok(RoomMessageEventContent::text_plain(format!(
+				"Appservice answered in {duration:?}."
+			))),
+			Err(e) => Ok(RoomMessageEventContent::text_plain(format!("Failed to ping appservice: {e}"))),
+		},
 	}
 }
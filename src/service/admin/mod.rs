@@ -1,5 +1,6 @@
 use std::{collections::BTreeMap, sync::Arc};
 
+use chrono::{DateTime, Utc};
 use clap::Parser;
 use regex::Regex;
 use ruma::{
@@ -43,6 +44,7 @@ pub(crate) mod room;
 pub(crate) mod room_alias;
 pub(crate) mod room_directory;
 pub(crate) mod room_moderation;
+pub(crate) mod room_search;
 pub(crate) mod server;
 pub(crate) mod user;
 
@@ -230,26 +232,65 @@ impl Service {
 		}
 	}
 
+	/// Runs an admin command outside of the admin room, e.g. from the command
+	/// line. `args` are the words of the command without the
+	/// `@conduit:server.name:` prefix and `body` is what would follow the
+	/// command in the admin room. Unlike in the admin room, invalid commands
+	/// are errors.
+	pub async fn run_command(&self, args: Vec<String>, body: &str) -> Result<RoomMessageEventContent> {
+		let server_name = services().globals.server_name();
+
+		let mut argv = vec![format!("@conduit:{server_name}:")];
+		argv.extend(args);
+
+		let admin_command = match self.parse_admin_argv(argv) {
+			Ok(command) => command,
+			Err(error) => {
+				let message = error
+					.to_string()
+					.replace("server.name", server_name.as_str());
+
+				// clap reports `--help` as an error too
+				if error.kind() == clap::error::ErrorKind::DisplayHelp {
+					return Ok(RoomMessageEventContent::text_plain(message));
+				}
+
+				return Err(Error::Error(message));
+			},
+		};
+
+		let body = body.lines().filter(|l| !l.trim().is_empty()).collect();
+
+		self.process_admin_command(admin_command, body).await
+	}
+
 	// Parse chat messages from the admin room into an AdminCommand object
 	fn parse_admin_command(&self, command_line: &str) -> Result<AdminCommand, String> {
+		let argv = command_line
+			.split_whitespace()
+			.map(ToOwned::to_owned)
+			.collect();
+
+		self.parse_admin_argv(argv)
+			.map_err(|error| error.to_string())
+	}
+
+	fn parse_admin_argv(&self, mut argv: Vec<String>) -> Result<AdminCommand, clap::Error> {
 		// Note: argv[0] is `@conduit:servername:`, which is treated as the main command
-		let mut argv = command_line.split_whitespace().collect::<Vec<_>>();
 
 		// Replace `help command` with `command --help`
 		// Clap has a help subcommand, but it omits the long help description.
 		if argv.len() > 1 && argv[1] == "help" {
 			argv.remove(1);
-			argv.push("--help");
+			argv.push("--help".to_owned());
 		}
 
 		// Backwards compatibility with `register_appservice`-style commands
-		let command_with_dashes;
 		if argv.len() > 1 && argv[1].contains('_') {
-			command_with_dashes = argv[1].replace('_', "-");
-			argv[1] = &command_with_dashes;
+			argv[1] = argv[1].replace('_', "-");
 		}
 
-		AdminCommand::try_parse_from(argv).map_err(|error| error.to_string())
+		AdminCommand::try_parse_from(argv)
 	}
 
 	async fn process_admin_command(&self, command: AdminCommand, body: Vec<&str>) -> Result<RoomMessageEventContent> {
@@ -735,6 +776,12 @@ fn escape_html(s: &str) -> String {
 		.replace('>', "&gt;")
 }
 
+fn format_millis(millis: u64) -> String {
+	DateTime::<Utc>::from_timestamp_millis(millis as i64)
+		.unwrap_or_default()
+		.to_rfc2822()
+}
+
 fn get_room_info(id: &OwnedRoomId) -> (OwnedRoomId, u64, String) {
 	(
 		id.clone(),
@@ -6,7 +6,8 @@ use ruma::{events::room::message::RoomMessageEventContent, OwnedRoomId};
 use crate::{
 	service::admin::{
 		escape_html, get_room_info, room_alias, room_alias::RoomAliasCommand, room_directory,
-		room_directory::RoomDirectoryCommand, room_moderation, room_moderation::RoomModerationCommand, PAGE_SIZE,
+		room_directory::RoomDirectoryCommand, room_moderation, room_moderation::RoomModerationCommand, room_search,
+		room_search::RoomSearchCommand, PAGE_SIZE,
 	},
 	services, Result,
 };
@@ -30,6 +31,10 @@ pub(crate) enum RoomCommand {
 	#[command(subcommand)]
 	/// - Manage the room directory
 	Directory(RoomDirectoryCommand),
+
+	#[command(subcommand)]
+	/// - Manage the search index
+	Search(RoomSearchCommand),
 }
 
 pub(crate) async fn process(command: RoomCommand, body: Vec<&str>) -> Result<RoomMessageEventContent> {
@@ -40,6 +45,8 @@ pub(crate) async fn process(command: RoomCommand, body: Vec<&str>) -> Result<Roo
 
 		RoomCommand::Moderation(command) => room_moderation::process(command, body).await,
 
+		RoomCommand::Search(command) => room_search::process(command, body).await,
+
 		RoomCommand::List {
 			page,
 		} => {
@@ -0,0 +1,92 @@
+use std::sync::Arc;
+
+use clap::Subcommand;
+use ruma::{events::room::message::RoomMessageEventContent, OwnedRoomId, RoomId};
+use tracing::{info, warn};
+
+use crate::{services, Result};
+
+#[cfg_attr(test, derive(Debug))]
+#[derive(Subcommand)]
+pub(crate) enum RoomSearchCommand {
+	/// - Rebuild the search index of a room, or of all rooms
+	///
+	/// The index is rebuilt in the background from the stored timeline. Use
+	/// this after a tokenizer change or if search results are wrong.
+	Reindex {
+		/// The room id of the room to reindex, all rooms if not given
+		room_id: Option<Box<RoomId>>,
+	},
+}
+
+pub(crate) async fn process(command: RoomSearchCommand, _body: Vec<&str>) -> Result<RoomMessageEventContent> {
+	match command {
+		RoomSearchCommand::Reindex {
+			room_id,
+		} => {
+			let room_ids: Vec<OwnedRoomId> = match room_id {
+				Some(room_id) => {
+					if !services().rooms.metadata.exists(&room_id)? {
+						return Ok(RoomMessageEventContent::text_plain("Room does not exist on this server."));
+					}
+					vec![room_id.into()]
+				},
+				None => services()
+					.rooms
+					.metadata
+					.iter_ids()
+					.filter_map(Result::ok)
+					.collect(),
+			};
+
+			let total = room_ids.len();
+			tokio::spawn(async move {
+				let mut failed = 0_usize;
+				for room_id in &room_ids {
+					// New pdus are indexed while the state lock is held
+					let mutex_state = Arc::clone(
+						services()
+							.globals
+							.roomid_mutex_state
+							.write()
+							.await
+							.entry(room_id.clone())
+							.or_default(),
+					);
+					let state_lock = mutex_state.lock().await;
+
+					// Reindexing scans the whole timeline of the room
+					let reindexed = {
+						let room_id = room_id.clone();
+						tokio::task::spawn_blocking(move || services().rooms.search.reindex_room(&room_id)).await
+					};
+
+					match reindexed {
+						Ok(Ok(())) => {},
+						Ok(Err(e)) => {
+							warn!("Failed to rebuild the search index of {room_id}: {e}");
+							failed += 1;
+						},
+						Err(e) => {
+							warn!("Rebuilding the search index of {room_id} panicked: {e}");
+							failed += 1;
+						},
+					}
+
+					drop(state_lock);
+				}
+
+				info!("Rebuilt the search index of {total} rooms, {failed} failed");
+				services()
+					.admin
+					.send_message(RoomMessageEventContent::text_plain(format!(
+						"Finished rebuilding the search index of {total} rooms ({failed} failed)."
+					)));
+			});
+
+			Ok(RoomMessageEventContent::text_plain(format!(
+				"Rebuilding the search index of {total} rooms in the background."
+			)))
+		},
+	}
+}
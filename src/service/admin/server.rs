@@ -1,7 +1,10 @@
+use std::{fmt::Write as _, time::Duration};
+
 use clap::Subcommand;
 use ruma::events::room::message::RoomMessageEventContent;
+use tracing::Level;
 
-use crate::{services, Result};
+use crate::{database, services, utils, Result};
 
 #[cfg_attr(test, derive(Debug))]
 #[derive(Subcommand)]
@@ -24,8 +27,11 @@ pub(crate) enum ServerCommand {
 		amount: u32,
 	},
 
-	/// - Performs an online backup of the database (only available for RocksDB
-	///   at the moment)
+	/// - Performs an online backup of the database and verifies it
+	///
+	/// The media directory is backed up as well if `database_backup_media` is
+	/// set, into a media directory shared by all backups that is never pruned.
+	/// Only RocksDB and SQLite databases can be backed up.
 	BackupDatabase,
 
 	/// - List database backups
@@ -33,6 +39,53 @@ pub(crate) enum ServerCommand {
 
 	/// - List database files
 	ListDatabaseFiles,
+
+	/// - Reload the config file and apply the settings that can change at
+	///   runtime
+	///
+	/// This is the same as sending conduwuit a SIGHUP. Settings that need a
+	/// restart are listed but not applied.
+	ReloadConfig,
+
+	/// - Change the log filter until the next restart
+	///
+	/// Takes the same directives as the `log` config option, e.g.
+	/// `warn,conduit::service::sending=debug`.
+	LogLevel {
+		filter: String,
+	},
+
+	/// - Send the log lines of a module to this room for a while
+	///
+	/// The module's log level is raised while capturing, so the lines are also
+	/// written to the server log. Release builds never log below info. The
+	/// admin and timeline modules, and modules containing them, can't be
+	/// captured.
+	LogCapture {
+		/// The module to capture, e.g. `conduit::service::sending`
+		module: String,
+
+		#[arg(long, default_value = "debug")]
+		/// The most verbose level to capture
+		level: Level,
+
+		#[arg(long, default_value_t = 5)]
+		/// How many minutes to capture for
+		minutes: u64,
+	},
+
+	/// - Stop the running log capture
+	StopLogCapture,
+
+	/// - Check the database for inconsistencies between its trees
+	///
+	/// This goes through every event, room, device and media entry, which can
+	/// take a while on large servers.
+	CheckDatabase {
+		#[arg(long)]
+		/// Repair the inconsistencies that can be fixed without losing data
+		repair: bool,
+	},
 }
 
 pub(crate) async fn process(command: ServerCommand, _body: Vec<&str>) -> Result<RoomMessageEventContent> {
@@ -73,22 +126,9 @@ pub(crate) async fn process(command: ServerCommand, _body: Vec<&str>) -> Result<
 			}
 		},
 		ServerCommand::BackupDatabase => {
-			if !cfg!(feature = "rocksdb") {
-				return Ok(RoomMessageEventContent::text_plain(
-					"Only RocksDB supports online backups in conduwuit.",
-				));
-			}
-
-			let mut result = tokio::task::spawn_blocking(move || match services().globals.db.backup() {
-				Ok(()) => String::new(),
-				Err(e) => (*e).to_string(),
-			})
-			.await
-			.unwrap();
-
-			if result.is_empty() {
-				result = services().globals.db.backup_list()?;
-			}
+			let result = tokio::task::spawn_blocking(database::backup::create_backup)
+				.await
+				.unwrap()?;
 
 			Ok(RoomMessageEventContent::text_plain(&result))
 		},
@@ -102,5 +142,54 @@ pub(crate) async fn process(command: ServerCommand, _body: Vec<&str>) -> Result<
 			let result = services().globals.db.file_list()?;
 			Ok(RoomMessageEventContent::notice_html(String::new(), result))
 		},
+		ServerCommand::ReloadConfig => {
+			let changes = services().globals.reload_config()?;
+			let mut msg = changes.to_string();
+
+			if changes
+				.applied
+				.iter()
+				.any(|key| key == "appservice_registrations")
+			{
+				let loaded = services().appservice.reload_registration_files().await?;
+				_ = writeln!(msg, "Loaded {loaded} appservices from registration files.");
+			}
+
+			Ok(RoomMessageEventContent::text_plain(msg))
+		},
+		ServerCommand::LogLevel {
+			filter,
+		} => {
+			utils::log::set_filter(&filter)?;
+
+			Ok(RoomMessageEventContent::text_plain(format!(
+				"Changed the log filter to {filter}."
+			)))
+		},
+		ServerCommand::LogCapture {
+			module,
+			level,
+			minutes,
+		} => {
+			utils::log::start_capture(module.clone(), level, Duration::from_secs(minutes.saturating_mul(60)))?;
+
+			Ok(RoomMessageEventContent::text_plain(format!(
+				"Capturing the {level} and more severe logs of {module} for {minutes} minutes."
+			)))
+		},
+		ServerCommand::StopLogCapture => {
+			if !utils::log::stop_capture()? {
+				return Ok(RoomMessageEventContent::text_plain("No logs are being captured."));
+			}
+
+			Ok(RoomMessageEventContent::text_plain("Stopping the log capture."))
+		},
+		ServerCommand::CheckDatabase {
+			repair,
+		} => {
+			let report = services().globals.db.check_consistency(repair).await?;
+
+			Ok(RoomMessageEventContent::text_plain(report.to_string()))
+		},
 	}
 }
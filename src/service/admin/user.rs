@@ -1,13 +1,17 @@
 use std::{fmt::Write as _, sync::Arc};
 
 use clap::Subcommand;
-use ruma::{events::room::message::RoomMessageEventContent, UserId};
+use ruma::{
+	api::client::push::{set_pusher, PusherKind},
+	events::room::message::RoomMessageEventContent,
+	UserId,
+};
 use tracing::{error, info, warn};
 
 use crate::{
 	api::client_server::{join_room_by_id_helper, leave_all_rooms, AUTO_GEN_PASSWORD_LENGTH},
-	service::admin::{escape_html, get_room_info},
-	services, utils, Result,
+	service::admin::{escape_html, format_millis, get_room_info},
+	services, utils, Error, Result,
 };
 
 #[cfg_attr(test, derive(Debug))]
@@ -27,6 +31,11 @@ pub(crate) enum UserCommand {
 		username: String,
 	},
 
+	/// - Grant a local user admin privileges by inviting them to the admin room
+	MakeAdmin {
+		user_id: Box<UserId>,
+	},
+
 	/// - Deactivate a user
 	///
 	/// User will not be removed from all rooms by default.
@@ -66,6 +75,26 @@ pub(crate) enum UserCommand {
 	ListJoinedRooms {
 		user_id: Box<UserId>,
 	},
+
+	/// - Lists the pushers of a local user and whether delivering notifications
+	///   to them works
+	ListPushers {
+		user_id: Box<UserId>,
+	},
+
+	/// - Binds an email address to a local user, so they can add an email
+	///   pusher for it if allow_email_pushers is enabled
+	AddEmail {
+		user_id: Box<UserId>,
+		address: String,
+	},
+
+	/// - Unbinds an email address from a local user and removes their email
+	///   pushers for it
+	RemoveEmail {
+		user_id: Box<UserId>,
+		address: String,
+	},
 }
 
 pub(crate) async fn process(command: UserCommand, body: Vec<&str>) -> Result<RoomMessageEventContent> {
@@ -89,19 +118,13 @@ pub(crate) async fn process(command: UserCommand, body: Vec<&str>) -> Result<Roo
 				services().globals.server_name(),
 			) {
 				Ok(id) => id,
-				Err(e) => {
-					return Ok(RoomMessageEventContent::text_plain(format!(
-						"The supplied username is not a valid username: {e}"
-					)))
-				},
+				Err(e) => return Err(Error::Error(format!("The supplied username is not a valid username: {e}"))),
 			};
 			if user_id.is_historical() {
-				return Ok(RoomMessageEventContent::text_plain(format!(
-					"Userid {user_id} is not allowed due to historical"
-				)));
+				return Err(Error::Error(format!("Userid {user_id} is not allowed due to historical")));
 			}
 			if services().users.exists(&user_id)? {
-				return Ok(RoomMessageEventContent::text_plain(format!("Userid {user_id} already exists")));
+				return Err(Error::Error(format!("Userid {user_id} already exists")));
 			}
 			// Create user
 			services().users.create(&user_id, Some(password.as_str()))?;
@@ -175,6 +198,33 @@ pub(crate) async fn process(command: UserCommand, body: Vec<&str>) -> Result<Roo
 				"Created user with user_id: {user_id} and password: `{password}`"
 			)))
 		},
+		UserCommand::MakeAdmin {
+			user_id,
+		} => {
+			if user_id.server_name() != services().globals.server_name() {
+				return Err(Error::Error(format!("User {user_id} does not belong to our server.")));
+			}
+
+			if !services().users.exists(&user_id)? {
+				return Err(Error::Error(format!("User {user_id} does not exist.")));
+			}
+
+			if services().users.is_admin(&user_id)? {
+				return Ok(RoomMessageEventContent::text_plain(format!("{user_id} is already an admin.")));
+			}
+
+			let displayname = services()
+				.users
+				.displayname(&user_id)?
+				.unwrap_or_else(|| user_id.localpart().to_owned());
+
+			services()
+				.admin
+				.make_user_admin(&user_id, displayname)
+				.await?;
+
+			Ok(RoomMessageEventContent::text_plain(format!("{user_id} is now an admin.")))
+		},
 		UserCommand::Deactivate {
 			leave_rooms,
 			user_id,
@@ -214,18 +264,12 @@ pub(crate) async fn process(command: UserCommand, body: Vec<&str>) -> Result<Roo
 				services().globals.server_name(),
 			) {
 				Ok(id) => id,
-				Err(e) => {
-					return Ok(RoomMessageEventContent::text_plain(format!(
-						"The supplied username is not a valid username: {e}"
-					)))
-				},
+				Err(e) => return Err(Error::Error(format!("The supplied username is not a valid username: {e}"))),
 			};
 
 			// check if user belongs to our server
 			if user_id.server_name() != services().globals.server_name() {
-				return Ok(RoomMessageEventContent::text_plain(format!(
-					"User {user_id} does not belong to our server."
-				)));
+				return Err(Error::Error(format!("User {user_id} does not belong to our server.")));
 			}
 
 			// Check if the specified user is valid
@@ -234,7 +278,7 @@ pub(crate) async fn process(command: UserCommand, body: Vec<&str>) -> Result<Roo
 					== UserId::parse_with_server_name("conduit", services().globals.server_name())
 						.expect("conduit user exists")
 			{
-				return Ok(RoomMessageEventContent::text_plain("The specified user does not exist!"));
+				return Err(Error::Error("The specified user does not exist!".to_owned()));
 			}
 
 			let new_password = utils::random_string(AUTO_GEN_PASSWORD_LENGTH);
@@ -246,9 +290,7 @@ pub(crate) async fn process(command: UserCommand, body: Vec<&str>) -> Result<Roo
 				Ok(()) => Ok(RoomMessageEventContent::text_plain(format!(
 					"Successfully reset the password for user {user_id}: `{new_password}`"
 				))),
-				Err(e) => Ok(RoomMessageEventContent::text_plain(format!(
-					"Couldn't reset the password for user {user_id}: {e}"
-				))),
+				Err(e) => Err(Error::Error(format!("Couldn't reset the password for user {user_id}: {e}"))),
 			}
 		},
 		UserCommand::DeactivateAll {
@@ -371,5 +413,94 @@ pub(crate) async fn process(command: UserCommand, body: Vec<&str>) -> Result<Roo
 			);
 			Ok(RoomMessageEventContent::text_html(output_plain, output_html))
 		},
+		UserCommand::ListPushers {
+			user_id,
+		} => {
+			if user_id.server_name() != services().globals.server_name() {
+				return Ok(RoomMessageEventContent::text_plain("User does not belong to our server."));
+			}
+
+			let pushers = services().pusher.get_pushers(&user_id)?;
+			if pushers.is_empty() {
+				return Ok(RoomMessageEventContent::text_plain("User has no pushers."));
+			}
+
+			let mut msg = format!("Pushers of {user_id}:\n");
+			for pusher in pushers {
+				let kind = match &pusher.kind {
+					PusherKind::Http(http) => format!("http {}", http.url),
+					PusherKind::Email(_) => "email".to_owned(),
+					_ => "unknown".to_owned(),
+				};
+				let status = services()
+					.pusher
+					.get_pusher_status(&user_id, &pusher.ids.pushkey)?;
+
+				_ = write!(
+					msg,
+					"\n{} ({}) on {}: {kind}\n",
+					pusher.ids.pushkey, pusher.ids.app_id, pusher.device_display_name
+				);
+
+				let retry_at = status.retry_at();
+				match (status.failures, status.last_error) {
+					(0, None) => msg.push_str("Delivering fine\n"),
+					(0, Some(error)) => _ = writeln!(msg, "Delivering fine, last error: {error}"),
+					(failures, error) => {
+						_ = writeln!(
+							msg,
+							"Failed {failures} times in a row, last at {}, retrying after {}: {}",
+							format_millis(status.last_failure),
+							format_millis(retry_at),
+							error.unwrap_or_default()
+						);
+					},
+				}
+			}
+
+			Ok(RoomMessageEventContent::text_plain(msg))
+		},
+		UserCommand::AddEmail {
+			user_id,
+			address,
+		} => {
+			if user_id.server_name() != services().globals.server_name() {
+				return Ok(RoomMessageEventContent::text_plain("User does not belong to our server."));
+			}
+
+			if address.parse::<lettre::Address>().is_err() {
+				return Ok(RoomMessageEventContent::text_plain("Invalid email address."));
+			}
+
+			services().users.add_email(&user_id, &address)?;
+
+			Ok(RoomMessageEventContent::text_plain(format!("Bound {address} to {user_id}.")))
+		},
+		UserCommand::RemoveEmail {
+			user_id,
+			address,
+		} => {
+			if user_id.server_name() != services().globals.server_name() {
+				return Ok(RoomMessageEventContent::text_plain("User does not belong to our server."));
+			}
+
+			if !services().users.has_email(&user_id, &address)? {
+				return Ok(RoomMessageEventContent::text_plain("Email address is not bound to this user."));
+			}
+
+			services().users.remove_email(&user_id, &address)?;
+
+			for pusher in services().pusher.get_pushers(&user_id)? {
+				if matches!(pusher.kind, PusherKind::Email(_)) && pusher.ids.pushkey.eq_ignore_ascii_case(&address) {
+					services()
+						.pusher
+						.set_pusher(&user_id, set_pusher::v3::PusherAction::Delete(pusher.ids))?;
+				}
+			}
+
+			Ok(RoomMessageEventContent::text_plain(format!(
+				"Unbound {address} from {user_id}."
+			)))
+		},
 	}
 }
@@ -1,14 +1,30 @@
 mod data;
 
-use std::collections::BTreeMap;
+use std::{
+	collections::{BTreeMap, BTreeSet},
+	fs,
+	path::PathBuf,
+	time::{Duration, Instant},
+};
 
 pub(crate) use data::Data;
 use futures_util::Future;
 use regex::RegexSet;
-use ruma::api::appservice::{Namespace, Registration};
+use ruma::{
+	api::{
+		appservice::{
+			ping::send_ping,
+			query::{query_room_alias, query_user_id},
+			Namespace, Registration,
+		},
+		client::error::ErrorKind,
+	},
+	OwnedRoomId, OwnedTransactionId, RoomAliasId, UserId,
+};
 use tokio::sync::RwLock;
+use tracing::{debug, info, warn};
 
-use crate::{services, Result};
+use crate::{services, Config, Error, Result};
 
 /// Compiled regular expressions for a namespace
 #[derive(Clone, Debug)]
@@ -82,6 +98,16 @@ pub struct RegistrationInfo {
 	pub rooms: NamespaceRegex,
 }
 
+impl RegistrationInfo {
+	/// Checks if the user belongs to the appservice, either as its sender or as
+	/// a user in its namespace
+	pub fn is_user_match(&self, user_id: &UserId) -> bool {
+		self.users.is_match(user_id.as_str())
+			|| (user_id.localpart() == self.registration.sender_localpart
+				&& user_id.server_name() == services().globals.server_name())
+	}
+}
+
 impl TryFrom<Registration> for RegistrationInfo {
 	type Error = regex::Error;
 
@@ -98,30 +124,50 @@ impl TryFrom<Registration> for RegistrationInfo {
 pub struct Service {
 	pub db: &'static dyn Data,
 	registration_info: RwLock<BTreeMap<String, RegistrationInfo>>,
+	/// Registration files and directories from the config
+	/// Appservices that were loaded from registration files
+	file_ids: RwLock<BTreeSet<String>>,
 }
 
 impl Service {
-	pub fn build(db: &'static dyn Data) -> Result<Self> {
-		let mut registration_info = BTreeMap::new();
-		// Inserting registrations into cache
-		for appservice in db.all()? {
-			registration_info.insert(
-				appservice.0,
-				appservice
-					.1
-					.try_into()
-					.expect("Should be validated on registration"),
-			);
-		}
+	pub fn build(db: &'static dyn Data, config: &Config) -> Result<Self> {
+		let file_registrations = load_registration_files(&config.appservice_registrations)?;
+		let file_ids = file_registrations.keys().cloned().collect();
 
 		Ok(Self {
 			db,
-			registration_info: RwLock::new(registration_info),
+			registration_info: RwLock::new(merge_registrations(db, file_registrations)?),
+			file_ids: RwLock::new(file_ids),
 		})
 	}
 
+	/// Re-reads the registration files listed in the most recently loaded
+	/// config, replacing the appservices loaded from files before. Returns how
+	/// many appservices were loaded.
+	///
+	/// Nothing changes if a file can't be read or is invalid.
+	pub async fn reload_registration_files(&self) -> Result<usize> {
+		let file_registrations = load_registration_files(&services().globals.appservice_registrations())?;
+		let loaded = file_registrations.len();
+		let file_ids = file_registrations.keys().cloned().collect();
+
+		let mut registration_info = self.registration_info.write().await;
+		*registration_info = merge_registrations(self.db, file_registrations)?;
+		*self.file_ids.write().await = file_ids;
+
+		info!("Loaded {loaded} appservices from registration files");
+		Ok(loaded)
+	}
+
+	/// Checks if the appservice was loaded from a registration file.
+	pub async fn is_from_file(&self, id: &str) -> bool { self.file_ids.read().await.contains(id) }
+
 	/// Registers an appservice and returns the ID to the caller
 	pub async fn register_appservice(&self, yaml: Registration) -> Result<String> {
+		if self.is_from_file(&yaml.id).await {
+			return Err(Error::AdminCommand("Appservice is managed by a registration file"));
+		}
+
 		services()
 			.appservice
 			.registration_info
@@ -138,13 +184,17 @@ impl Service {
 	///
 	/// * `service_name` - the name you send to register the service previously
 	pub async fn unregister_appservice(&self, service_name: &str) -> Result<()> {
+		if self.is_from_file(service_name).await {
+			return Err(Error::AdminCommand("Appservice is managed by a registration file"));
+		}
+
 		services()
 			.appservice
 			.registration_info
 			.write()
 			.await
 			.remove(service_name)
-			.ok_or_else(|| crate::Error::AdminCommand("Appservice not found"))?;
+			.ok_or_else(|| Error::AdminCommand("Appservice not found"))?;
 
 		self.db.unregister_appservice(service_name)
 	}
@@ -167,6 +217,138 @@ impl Service {
 			.collect()
 	}
 
+	/// Checks if an appservice claims the user exclusively.
+	pub async fn is_exclusive_user_id(&self, user_id: &UserId) -> bool {
+		self.read()
+			.await
+			.values()
+			.any(|info| info.users.is_exclusive_match(user_id.as_str()))
+	}
+
+	/// Checks if an appservice claims the room alias exclusively.
+	pub async fn is_exclusive_alias(&self, alias: &RoomAliasId) -> bool {
+		self.read()
+			.await
+			.values()
+			.any(|info| info.aliases.is_exclusive_match(alias.as_str()))
+	}
+
+	/// Asks the appservices claiming the user exclusively whether it exists,
+	/// giving them the chance to create it. Returns true if the user exists
+	/// afterwards.
+	pub async fn query_user_id(&self, user_id: &UserId) -> Result<bool> {
+		let registrations = self
+			.read()
+			.await
+			.values()
+			.filter(|info| info.users.is_exclusive_match(user_id.as_str()))
+			.map(|info| info.registration.clone())
+			.collect::<Vec<_>>();
+
+		for registration in registrations {
+			let id = registration.id.clone();
+			match services()
+				.sending
+				.send_appservice_request(
+					registration,
+					query_user_id::v1::Request {
+						user_id: user_id.to_owned(),
+					},
+				)
+				.await
+			{
+				Ok(Some(_)) if services().users.exists(user_id)? => return Ok(true),
+				Ok(_) => debug!("Appservice {id} does not know user {user_id}"),
+				Err(e) => warn!("Failed to query appservice {id} for user {user_id}: {e}"),
+			}
+		}
+
+		Ok(false)
+	}
+
+	/// Asks the appservices claiming the room alias exclusively whether it
+	/// exists, giving them the chance to create the room. Returns the room the
+	/// alias points to afterwards.
+	pub async fn query_room_alias(&self, alias: &RoomAliasId) -> Result<Option<OwnedRoomId>> {
+		let registrations = self
+			.read()
+			.await
+			.values()
+			.filter(|info| info.aliases.is_exclusive_match(alias.as_str()))
+			.map(|info| info.registration.clone())
+			.collect::<Vec<_>>();
+
+		for registration in registrations {
+			let id = registration.id.clone();
+			match services()
+				.sending
+				.send_appservice_request(
+					registration,
+					query_room_alias::v1::Request {
+						room_alias: alias.to_owned(),
+					},
+				)
+				.await
+			{
+				Ok(Some(_)) => {
+					if let Some(room_id) = services().rooms.alias.resolve_local_alias(alias)? {
+						return Ok(Some(room_id));
+					}
+				},
+				Ok(None) => {},
+				Err(e) => warn!("Failed to query appservice {id} for alias {alias}: {e}"),
+			}
+		}
+
+		Ok(None)
+	}
+
+	/// Checks that the appservice can be reached, returning how long it took
+	/// to answer.
+	pub async fn ping(&self, id: &str, transaction_id: Option<OwnedTransactionId>) -> Result<Duration> {
+		let registration = self
+			.get_registration(id)
+			.await
+			.ok_or(Error::BadRequest(ErrorKind::NotFound, "Appservice not found."))?;
+
+		if registration.url.is_none() {
+			return Err(Error::BadRequest(ErrorKind::UrlNotSet, "Appservice has no URL set."));
+		}
+
+		let start = Instant::now();
+		match services()
+			.sending
+			.send_appservice_request(
+				registration,
+				send_ping::v1::Request {
+					transaction_id,
+				},
+			)
+			.await
+		{
+			Ok(_) => Ok(start.elapsed()),
+			Err(Error::ReqwestError {
+				source,
+			}) if source.is_timeout() => Err(Error::BadRequest(
+				ErrorKind::ConnectionTimeout,
+				"Appservice did not answer in time.",
+			)),
+			Err(Error::ReqwestError {
+				..
+			}) => Err(Error::BadRequest(
+				ErrorKind::ConnectionFailed,
+				"Could not connect to the appservice.",
+			)),
+			Err(_) => Err(Error::BadRequest(
+				ErrorKind::BadStatus {
+					status: None,
+					body: None,
+				},
+				"Appservice returned an error.",
+			)),
+		}
+	}
+
 	pub async fn find_from_token(&self, token: &str) -> Option<RegistrationInfo> {
 		self.read()
 			.await
@@ -179,3 +361,79 @@ impl Service {
 		self.registration_info.read()
 	}
 }
+
+/// Reads the registration files, and the YAML files in the registration
+/// directories.
+fn load_registration_files(paths: &[PathBuf]) -> Result<BTreeMap<String, Registration>> {
+	let mut files = Vec::new();
+	for path in paths {
+		if !path.is_dir() {
+			files.push(path.clone());
+			continue;
+		}
+
+		let entries = fs::read_dir(path).map_err(|e| {
+			Error::bad_config(&format!(
+				"Could not read appservice registration directory {}: {e}",
+				path.display()
+			))
+		})?;
+
+		for entry in entries {
+			let file = entry?.path();
+			if file
+				.extension()
+				.is_some_and(|extension| extension == "yaml" || extension == "yml")
+			{
+				files.push(file);
+			}
+		}
+	}
+
+	files.sort_unstable();
+
+	let mut registrations = BTreeMap::new();
+	for file in files {
+		let yaml = fs::read_to_string(&file).map_err(|e| {
+			Error::bad_config(&format!("Could not read appservice registration file {}: {e}", file.display()))
+		})?;
+
+		let registration = serde_yaml::from_str::<Registration>(&yaml)
+			.map_err(|e| Error::bad_config(&format!("Invalid appservice registration file {}: {e}", file.display())))?;
+
+		let id = registration.id.clone();
+		if registrations.insert(id.clone(), registration).is_some() {
+			return Err(Error::bad_config(&format!(
+				"Appservice {id} is registered in more than one registration file"
+			)));
+		}
+	}
+
+	Ok(registrations)
+}
+
+/// Combines the registrations in the database with the ones from files, which
+/// take precedence.
+fn merge_registrations(
+	db: &dyn Data, file_registrations: BTreeMap<String, Registration>,
+) -> Result<BTreeMap<String, RegistrationInfo>> {
+	let mut registration_info = BTreeMap::new();
+	for (id, registration) in db.all()? {
+		registration_info.insert(
+			id,
+			registration
+				.try_into()
+				.expect("Should be validated on registration"),
+		);
+	}
+
+	for (id, registration) in file_registrations {
+		if registration_info.contains_key(&id) {
+			warn!("Appservice registration file for {id} overrides the registration in the database");
+		}
+
+		registration_info.insert(id, registration.try_into()?);
+	}
+
+	Ok(registration_info)
+}
@@ -7,7 +7,10 @@ use ruma::{
 	DeviceId, OwnedServerSigningKeyId, ServerName, UserId,
 };
 
-use crate::{database::abstraction::Cork, Result};
+use crate::{
+	database::{abstraction::Cork, check::CheckReport},
+	Result,
+};
 
 #[async_trait]
 pub trait Data: Send + Sync {
@@ -35,6 +38,8 @@ pub trait Data: Send + Sync {
 	fn signing_keys_for(&self, origin: &ServerName) -> Result<BTreeMap<OwnedServerSigningKeyId, VerifyKey>>;
 	fn database_version(&self) -> Result<u64>;
 	fn bump_database_version(&self, new_version: u64) -> Result<()>;
+	#[allow(unused_qualifications)] // async traits
+	async fn check_consistency(&self, repair: bool) -> Result<CheckReport>;
 	fn backup(&self) -> Result<(), Box<dyn Error>> { unimplemented!() }
 	fn backup_list(&self) -> Result<String> { Ok(String::new()) }
 	fn file_list(&self) -> Result<String> { Ok(String::new()) }
@@ -1,11 +1,11 @@
 use std::{
 	collections::{BTreeMap, HashMap},
-	fs,
+	fmt, fs,
 	future::Future,
 	path::PathBuf,
 	sync::{
 		atomic::{self, AtomicBool},
-		Arc,
+		Arc, RwLock as StdRwLock,
 	},
 	time::Instant,
 };
@@ -24,10 +24,13 @@ use ruma::{
 	DeviceId, OwnedDeviceId, OwnedEventId, OwnedRoomId, OwnedServerName, OwnedServerSigningKeyId, OwnedUserId,
 	RoomVersionId, ServerName, UserId,
 };
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::{broadcast, watch::Receiver, Mutex, RwLock, Semaphore};
-use tracing::{error, info};
+use tracing::{error, info, warn};
+use tracing_subscriber::EnvFilter;
 
-use crate::{services, Config, Result};
+use crate::{config::RELOADABLE_KEYS, services, utils, Config, Error, Result};
 
 pub mod client;
 mod data;
@@ -42,7 +45,11 @@ type SyncHandle = (
 pub struct Service<'a> {
 	pub db: &'static dyn Data,
 
+	/// The config from startup. Settings that can be reloaded at runtime must
+	/// be read from `reloaded_config` through their getters instead.
 	pub config: Config,
+	/// The most recently loaded config, see [`Service::reload_config`]
+	reloaded_config: StdRwLock<Arc<Config>>,
 	keypair: Arc<ruma::signatures::Ed25519KeyPair>,
 	jwt_decoding_key: Option<jsonwebtoken::DecodingKey>,
 	pub resolver: Arc<resolver::Resolver>,
@@ -53,7 +60,7 @@ pub struct Service<'a> {
 	pub bad_signature_ratelimiter: Arc<RwLock<HashMap<Vec<String>, RateLimitState>>>,
 	pub bad_query_ratelimiter: Arc<RwLock<HashMap<OwnedServerName, RateLimitState>>>,
 	pub servername_ratelimiter: Arc<RwLock<HashMap<OwnedServerName, Arc<Semaphore>>>>,
-	pub sync_receivers: RwLock<HashMap<(OwnedUserId, OwnedDeviceId), SyncHandle>>,
+	pub sync_receivers: RwLock<HashMap<(OwnedUserId, OwnedDeviceId, Option<String>), SyncHandle>>,
 	pub roomid_mutex_insert: RwLock<HashMap<OwnedRoomId, Arc<Mutex<()>>>>,
 	pub roomid_mutex_state: RwLock<HashMap<OwnedRoomId, Arc<Mutex<()>>>>,
 	pub roomid_mutex_federation: RwLock<HashMap<OwnedRoomId, Arc<Mutex<()>>>>, // this lock will be held longer
@@ -93,6 +100,31 @@ impl Default for RotationHandler {
 	fn default() -> Self { Self::new() }
 }
 
+/// The keys that changed when the config was reloaded
+pub struct ConfigChanges {
+	/// Keys that took effect right away
+	pub applied: Vec<String>,
+	/// Keys that differ from the config the server was started with and only
+	/// take effect after a restart
+	pub need_restart: Vec<String>,
+}
+
+impl fmt::Display for ConfigChanges {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		if self.applied.is_empty() {
+			writeln!(f, "No settings that can be reloaded have changed.")?;
+		} else {
+			writeln!(f, "Applied changes to: {}", self.applied.join(", "))?;
+		}
+
+		if !self.need_restart.is_empty() {
+			writeln!(f, "Changes that need a restart: {}", self.need_restart.join(", "))?;
+		}
+
+		Ok(())
+	}
+}
+
 impl Service<'_> {
 	pub fn load(db: &'static dyn Data, config: &Config) -> Result<Self> {
 		let keypair = db.load_keypair();
@@ -138,6 +170,7 @@ impl Service<'_> {
 		let mut s = Self {
 			db,
 			config: config.clone(),
+			reloaded_config: StdRwLock::new(Arc::new(config.clone())),
 			keypair: Arc::new(keypair),
 			resolver: resolver.clone(),
 			client: client::Client::new(config, &resolver),
@@ -195,15 +228,86 @@ impl Service<'_> {
 
 	pub fn flush(&self) -> Result<()> { self.db.flush() }
 
+	/// The most recently loaded config, which the settings in
+	/// [`RELOADABLE_KEYS`] are read from
+	fn reloaded(&self) -> Arc<Config> { Arc::clone(&self.reloaded_config.read().unwrap()) }
+
+	/// Reads the config again and applies the settings in [`RELOADABLE_KEYS`].
+	/// Nothing changes if the new config is invalid.
+	pub fn reload_config(&self) -> Result<ConfigChanges> {
+		let config = Config::new(self.config.config_path.clone())?;
+
+		let applied = self
+			.reloaded()
+			.changed_keys(&config)
+			.into_iter()
+			.filter(|key| RELOADABLE_KEYS.contains(&key.as_str()))
+			.collect::<Vec<_>>();
+		let need_restart = self
+			.config
+			.changed_keys(&config)
+			.into_iter()
+			.filter(|key| !RELOADABLE_KEYS.contains(&key.as_str()))
+			.collect();
+
+		let log_filter = if applied.iter().any(|key| key == "log") {
+			EnvFilter::try_new(&config.log).map_err(|e| Error::bad_config(&format!("Invalid log filter: {e}")))?;
+			Some(config.log.clone())
+		} else {
+			None
+		};
+
+		*self.reloaded_config.write().unwrap() = Arc::new(config);
+
+		if let Some(log_filter) = log_filter {
+			if let Err(e) = utils::log::set_filter(&log_filter) {
+				error!("Failed to replace the log filter: {e}");
+			}
+		}
+
+		Ok(ConfigChanges {
+			applied,
+			need_restart,
+		})
+	}
+
+	/// Reloads the config and then the appservice registration files on SIGHUP
+	#[cfg(unix)]
+	pub fn start_reload_handler(&self) {
+		tokio::spawn(async {
+			let mut hangup = signal(SignalKind::hangup()).expect("Failed to register SIGHUP signal receiver");
+			while hangup.recv().await.is_some() {
+				match services().globals.reload_config() {
+					Ok(changes) => {
+						info!("Reloaded the config, applied: {:?}", changes.applied);
+						if !changes.need_restart.is_empty() {
+							warn!("Changes to {:?} need a restart to take effect", changes.need_restart);
+						}
+					},
+					Err(e) => error!("Failed to reload the config: {e}"),
+				}
+
+				// The files may have changed even if their paths didn't
+				if let Err(e) = services().appservice.reload_registration_files().await {
+					error!("Failed to reload appservice registration files: {e}");
+				}
+			}
+		});
+	}
+
 	pub fn server_name(&self) -> &ServerName { self.config.server_name.as_ref() }
 
 	pub fn max_request_size(&self) -> u32 { self.config.max_request_size }
 
 	pub fn max_fetch_prev_events(&self) -> u16 { self.config.max_fetch_prev_events }
 
-	pub fn allow_registration(&self) -> bool { self.config.allow_registration }
+	pub fn allow_registration(&self) -> bool { self.reloaded().allow_registration }
+
+	pub fn appservice_registrations(&self) -> Vec<PathBuf> { self.reloaded().appservice_registrations.clone() }
+
+	pub fn allow_guest_registration(&self) -> bool { self.reloaded().allow_guest_registration }
 
-	pub fn allow_guest_registration(&self) -> bool { self.config.allow_guest_registration }
+	pub fn registration_token(&self) -> Option<String> { self.reloaded().registration_token.clone() }
 
 	pub fn allow_encryption(&self) -> bool { self.config.allow_encryption }
 
@@ -229,7 +333,7 @@ impl Service<'_> {
 
 	pub fn allow_check_for_updates(&self) -> bool { self.config.allow_check_for_updates }
 
-	pub fn trusted_servers(&self) -> &[OwnedServerName] { &self.config.trusted_servers }
+	pub fn trusted_servers(&self) -> Vec<OwnedServerName> { self.reloaded().trusted_servers.clone() }
 
 	pub fn query_trusted_key_servers_first(&self) -> bool { self.config.query_trusted_key_servers_first }
 
@@ -245,6 +349,16 @@ impl Service<'_> {
 
 	pub fn turn_ttl(&self) -> u64 { self.config.turn_ttl }
 
+	pub fn openid_token_ttl(&self) -> u64 { self.config.openid_token_ttl }
+
+	pub fn allow_email_pushers(&self) -> bool { self.config.allow_email_pushers }
+
+	pub fn smtp_url(&self) -> Option<&str> { self.config.smtp_url.as_deref() }
+
+	pub fn email_from(&self) -> Option<&str> { self.config.email_from.as_deref() }
+
+	pub fn email_notification_delay(&self) -> u64 { self.config.email_notification_delay }
+
 	pub fn turn_uris(&self) -> &[String] { &self.config.turn_uris }
 
 	pub fn turn_username(&self) -> &String { &self.config.turn_username }
@@ -257,35 +371,51 @@ impl Service<'_> {
 
 	pub fn emergency_password(&self) -> &Option<String> { &self.config.emergency_password }
 
-	pub fn url_preview_domain_contains_allowlist(&self) -> &Vec<String> {
-		&self.config.url_preview_domain_contains_allowlist
+	pub fn url_preview_domain_contains_allowlist(&self) -> Vec<String> {
+		self.reloaded()
+			.url_preview_domain_contains_allowlist
+			.clone()
 	}
 
-	pub fn url_preview_domain_explicit_allowlist(&self) -> &Vec<String> {
-		&self.config.url_preview_domain_explicit_allowlist
+	pub fn url_preview_domain_explicit_allowlist(&self) -> Vec<String> {
+		self.reloaded()
+			.url_preview_domain_explicit_allowlist
+			.clone()
 	}
 
-	pub fn url_preview_url_contains_allowlist(&self) -> &Vec<String> { &self.config.url_preview_url_contains_allowlist }
+	pub fn url_preview_url_contains_allowlist(&self) -> Vec<String> {
+		self.reloaded().url_preview_url_contains_allowlist.clone()
+	}
 
-	pub fn url_preview_max_spider_size(&self) -> usize { self.config.url_preview_max_spider_size }
+	pub fn url_preview_max_spider_size(&self) -> usize { self.reloaded().url_preview_max_spider_size }
 
-	pub fn url_preview_check_root_domain(&self) -> bool { self.config.url_preview_check_root_domain }
+	pub fn url_preview_check_root_domain(&self) -> bool { self.reloaded().url_preview_check_root_domain }
 
-	pub fn forbidden_alias_names(&self) -> &RegexSet { &self.config.forbidden_alias_names }
+	pub fn forbidden_alias_names(&self) -> RegexSet { self.reloaded().forbidden_alias_names.clone() }
 
-	pub fn forbidden_usernames(&self) -> &RegexSet { &self.config.forbidden_usernames }
+	pub fn forbidden_usernames(&self) -> RegexSet { self.reloaded().forbidden_usernames.clone() }
 
 	pub fn allow_local_presence(&self) -> bool { self.config.allow_local_presence }
 
-	pub fn allow_incoming_presence(&self) -> bool { self.config.allow_incoming_presence }
+	pub fn allow_incoming_presence(&self) -> bool { self.reloaded().allow_incoming_presence }
+
+	pub fn allow_outgoing_presence(&self) -> bool { self.reloaded().allow_outgoing_presence }
+
+	pub fn presence_idle_timeout_s(&self) -> u64 { self.reloaded().presence_idle_timeout_s }
 
-	pub fn allow_outgoing_presence(&self) -> bool { self.config.allow_outgoing_presence }
+	pub fn presence_offline_timeout_s(&self) -> u64 { self.reloaded().presence_offline_timeout_s }
 
-	pub fn presence_idle_timeout_s(&self) -> u64 { self.config.presence_idle_timeout_s }
+	pub fn allow_incoming_read_receipts(&self) -> bool { self.reloaded().allow_incoming_read_receipts }
 
-	pub fn presence_offline_timeout_s(&self) -> u64 { self.config.presence_offline_timeout_s }
+	pub fn allow_incoming_typing(&self) -> bool { self.reloaded().allow_incoming_typing }
 
-	pub fn allow_incoming_read_receipts(&self) -> bool { self.config.allow_incoming_read_receipts }
+	pub fn allow_outgoing_typing(&self) -> bool { self.reloaded().allow_outgoing_typing }
+
+	pub fn typing_federation_timeout_s(&self) -> u64 { self.reloaded().typing_federation_timeout_s }
+
+	pub fn typing_client_timeout_min_s(&self) -> u64 { self.reloaded().typing_client_timeout_min_s }
+
+	pub fn typing_client_timeout_max_s(&self) -> u64 { self.reloaded().typing_client_timeout_max_s }
 
 	pub fn rocksdb_log_level(&self) -> &String { &self.config.rocksdb_log_level }
 
@@ -303,7 +433,9 @@ impl Service<'_> {
 
 	pub fn rocksdb_bottommost_compression_level(&self) -> i32 { self.config.rocksdb_bottommost_compression_level }
 
-	pub fn prevent_media_downloads_from(&self) -> &[OwnedServerName] { &self.config.prevent_media_downloads_from }
+	pub fn prevent_media_downloads_from(&self) -> Vec<OwnedServerName> {
+		self.reloaded().prevent_media_downloads_from.clone()
+	}
 
 	pub fn ip_range_denylist(&self) -> &[String] { &self.config.ip_range_denylist }
 
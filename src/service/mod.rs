@@ -58,9 +58,10 @@ impl Services<'_> {
 		db: &'static D, config: &Config,
 	) -> Result<Self> {
 		Ok(Self {
-			appservice: appservice::Service::build(db)?,
+			appservice: appservice::Service::build(db, config)?,
 			pusher: pusher::Service {
 				db,
+				email_digests: Arc::new(StdMutex::new(HashMap::new())),
 			},
 			rooms: rooms::Service {
 				alias: rooms::alias::Service {
@@ -120,6 +121,7 @@ impl Services<'_> {
 						(f64::from(config.stateinfo_cache_capacity) * config.conduit_cache_capacity_modifier) as usize,
 					)),
 				},
+				third_party_invite: rooms::third_party_invite::Service,
 				timeline: rooms::timeline::Service {
 					db,
 					lasttimelinecount_cache: Mutex::new(HashMap::new()),
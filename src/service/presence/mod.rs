@@ -16,7 +16,7 @@ use tokio::{
 };
 use tracing::{debug, error};
 
-use crate::{services, utils, Config, Error, Result};
+use crate::{service::sending::AppserviceEduTarget, services, utils, Config, Error, Result};
 
 /// Represents data required to be kept in order to implement the presence
 /// specification.
@@ -109,7 +109,7 @@ impl Service {
 
 	/// Pings the presence of the given user in the given room, setting the
 	/// specified state.
-	pub fn ping_presence(&self, user_id: &UserId, new_state: &PresenceState) -> Result<()> {
+	pub async fn ping_presence(&self, user_id: &UserId, new_state: &PresenceState) -> Result<()> {
 		const REFRESH_TIMEOUT: u64 = 60 * 25 * 1000;
 
 		let last_presence = self.db.get_presence(user_id)?;
@@ -135,10 +135,11 @@ impl Service {
 		let last_active_ago = UInt::new(0);
 		let currently_active = *new_state == PresenceState::Online;
 		self.set_presence(user_id, new_state, Some(currently_active), last_active_ago, status_msg)
+			.await
 	}
 
 	/// Adds a presence event which will be saved until a new event replaces it.
-	pub fn set_presence(
+	pub async fn set_presence(
 		&self, user_id: &UserId, presence_state: &PresenceState, currently_active: Option<bool>,
 		last_active_ago: Option<UInt>, status_msg: Option<String>,
 	) -> Result<()> {
@@ -147,8 +148,8 @@ impl Service {
 
 		if self.timeout_remote_users || user_id.server_name() == services().globals.server_name() {
 			let timeout = match presence_state {
-				PresenceState::Online => services().globals.config.presence_idle_timeout_s,
-				_ => services().globals.config.presence_offline_timeout_s,
+				PresenceState::Online => services().globals.presence_idle_timeout_s(),
+				_ => services().globals.presence_offline_timeout_s(),
 			};
 
 			self.timer_sender
@@ -159,6 +160,19 @@ impl Service {
 				})?;
 		}
 
+		if let Some(presence) = self.get_presence(user_id)? {
+			if let Err(e) = services()
+				.sending
+				.send_edu_appservices(
+					AppserviceEduTarget::Presence(user_id),
+					serde_json::to_vec(&presence).expect("Serialized PresenceEvent"),
+				)
+				.await
+			{
+				error!("Failed to queue presence of {user_id} for appservices: {e}");
+			}
+		}
+
 		Ok(())
 	}
 
@@ -182,7 +196,7 @@ impl Service {
 				}
 
 				Some(user_id) = presence_timers.next() => {
-					process_presence_timer(&user_id)?;
+					process_presence_timer(&user_id).await?;
 				}
 			}
 		}
@@ -195,9 +209,9 @@ async fn presence_timer(user_id: OwnedUserId, timeout: Duration) -> OwnedUserId
 	user_id
 }
 
-fn process_presence_timer(user_id: &OwnedUserId) -> Result<()> {
-	let idle_timeout = services().globals.config.presence_idle_timeout_s * 1_000;
-	let offline_timeout = services().globals.config.presence_offline_timeout_s * 1_000;
+async fn process_presence_timer(user_id: &OwnedUserId) -> Result<()> {
+	let idle_timeout = services().globals.presence_idle_timeout_s() * 1_000;
+	let offline_timeout = services().globals.presence_offline_timeout_s() * 1_000;
 
 	let mut presence_state = PresenceState::Offline;
 	let mut last_active_ago = None;
@@ -222,7 +236,8 @@ fn process_presence_timer(user_id: &OwnedUserId) -> Result<()> {
 	if let Some(new_state) = new_state {
 		services()
 			.presence
-			.set_presence(user_id, &new_state, Some(false), last_active_ago, status_msg)?;
+			.set_presence(user_id, &new_state, Some(false), last_active_ago, status_msg)
+			.await?;
 	}
 
 	Ok(())
@@ -1,8 +1,9 @@
 use ruma::{
 	api::client::push::{set_pusher, Pusher},
-	UserId,
+	OwnedUserId, UserId,
 };
 
+use super::{PusherStatus, StoredNotification};
 use crate::Result;
 
 pub trait Data: Send + Sync {
@@ -13,4 +14,40 @@ pub trait Data: Send + Sync {
 	fn get_pushers(&self, sender: &UserId) -> Result<Vec<Pusher>>;
 
 	fn get_pushkeys<'a>(&'a self, sender: &UserId) -> Box<dyn Iterator<Item = Result<String>> + 'a>;
+
+	/// Stores a notification of the user for the pdu with the given count.
+	fn add_notification(&self, user_id: &UserId, count: u64, notification: &StoredNotification) -> Result<()>;
+
+	/// Returns the notifications of the user with a count lower than `until`,
+	/// newest first.
+	fn notifications_until<'a>(
+		&'a self, user_id: &UserId, until: u64,
+	) -> Box<dyn Iterator<Item = Result<(u64, StoredNotification)>> + 'a>;
+
+	/// Removes all notifications that were created before `until`
+	/// (milliseconds since the unix epoch).
+	fn remove_notifications_before(&self, until: u64) -> Result<()>;
+
+	/// Returns the token of the unsubscribe link in emails of an email pusher.
+	fn get_unsubscribe_token(&self, sender: &UserId, pushkey: &str) -> Result<Option<String>>;
+
+	fn set_unsubscribe_token(&self, sender: &UserId, pushkey: &str, token: &str) -> Result<()>;
+
+	/// Find out which email pusher an unsubscribe token belongs to.
+	fn find_from_unsubscribe_token(&self, token: &str) -> Result<Option<(OwnedUserId, String)>>;
+
+	/// Remembers that an email with the notifications after `since` is due for
+	/// the email pusher at `due_at` (milliseconds since the unix epoch).
+	fn set_email_digest(&self, sender: &UserId, pushkey: &str, since: u64, due_at: u64) -> Result<()>;
+
+	fn remove_email_digest(&self, sender: &UserId, pushkey: &str) -> Result<()>;
+
+	/// Returns the pending emails: user, pushkey, `since` and `due_at`.
+	#[allow(clippy::type_complexity)]
+	fn email_digests<'a>(&'a self) -> Box<dyn Iterator<Item = Result<(OwnedUserId, String, u64, u64)>> + 'a>;
+
+	/// Returns how delivering notifications to the pusher went recently.
+	fn get_pusher_status(&self, sender: &UserId, pushkey: &str) -> Result<Option<PusherStatus>>;
+
+	fn set_pusher_status(&self, sender: &UserId, pushkey: &str, status: &PusherStatus) -> Result<()>;
 }
@@ -0,0 +1,315 @@
+use std::{collections::BTreeMap, fmt::Write};
+
+use lettre::{
+	message::{
+		header::{HeaderName, HeaderValue},
+		Mailbox, MultiPart,
+	},
+	AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use ruma::{api::client::error::ErrorKind, events::TimelineEventType, OwnedRoomId, UserId};
+use serde::Deserialize;
+use tracing::{debug, warn};
+
+use crate::{services, utils, Error, PduEvent, Result};
+
+/// Most events listed in a single email.
+const MAX_DIGEST_EVENTS: usize = 50;
+
+/// Length of the token in unsubscribe links.
+const UNSUBSCRIBE_TOKEN_LENGTH: usize = 32;
+
+#[derive(Deserialize)]
+struct ExtractBody {
+	body: Option<String>,
+}
+
+/// Sends an email listing the events that notified the user after `since` and
+/// that were not read yet.
+pub(super) async fn send_digest(user_id: &UserId, address: &str, since: u64) -> Result<()> {
+	let (Some(smtp_url), Some(email_from)) = (services().globals.smtp_url(), services().globals.email_from()) else {
+		debug!("Not sending email notification because SMTP is not configured");
+		return Ok(());
+	};
+
+	// The address may have been unbound while the digest was pending
+	if !services().globals.allow_email_pushers() || !services().users.has_email(user_id, address)? {
+		debug!("Not sending email notification because the address is no longer allowed");
+		return Ok(());
+	}
+
+	let mut rooms = BTreeMap::<OwnedRoomId, Vec<PduEvent>>::new();
+	let mut total = 0;
+
+	for result in services().pusher.notifications_until(user_id, u64::MAX) {
+		let (count, notification) = result?;
+		if count <= since {
+			break;
+		}
+
+		if services()
+			.rooms
+			.user
+			.last_notification_read(user_id, &notification.room_id)?
+			> count
+		{
+			continue;
+		}
+
+		let Some(pdu) = services().rooms.timeline.get_pdu(&notification.event_id)? else {
+			continue;
+		};
+
+		rooms.entry(notification.room_id).or_default().push(pdu);
+
+		total += 1;
+		if total == MAX_DIGEST_EVENTS {
+			break;
+		}
+	}
+
+	if total == 0 {
+		debug!("Everything was read before the email notification for {user_id} was due");
+		return Ok(());
+	}
+
+	let server_name = services().globals.server_name();
+	let unsubscribe_link = format!(
+		"{}/_conduwuit/email/unsubscribe?token={}",
+		services()
+			.globals
+			.well_known_client()
+			.as_ref()
+			.map_or_else(|| format!("https://{server_name}"), |url| url.trim_end_matches('/').to_owned()),
+		unsubscribe_token(user_id, address)?
+	);
+
+	let mut plain = format!("You have unread messages on {server_name}:\n");
+	let mut html = format!("<p>You have unread messages on {}:</p>\n", escape(server_name.as_str()));
+
+	for (room_id, pdus) in &rooms {
+		let room_name = services()
+			.rooms
+			.state_accessor
+			.get_name(room_id)?
+			.unwrap_or_else(|| room_id.to_string());
+
+		_ = write!(plain, "\n{room_name}\n");
+		_ = write!(html, "<h3>{}</h3>\n<ul>\n", escape(&room_name));
+
+		// Notifications are newest first, the email reads from top to bottom
+		for pdu in pdus.iter().rev() {
+			let sender = services()
+				.users
+				.displayname(&pdu.sender)?
+				.unwrap_or_else(|| pdu.sender.to_string());
+			let body = event_summary(pdu);
+
+			_ = writeln!(plain, "  {sender}: {body}");
+			_ = writeln!(html, "<li><b>{}</b>: {}</li>", escape(&sender), escape(&body));
+		}
+
+		html.push_str("</ul>\n");
+	}
+
+	_ = write!(plain, "\nTo stop receiving these emails, open {unsubscribe_link}\n");
+	_ = write!(
+		html,
+		"<p><a href=\"{}\">Unsubscribe from these emails</a></p>\n",
+		escape(&unsubscribe_link)
+	);
+
+	let message = build_message(
+		email_from,
+		address,
+		format!("{total} unread messages on {server_name}"),
+		plain,
+		html,
+		&unsubscribe_link,
+	)?;
+
+	send_message(smtp_url, message).await
+}
+
+/// Builds the email, with the headers mail clients use to offer one-click
+/// unsubscribing (RFC 8058).
+fn build_message(
+	from: &str, to: &str, subject: String, plain: String, html: String, unsubscribe_link: &str,
+) -> Result<Message> {
+	Message::builder()
+		.from(
+			from.parse::<Mailbox>()
+				.map_err(|_| Error::bad_config("email_from is not a valid email address."))?,
+		)
+		.to(to
+			.parse::<Mailbox>()
+			.map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid email address."))?)
+		.subject(subject)
+		.raw_header(HeaderValue::new(
+			HeaderName::new_from_ascii_str("List-Unsubscribe"),
+			format!("<{unsubscribe_link}>"),
+		))
+		.raw_header(HeaderValue::new(
+			HeaderName::new_from_ascii_str("List-Unsubscribe-Post"),
+			"List-Unsubscribe=One-Click".to_owned(),
+		))
+		.multipart(MultiPart::alternative_plain_html(plain, html))
+		.map_err(|e| {
+			warn!("Failed to build email notification: {e}");
+			Error::BadServerResponse("Failed to build email notification.")
+		})
+}
+
+async fn send_message(smtp_url: &str, message: Message) -> Result<()> {
+	let transport = AsyncSmtpTransport::<Tokio1Executor>::from_url(smtp_url)
+		.map_err(|_| Error::bad_config("smtp_url is invalid."))?
+		.build();
+
+	transport.send(message).await.map_err(|e| {
+		warn!("Failed to send email notification: {e}");
+		Error::BadServerResponse("Failed to send email notification.")
+	})?;
+
+	Ok(())
+}
+
+/// Returns the token of the unsubscribe link of an email pusher, creating it
+/// on first use.
+fn unsubscribe_token(user_id: &UserId, address: &str) -> Result<String> {
+	if let Some(token) = services()
+		.pusher
+		.db
+		.get_unsubscribe_token(user_id, address)?
+	{
+		return Ok(token);
+	}
+
+	let token = utils::random_string(UNSUBSCRIBE_TOKEN_LENGTH);
+	services()
+		.pusher
+		.db
+		.set_unsubscribe_token(user_id, address, &token)?;
+
+	Ok(token)
+}
+
+/// A single line describing the event, never revealing encrypted content.
+fn event_summary(pdu: &PduEvent) -> String {
+	match pdu.kind {
+		TimelineEventType::RoomEncrypted => "Encrypted message".to_owned(),
+		TimelineEventType::RoomMember => "Membership change".to_owned(),
+		_ => serde_json::from_str::<ExtractBody>(pdu.content.get())
+			.ok()
+			.and_then(|content| content.body)
+			.map_or_else(
+				|| pdu.kind.to_string(),
+				|body| body.lines().next().unwrap_or_default().to_owned(),
+			),
+	}
+}
+
+fn escape(text: &str) -> String {
+	let mut escaped = String::with_capacity(text.len());
+	for c in text.chars() {
+		match c {
+			'&' => escaped.push_str("&amp;"),
+			'<' => escaped.push_str("&lt;"),
+			'>' => escaped.push_str("&gt;"),
+			'"' => escaped.push_str("&quot;"),
+			'\'' => escaped.push_str("&#39;"),
+			_ => escaped.push(c),
+		}
+	}
+	escaped
+}
+
+#[cfg(test)]
+mod tests {
+	use tokio::{
+		io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+		net::TcpListener,
+	};
+
+	use super::{build_message, send_message};
+
+	/// Accepts a single SMTP session and returns the data of the mail that
+	/// was sent in it.
+	async fn fake_smtp_server(listener: TcpListener) -> String {
+		let (stream, _) = listener.accept().await.unwrap();
+		let (reader, mut writer) = stream.into_split();
+		let mut reader = BufReader::new(reader);
+
+		writer.write_all(b"220 localhost ESMTP\r\n").await.unwrap();
+
+		let mut data = String::new();
+		let mut line = String::new();
+		loop {
+			line.clear();
+			if reader.read_line(&mut line).await.unwrap() == 0 {
+				break;
+			}
+
+			let command = line.to_ascii_uppercase();
+			if command.starts_with("DATA") {
+				writer.write_all(b"354 Go ahead\r\n").await.unwrap();
+				loop {
+					line.clear();
+					reader.read_line(&mut line).await.unwrap();
+					if line == ".\r\n" {
+						break;
+					}
+					data.push_str(&line);
+				}
+				writer.write_all(b"250 Queued\r\n").await.unwrap();
+			} else if command.starts_with("QUIT") {
+				writer.write_all(b"221 Bye\r\n").await.unwrap();
+				break;
+			} else {
+				writer.write_all(b"250 OK\r\n").await.unwrap();
+			}
+		}
+
+		data
+	}
+
+	#[tokio::test]
+	async fn sends_message_with_unsubscribe_headers() {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let port = listener.local_addr().unwrap().port();
+		let server = tokio::spawn(fake_smtp_server(listener));
+
+		let message = build_message(
+			"conduwuit@example.com",
+			"alice@example.com",
+			"2 unread messages on example.com".to_owned(),
+			"You have unread messages".to_owned(),
+			"<p>You have unread messages</p>".to_owned(),
+			"https://example.com/_conduwuit/email/unsubscribe?token=abc",
+		)
+		.unwrap();
+
+		send_message(&format!("smtp://127.0.0.1:{port}"), message)
+			.await
+			.unwrap();
+
+		let data = server.await.unwrap();
+		assert!(data.contains("To: alice@example.com"));
+		assert!(data.contains("Subject: 2 unread messages on example.com"));
+		assert!(data.contains("List-Unsubscribe: <https://example.com/_conduwuit/email/unsubscribe?token=abc>"));
+		assert!(data.contains("List-Unsubscribe-Post: List-Unsubscribe=One-Click"));
+		assert!(data.contains("You have unread messages"));
+	}
+
+	#[test]
+	fn rejects_invalid_address() {
+		assert!(build_message(
+			"conduwuit@example.com",
+			"not an address",
+			String::new(),
+			String::new(),
+			String::new(),
+			"https://example.com",
+		)
+		.is_err());
+	}
+}
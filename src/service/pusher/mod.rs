@@ -1,12 +1,23 @@
 mod data;
-use std::{fmt::Debug, mem};
+mod email;
+
+use std::{
+	collections::HashMap,
+	fmt::Debug,
+	mem,
+	sync::{Arc, Mutex},
+	time::Duration,
+};
 
 use bytes::BytesMut;
 pub use data::Data;
 use ipaddress::IPAddress;
 use ruma::{
 	api::{
-		client::push::{set_pusher, Pusher, PusherKind},
+		client::{
+			error::ErrorKind,
+			push::{set_pusher, Pusher, PusherKind},
+		},
 		push_gateway::send_event_notification::{
 			self,
 			v1::{Device, Notification, NotificationCounts, NotificationPriority},
@@ -18,18 +29,97 @@ use ruma::{
 	},
 	push::{Action, PushConditionPowerLevelsCtx, PushConditionRoomCtx, PushFormat, Ruleset, Tweak},
 	serde::Raw,
-	uint, RoomId, UInt, UserId,
+	OwnedEventId, OwnedRoomId, OwnedUserId, RoomId, UInt, UserId,
 };
+use serde::{Deserialize, Serialize};
 use tracing::{debug, info, warn};
 
-use crate::{services, Error, PduEvent, Result};
+use super::rooms::timeline::PduCount;
+use crate::{services, utils, Error, PduEvent, Result};
+
+/// Notifications older than this are no longer returned by `/notifications`.
+const NOTIFICATION_RETENTION: Duration = Duration::from_secs(60 * 60 * 24 * 30);
+
+/// Delay before retrying a push gateway after it failed once, doubled with
+/// every further failure.
+const PUSH_RETRY_INITIAL: Duration = Duration::from_secs(30);
+
+/// Longest delay between retries of a failing push gateway.
+const PUSH_RETRY_MAX: Duration = Duration::from_secs(60 * 60 * 24);
+
+/// How long to wait for a push gateway to answer.
+const PUSH_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How delivering notifications to a pusher went, kept across restarts.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct PusherStatus {
+	/// Failed deliveries since the last successful one.
+	pub failures: u32,
+	/// Milliseconds since the unix epoch.
+	pub last_failure: u64,
+	pub last_error: Option<String>,
+}
+
+impl PusherStatus {
+	/// Returns when the pusher may be tried again, in milliseconds since the
+	/// unix epoch.
+	pub fn retry_at(&self) -> u64 {
+		if self.failures == 0 {
+			return 0;
+		}
+
+		let delay = PUSH_RETRY_INITIAL
+			.saturating_mul(2_u32.saturating_pow(self.failures - 1))
+			.min(PUSH_RETRY_MAX);
+
+		self.last_failure.saturating_add(delay.as_millis() as u64)
+	}
+}
+
+/// An event that notified a user, as returned by `/notifications`.
+#[derive(Serialize, Deserialize)]
+pub struct StoredNotification {
+	pub room_id: OwnedRoomId,
+	pub event_id: OwnedEventId,
+	pub actions: Vec<Action>,
+	pub highlight: bool,
+	#[serde(skip)]
+	pub ts: u64, // Milliseconds since the unix epoch, stored next to the notification
+}
 
 pub struct Service {
 	pub db: &'static dyn Data,
+	/// Email pushers waiting for their digest, with the count of the last
+	/// event that is not part of it.
+	pub email_digests: Arc<Mutex<HashMap<(OwnedUserId, String), u64>>>,
 }
 
 impl Service {
+	/// Adds, replaces or removes a pusher. Email pushers are only accepted for
+	/// addresses bound to the user, otherwise anyone could make the server
+	/// send mail anywhere.
 	pub fn set_pusher(&self, sender: &UserId, pusher: set_pusher::v3::PusherAction) -> Result<()> {
+		if let set_pusher::v3::PusherAction::Post(data) = &pusher {
+			if matches!(data.pusher.kind, PusherKind::Email(_)) {
+				if !services().globals.allow_email_pushers() {
+					return Err(Error::BadRequest(
+						ErrorKind::forbidden(),
+						"Email pushers are not allowed on this server.",
+					));
+				}
+
+				if !services()
+					.users
+					.has_email(sender, &data.pusher.ids.pushkey)?
+				{
+					return Err(Error::BadRequest(
+						ErrorKind::forbidden(),
+						"Email address is not bound to this account.",
+					));
+				}
+			}
+		}
+
 		self.db.set_pusher(sender, pusher)
 	}
 
@@ -43,6 +133,78 @@ impl Service {
 		self.db.get_pushkeys(sender)
 	}
 
+	pub fn get_pusher_status(&self, sender: &UserId, pushkey: &str) -> Result<PusherStatus> {
+		Ok(self
+			.db
+			.get_pusher_status(sender, pushkey)?
+			.unwrap_or_default())
+	}
+
+	/// Returns an error if the push gateway of the pusher failed recently and
+	/// should not be tried again yet.
+	pub fn check_backoff(&self, sender: &UserId, pushkey: &str) -> Result<()> {
+		if utils::millis_since_unix_epoch() < self.get_pusher_status(sender, pushkey)?.retry_at() {
+			return Err(Error::BadServerResponse("Push gateway failed recently, backing off."));
+		}
+
+		Ok(())
+	}
+
+	/// Remembers the outcome of delivering a notification to the pusher.
+	pub fn record_delivery(&self, sender: &UserId, pushkey: &str, result: &Result<()>) -> Result<()> {
+		// The pusher may have been removed while the notification was sent, e.g.
+		// because the gateway rejected the pushkey
+		if self.get_pusher(sender, pushkey)?.is_none() {
+			return Ok(());
+		}
+
+		let mut status = self.get_pusher_status(sender, pushkey)?;
+		match result {
+			Ok(()) if status.failures == 0 => return Ok(()),
+			Ok(()) => status.failures = 0,
+			Err(e) => {
+				status.failures = status.failures.saturating_add(1);
+				status.last_failure = utils::millis_since_unix_epoch();
+				status.last_error = Some(e.to_string());
+			},
+		}
+
+		self.db.set_pusher_status(sender, pushkey, &status)
+	}
+
+	/// Remembers that the pdu with the given count notified the user.
+	pub fn add_notification(&self, user_id: &UserId, count: u64, pdu: &PduEvent, actions: &[Action]) -> Result<()> {
+		let highlight = actions
+			.iter()
+			.any(|action| matches!(action, Action::SetTweak(Tweak::Highlight(true))));
+
+		self.db.add_notification(
+			user_id,
+			count,
+			&StoredNotification {
+				room_id: pdu.room_id.clone(),
+				event_id: pdu.event_id.as_ref().to_owned(),
+				actions: actions.to_vec(),
+				highlight,
+				ts: utils::millis_since_unix_epoch(),
+			},
+		)
+	}
+
+	/// Returns the notifications of the user older than `until`, newest first.
+	pub fn notifications_until<'a>(
+		&'a self, user_id: &UserId, until: u64,
+	) -> impl Iterator<Item = Result<(u64, StoredNotification)>> + 'a {
+		self.db.notifications_until(user_id, until)
+	}
+
+	/// Forgets notifications older than 30 days.
+	pub fn remove_old_notifications(&self) -> Result<()> {
+		self.db.remove_notifications_before(
+			utils::millis_since_unix_epoch().saturating_sub(NOTIFICATION_RETENTION.as_millis() as u64),
+		)
+	}
+
 	#[tracing::instrument(skip(self, destination, request))]
 	pub async fn send_request<T>(&self, destination: &str, request: T) -> Result<T::IncomingResponse>
 	where
@@ -58,10 +220,10 @@ impl Service {
 			})?
 			.map(BytesMut::freeze);
 
-		let reqwest_request = reqwest::Request::try_from(http_request)?;
+		let mut reqwest_request = reqwest::Request::try_from(http_request)?;
 
-		// TODO: we could keep this very short and let expo backoff do it's thing...
-		//*reqwest_request.timeout_mut() = Some(Duration::from_secs(5));
+		// Kept short, failing pushers are retried with exponential backoff
+		*reqwest_request.timeout_mut() = Some(PUSH_REQUEST_TIMEOUT);
 
 		let url = reqwest_request.url().clone();
 
@@ -155,9 +317,9 @@ impl Service {
 		}
 	}
 
-	#[tracing::instrument(skip(self, user, unread, pusher, ruleset, pdu))]
+	#[tracing::instrument(skip(self, user, counts, pusher, ruleset, pdu))]
 	pub async fn send_push_notice(
-		&self, user: &UserId, unread: UInt, pusher: &Pusher, ruleset: Ruleset, pdu: &PduEvent,
+		&self, user: &UserId, counts: NotificationCounts, pusher: &Pusher, ruleset: Ruleset, pdu: &PduEvent,
 	) -> Result<()> {
 		let mut notify = None;
 		let mut tweaks = Vec::new();
@@ -193,7 +355,7 @@ impl Service {
 		}
 
 		if notify == Some(true) {
-			self.send_notice(unread, pusher, tweaks, pdu).await?;
+			self.send_notice(user, counts, pusher, tweaks, pdu).await?;
 		}
 		// Else the event triggered no actions
 
@@ -231,9 +393,10 @@ impl Service {
 		Ok(ruleset.get_actions(pdu, &ctx))
 	}
 
-	#[tracing::instrument(skip(self, unread, pusher, tweaks, event))]
-	async fn send_notice(&self, unread: UInt, pusher: &Pusher, tweaks: Vec<Tweak>, event: &PduEvent) -> Result<()> {
-		// TODO: email
+	#[tracing::instrument(skip(self, user, counts, pusher, tweaks, event))]
+	async fn send_notice(
+		&self, user: &UserId, counts: NotificationCounts, pusher: &Pusher, tweaks: Vec<Tweak>, event: &PduEvent,
+	) -> Result<()> {
 		match &pusher.kind {
 			PusherKind::Http(http) => {
 				// TODO:
@@ -258,8 +421,7 @@ impl Service {
 				notifi.prio = NotificationPriority::Low;
 				notifi.event_id = Some((*event.event_id).to_owned());
 				notifi.room_id = Some((*event.room_id).to_owned());
-				// TODO: missed calls
-				notifi.counts = NotificationCounts::new(unread, uint!(0));
+				notifi.counts = counts;
 
 				if event.kind == TimelineEventType::RoomEncrypted
 					|| tweaks
@@ -269,10 +431,7 @@ impl Service {
 					notifi.prio = NotificationPriority::High;
 				}
 
-				if event_id_only {
-					self.send_request(&http.url, send_event_notification::v1::Request::new(notifi))
-						.await?;
-				} else {
+				if !event_id_only {
 					notifi.sender = Some(event.sender.clone());
 					notifi.event_type = Some(event.kind.clone());
 					notifi.content = serde_json::value::to_raw_value(&event.content).ok();
@@ -284,16 +443,128 @@ impl Service {
 					notifi.sender_display_name = services().users.displayname(&event.sender)?;
 
 					notifi.room_name = services().rooms.state_accessor.get_name(&event.room_id)?;
+				}
+
+				let response = self
+					.send_request(&http.url, send_event_notification::v1::Request::new(notifi))
+					.await?;
 
-					self.send_request(&http.url, send_event_notification::v1::Request::new(notifi))
-						.await?;
+				// The gateway no longer knows this pushkey, so it will never be delivered again
+				if response.rejected.contains(&pusher.ids.pushkey) {
+					info!("Push gateway rejected pushkey of {user}, removing the pusher");
+					self.set_pusher(user, set_pusher::v3::PusherAction::Delete(pusher.ids.clone()))?;
 				}
 
 				Ok(())
 			},
-			// TODO: Handle email
-			//PusherKind::Email(_) => Ok(()),
+			PusherKind::Email(_) => self.schedule_email_digest(user, &pusher.ids.pushkey, event),
 			_ => Ok(()),
 		}
 	}
+
+	/// Sends an email with all unread notifications once the configured delay
+	/// passed, unless one is already scheduled for this pusher.
+	fn schedule_email_digest(&self, user: &UserId, address: &str, event: &PduEvent) -> Result<()> {
+		if services().globals.smtp_url().is_none() {
+			debug!("Ignoring email pusher of {user} because SMTP is not configured");
+			return Ok(());
+		}
+
+		// Pushers added before email pushers were restricted, or whose address was
+		// unbound since
+		if !services().globals.allow_email_pushers() || !services().users.has_email(user, address)? {
+			debug!("Ignoring email pusher of {user} because its address is not allowed");
+			return Ok(());
+		}
+
+		let Some(PduCount::Normal(count)) = services().rooms.timeline.get_pdu_count(&event.event_id)? else {
+			return Ok(());
+		};
+
+		let key = (user.to_owned(), address.to_owned());
+		let since = count.saturating_sub(1);
+		let due_at = utils::millis_since_unix_epoch().saturating_add(
+			services()
+				.globals
+				.email_notification_delay()
+				.saturating_mul(1000),
+		);
+		{
+			let mut email_digests = self.email_digests.lock().unwrap();
+			if email_digests.contains_key(&key) {
+				return Ok(());
+			}
+			// Persisted so the email is still sent after a restart
+			self.db.set_email_digest(user, address, since, due_at)?;
+			email_digests.insert(key.clone(), since);
+		}
+
+		self.spawn_email_digest(key, due_at);
+
+		Ok(())
+	}
+
+	/// Schedules the emails that were pending when the server stopped.
+	pub fn start_email_digests(&self) {
+		for result in self.db.email_digests() {
+			let (user, address, since, due_at) = match result {
+				Ok(digest) => digest,
+				Err(e) => {
+					warn!("Failed to load pending email notification: {e}");
+					continue;
+				},
+			};
+
+			let key = (user, address);
+			self.email_digests
+				.lock()
+				.unwrap()
+				.insert(key.clone(), since);
+			self.spawn_email_digest(key, due_at);
+		}
+	}
+
+	fn spawn_email_digest(&self, key: (OwnedUserId, String), due_at: u64) {
+		let email_digests = Arc::clone(&self.email_digests);
+		tokio::spawn(async move {
+			let delay = due_at.saturating_sub(utils::millis_since_unix_epoch());
+			tokio::time::sleep(Duration::from_millis(delay)).await;
+
+			let Some(since) = email_digests.lock().unwrap().remove(&key) else {
+				return;
+			};
+
+			let (user, address) = key;
+			if let Err(e) = services().pusher.db.remove_email_digest(&user, &address) {
+				warn!("Failed to remove pending email notification of {user}: {e}");
+			}
+			// The pusher was removed, e.g. by unsubscribing, while the email was pending
+			if !matches!(services().pusher.get_pusher(&user, &address), Ok(Some(_))) {
+				return;
+			}
+			if let Err(e) = email::send_digest(&user, &address, since).await {
+				warn!("Failed to send email notification to {user}: {e}");
+			}
+		});
+	}
+
+	/// Returns the user and address of the email pusher an unsubscribe token
+	/// belongs to.
+	pub fn find_from_unsubscribe_token(&self, token: &str) -> Result<Option<(OwnedUserId, String)>> {
+		self.db.find_from_unsubscribe_token(token)
+	}
+
+	/// Removes the email pusher an unsubscribe link was sent for. Returns the
+	/// user it belonged to.
+	pub fn unsubscribe_email(&self, token: &str) -> Result<Option<OwnedUserId>> {
+		let Some((user, address)) = self.db.find_from_unsubscribe_token(token)? else {
+			return Ok(None);
+		};
+
+		if let Some(pusher) = self.get_pusher(&user, &address)? {
+			self.set_pusher(&user, set_pusher::v3::PusherAction::Delete(pusher.ids))?;
+		}
+
+		Ok(Some(user))
+	}
 }
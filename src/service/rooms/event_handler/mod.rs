@@ -414,7 +414,10 @@ impl Service {
 			if !state_res::event_auth::auth_check(
 				&room_version,
 				&incoming_pdu,
-				None::<PduEvent>, // TODO: third party invite
+				services()
+					.rooms
+					.third_party_invite
+					.auth_event(&incoming_pdu, |k, s| auth_events.get(&(k.to_string().into(), s.to_owned()))),
 				|k, s| auth_events.get(&(k.to_string().into(), s.to_owned())),
 			)
 			.map_err(|_e| Error::BadRequest(ErrorKind::InvalidParam, "Auth check failed"))?
@@ -704,20 +707,24 @@ impl Service {
 
 		debug!("Starting auth check");
 		// 11. Check the auth of the event passes based on the state of the event
+		let state_fetch = |k: &StateEventType, s: &str| {
+			services()
+				.rooms
+				.short
+				.get_shortstatekey(&k.to_string().into(), s)
+				.ok()
+				.flatten()
+				.and_then(|shortstatekey| state_at_incoming_event.get(&shortstatekey))
+				.and_then(|event_id| services().rooms.timeline.get_pdu(event_id).ok().flatten())
+		};
 		let check_result = state_res::event_auth::auth_check(
 			&room_version,
 			&incoming_pdu,
-			None::<PduEvent>, // TODO: third party invite
-			|k, s| {
-				services()
-					.rooms
-					.short
-					.get_shortstatekey(&k.to_string().into(), s)
-					.ok()
-					.flatten()
-					.and_then(|shortstatekey| state_at_incoming_event.get(&shortstatekey))
-					.and_then(|event_id| services().rooms.timeline.get_pdu(event_id).ok().flatten())
-			},
+			services()
+				.rooms
+				.third_party_invite
+				.auth_event(&incoming_pdu, state_fetch),
+			state_fetch,
 		)
 		.map_err(|_e| Error::BadRequest(ErrorKind::InvalidParam, "Auth check failed."))?;
 
@@ -735,9 +742,15 @@ impl Service {
 			&incoming_pdu.content,
 		)?;
 
-		let soft_fail = !state_res::event_auth::auth_check(&room_version, &incoming_pdu, None::<PduEvent>, |k, s| {
-			auth_events.get(&(k.clone(), s.to_owned()))
-		})
+		let soft_fail = !state_res::event_auth::auth_check(
+			&room_version,
+			&incoming_pdu,
+			services()
+				.rooms
+				.third_party_invite
+				.auth_event(&incoming_pdu, |k, s| auth_events.get(&(k.clone(), s.to_owned()))),
+			|k, s| auth_events.get(&(k.clone(), s.to_owned())),
+		)
 		.map_err(|_e| Error::BadRequest(ErrorKind::InvalidParam, "Auth check failed."))?;
 
 		// 13. Use state resolution to find new room state
@@ -1443,7 +1456,7 @@ impl Service {
 		&self, mut servers: BTreeMap<OwnedServerName, BTreeMap<OwnedServerSigningKeyId, QueryCriteria>>,
 		pub_key_map: &RwLock<BTreeMap<String, BTreeMap<String, Base64>>>,
 	) -> Result<()> {
-		for server in services().globals.trusted_servers() {
+		for server in &services().globals.trusted_servers() {
 			info!("Asking batch signing keys from trusted server {}", server);
 			match services()
 				.sending
@@ -1732,7 +1745,7 @@ impl Service {
 				 keys"
 			);
 
-			for server in services().globals.trusted_servers() {
+			for server in &services().globals.trusted_servers() {
 				debug!("Asking notary server {server} for {origin}'s signing key");
 				if let Some(server_keys) = services()
 					.sending
@@ -1839,7 +1852,7 @@ impl Service {
 				}
 			}
 
-			for server in services().globals.trusted_servers() {
+			for server in &services().globals.trusted_servers() {
 				debug!("Asking notary server {server} for {origin}'s signing key");
 				if let Some(server_keys) = services()
 					.sending
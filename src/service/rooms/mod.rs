@@ -14,6 +14,7 @@ pub mod state;
 pub mod state_accessor;
 pub mod state_cache;
 pub mod state_compressor;
+pub mod third_party_invite;
 pub mod threads;
 pub mod timeline;
 pub mod typing;
@@ -56,6 +57,7 @@ pub struct Service {
 	pub state_accessor: state_accessor::Service,
 	pub state_cache: state_cache::Service,
 	pub state_compressor: state_compressor::Service,
+	pub third_party_invite: third_party_invite::Service,
 	pub timeline: timeline::Service,
 	pub threads: threads::Service,
 	pub typing: typing::Service,
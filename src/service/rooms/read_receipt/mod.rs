@@ -2,8 +2,9 @@ mod data;
 
 pub use data::Data;
 use ruma::{events::receipt::ReceiptEvent, serde::Raw, OwnedUserId, RoomId, UserId};
+use tracing::warn;
 
-use crate::{services, Result};
+use crate::{service::sending::AppserviceEduTarget, services, Result};
 
 pub struct Service {
 	pub db: &'static dyn Data,
@@ -11,9 +12,18 @@ pub struct Service {
 
 impl Service {
 	/// Replaces the previous read receipt.
-	pub fn readreceipt_update(&self, user_id: &UserId, room_id: &RoomId, event: ReceiptEvent) -> Result<()> {
+	pub async fn readreceipt_update(&self, user_id: &UserId, room_id: &RoomId, event: ReceiptEvent) -> Result<()> {
+		let serialized = serde_json::to_vec(&event).expect("Serialized ReceiptEvent");
+
 		self.db.readreceipt_update(user_id, room_id, event)?;
 		services().sending.flush_room(room_id)?;
+		if let Err(e) = services()
+			.sending
+			.send_edu_appservices(AppserviceEduTarget::Room(room_id), serialized)
+			.await
+		{
+			warn!("Failed to queue read receipt in {room_id} for appservices: {e}");
+		}
 
 		Ok(())
 	}
@@ -1,11 +1,29 @@
-use ruma::RoomId;
-
+use super::Posting;
 use crate::Result;
 
-type SearchPdusResult<'a> = Result<Option<(Box<dyn Iterator<Item = Vec<u8>> + 'a>, Vec<String>)>>;
-
 pub trait Data: Send + Sync {
-	fn index_pdu(&self, shortroomid: u64, pdu_id: &[u8], message_body: &str) -> Result<()>;
+	/// Adds a pdu to the index. `tokens` maps each token to the number of
+	/// times it appears in the message, `length` is the total number of
+	/// tokens.
+	fn index_pdu(&self, shortroomid: u64, pdu_id: &[u8], tokens: &[(String, u32)], length: u32) -> Result<()>;
+
+	/// Removes a pdu from the index. `tokens` and `length` have to be the ones
+	/// it was indexed with.
+	fn deindex_pdu(&self, shortroomid: u64, pdu_id: &[u8], tokens: &[String], length: u32) -> Result<()>;
+
+	/// Returns all pdus in the room containing the token, or any token
+	/// starting with it if `prefix` is set.
+	fn postings<'a>(&'a self, shortroomid: u64, token: &str, prefix: bool) -> Box<dyn Iterator<Item = Posting> + 'a>;
+
+	/// Returns the number of indexed pdus in the room and the sum of their
+	/// lengths.
+	fn room_stats(&self, shortroomid: u64) -> Result<(u64, u64)>;
+
+	/// Removes every index entry of the room.
+	fn clear_room(&self, shortroomid: u64) -> Result<()>;
+
+	/// Whether a migration left the index of every room to be rebuilt.
+	fn rebuild_pending(&self) -> Result<bool>;
 
-	fn search_pdus<'a>(&'a self, room_id: &RoomId, search_string: &str) -> SearchPdusResult<'a>;
+	fn set_rebuild_pending(&self, pending: bool) -> Result<()>;
 }
@@ -1,9 +1,45 @@
 mod data;
+mod tokenizer;
+
+use std::collections::{BTreeMap, HashMap};
 
 pub use data::Data;
-use ruma::RoomId;
+use ruma::{events::TimelineEventType, user_id, RoomId};
+use serde::Deserialize;
+use tokenizer::Term;
+
+use super::timeline::PduCount;
+use crate::{database::key_value::rooms::timeline::pdu_count, services, Result};
+
+/// BM25 term frequency saturation.
+const K1: f64 = 1.2;
+/// BM25 document length normalization.
+const B: f64 = 0.75;
+/// Postings read per search term, so that very common words or short
+/// prefixes can't make a search load the whole room.
+const MAX_POSTINGS_PER_TERM: usize = 10_000;
+
+/// A pdu containing a token, as stored in the index.
+pub struct Posting {
+	pub pdu_id: Vec<u8>,
+	/// How often the token appears in the message.
+	pub frequency: u32,
+	/// Number of tokens in the message, 0 if unknown.
+	pub length: u32,
+}
+
+/// A pdu matching a search query.
+pub struct SearchHit {
+	pub pdu_id: Vec<u8>,
+	pub count: PduCount,
+	/// BM25 score of the pdu for the query, higher is more relevant.
+	pub rank: f64,
+}
 
-use crate::Result;
+#[derive(Deserialize)]
+struct ExtractBody {
+	body: Option<String>,
+}
 
 pub struct Service {
 	pub db: &'static dyn Data,
@@ -12,13 +48,205 @@ pub struct Service {
 impl Service {
 	#[tracing::instrument(skip(self))]
 	pub fn index_pdu(&self, shortroomid: u64, pdu_id: &[u8], message_body: &str) -> Result<()> {
-		self.db.index_pdu(shortroomid, pdu_id, message_body)
+		let tokens = tokenizer::tokenize(message_body);
+		if tokens.is_empty() {
+			return Ok(());
+		}
+
+		let length = u32::try_from(tokens.len()).unwrap_or(u32::MAX);
+		let mut frequencies = BTreeMap::<String, u32>::new();
+		for token in tokens {
+			*frequencies.entry(token).or_default() += 1;
+		}
+
+		self.db
+			.index_pdu(shortroomid, pdu_id, &frequencies.into_iter().collect::<Vec<_>>(), length)
+	}
+
+	/// Removes a pdu from the index, e.g. because it was redacted.
+	#[tracing::instrument(skip(self))]
+	pub fn deindex_pdu(&self, shortroomid: u64, pdu_id: &[u8], message_body: &str) -> Result<()> {
+		let mut tokens = tokenizer::tokenize(message_body);
+		if tokens.is_empty() {
+			return Ok(());
+		}
+
+		let length = u32::try_from(tokens.len()).unwrap_or(u32::MAX);
+		tokens.sort_unstable();
+		tokens.dedup();
+
+		self.db.deindex_pdu(shortroomid, pdu_id, &tokens, length)
+	}
+
+	/// Returns all pdus in the room matching every term of the search query,
+	/// ranked with BM25, and the strings clients should highlight.
+	#[tracing::instrument(skip(self))]
+	pub fn search_pdus(&self, room_id: &RoomId, search_string: &str) -> Result<Option<(Vec<SearchHit>, Vec<String>)>> {
+		let Some(shortroomid) = services().rooms.short.get_shortroomid(room_id)? else {
+			return Ok(None);
+		};
+
+		let (terms, highlights) = tokenizer::parse_query(search_string);
+
+		let mut phrases = Vec::new();
+		let mut lookups = Vec::new();
+		for term in terms {
+			match term {
+				Term::Word(word) => lookups.push((word, false)),
+				Term::Prefix(prefix) => lookups.push((prefix, true)),
+				Term::Phrase(tokens) => {
+					lookups.extend(tokens.iter().cloned().map(|token| (token, false)));
+					phrases.push(tokens);
+				},
+			}
+		}
+
+		if lookups.is_empty() {
+			return Ok(None);
+		}
+
+		let (indexed, total_length) = self.db.room_stats(shortroomid)?;
+		let indexed = indexed.max(1) as f64;
+		let average_length = (total_length as f64 / indexed).max(1.0);
+
+		// pdu id => (term frequency, length) for every lookup
+		let mut matches: Option<HashMap<Vec<u8>, Vec<(u32, u32)>>> = None;
+		let mut idfs = Vec::with_capacity(lookups.len());
+
+		for (token, prefix) in &lookups {
+			let mut postings = HashMap::<Vec<u8>, (u32, u32)>::new();
+			let mut found = 0_usize;
+			for posting in self
+				.db
+				.postings(shortroomid, token, *prefix)
+				.take(MAX_POSTINGS_PER_TERM)
+			{
+				found = found.saturating_add(1);
+
+				// Only pdus matching every previous term can still be hits
+				if matches
+					.as_ref()
+					.is_some_and(|matches| !matches.contains_key(&posting.pdu_id))
+				{
+					continue;
+				}
+
+				let entry = postings.entry(posting.pdu_id).or_default();
+				entry.0 = entry.0.saturating_add(posting.frequency);
+				entry.1 = posting.length;
+			}
+
+			let found = found as f64;
+			idfs.push(((indexed - found + 0.5) / (found + 0.5) + 1.0).ln());
+
+			matches = Some(match matches {
+				None => postings
+					.into_iter()
+					.map(|(pdu_id, posting)| (pdu_id, vec![posting]))
+					.collect(),
+				Some(mut matches) => {
+					matches.retain(|pdu_id, found| match postings.get(pdu_id) {
+						Some(posting) => {
+							found.push(*posting);
+							true
+						},
+						None => false,
+					});
+					matches
+				},
+			});
+		}
+
+		let mut hits = Vec::new();
+		for (pdu_id, postings) in matches.unwrap_or_default() {
+			let Ok(count) = pdu_count(&pdu_id) else {
+				continue;
+			};
+
+			if !phrases.is_empty() && !self.contains_phrases(&pdu_id, &phrases)? {
+				continue;
+			}
+
+			let rank = postings
+				.iter()
+				.zip(&idfs)
+				.map(|(&(frequency, length), idf)| {
+					let frequency = f64::from(frequency);
+					let length = if length == 0 {
+						average_length
+					} else {
+						f64::from(length)
+					};
+
+					idf * frequency * (K1 + 1.0) / (frequency + K1 * (1.0 - B + B * length / average_length))
+				})
+				.sum();
+
+			hits.push(SearchHit {
+				pdu_id,
+				count,
+				rank,
+			});
+		}
+
+		Ok(Some((hits, highlights)))
 	}
 
+	/// Whether the index of every room still has to be rebuilt after a
+	/// migration.
+	pub fn rebuild_pending(&self) -> Result<bool> { self.db.rebuild_pending() }
+
+	pub fn set_rebuild_pending(&self, pending: bool) -> Result<()> { self.db.set_rebuild_pending(pending) }
+
+	/// Rebuilds the index of a room from its timeline.
 	#[tracing::instrument(skip(self))]
-	pub fn search_pdus<'a>(
-		&'a self, room_id: &RoomId, search_string: &str,
-	) -> Result<Option<(impl Iterator<Item = Vec<u8>> + 'a, Vec<String>)>> {
-		self.db.search_pdus(room_id, search_string)
+	pub fn reindex_room(&self, room_id: &RoomId) -> Result<()> {
+		let Some(shortroomid) = services().rooms.short.get_shortroomid(room_id)? else {
+			return Ok(());
+		};
+
+		self.db.clear_room(shortroomid)?;
+
+		for result in services()
+			.rooms
+			.timeline
+			.all_pdus(user_id!("@doesntmatter:conduit.rs"), room_id)?
+		{
+			let Ok((_, pdu)) = result else {
+				continue;
+			};
+
+			if pdu.kind != TimelineEventType::RoomMessage {
+				continue;
+			}
+
+			let Ok(ExtractBody {
+				body: Some(body),
+			}) = serde_json::from_str(pdu.content.get())
+			else {
+				continue;
+			};
+
+			if let Some(pdu_id) = services().rooms.timeline.get_pdu_id(&pdu.event_id)? {
+				self.index_pdu(shortroomid, &pdu_id, &body)?;
+			}
+		}
+
+		Ok(())
+	}
+
+	fn contains_phrases(&self, pdu_id: &[u8], phrases: &[Vec<String>]) -> Result<bool> {
+		let Some(pdu) = services().rooms.timeline.get_pdu_from_id(pdu_id)? else {
+			return Ok(false);
+		};
+
+		let body = serde_json::from_str::<ExtractBody>(pdu.content.get())
+			.ok()
+			.and_then(|content| content.body)
+			.unwrap_or_default();
+
+		Ok(phrases
+			.iter()
+			.all(|phrase| tokenizer::contains_phrase(&body, phrase)))
 	}
 }
@@ -0,0 +1,210 @@
+use std::mem;
+
+/// Longest token that is indexed, in bytes.
+const MAX_TOKEN_LENGTH: usize = 50;
+
+/// A single part of a parsed search query. All terms of a query have to match.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Term {
+	/// Matches the exact token.
+	Word(String),
+	/// Matches every token starting with this one (`foo*`).
+	Prefix(String),
+	/// Matches the tokens only if they follow each other in the message
+	/// (`"foo bar"`).
+	Phrase(Vec<String>),
+}
+
+/// Splits a message body into the lowercase tokens that are stored in the
+/// search index.
+///
+/// Words are runs of alphanumeric characters. Scripts that don't separate
+/// words with spaces (Han, Kana, Hangul) are split into overlapping bigrams
+/// instead, followed by the last character of the run on its own, so that
+/// every character starts at least one token and prefix lookups can find
+/// single characters.
+pub fn tokenize(text: &str) -> Vec<String> { split(text, true) }
+
+/// Parses a search query into its terms and the strings clients should
+/// highlight in the results.
+pub fn parse_query(query: &str) -> (Vec<Term>, Vec<String>) {
+	let mut terms = Vec::new();
+	let mut highlights = Vec::new();
+
+	// Every odd part was inside quotes, an unterminated quote runs until the end
+	for (i, part) in query.split('"').enumerate() {
+		if i % 2 == 1 {
+			let tokens = split(part, false);
+			match tokens.len() {
+				0 => continue,
+				1 => terms.extend(tokens.into_iter().map(|token| word_or_prefix(token, false))),
+				_ => terms.push(Term::Phrase(tokens)),
+			}
+			highlights.push(part.trim().to_lowercase());
+			continue;
+		}
+
+		for chunk in part.split_whitespace() {
+			let tokens = split(chunk, false);
+			let last = tokens.len().saturating_sub(1);
+			for (j, token) in tokens.into_iter().enumerate() {
+				highlights.push(token.clone());
+				terms.push(word_or_prefix(token, j == last && chunk.ends_with('*')));
+			}
+		}
+	}
+
+	highlights.sort_unstable();
+	highlights.dedup();
+
+	(terms, highlights)
+}
+
+/// Returns true if `needle` appears in `haystack` as consecutive tokens.
+pub fn contains_phrase(haystack: &str, needle: &[String]) -> bool {
+	split(haystack, false)
+		.windows(needle.len())
+		.any(|window| window == needle)
+}
+
+fn word_or_prefix(token: String, prefix: bool) -> Term {
+	// A lone ideograph is never indexed as a whole token, only as the start of one
+	let mut chars = token.chars();
+	let single_cjk = chars.next().is_some_and(is_cjk) && chars.next().is_none();
+
+	if prefix || single_cjk {
+		Term::Prefix(token)
+	} else {
+		Term::Word(token)
+	}
+}
+
+fn split(text: &str, index: bool) -> Vec<String> {
+	let mut tokens = Vec::new();
+	let mut word = String::new();
+	let mut run = Vec::new();
+
+	for c in text.chars() {
+		if is_cjk(c) {
+			push_word(&mut word, &mut tokens);
+			run.push(c);
+		} else if c.is_alphanumeric() {
+			push_run(&mut run, &mut tokens, index);
+			word.extend(c.to_lowercase());
+		} else {
+			push_word(&mut word, &mut tokens);
+			push_run(&mut run, &mut tokens, index);
+		}
+	}
+
+	push_word(&mut word, &mut tokens);
+	push_run(&mut run, &mut tokens, index);
+
+	tokens
+}
+
+fn push_word(word: &mut String, tokens: &mut Vec<String>) {
+	let word = mem::take(word);
+	if !word.is_empty() && word.len() <= MAX_TOKEN_LENGTH {
+		tokens.push(word);
+	}
+}
+
+fn push_run(run: &mut Vec<char>, tokens: &mut Vec<String>, index: bool) {
+	match run.len() {
+		0 => return,
+		1 => tokens.push(run[0].to_string()),
+		_ => {
+			tokens.extend(run.windows(2).map(|pair| pair.iter().collect()));
+			if index {
+				tokens.push(run[run.len() - 1].to_string());
+			}
+		},
+	}
+	run.clear();
+}
+
+fn is_cjk(c: char) -> bool {
+	matches!(c,
+		'\u{1100}'..='\u{11FF}' // Hangul Jamo
+		| '\u{3040}'..='\u{30FF}' // Hiragana and Katakana
+		| '\u{3130}'..='\u{318F}' // Hangul Compatibility Jamo
+		| '\u{3400}'..='\u{4DBF}' // CJK Unified Ideographs Extension A
+		| '\u{4E00}'..='\u{9FFF}' // CJK Unified Ideographs
+		| '\u{AC00}'..='\u{D7AF}' // Hangul Syllables
+		| '\u{F900}'..='\u{FAFF}' // CJK Compatibility Ideographs
+		| '\u{20000}'..='\u{2FA1F}' // Supplementary Ideographic Plane
+	)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{contains_phrase, parse_query, tokenize, Term};
+
+	fn strings(tokens: &[&str]) -> Vec<String> { tokens.iter().map(ToString::to_string).collect() }
+
+	#[test]
+	fn tokenize_words() {
+		assert_eq!(tokenize("Hello, World! Héllo"), strings(&["hello", "world", "héllo"]));
+		assert_eq!(tokenize("  ...  "), Vec::<String>::new());
+		assert_eq!(tokenize(&"a".repeat(51)), Vec::<String>::new());
+	}
+
+	#[test]
+	fn tokenize_cjk_bigrams() {
+		assert_eq!(tokenize("東京タワー"), strings(&["東京", "京タ", "タワ", "ワー", "ー"]));
+		assert_eq!(tokenize("abc東京def"), strings(&["abc", "東京", "京", "def"]));
+		assert_eq!(tokenize("猫"), strings(&["猫"]));
+	}
+
+	#[test]
+	fn parse_words_and_prefixes() {
+		let (terms, highlights) = parse_query("Foo ba*");
+		assert_eq!(terms, vec![Term::Word("foo".to_owned()), Term::Prefix("ba".to_owned())]);
+		assert_eq!(highlights, strings(&["ba", "foo"]));
+	}
+
+	#[test]
+	fn parse_phrases() {
+		let (terms, highlights) = parse_query("\"Hello World\" bar");
+		assert_eq!(
+			terms,
+			vec![Term::Phrase(strings(&["hello", "world"])), Term::Word("bar".to_owned())]
+		);
+		assert_eq!(highlights, strings(&["bar", "hello world"]));
+
+		// A quoted single word is just a word, an unterminated quote runs until the end
+		assert_eq!(parse_query("\"foo\"").0, vec![Term::Word("foo".to_owned())]);
+		assert_eq!(parse_query("\"foo bar").0, vec![Term::Phrase(strings(&["foo", "bar"]))]);
+	}
+
+	#[test]
+	fn parse_cjk() {
+		assert_eq!(
+			parse_query("東京タワー").0,
+			vec![
+				Term::Word("東京".to_owned()),
+				Term::Word("京タ".to_owned()),
+				Term::Word("タワ".to_owned()),
+				Term::Word("ワー".to_owned()),
+			]
+		);
+
+		// A single ideograph only matches as the start of an indexed bigram
+		assert_eq!(parse_query("猫").0, vec![Term::Prefix("猫".to_owned())]);
+	}
+
+	#[test]
+	fn phrases_have_to_be_consecutive() {
+		let phrase = strings(&["hello", "world"]);
+		assert!(contains_phrase("I said: Hello world!", &phrase));
+		assert!(!contains_phrase("world, hello", &phrase));
+		assert!(!contains_phrase("hello there world", &phrase));
+
+		let Term::Phrase(phrase) = parse_query("\"東京タワー\"").0.remove(0) else {
+			panic!("quoted CJK words are a phrase");
+		};
+		assert!(contains_phrase("東京タワーに行く", &phrase));
+		assert!(!contains_phrase("京都タワー", &phrase));
+	}
+}
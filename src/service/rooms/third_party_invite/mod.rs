@@ -0,0 +1,297 @@
+use std::collections::BTreeMap;
+
+use base64::{engine::general_purpose, Engine as _};
+use ruma::{
+	api::client::{error::ErrorKind, membership::Invite3pid},
+	events::{
+		room::{
+			canonical_alias::RoomCanonicalAliasEventContent,
+			member::ThirdPartyInvite,
+			third_party_invite::{PublicKey, RoomThirdPartyInviteEventContent},
+		},
+		StateEventType, TimelineEventType,
+	},
+	serde::Base64,
+	state_res::Event,
+	CanonicalJsonObject, CanonicalJsonValue, OwnedUserId, RoomId, UserId,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::{services, Error, Result};
+
+#[derive(Deserialize)]
+struct HashDetailsResponse {
+	algorithms: Vec<String>,
+	lookup_pepper: String,
+}
+
+#[derive(Serialize)]
+struct LookupRequest<'a> {
+	addresses: Vec<String>,
+	algorithm: &'a str,
+	pepper: &'a str,
+}
+
+#[derive(Deserialize)]
+struct LookupResponse {
+	mappings: BTreeMap<String, OwnedUserId>,
+}
+
+#[derive(Serialize)]
+struct StoreInviteRequest<'a> {
+	medium: &'a str,
+	address: &'a str,
+	room_id: &'a RoomId,
+	sender: &'a UserId,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	room_alias: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	room_name: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	room_avatar_url: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	sender_display_name: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	sender_avatar_url: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct StoreInviteResponse {
+	token: String,
+	public_keys: Vec<PublicKey>,
+	display_name: String,
+}
+
+pub struct Service;
+
+impl Service {
+	/// Asks the identity server from the invite whether the third party
+	/// identifier is already bound to a Matrix ID.
+	#[tracing::instrument(skip(self, invite), fields(id_server = %invite.id_server))]
+	pub async fn lookup(&self, invite: &Invite3pid) -> Result<Option<OwnedUserId>> {
+		let client = &services().globals.client.default;
+		let base = identity_server_base(&invite.id_server)?;
+
+		let hash_details: HashDetailsResponse = client
+			.get(format!("{base}/_matrix/identity/v2/hash_details"))
+			.bearer_auth(&invite.id_access_token)
+			.send()
+			.await?
+			.error_for_status()?
+			.json()
+			.await?;
+
+		let medium = invite.medium.as_str();
+		let (algorithm, address) = if hash_details.algorithms.iter().any(|a| a == "sha256") {
+			let input = format!("{} {medium} {}", invite.address, hash_details.lookup_pepper);
+			let digest = ring::digest::digest(&ring::digest::SHA256, input.as_bytes());
+			("sha256", general_purpose::URL_SAFE_NO_PAD.encode(digest.as_ref()))
+		} else if hash_details.algorithms.iter().any(|a| a == "none") {
+			("none", format!("{} {medium}", invite.address))
+		} else {
+			return Err(Error::BadServerResponse(
+				"Identity server does not support any known lookup algorithm.",
+			));
+		};
+
+		let mut response: LookupResponse = client
+			.post(format!("{base}/_matrix/identity/v2/lookup"))
+			.bearer_auth(&invite.id_access_token)
+			.json(&LookupRequest {
+				addresses: vec![address.clone()],
+				algorithm,
+				pepper: &hash_details.lookup_pepper,
+			})
+			.send()
+			.await?
+			.error_for_status()?
+			.json()
+			.await?;
+
+		Ok(response.mappings.remove(&address))
+	}
+
+	/// Stores a pending invite on the identity server and returns the state
+	/// key and content of the `m.room.third_party_invite` event to send.
+	#[tracing::instrument(skip(self, invite), fields(id_server = %invite.id_server))]
+	pub async fn store_invite(
+		&self, sender_user: &UserId, room_id: &RoomId, invite: &Invite3pid,
+	) -> Result<(String, RoomThirdPartyInviteEventContent)> {
+		let base = identity_server_base(&invite.id_server)?;
+
+		let room_name = services().rooms.state_accessor.get_name(room_id)?;
+		let room_avatar_url = services()
+			.rooms
+			.state_accessor
+			.get_avatar(room_id)?
+			.into_option()
+			.and_then(|avatar| avatar.url)
+			.map(|url| url.to_string());
+		let room_alias = services()
+			.rooms
+			.state_accessor
+			.room_state_get(room_id, &StateEventType::RoomCanonicalAlias, "")?
+			.and_then(|event| serde_json::from_str::<RoomCanonicalAliasEventContent>(event.content.get()).ok())
+			.and_then(|content| content.alias)
+			.map(|alias| alias.to_string());
+
+		let response: StoreInviteResponse = services()
+			.globals
+			.client
+			.default
+			.post(format!("{base}/_matrix/identity/v2/store-invite"))
+			.bearer_auth(&invite.id_access_token)
+			.json(&StoreInviteRequest {
+				medium: invite.medium.as_str(),
+				address: &invite.address,
+				room_id,
+				sender: sender_user,
+				room_alias,
+				room_name,
+				room_avatar_url,
+				sender_display_name: services().users.displayname(sender_user)?,
+				sender_avatar_url: services()
+					.users
+					.avatar_url(sender_user)?
+					.map(|url| url.to_string()),
+			})
+			.send()
+			.await?
+			.error_for_status()?
+			.json()
+			.await?;
+
+		let Some(first_key) = response.public_keys.first() else {
+			return Err(Error::BadServerResponse("Identity server did not return any public keys."));
+		};
+
+		let content = RoomThirdPartyInviteEventContent {
+			display_name: response.display_name,
+			key_validity_url: first_key.key_validity_url.clone().unwrap_or_default(),
+			public_key: first_key.public_key.clone(),
+			public_keys: Some(response.public_keys),
+		};
+
+		Ok((response.token, content))
+	}
+
+	/// Returns the `m.room.third_party_invite` event a membership event refers
+	/// to, but only if one of the signatures in its `signed` block was made by
+	/// one of the public keys in that event. This is what should be passed to
+	/// the auth rules as the current third party invite.
+	pub fn auth_event<E, F>(&self, incoming_event: &impl Event, fetch_state: F) -> Option<E>
+	where
+		E: Event,
+		F: Fn(&StateEventType, &str) -> Option<E>,
+	{
+		if *incoming_event.event_type() != TimelineEventType::RoomMember {
+			return None;
+		}
+
+		let content: CanonicalJsonObject = serde_json::from_str(incoming_event.content().get()).ok()?;
+		let Some(CanonicalJsonValue::Object(third_party_invite)) = content.get("third_party_invite") else {
+			return None;
+		};
+		let Some(CanonicalJsonValue::Object(signed)) = third_party_invite.get("signed") else {
+			return None;
+		};
+		let Some(CanonicalJsonValue::String(token)) = signed.get("token") else {
+			return None;
+		};
+
+		let invite_event = fetch_state(&StateEventType::RoomThirdPartyInvite, token)?;
+		let invite_content: RoomThirdPartyInviteEventContent = serde_json::from_str(invite_event.content().get())
+			.map_err(|e| warn!("Invalid m.room.third_party_invite event {}: {e}", invite_event.event_id()))
+			.ok()?;
+
+		let public_keys = invite_content
+			.public_keys
+			.unwrap_or_default()
+			.into_iter()
+			.map(|key| key.public_key)
+			.chain(std::iter::once(invite_content.public_key))
+			.collect::<Vec<_>>();
+
+		if verify_signed(signed, &public_keys) {
+			Some(invite_event)
+		} else {
+			debug!(
+				"Third party invite signature for token {token} in {} does not match any public key",
+				incoming_event.event_id()
+			);
+			None
+		}
+	}
+
+	/// Builds the `third_party_invite` field of an `m.room.member` invite
+	/// event from the `signed` block an identity server handed out.
+	pub fn from_signed(&self, display_name: String, signed: CanonicalJsonObject) -> Result<ThirdPartyInvite> {
+		serde_json::from_value(serde_json::json!({
+			"display_name": display_name,
+			"signed": signed,
+		}))
+		.map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid signed third party invite."))
+	}
+
+	/// Returns the display name of the pending `m.room.third_party_invite`
+	/// event with the given token, if the room has one.
+	pub fn display_name(&self, room_id: &RoomId, token: &str) -> Result<Option<String>> {
+		services()
+			.rooms
+			.state_accessor
+			.room_state_get(room_id, &StateEventType::RoomThirdPartyInvite, token)?
+			.map(|event| {
+				serde_json::from_str::<RoomThirdPartyInviteEventContent>(event.content.get())
+					.map(|content| content.display_name)
+					.map_err(|_| Error::bad_database("Invalid m.room.third_party_invite event in database."))
+			})
+			.transpose()
+	}
+}
+
+/// Checks every signature in a `signed` block against every public key given
+/// by the identity server, returning true if any of them matches.
+fn verify_signed(signed: &CanonicalJsonObject, public_keys: &[Base64]) -> bool {
+	let Some(CanonicalJsonValue::Object(signatures)) = signed.get("signatures") else {
+		return false;
+	};
+
+	for (entity, entity_signatures) in signatures {
+		let CanonicalJsonValue::Object(entity_signatures) = entity_signatures else {
+			continue;
+		};
+
+		for (key_id, signature) in entity_signatures {
+			let mut single = signed.clone();
+			single.insert(
+				"signatures".to_owned(),
+				CanonicalJsonValue::Object(BTreeMap::from_iter([(
+					entity.clone(),
+					CanonicalJsonValue::Object(BTreeMap::from_iter([(key_id.clone(), signature.clone())])),
+				)])),
+			);
+
+			for public_key in public_keys {
+				let public_key_map = BTreeMap::from_iter([(
+					entity.clone(),
+					BTreeMap::from_iter([(key_id.clone(), public_key.clone())]),
+				)]);
+
+				if ruma::signatures::verify_json(&public_key_map, &single).is_ok() {
+					return true;
+				}
+			}
+		}
+	}
+
+	false
+}
+
+fn identity_server_base(id_server: &str) -> Result<String> {
+	if id_server.is_empty() || id_server.contains(['/', '?', '#', '@']) {
+		return Err(Error::BadRequest(ErrorKind::InvalidParam, "Invalid identity server name."));
+	}
+
+	Ok(format!("https://{id_server}"))
+}
@@ -62,7 +62,21 @@ pub trait Data: Send + Sync {
 		&'a self, user_id: &UserId, room_id: &RoomId, from: PduCount,
 	) -> Result<Box<dyn Iterator<Item = Result<(PduCount, PduEvent)>> + 'a>>;
 
+	/// Returns the ids of the pdus ordered by how close their
+	/// `origin_server_ts` is to `ts`: the ones at or before it, latest first,
+	/// if `backwards` is set, otherwise the ones at or after it, earliest
+	/// first.
+	fn pdu_ids_by_timestamp<'a>(
+		&'a self, room_id: &RoomId, ts: u64, backwards: bool,
+	) -> Result<Box<dyn Iterator<Item = Vec<u8>> + 'a>>;
+
 	fn increment_notification_counts(
 		&self, room_id: &RoomId, notifies: Vec<OwnedUserId>, highlights: Vec<OwnedUserId>,
 	) -> Result<()>;
+
+	fn increment_thread_notification_counts(
+		&self, room_id: &RoomId, thread_id: &EventId, notifies: &[OwnedUserId], highlights: &[OwnedUserId],
+	) -> Result<()>;
+
+	fn increment_missed_call_counts(&self, room_id: &RoomId, users: &[OwnedUserId]) -> Result<()>;
 }
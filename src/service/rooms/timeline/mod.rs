@@ -9,7 +9,7 @@ use std::{
 pub use data::Data;
 use rand::prelude::SliceRandom;
 use ruma::{
-	api::{client::error::ErrorKind, federation},
+	api::{client::error::ErrorKind, federation, Direction},
 	canonical_json::to_canonical_value,
 	events::{
 		push_rules::PushRulesEvent,
@@ -25,8 +25,8 @@ use ruma::{
 	serde::Base64,
 	state_res,
 	state_res::{Event, RoomVersion},
-	uint, user_id, CanonicalJsonObject, CanonicalJsonValue, EventId, OwnedEventId, OwnedRoomId, OwnedServerName,
-	RoomId, RoomVersionId, ServerName, UserId,
+	uint, user_id, CanonicalJsonObject, CanonicalJsonValue, EventId, MilliSecondsSinceUnixEpoch, OwnedEventId,
+	OwnedRoomId, OwnedServerName, RoomId, RoomVersionId, ServerName, UserId,
 };
 use serde::Deserialize;
 use serde_json::value::{to_raw_value, RawValue as RawJsonValue};
@@ -387,11 +387,12 @@ impl Service {
 			let mut highlight = false;
 			let mut notify = false;
 
-			for action in
+			let actions =
 				services()
 					.pusher
-					.get_actions(user, &rules_for_user, &power_levels, &sync_pdu, &pdu.room_id)?
-			{
+					.get_actions(user, &rules_for_user, &power_levels, &sync_pdu, &pdu.room_id)?;
+
+			for action in actions {
 				match action {
 					Action::Notify => notify = true,
 					Action::SetTweak(Tweak::Highlight(true)) => {
@@ -403,6 +404,9 @@ impl Service {
 
 			if notify {
 				notifies.push(user.clone());
+				services()
+					.pusher
+					.add_notification(user, count2, pdu, actions)?;
 			}
 
 			if highlight {
@@ -414,6 +418,22 @@ impl Service {
 			}
 		}
 
+		// Events in a thread are also counted per thread (MSC3773)
+		if let Some(thread_id) = serde_json::from_str::<ExtractRelatesTo>(pdu.content.get())
+			.ok()
+			.and_then(|content| match content.relates_to {
+				Relation::Thread(thread) => Some(thread.event_id),
+				_ => None,
+			}) {
+			self.db
+				.increment_thread_notification_counts(&pdu.room_id, &thread_id, &notifies, &highlights)?;
+		}
+
+		if pdu.kind == TimelineEventType::CallInvite {
+			self.db
+				.increment_missed_call_counts(&pdu.room_id, &notifies)?;
+		}
+
 		self.db
 			.increment_notification_counts(&pdu.room_id, notifies, highlights)?;
 
@@ -746,7 +766,10 @@ impl Service {
 		let auth_check = state_res::auth_check(
 			&room_version,
 			&pdu,
-			None::<PduEvent>, // TODO: third_party_invite
+			services()
+				.rooms
+				.third_party_invite
+				.auth_event(&pdu, |k, s| auth_events.get(&(k.clone(), s.to_owned()))),
 			|k, s| auth_events.get(&(k.clone(), s.to_owned())),
 		)
 		.map_err(|e| {
@@ -1039,6 +1062,30 @@ impl Service {
 			let mut pdu = self
 				.get_pdu_from_id(&pdu_id)?
 				.ok_or_else(|| Error::bad_database("PDU ID points to invalid PDU."))?;
+
+			if pdu.kind == TimelineEventType::RoomMessage {
+				#[derive(Deserialize)]
+				struct ExtractBody {
+					body: Option<String>,
+				}
+
+				if let Ok(ExtractBody {
+					body: Some(body),
+				}) = serde_json::from_str(pdu.content.get())
+				{
+					let shortroomid = services()
+						.rooms
+						.short
+						.get_shortroomid(&pdu.room_id)?
+						.ok_or_else(|| Error::bad_database("Room of redacted PDU has no shortroomid."))?;
+
+					services()
+						.rooms
+						.search
+						.deindex_pdu(shortroomid, &pdu_id, &body)?;
+				}
+			}
+
 			let room_version_id = services().rooms.state.get_room_version(&pdu.room_id)?;
 			pdu.redact(room_version_id, reason)?;
 			self.replace_pdu(
@@ -1172,6 +1219,143 @@ impl Service {
 		Ok(())
 	}
 
+	/// Returns the local timeline event closest to `ts` in the given
+	/// direction.
+	pub fn pdu_by_timestamp(
+		&self, room_id: &RoomId, ts: MilliSecondsSinceUnixEpoch, dir: Direction,
+	) -> Result<Option<PduEvent>> {
+		self.pdus_by_timestamp(room_id, ts, dir)?.next().transpose()
+	}
+
+	/// Returns the local timeline events starting with the one closest to
+	/// `ts`, moving away from it in the given direction.
+	pub fn pdus_by_timestamp<'a>(
+		&'a self, room_id: &RoomId, ts: MilliSecondsSinceUnixEpoch, dir: Direction,
+	) -> Result<impl Iterator<Item = Result<PduEvent>> + 'a> {
+		Ok(self
+			.db
+			.pdu_ids_by_timestamp(room_id, ts.get().into(), dir == Direction::Backward)?
+			.filter_map(|pdu_id| self.get_pdu_from_id(&pdu_id).transpose()))
+	}
+
+	/// Like [`Self::pdu_by_timestamp`], but asks other servers in the room when
+	/// we have nothing locally or the local event borders a gap in our history,
+	/// backfilling their answer if it is closer to `ts`.
+	#[tracing::instrument(skip(self))]
+	pub async fn fetch_pdu_by_timestamp(
+		&self, room_id: &RoomId, ts: MilliSecondsSinceUnixEpoch, dir: Direction,
+	) -> Result<Option<PduEvent>> {
+		let local = self.pdu_by_timestamp(room_id, ts, dir)?;
+
+		if let Some(pdu) = &local {
+			let has_gap = pdu
+				.prev_events
+				.iter()
+				.any(|prev_event| !matches!(self.get_pdu_id(prev_event), Ok(Some(_))));
+			if !has_gap {
+				return Ok(local);
+			}
+		}
+
+		let mut servers = services()
+			.rooms
+			.state_cache
+			.room_servers(room_id)
+			.filter_map(Result::ok)
+			.filter(|server| server != services().globals.server_name())
+			.collect::<Vec<_>>();
+		servers.shuffle(&mut rand::thread_rng());
+
+		for server in servers.into_iter().take(5) {
+			let response = match services()
+				.sending
+				.send_federation_request(
+					&server,
+					federation::event::get_event_by_timestamp::v1::Request {
+						room_id: room_id.to_owned(),
+						ts,
+						dir,
+					},
+				)
+				.await
+			{
+				Ok(response) => response,
+				Err(e) => {
+					warn!("{server} failed to provide an event by timestamp for {room_id}: {e}");
+					continue;
+				},
+			};
+
+			let remote_ts = response.origin_server_ts;
+			let in_direction = match dir {
+				Direction::Forward => remote_ts >= ts,
+				Direction::Backward => remote_ts <= ts,
+			};
+			if !in_direction {
+				continue;
+			}
+
+			if let Some(pdu) = &local {
+				let local_ts = MilliSecondsSinceUnixEpoch(pdu.origin_server_ts);
+				let closer = match dir {
+					Direction::Forward => remote_ts < local_ts,
+					Direction::Backward => remote_ts > local_ts,
+				};
+				if !closer {
+					return Ok(local);
+				}
+			}
+
+			if self.get_pdu_id(&response.event_id)?.is_none() {
+				let event = match services()
+					.sending
+					.send_federation_request(
+						&server,
+						federation::event::get_event::v1::Request {
+							event_id: response.event_id.clone(),
+						},
+					)
+					.await
+				{
+					Ok(event) => event,
+					Err(e) => {
+						warn!("{server} failed to provide {}: {e}", response.event_id);
+						continue;
+					},
+				};
+
+				// Don't backfill events of other rooms
+				match server_server::parse_incoming_pdu(&event.pdu) {
+					Ok((_, _, event_room_id)) if event_room_id == room_id => {},
+					_ => {
+						warn!("{server} provided {} which is not an event of {room_id}", response.event_id);
+						continue;
+					},
+				}
+
+				let pub_key_map = RwLock::new(BTreeMap::new());
+				if let Err(e) = self.backfill_pdu(&server, event.pdu, &pub_key_map).await {
+					warn!("Failed to backfill {} from {server}: {e}", response.event_id);
+					continue;
+				}
+			}
+
+			if let Some(pdu) = self.get_non_outlier_pdu(&response.event_id)? {
+				if pdu.room_id != room_id || MilliSecondsSinceUnixEpoch(pdu.origin_server_ts) != remote_ts {
+					warn!(
+						"{server} provided {} for {room_id}, but it is in another room or has another timestamp",
+						response.event_id
+					);
+					continue;
+				}
+
+				return Ok(Some(pdu));
+			}
+		}
+
+		Ok(local)
+	}
+
 	#[tracing::instrument(skip(self, pdu))]
 	pub async fn backfill_pdu(
 		&self, origin: &ServerName, pdu: Box<RawJsonValue>,
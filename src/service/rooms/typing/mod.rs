@@ -2,13 +2,13 @@ use std::collections::BTreeMap;
 
 use ruma::{
 	api::federation::transactions::edu::{Edu, TypingContent},
-	events::SyncEphemeralRoomEvent,
+	events::{typing::TypingEvent, SyncEphemeralRoomEvent},
 	OwnedRoomId, OwnedUserId, RoomId, UserId,
 };
 use tokio::sync::{broadcast, RwLock};
-use tracing::debug;
+use tracing::{debug, warn};
 
-use crate::{services, utils, Result};
+use crate::{service::sending::AppserviceEduTarget, services, utils, Result};
 
 pub struct Service {
 	pub typing: RwLock<BTreeMap<OwnedRoomId, BTreeMap<OwnedUserId, u64>>>, // u64 is unix timestamp of timeout
@@ -35,6 +35,9 @@ impl Service {
 			.insert(room_id.to_owned(), services().globals.next_count()?);
 		_ = self.typing_update_sender.send(room_id.to_owned());
 
+		// update appservices
+		self.appservice_send(room_id).await;
+
 		// update federation
 		if user_id.server_name() == services().globals.server_name() {
 			self.federation_send(room_id, user_id, true)?;
@@ -59,6 +62,9 @@ impl Service {
 			.insert(room_id.to_owned(), services().globals.next_count()?);
 		_ = self.typing_update_sender.send(room_id.to_owned());
 
+		// update appservices
+		self.appservice_send(room_id).await;
+
 		// update federation
 		if user_id.server_name() == services().globals.server_name() {
 			self.federation_send(room_id, user_id, false)?;
@@ -99,7 +105,7 @@ impl Service {
 		};
 
 		if !removable.is_empty() {
-			let typing = &mut self.typing.write().await;
+			let mut typing = self.typing.write().await;
 			let room = typing.entry(room_id.to_owned()).or_default();
 			for user in &removable {
 				debug!("typing maintain remove {:?} in {:?}", &user, room_id);
@@ -111,6 +117,10 @@ impl Service {
 				.await
 				.insert(room_id.to_owned(), services().globals.next_count()?);
 			_ = self.typing_update_sender.send(room_id.to_owned());
+			drop(typing);
+
+			// update appservices
+			self.appservice_send(room_id).await;
 
 			// update federation
 			for user in removable {
@@ -152,12 +162,41 @@ impl Service {
 		})
 	}
 
+	/// Sends everyone typing in the room to the appservices that receive
+	/// ephemeral events. Failures are only logged, the typing update itself
+	/// succeeded.
+	async fn appservice_send(&self, room_id: &RoomId) {
+		let content = match self.typings_all(room_id).await {
+			Ok(event) => event.content,
+			Err(e) => {
+				warn!("Failed to read typing users of {room_id} for appservices: {e}");
+				return;
+			},
+		};
+
+		let event = TypingEvent {
+			content,
+			room_id: room_id.to_owned(),
+		};
+
+		if let Err(e) = services()
+			.sending
+			.send_edu_appservices(
+				AppserviceEduTarget::Room(room_id),
+				serde_json::to_vec(&event).expect("Serialized TypingEvent"),
+			)
+			.await
+		{
+			warn!("Failed to queue typing update of {room_id} for appservices: {e}");
+		}
+	}
+
 	fn federation_send(&self, room_id: &RoomId, user_id: &UserId, typing: bool) -> Result<()> {
 		debug_assert!(
 			user_id.server_name() == services().globals.server_name(),
 			"tried to broadcast typing status of remote user",
 		);
-		if !services().globals.config.allow_outgoing_typing {
+		if !services().globals.allow_outgoing_typing() {
 			return Ok(());
 		}
 
@@ -1,4 +1,6 @@
-use ruma::{OwnedRoomId, OwnedUserId, RoomId, UserId};
+use std::collections::BTreeMap;
+
+use ruma::{EventId, OwnedEventId, OwnedRoomId, OwnedUserId, RoomId, UserId};
 
 use crate::Result;
 
@@ -9,6 +11,20 @@ pub trait Data: Send + Sync {
 
 	fn highlight_count(&self, user_id: &UserId, room_id: &RoomId) -> Result<u64>;
 
+	fn missed_call_count(&self, user_id: &UserId, room_id: &RoomId) -> Result<u64>;
+
+	/// Returns the notification and highlight counts of every thread in the
+	/// room that has unread notifications.
+	fn thread_notification_counts(
+		&self, user_id: &UserId, room_id: &RoomId,
+	) -> Result<BTreeMap<OwnedEventId, (u64, u64)>>;
+
+	/// Resets the counts of a single thread, or of the main timeline if
+	/// `thread_id` is None. The room counts keep covering all threads.
+	fn reset_thread_notification_counts(
+		&self, user_id: &UserId, room_id: &RoomId, thread_id: Option<&EventId>,
+	) -> Result<()>;
+
 	// Returns the count at which the last reset_notification_counts was called
 	fn last_notification_read(&self, user_id: &UserId, room_id: &RoomId) -> Result<u64>;
 
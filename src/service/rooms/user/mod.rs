@@ -1,9 +1,11 @@
 mod data;
 
+use std::collections::BTreeMap;
+
 pub use data::Data;
-use ruma::{OwnedRoomId, OwnedUserId, RoomId, UserId};
+use ruma::{EventId, OwnedEventId, OwnedRoomId, OwnedUserId, RoomId, UserId};
 
-use crate::Result;
+use crate::{services, Result};
 
 pub struct Service {
 	pub db: &'static dyn Data,
@@ -22,6 +24,38 @@ impl Service {
 		self.db.highlight_count(user_id, room_id)
 	}
 
+	pub fn missed_call_count(&self, user_id: &UserId, room_id: &RoomId) -> Result<u64> {
+		self.db.missed_call_count(user_id, room_id)
+	}
+
+	pub fn thread_notification_counts(
+		&self, user_id: &UserId, room_id: &RoomId,
+	) -> Result<BTreeMap<OwnedEventId, (u64, u64)>> {
+		self.db.thread_notification_counts(user_id, room_id)
+	}
+
+	/// Returns the unread notifications and missed calls of the user across
+	/// all joined rooms, as shown on the badge of push notifications.
+	pub fn badge_counts(&self, user_id: &UserId) -> Result<(u64, u64)> {
+		let mut unread = 0;
+		let mut missed_calls = 0;
+		for room_id in services().rooms.state_cache.rooms_joined(user_id) {
+			let room_id = room_id?;
+			unread += self.db.notification_count(user_id, &room_id)?;
+			missed_calls += self.db.missed_call_count(user_id, &room_id)?;
+		}
+
+		Ok((unread, missed_calls))
+	}
+
+	/// Marks a thread as read, or the main timeline if `thread_id` is None.
+	pub fn reset_thread_notification_counts(
+		&self, user_id: &UserId, room_id: &RoomId, thread_id: Option<&EventId>,
+	) -> Result<()> {
+		self.db
+			.reset_thread_notification_counts(user_id, room_id, thread_id)
+	}
+
 	pub fn last_notification_read(&self, user_id: &UserId, room_id: &RoomId) -> Result<u64> {
 		self.db.last_notification_read(user_id, room_id)
 	}
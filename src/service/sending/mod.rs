@@ -2,7 +2,7 @@ use std::{
 	cmp,
 	collections::{BTreeMap, HashMap, HashSet},
 	fmt::Debug,
-	sync::Arc,
+	sync::{Arc, Mutex as StdMutex},
 	time::{Duration, Instant},
 };
 
@@ -19,19 +19,21 @@ use ruma::{
 				DeviceListUpdateContent, Edu, PresenceContent, PresenceUpdate, ReceiptContent, ReceiptData, ReceiptMap,
 			},
 		},
+		push_gateway::send_event_notification::v1::NotificationCounts,
 		OutgoingRequest,
 	},
 	device_id,
 	events::{push_rules::PushRulesEvent, receipt::ReceiptType, AnySyncEphemeralRoomEvent, GlobalAccountDataEventType},
-	push, uint, MilliSecondsSinceUnixEpoch, OwnedServerName, OwnedUserId, RoomId, ServerName, UInt, UserId,
+	push, uint, MilliSecondsSinceUnixEpoch, OwnedRoomId, OwnedServerName, OwnedUserId, RoomId, ServerName, UserId,
 };
+use serde::Deserialize;
 use tokio::{
 	select,
 	sync::{mpsc, Mutex, Semaphore},
 };
 use tracing::{error, warn};
 
-use crate::{services, utils::calculate_hash, Config, Error, PduEvent, Result};
+use crate::{services, utils, utils::calculate_hash, Config, Error, PduEvent, Result};
 
 pub mod appservice;
 pub mod data;
@@ -48,6 +50,14 @@ pub struct Service {
 	startup_netburst: bool,
 	startup_netburst_keep: i64,
 	timeout: u64,
+	appservice_status: StdMutex<HashMap<String, AppserviceStatus>>,
+	/// Destinations to retry without waiting for their backoff
+	forced_retries: StdMutex<HashSet<OutgoingKind>>,
+}
+
+#[derive(Deserialize)]
+struct ExtractToUserId {
+	to_user_id: Option<OwnedUserId>,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
@@ -65,6 +75,29 @@ pub enum SendingEventType {
 	Flush,        // none
 }
 
+/// Which appservices an ephemeral event is meant for (MSC2409).
+pub enum AppserviceEduTarget<'a> {
+	/// Typing and receipts, for appservices in the room.
+	Room(&'a RoomId),
+	/// Presence, for appservices the user belongs to or shares a room with.
+	Presence(&'a UserId),
+	/// To-device events, for the appservice the user belongs to.
+	ToDevice(&'a UserId),
+}
+
+/// How deliveries to an appservice went since the server started.
+#[derive(Clone, Debug, Default)]
+pub struct AppserviceStatus {
+	/// When a transaction was last delivered, in milliseconds since the epoch.
+	pub last_success: Option<u64>,
+	/// When and why delivering a transaction last failed.
+	pub last_error: Option<(u64, String)>,
+	/// How often the pending transaction failed in a row.
+	pub failures: u32,
+	/// When the pending transaction is retried at the earliest.
+	pub retry_at: Option<u64>,
+}
+
 enum TransactionStatus {
 	Running,
 	Failed(u32, Instant), // number of times failed, time of last failure
@@ -82,6 +115,8 @@ impl Service {
 			startup_netburst: config.startup_netburst,
 			startup_netburst_keep: config.startup_netburst_keep,
 			timeout: config.sender_timeout,
+			appservice_status: StdMutex::new(HashMap::new()),
+			forced_retries: StdMutex::new(HashSet::new()),
 		})
 	}
 
@@ -192,6 +227,74 @@ impl Service {
 		Ok(())
 	}
 
+	/// Queues an ephemeral event for the appservices that registered with
+	/// `receive_ephemeral` and are interested in it.
+	#[tracing::instrument(skip(self, target, serialized))]
+	pub async fn send_edu_appservices(&self, target: AppserviceEduTarget<'_>, serialized: Vec<u8>) -> Result<()> {
+		let mut requests = Vec::new();
+		// Read once for all appservices, presence updates are frequent
+		let mut joined_rooms: Option<Vec<OwnedRoomId>> = None;
+		for appservice in services().appservice.read().await.values() {
+			if !appservice.registration.receive_ephemeral {
+				continue;
+			}
+
+			let interested = match target {
+				AppserviceEduTarget::Room(room_id) => services()
+					.rooms
+					.state_cache
+					.appservice_in_room(room_id, appservice)?,
+				AppserviceEduTarget::Presence(user_id) => {
+					appservice.is_user_match(user_id)
+						|| joined_rooms
+							.get_or_insert_with(|| {
+								services()
+									.rooms
+									.state_cache
+									.rooms_joined(user_id)
+									.filter_map(Result::ok)
+									.collect()
+							})
+							.iter()
+							.any(|room_id| {
+								services()
+									.rooms
+									.state_cache
+									.appservice_in_room(room_id, appservice)
+									.unwrap_or(false)
+							})
+				},
+				AppserviceEduTarget::ToDevice(user_id) => appservice.is_user_match(user_id),
+			};
+
+			if interested {
+				requests.push((
+					OutgoingKind::Appservice(appservice.registration.id.clone()),
+					SendingEventType::Edu(serialized.clone()),
+				));
+			}
+		}
+
+		if requests.is_empty() {
+			return Ok(());
+		}
+
+		let _cork = services().globals.db.cork()?;
+		let keys = self.db.queue_requests(
+			&requests
+				.iter()
+				.map(|(o, e)| (o, e.clone()))
+				.collect::<Vec<_>>(),
+		)?;
+		for ((outgoing_kind, event), key) in requests.into_iter().zip(keys) {
+			self.sender
+				.send((outgoing_kind.clone(), event, key))
+				.unwrap();
+		}
+
+		Ok(())
+	}
+
 	#[tracing::instrument(skip(self, room_id))]
 	pub fn flush_room(&self, room_id: &RoomId) -> Result<()> {
 		let servers = services()
@@ -227,6 +330,76 @@ impl Service {
 		Ok(())
 	}
 
+	/// Returns how deliveries to the appservice went since the server started.
+	pub fn appservice_status(&self, appservice_id: &str) -> AppserviceStatus {
+		self.appservice_status
+			.lock()
+			.unwrap()
+			.get(appservice_id)
+			.cloned()
+			.unwrap_or_default()
+	}
+
+	/// Returns how many events wait to be delivered to the appservice,
+	/// including the ones of the pending transaction.
+	pub fn appservice_queued_count(&self, appservice_id: &str) -> usize {
+		let outgoing_kind = OutgoingKind::Appservice(appservice_id.to_owned());
+		self.db.active_requests_for(&outgoing_kind).count() + self.db.queued_requests(&outgoing_kind).count()
+	}
+
+	/// Retries the failed transaction of an appservice now instead of waiting
+	/// for its backoff. Returns false if no transaction failed.
+	pub fn retry_appservice(&self, appservice_id: &str) -> bool {
+		if !self
+			.appservice_status
+			.lock()
+			.unwrap()
+			.get(appservice_id)
+			.is_some_and(|status| status.failures > 0)
+		{
+			return false;
+		}
+
+		let outgoing_kind = OutgoingKind::Appservice(appservice_id.to_owned());
+		self.forced_retries
+			.lock()
+			.unwrap()
+			.insert(outgoing_kind.clone());
+		self.sender
+			.send((outgoing_kind, SendingEventType::Flush, Vec::new()))
+			.unwrap();
+
+		true
+	}
+
+	fn record_appservice_delivery(&self, appservice_id: &str, result: Result<(), &Error>) {
+		let mut appservice_status = self.appservice_status.lock().unwrap();
+		let status = appservice_status
+			.entry(appservice_id.to_owned())
+			.or_default();
+		let now = utils::millis_since_unix_epoch();
+
+		match result {
+			Ok(()) => {
+				status.last_success = Some(now);
+				status.failures = 0;
+				status.retry_at = None;
+			},
+			Err(e) => {
+				status.last_error = Some((now, e.to_string()));
+				status.failures = status.failures.saturating_add(1);
+				status.retry_at = Some(now.saturating_add(self.backoff(status.failures).as_millis() as u64));
+			},
+		}
+	}
+
+	/// How long to wait before retrying a destination that failed `tries` times
+	/// in a row (exponential backoff).
+	fn backoff(&self, tries: u32) -> Duration {
+		const MAX_DURATION: Duration = Duration::from_secs(60 * 60 * 24);
+		cmp::min(Duration::from_secs(self.timeout) * tries * tries, MAX_DURATION)
+	}
+
 	#[tracing::instrument(skip(self, request), name = "request")]
 	pub async fn send_federation_request<T>(&self, dest: &ServerName, request: T) -> Result<T::IncomingResponse>
 	where
@@ -310,6 +483,11 @@ impl Service {
 				Some(response) = futures.next() => {
 					match response {
 						Ok(outgoing_kind) => {
+							if let OutgoingKind::Appservice(id) = &outgoing_kind {
+								self.record_appservice_delivery(id, Ok(()));
+							}
+							self.forced_retries.lock().unwrap().remove(&outgoing_kind);
+
 							let _cork = services().globals.db.cork();
 							self.db.delete_all_active_requests_for(&outgoing_kind)?;
 
@@ -331,7 +509,11 @@ impl Service {
 								current_transaction_status.remove(&outgoing_kind);
 							}
 						}
-						Err((outgoing_kind, _)) => {
+						Err((outgoing_kind, error)) => {
+							if let OutgoingKind::Appservice(id) = &outgoing_kind {
+								self.record_appservice_delivery(id, Err(&error));
+							}
+
 							current_transaction_status.entry(outgoing_kind).and_modify(|e| *e = match e {
 								TransactionStatus::Running => TransactionStatus::Failed(1, Instant::now()),
 								TransactionStatus::Retrying(n) => TransactionStatus::Failed(*n+1, Instant::now()),
@@ -409,14 +591,14 @@ impl Service {
 	) -> Result<(bool, bool)> {
 		let (mut allow, mut retry) = (true, false);
 		current_transaction_status
-			.entry(outgoing_kind)
+			.entry(outgoing_kind.clone())
 			.and_modify(|e| match e {
 				TransactionStatus::Failed(tries, time) => {
-					// Fail if a request has failed recently (exponential backoff)
-					const MAX_DURATION: Duration = Duration::from_secs(60 * 60 * 24);
-					let mut min_elapsed_duration = Duration::from_secs(self.timeout) * (*tries) * (*tries);
-					min_elapsed_duration = cmp::min(min_elapsed_duration, MAX_DURATION);
-					if time.elapsed() < min_elapsed_duration {
+					// Fail if a request has failed recently (exponential backoff), unless an
+					// admin asked to retry it now
+					if time.elapsed() < self.backoff(*tries)
+						&& !self.forced_retries.lock().unwrap().remove(&outgoing_kind)
+					{
 						allow = false;
 					} else {
 						retry = true;
@@ -601,6 +783,8 @@ async fn handle_events_kind_appservice(
 	kind: &OutgoingKind, id: &String, events: Vec<SendingEventType>,
 ) -> Result<OutgoingKind, (OutgoingKind, Error)> {
 	let mut pdu_jsons = Vec::new();
+	let mut ephemeral = Vec::new();
+	let mut to_device = Vec::new();
 
 	for event in &events {
 		match event {
@@ -620,13 +804,31 @@ async fn handle_events_kind_appservice(
 						.to_room_event(),
 				);
 			},
-			SendingEventType::Edu(_) | SendingEventType::Flush => {
-				// Appservices don't need EDUs (?) and flush only;
-				// no new content
+			SendingEventType::Edu(edu) => {
+				// Queued by send_edu_appservices, only to-device events have a recipient
+				let invalid = |_| (kind.clone(), Error::bad_database("[Appservice] Invalid EDU in db."));
+				if serde_json::from_slice::<ExtractToUserId>(edu)
+					.map_err(invalid)?
+					.to_user_id
+					.is_some()
+				{
+					to_device.push(serde_json::from_slice(edu).map_err(invalid)?);
+				} else {
+					ephemeral.push(serde_json::from_slice(edu).map_err(invalid)?);
+				}
+			},
+			SendingEventType::Flush => {
+				// flush only; no new content
 			},
 		}
 	}
 
+	// Flushes, e.g. from `retry_appservice`, carry no key and no content. Don't
+	// send the appservice an empty transaction for them.
+	if pdu_jsons.is_empty() && ephemeral.is_empty() && to_device.is_empty() {
+		return Ok(kind.clone());
+	}
+
 	let permit = services().sending.maximum_requests.acquire().await;
 
 	let response = match appservice::send_request(
@@ -642,6 +844,8 @@ async fn handle_events_kind_appservice(
 			})?,
 		ruma::api::appservice::event::push_events::v1::Request {
 			events: pdu_jsons,
+			ephemeral,
+			to_device,
 			txn_id: (&*general_purpose::URL_SAFE_NO_PAD.encode(calculate_hash(
 				&events
 					.iter()
@@ -669,12 +873,19 @@ async fn handle_events_kind_appservice(
 async fn handle_events_kind_push(
 	kind: &OutgoingKind, userid: &OwnedUserId, pushkey: &String, events: Vec<SendingEventType>,
 ) -> Result<OutgoingKind, (OutgoingKind, Error)> {
+	// Keep the events queued until the push gateway may be tried again
+	services()
+		.pusher
+		.check_backoff(userid, pushkey)
+		.map_err(|e| (kind.clone(), e))?;
+
 	let mut pdus = Vec::new();
 
 	for event in &events {
 		match event {
 			SendingEventType::Pdu(pdu_id) => {
-				pdus.push(
+				pdus.push((
+					pdu_id,
 					services()
 						.rooms
 						.timeline
@@ -686,7 +897,7 @@ async fn handle_events_kind_push(
 								Error::bad_database("[Push] Event in servernamevent_datas not found in db."),
 							)
 						})?,
-				);
+				));
 			},
 			SendingEventType::Edu(_) | SendingEventType::Flush => {
 				// Push gateways don't need EDUs (?) and flush only;
@@ -695,11 +906,32 @@ async fn handle_events_kind_push(
 		}
 	}
 
-	for pdu in pdus {
+	if pdus.is_empty() {
+		return Ok(kind.clone());
+	}
+
+	// The badge counts go over all joined rooms of the user, so they are the same
+	// for every push in the batch
+	let (unread, missed_calls) = services()
+		.rooms
+		.user
+		.badge_counts(userid)
+		.map_err(|e| (kind.clone(), e))?;
+	let counts = NotificationCounts::new(
+		unread
+			.try_into()
+			.expect("notification count can't go that high"),
+		missed_calls
+			.try_into()
+			.expect("missed call count can't go that high"),
+	);
+
+	for (pdu_id, pdu) in pdus {
 		// Redacted events are not notification targets (we don't send push for them)
 		if let Some(unsigned) = &pdu.unsigned {
 			if let Ok(unsigned) = serde_json::from_str::<serde_json::Value>(unsigned.get()) {
 				if unsigned.get("redacted_because").is_some() {
+					mark_push_done(kind, pdu_id)?;
 					continue;
 				}
 			}
@@ -710,6 +942,7 @@ async fn handle_events_kind_push(
 			.get_pusher(userid, pushkey)
 			.map_err(|e| (kind.clone(), e))?
 		else {
+			mark_push_done(kind, pdu_id)?;
 			continue;
 		};
 
@@ -720,29 +953,46 @@ async fn handle_events_kind_push(
 			.and_then(|event| serde_json::from_str::<PushRulesEvent>(event.get()).ok())
 			.map_or_else(|| push::Ruleset::server_default(userid), |ev: PushRulesEvent| ev.content.global);
 
-		let unread: UInt = services()
-			.rooms
-			.user
-			.notification_count(userid, &pdu.room_id)
-			.map_err(|e| (kind.clone(), e))?
-			.try_into()
-			.expect("notification count can't go that high");
-
 		let permit = services().sending.maximum_requests.acquire().await;
 
-		let _response = services()
+		let response = services()
 			.pusher
-			.send_push_notice(userid, unread, &pusher, rules_for_user, &pdu)
-			.await
-			.map(|_response| kind.clone())
-			.map_err(|e| (kind.clone(), e));
+			.send_push_notice(userid, counts.clone(), &pusher, rules_for_user, &pdu)
+			.await;
 
 		drop(permit);
+
+		services()
+			.pusher
+			.record_delivery(userid, pushkey, &response)
+			.map_err(|e| (kind.clone(), e))?;
+
+		// The notifications that were delivered are no longer active, so only this
+		// one and the rest of the batch are sent again once the backoff passed
+		if let Err(e) = response {
+			warn!("Failed to send push notification for {} to {userid}: {e}", pdu.event_id);
+			return Err((kind.clone(), e));
+		}
+
+		mark_push_done(kind, pdu_id)?;
 	}
 
 	Ok(kind.clone())
 }
 
+/// Removes a notification from the active requests of a push batch once it
+/// was handled.
+fn mark_push_done(kind: &OutgoingKind, pdu_id: &[u8]) -> Result<(), (OutgoingKind, Error)> {
+	let mut key = kind.get_prefix();
+	key.extend_from_slice(pdu_id);
+
+	services()
+		.sending
+		.db
+		.delete_active_request(key)
+		.map_err(|e| (kind.clone(), e))
+}
+
 #[tracing::instrument(skip(kind, events), name = "")]
 async fn handle_events_kind_normal(
 	kind: &OutgoingKind, dest: &OwnedServerName, events: Vec<SendingEventType>,
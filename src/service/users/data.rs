@@ -8,6 +8,7 @@ use ruma::{
 	DeviceId, DeviceKeyAlgorithm, DeviceKeyId, OwnedDeviceId, OwnedDeviceKeyId, OwnedMxcUri, OwnedUserId, UInt, UserId,
 };
 
+use super::SlidingSyncCache;
 use crate::Result;
 
 pub trait Data: Send + Sync {
@@ -85,6 +86,23 @@ pub trait Data: Send + Sync {
 	fn count_one_time_keys(&self, user_id: &UserId, device_id: &DeviceId)
 		-> Result<BTreeMap<DeviceKeyAlgorithm, UInt>>;
 
+	/// Stores the fallback key of a device, replacing any previous fallback
+	/// key for the same algorithm.
+	fn add_fallback_key(
+		&self, user_id: &UserId, device_id: &DeviceId, fallback_key_key: &DeviceKeyId,
+		fallback_key_value: &Raw<OneTimeKey>,
+	) -> Result<()>;
+
+	/// Returns the fallback key of a device and marks it as used. Fallback
+	/// keys are handed out again until the device replaces them.
+	fn take_fallback_key(
+		&self, user_id: &UserId, device_id: &DeviceId, key_algorithm: &DeviceKeyAlgorithm,
+	) -> Result<Option<(OwnedDeviceKeyId, Raw<OneTimeKey>)>>;
+
+	/// Returns the algorithms the device has a fallback key for that was not
+	/// claimed yet.
+	fn unused_fallback_key_types(&self, user_id: &UserId, device_id: &DeviceId) -> Result<Vec<DeviceKeyAlgorithm>>;
+
 	fn add_device_keys(&self, user_id: &UserId, device_id: &DeviceId, device_keys: &Raw<DeviceKeys>) -> Result<()>;
 
 	fn add_cross_signing_keys(
@@ -126,9 +144,17 @@ pub trait Data: Send + Sync {
 		content: serde_json::Value,
 	) -> Result<()>;
 
-	fn get_to_device_events(&self, user_id: &UserId, device_id: &DeviceId) -> Result<Vec<Raw<AnyToDeviceEvent>>>;
+	/// Returns the to-device events the sync stream has not acknowledged yet.
+	fn get_to_device_events(
+		&self, user_id: &UserId, device_id: &DeviceId, stream: Option<&str>,
+	) -> Result<Vec<Raw<AnyToDeviceEvent>>>;
 
-	fn remove_to_device_events(&self, user_id: &UserId, device_id: &DeviceId, until: u64) -> Result<()>;
+	/// Acknowledges all to-device events up to `until` for the sync stream.
+	/// Events are only removed once every stream of the device that was used
+	/// after `expired_before` acknowledged them.
+	fn remove_to_device_events(
+		&self, user_id: &UserId, device_id: &DeviceId, stream: Option<&str>, until: u64, expired_before: u64,
+	) -> Result<()>;
 
 	fn update_device_metadata(&self, user_id: &UserId, device_id: &DeviceId, device: &Device) -> Result<()>;
 
@@ -143,4 +169,40 @@ pub trait Data: Send + Sync {
 	fn create_filter(&self, user_id: &UserId, filter: &FilterDefinition) -> Result<String>;
 
 	fn get_filter(&self, user_id: &UserId, filter_id: &str) -> Result<Option<FilterDefinition>>;
+
+	/// Stores an OpenID token for the user that expires at `expires_at`
+	/// (milliseconds since the unix epoch).
+	fn create_openid_token(&self, user_id: &UserId, token: &str, expires_at: u64) -> Result<()>;
+
+	/// Removes the OpenID tokens that expired without being used again.
+	fn remove_expired_openid_tokens(&self) -> Result<()>;
+
+	/// Find out which user an OpenID token belongs to. Expired tokens are
+	/// removed and yield `None`.
+	fn find_from_openid_token(&self, token: &str) -> Result<Option<OwnedUserId>>;
+
+	/// Binds an email address to the user.
+	fn add_email(&self, user_id: &UserId, address: &str) -> Result<()>;
+
+	/// Unbinds an email address from the user.
+	fn remove_email(&self, user_id: &UserId, address: &str) -> Result<()>;
+
+	/// Returns the email addresses bound to the user and when they were added.
+	fn emails<'a>(&'a self, user_id: &UserId) -> Box<dyn Iterator<Item = Result<(String, u64)>> + 'a>;
+
+	/// Returns the persisted state of a sliding sync connection.
+	fn get_sync_connection(
+		&self, user_id: &UserId, device_id: &DeviceId, conn_id: &str,
+	) -> Result<Option<SlidingSyncCache>>;
+
+	/// Persists the state of a sliding sync connection.
+	fn set_sync_connection(
+		&self, user_id: &UserId, device_id: &DeviceId, conn_id: &str, cache: &SlidingSyncCache,
+	) -> Result<()>;
+
+	fn remove_sync_connection(&self, user_id: &UserId, device_id: &DeviceId, conn_id: &str) -> Result<()>;
+
+	/// Removes all sliding sync connections that were last used before
+	/// `until` (milliseconds since the unix epoch).
+	fn remove_sync_connections_before(&self, until: u64) -> Result<()>;
 }
@@ -3,6 +3,7 @@ use std::{
 	collections::{BTreeMap, BTreeSet},
 	mem,
 	sync::{Arc, Mutex},
+	time::Duration,
 };
 
 pub use data::Data;
@@ -22,14 +23,38 @@ use ruma::{
 	DeviceId, DeviceKeyAlgorithm, DeviceKeyId, OwnedDeviceId, OwnedDeviceKeyId, OwnedMxcUri, OwnedRoomId, OwnedUserId,
 	RoomAliasId, UInt, UserId,
 };
+use serde::{Deserialize, Serialize};
+use tracing::warn;
 
-use crate::{services, Error, Result};
+use crate::{service::sending::AppserviceEduTarget, services, utils, Error, Result};
 
+/// Sliding sync connections that were not used for this long are forgotten.
+const SLIDING_SYNC_CONNECTION_TTL: Duration = Duration::from_secs(60 * 60 * 24 * 7);
+
+/// Sliding sync connections whose state didn't change are saved this often,
+/// so they don't expire while they are used.
+const SLIDING_SYNC_CONNECTION_SAVE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Additional sync streams of a device that were not used for this long stop
+/// holding back to-device events.
+const SYNC_STREAM_TTL: Duration = Duration::from_secs(60 * 60 * 24 * 7);
+
+#[derive(Default, Serialize, Deserialize)]
 pub struct SlidingSyncCache {
 	lists: BTreeMap<String, SyncRequestList>,
 	subscriptions: BTreeMap<OwnedRoomId, sync_events::v4::RoomSubscription>,
 	known_rooms: BTreeMap<String, BTreeMap<OwnedRoomId, u64>>, // For every room, the roomsince number
 	extensions: ExtensionsConfig,
+	#[serde(skip)]
+	last_used: u64, // Milliseconds since the unix epoch, stored next to the state
+	#[serde(skip)]
+	saved: Vec<u8>, // The state as it was last saved
+}
+
+impl SlidingSyncCache {
+	pub fn last_used(&self) -> u64 { self.last_used }
+
+	pub fn set_last_used(&mut self, last_used: u64) { self.last_used = last_used; }
 }
 
 type DbConnections = Mutex<BTreeMap<(OwnedUserId, OwnedDeviceId, String), Arc<Mutex<SlidingSyncCache>>>>;
@@ -44,12 +69,81 @@ impl Service {
 	pub fn exists(&self, user_id: &UserId) -> Result<bool> { self.db.exists(user_id) }
 
 	pub fn forget_sync_request_connection(&self, user_id: OwnedUserId, device_id: OwnedDeviceId, conn_id: String) {
+		if let Err(e) = self
+			.db
+			.remove_sync_connection(&user_id, &device_id, &conn_id)
+		{
+			warn!("Failed to remove sliding sync connection {conn_id} of {user_id}: {e}");
+		}
+
 		self.connections
 			.lock()
 			.unwrap()
 			.remove(&(user_id, device_id, conn_id));
 	}
 
+	/// Returns the state of a sliding sync connection, restoring it from the
+	/// database if it is not in memory yet.
+	fn sync_connection(
+		&self, user_id: OwnedUserId, device_id: OwnedDeviceId, conn_id: String,
+	) -> Arc<Mutex<SlidingSyncCache>> {
+		let mut cache = self.connections.lock().unwrap();
+		let key = (user_id, device_id, conn_id);
+		if let Some(cached) = cache.get(&key) {
+			return Arc::clone(cached);
+		}
+
+		let restored = match self.db.get_sync_connection(&key.0, &key.1, &key.2) {
+			Ok(Some(restored)) if restored.last_used() > expired_before(SLIDING_SYNC_CONNECTION_TTL) => restored,
+			Ok(_) => SlidingSyncCache::default(),
+			Err(e) => {
+				warn!("Failed to restore sliding sync connection {} of {}: {e}", key.2, key.0);
+				SlidingSyncCache::default()
+			},
+		};
+
+		Arc::clone(
+			cache
+				.entry(key)
+				.or_insert_with(|| Arc::new(Mutex::new(restored))),
+		)
+	}
+
+	/// Persists the state of a sliding sync connection so clients can resume
+	/// it after a restart. Unchanged state is only written every
+	/// [`SLIDING_SYNC_CONNECTION_SAVE_INTERVAL`].
+	pub fn save_sync_request_connection(
+		&self, user_id: OwnedUserId, device_id: OwnedDeviceId, conn_id: String,
+	) -> Result<()> {
+		let cached = self.sync_connection(user_id.clone(), device_id.clone(), conn_id.clone());
+		let cached = &mut cached.lock().unwrap();
+
+		let state = serde_json::to_vec(&**cached).expect("SlidingSyncCache can be serialized");
+		if state == cached.saved && cached.last_used > expired_before(SLIDING_SYNC_CONNECTION_SAVE_INTERVAL) {
+			return Ok(());
+		}
+
+		cached.set_last_used(utils::millis_since_unix_epoch());
+		self.db
+			.set_sync_connection(&user_id, &device_id, &conn_id, cached)?;
+		cached.saved = state;
+
+		Ok(())
+	}
+
+	/// Forgets all sliding sync connections that were not used for a week.
+	pub fn remove_expired_sync_connections(&self) -> Result<()> {
+		let expired_before = expired_before(SLIDING_SYNC_CONNECTION_TTL);
+
+		self.connections.lock().unwrap().retain(|_, cached| {
+			// Connections that were never saved are still being set up
+			let last_used = cached.lock().unwrap().last_used();
+			last_used == 0 || last_used > expired_before
+		});
+
+		self.db.remove_sync_connections_before(expired_before)
+	}
+
 	pub fn update_sync_request_with_cache(
 		&self, user_id: OwnedUserId, device_id: OwnedDeviceId, request: &mut sync_events::v4::Request,
 	) -> BTreeMap<String, BTreeMap<OwnedRoomId, u64>> {
@@ -57,21 +151,8 @@ impl Service {
 			return BTreeMap::new();
 		};
 
-		let mut cache = self.connections.lock().unwrap();
-		let cached = Arc::clone(
-			cache
-				.entry((user_id, device_id, conn_id))
-				.or_insert_with(|| {
-					Arc::new(Mutex::new(SlidingSyncCache {
-						lists: BTreeMap::new(),
-						subscriptions: BTreeMap::new(),
-						known_rooms: BTreeMap::new(),
-						extensions: ExtensionsConfig::default(),
-					}))
-				}),
-		);
+		let cached = self.sync_connection(user_id, device_id, conn_id);
 		let cached = &mut cached.lock().unwrap();
-		drop(cache);
 
 		for (list_id, list) in &mut request.lists {
 			if let Some(cached_list) = cached.lists.get(list_id) {
@@ -165,6 +246,42 @@ impl Service {
 			.clone()
 			.or_else(|| cached.extensions.account_data.rooms.clone());
 
+		request.extensions.receipts.enabled = request
+			.extensions
+			.receipts
+			.enabled
+			.or(cached.extensions.receipts.enabled);
+		request.extensions.receipts.lists = request
+			.extensions
+			.receipts
+			.lists
+			.clone()
+			.or_else(|| cached.extensions.receipts.lists.clone());
+		request.extensions.receipts.rooms = request
+			.extensions
+			.receipts
+			.rooms
+			.clone()
+			.or_else(|| cached.extensions.receipts.rooms.clone());
+
+		request.extensions.typing.enabled = request
+			.extensions
+			.typing
+			.enabled
+			.or(cached.extensions.typing.enabled);
+		request.extensions.typing.lists = request
+			.extensions
+			.typing
+			.lists
+			.clone()
+			.or_else(|| cached.extensions.typing.lists.clone());
+		request.extensions.typing.rooms = request
+			.extensions
+			.typing
+			.rooms
+			.clone()
+			.or_else(|| cached.extensions.typing.rooms.clone());
+
 		cached.extensions = request.extensions.clone();
 
 		cached.known_rooms.clone()
@@ -174,21 +291,8 @@ impl Service {
 		&self, user_id: OwnedUserId, device_id: OwnedDeviceId, conn_id: String,
 		subscriptions: BTreeMap<OwnedRoomId, sync_events::v4::RoomSubscription>,
 	) {
-		let mut cache = self.connections.lock().unwrap();
-		let cached = Arc::clone(
-			cache
-				.entry((user_id, device_id, conn_id))
-				.or_insert_with(|| {
-					Arc::new(Mutex::new(SlidingSyncCache {
-						lists: BTreeMap::new(),
-						subscriptions: BTreeMap::new(),
-						known_rooms: BTreeMap::new(),
-						extensions: ExtensionsConfig::default(),
-					}))
-				}),
-		);
+		let cached = self.sync_connection(user_id, device_id, conn_id);
 		let cached = &mut cached.lock().unwrap();
-		drop(cache);
 
 		cached.subscriptions = subscriptions;
 	}
@@ -197,21 +301,8 @@ impl Service {
 		&self, user_id: OwnedUserId, device_id: OwnedDeviceId, conn_id: String, list_id: String,
 		new_cached_rooms: BTreeSet<OwnedRoomId>, globalsince: u64,
 	) {
-		let mut cache = self.connections.lock().unwrap();
-		let cached = Arc::clone(
-			cache
-				.entry((user_id, device_id, conn_id))
-				.or_insert_with(|| {
-					Arc::new(Mutex::new(SlidingSyncCache {
-						lists: BTreeMap::new(),
-						subscriptions: BTreeMap::new(),
-						known_rooms: BTreeMap::new(),
-						extensions: ExtensionsConfig::default(),
-					}))
-				}),
-		);
+		let cached = self.sync_connection(user_id, device_id, conn_id);
 		let cached = &mut cached.lock().unwrap();
-		drop(cache);
 
 		for (roomid, lastsince) in cached
 			.known_rooms
@@ -351,6 +442,24 @@ impl Service {
 		self.db.count_one_time_keys(user_id, device_id)
 	}
 
+	pub fn add_fallback_key(
+		&self, user_id: &UserId, device_id: &DeviceId, fallback_key_key: &DeviceKeyId,
+		fallback_key_value: &Raw<OneTimeKey>,
+	) -> Result<()> {
+		self.db
+			.add_fallback_key(user_id, device_id, fallback_key_key, fallback_key_value)
+	}
+
+	pub fn take_fallback_key(
+		&self, user_id: &UserId, device_id: &DeviceId, key_algorithm: &DeviceKeyAlgorithm,
+	) -> Result<Option<(OwnedDeviceKeyId, Raw<OneTimeKey>)>> {
+		self.db.take_fallback_key(user_id, device_id, key_algorithm)
+	}
+
+	pub fn unused_fallback_key_types(&self, user_id: &UserId, device_id: &DeviceId) -> Result<Vec<DeviceKeyAlgorithm>> {
+		self.db.unused_fallback_key_types(user_id, device_id)
+	}
+
 	pub fn add_device_keys(&self, user_id: &UserId, device_id: &DeviceId, device_keys: &Raw<DeviceKeys>) -> Result<()> {
 		self.db.add_device_keys(user_id, device_id, device_keys)
 	}
@@ -412,20 +521,48 @@ impl Service {
 		self.db.get_user_signing_key(user_id)
 	}
 
-	pub fn add_to_device_event(
+	pub async fn add_to_device_event(
 		&self, sender: &UserId, target_user_id: &UserId, target_device_id: &DeviceId, event_type: &str,
 		content: serde_json::Value,
 	) -> Result<()> {
+		// Appservices manage the devices of their users themselves (MSC4203)
+		let appservice_event = serde_json::to_vec(&serde_json::json!({
+			"type": event_type,
+			"sender": sender,
+			"to_user_id": target_user_id,
+			"to_device_id": target_device_id,
+			"content": content,
+		}))
+		.expect("to-device event can be serialized");
+
 		self.db
-			.add_to_device_event(sender, target_user_id, target_device_id, event_type, content)
+			.add_to_device_event(sender, target_user_id, target_device_id, event_type, content)?;
+
+		// The event was stored for the recipient's devices, so don't fail the request
+		if let Err(e) = services()
+			.sending
+			.send_edu_appservices(AppserviceEduTarget::ToDevice(target_user_id), appservice_event)
+			.await
+		{
+			warn!("Failed to queue to-device event for appservices of {target_user_id}: {e}");
+		}
+
+		Ok(())
 	}
 
-	pub fn get_to_device_events(&self, user_id: &UserId, device_id: &DeviceId) -> Result<Vec<Raw<AnyToDeviceEvent>>> {
-		self.db.get_to_device_events(user_id, device_id)
+	pub fn get_to_device_events(
+		&self, user_id: &UserId, device_id: &DeviceId, stream: Option<&str>,
+	) -> Result<Vec<Raw<AnyToDeviceEvent>>> {
+		self.db.get_to_device_events(user_id, device_id, stream)
 	}
 
-	pub fn remove_to_device_events(&self, user_id: &UserId, device_id: &DeviceId, until: u64) -> Result<()> {
-		self.db.remove_to_device_events(user_id, device_id, until)
+	/// Removes the to-device events the sync stream received up to `until`.
+	/// Streams that were not used for a week no longer hold back events.
+	pub fn remove_to_device_events(
+		&self, user_id: &UserId, device_id: &DeviceId, stream: Option<&str>, until: u64,
+	) -> Result<()> {
+		self.db
+			.remove_to_device_events(user_id, device_id, stream, until, expired_before(SYNC_STREAM_TTL))
 	}
 
 	pub fn update_device_metadata(&self, user_id: &UserId, device_id: &DeviceId, device: &Device) -> Result<()> {
@@ -458,7 +595,13 @@ impl Service {
 		// account is deactivated.
 		self.db.set_password(user_id, None)?;
 
-		// TODO: Unhook 3PID
+		for address in self
+			.emails(user_id)
+			.map(|email| email.map(|(address, _)| address))
+		{
+			self.db.remove_email(user_id, &address?)?;
+		}
+
 		Ok(())
 	}
 
@@ -470,6 +613,53 @@ impl Service {
 	pub fn get_filter(&self, user_id: &UserId, filter_id: &str) -> Result<Option<FilterDefinition>> {
 		self.db.get_filter(user_id, filter_id)
 	}
+
+	/// Stores an OpenID token for the user and returns the number of seconds
+	/// it stays valid.
+	pub fn create_openid_token(&self, user_id: &UserId, token: &str) -> Result<u64> {
+		let expires_in = services().globals.openid_token_ttl();
+		let expires_at = utils::millis_since_unix_epoch().saturating_add(expires_in.saturating_mul(1000));
+
+		self.db.create_openid_token(user_id, token, expires_at)?;
+
+		Ok(expires_in)
+	}
+
+	/// Forgets expired OpenID tokens, which would stay forever if they are
+	/// never used again.
+	pub fn remove_expired_openid_tokens(&self) -> Result<()> { self.db.remove_expired_openid_tokens() }
+
+	/// Find out which user an OpenID token belongs to.
+	pub fn find_from_openid_token(&self, token: &str) -> Result<Option<OwnedUserId>> {
+		self.db.find_from_openid_token(token)
+	}
+
+	/// Binds an email address to the user, e.g. so they can receive email
+	/// notifications there. Only admins can do this, there is no validation.
+	pub fn add_email(&self, user_id: &UserId, address: &str) -> Result<()> {
+		self.db.add_email(user_id, &address.to_lowercase())
+	}
+
+	pub fn remove_email(&self, user_id: &UserId, address: &str) -> Result<()> {
+		self.db.remove_email(user_id, &address.to_lowercase())
+	}
+
+	/// Returns the email addresses bound to the user and when they were added.
+	pub fn emails<'a>(&'a self, user_id: &UserId) -> impl Iterator<Item = Result<(String, u64)>> + 'a {
+		self.db.emails(user_id)
+	}
+
+	/// Whether the email address is bound to the user.
+	pub fn has_email(&self, user_id: &UserId, address: &str) -> Result<bool> {
+		let address = address.to_lowercase();
+		for email in self.emails(user_id) {
+			if email?.0 == address {
+				return Ok(true);
+			}
+		}
+
+		Ok(false)
+	}
 }
 
 /// Ensure that a user only sees signatures from themselves and the target user
@@ -494,3 +684,6 @@ pub(crate) fn clean_signatures<F: Fn(&UserId) -> bool>(
 
 	Ok(())
 }
+
+/// Anything last used before this is expired.
+fn expired_before(ttl: Duration) -> u64 { utils::millis_since_unix_epoch().saturating_sub(ttl.as_millis() as u64) }
@@ -11,8 +11,9 @@ use ruma::{
 use thiserror::Error;
 use tracing::{error, info};
 use ErrorKind::{
-	Forbidden, GuestAccessForbidden, LimitExceeded, MissingToken, NotFound, ThreepidAuthFailed, ThreepidDenied,
-	TooLarge, Unauthorized, Unknown, UnknownToken, Unrecognized, UserDeactivated, WrongRoomKeysVersion,
+	BadStatus, ConnectionFailed, ConnectionTimeout, Forbidden, GuestAccessForbidden, LimitExceeded, MissingToken,
+	NotFound, ThreepidAuthFailed, ThreepidDenied, TooLarge, Unauthorized, Unknown, UnknownToken, Unrecognized,
+	UserDeactivated, WrongRoomKeysVersion,
 };
 
 use crate::RumaResponse;
@@ -135,6 +136,11 @@ impl Error {
 						..
 					} => StatusCode::TOO_MANY_REQUESTS,
 					TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+					BadStatus {
+						..
+					}
+					| ConnectionFailed => StatusCode::BAD_GATEWAY,
+					ConnectionTimeout => StatusCode::GATEWAY_TIMEOUT,
 					_ => StatusCode::BAD_REQUEST,
 				},
 			),
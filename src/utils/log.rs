@@ -0,0 +1,323 @@
+//! Changing the log filter at runtime and copying the log lines of a module
+//! into the admin room.
+//!
+//! A capture adds a directive for its module to the log filter, so the lines
+//! it copies are also written to the server log while it runs.
+
+use std::{
+	cmp,
+	fmt::{self, Write as _},
+	sync::{
+		atomic::{AtomicUsize, Ordering},
+		Arc, RwLock,
+	},
+	time::Duration,
+};
+
+use ruma::events::room::message::RoomMessageEventContent;
+use tokio::{
+	sync::mpsc::{self, error::TrySendError},
+	time::{self, Instant},
+};
+use tracing::{
+	field::{Field, Visit},
+	Event, Level, Subscriber,
+};
+use tracing_subscriber::{
+	layer::{Context, Layer},
+	reload, EnvFilter, Registry,
+};
+
+use crate::{services, Error, Result};
+
+/// Replaces the log filter set up in `main.rs`
+pub type FilterHandle = reload::Handle<EnvFilter, Registry>;
+
+/// How long captured lines are collected before they are sent as one message
+const BATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Lines after these are dropped from a message so busy modules don't flood
+/// the admin room
+const MAX_LINES_PER_MESSAGE: usize = 50;
+
+/// Modules whose logs can't be captured. Sending captured lines to the admin
+/// room logs in these, which would be captured again, and their lines can
+/// contain admin room messages such as generated passwords.
+const UNCAPTURABLE_MODULES: &[&str] = &[
+	"conduit::service::admin",
+	"conduit::service::rooms::timeline",
+	"conduit::utils::log",
+];
+
+/// Unset if the filter can't be changed with the tracing setup in use
+static FILTER_HANDLE: RwLock<Option<FilterHandle>> = RwLock::new(None);
+
+/// The filter without the directive of a running capture
+static FILTER: RwLock<String> = RwLock::new(String::new());
+
+static CAPTURE: RwLock<Option<Capture>> = RwLock::new(None);
+
+struct Capture {
+	target: String,
+	level: Level,
+	sender: mpsc::Sender<String>,
+	/// Lines that didn't fit into the channel because it was full
+	dropped: Arc<AtomicUsize>,
+	/// Tells captures apart, so one that ends does not stop the next one
+	started: Instant,
+}
+
+/// Lets the log filter be changed at runtime. `filter` is what the handle's
+/// filter was created from.
+pub fn set_filter_handle(handle: FilterHandle, filter: String) {
+	*FILTER.write().unwrap() = filter;
+	*FILTER_HANDLE.write().unwrap() = Some(handle);
+}
+
+/// Replaces the log filter until the next restart, keeping the directive of a
+/// running capture
+pub(crate) fn set_filter(filter: &str) -> Result<()> {
+	EnvFilter::try_new(filter).map_err(|e| Error::bad_config(&format!("Invalid log filter: {e}")))?;
+
+	*FILTER.write().unwrap() = filter.to_owned();
+
+	apply_filter()
+}
+
+/// Starts copying the log lines of `target` and its submodules up to `level`
+/// into the admin room for `duration`
+pub(crate) fn start_capture(target: String, level: Level, duration: Duration) -> Result<()> {
+	if let Some(module) = UNCAPTURABLE_MODULES
+		.iter()
+		.find(|module| overlaps(&target, module))
+	{
+		return Err(Error::Error(format!(
+			"The logs of {module} can't be captured, capture a module that does not contain it."
+		)));
+	}
+
+	// Bounded so lines logged faster than they are forwarded are dropped instead
+	// of piling up in memory
+	let (sender, receiver) = mpsc::channel(MAX_LINES_PER_MESSAGE);
+	let dropped = Arc::new(AtomicUsize::new(0));
+	let started = Instant::now();
+
+	{
+		let mut capture = CAPTURE.write().unwrap();
+		if let Some(running) = &*capture {
+			return Err(Error::Error(format!(
+				"Already capturing the logs of {}, stop that capture first.",
+				running.target
+			)));
+		}
+
+		*capture = Some(Capture {
+			target: target.clone(),
+			level,
+			sender,
+			dropped: Arc::clone(&dropped),
+			started,
+		});
+	}
+
+	if let Err(e) = apply_filter() {
+		CAPTURE.write().unwrap().take();
+		return Err(e);
+	}
+
+	tokio::spawn(forward(receiver, dropped, target, started, started + duration));
+
+	Ok(())
+}
+
+/// Stops the running capture, returns false if there was none
+pub(crate) fn stop_capture() -> Result<bool> {
+	// Dropping the sender ends the task forwarding the lines
+	if CAPTURE.write().unwrap().take().is_none() {
+		return Ok(false);
+	}
+
+	apply_filter()?;
+
+	Ok(true)
+}
+
+fn apply_filter() -> Result<()> {
+	let mut directives = FILTER.read().unwrap().clone();
+	if let Some(capture) = &*CAPTURE.read().unwrap() {
+		_ = write!(
+			directives,
+			",{}={}",
+			capture.target,
+			capture.level.as_str().to_ascii_lowercase()
+		);
+	}
+
+	let filter = EnvFilter::try_new(&directives).map_err(|e| Error::bad_config(&format!("Invalid log filter: {e}")))?;
+
+	let handle = FILTER_HANDLE.read().unwrap();
+	let Some(handle) = &*handle else {
+		return Err(Error::Error(
+			"The log filter can't be changed at runtime with the tracing setup of this build.".to_owned(),
+		));
+	};
+
+	handle
+		.reload(filter)
+		.map_err(|e| Error::Error(format!("Failed to replace the log filter: {e}")))
+}
+
+/// Sends the captured lines to the admin room in batches until the capture is
+/// stopped or `deadline` is reached
+async fn forward(
+	mut receiver: mpsc::Receiver<String>, dropped: Arc<AtomicUsize>, target: String, started: Instant,
+	deadline: Instant,
+) {
+	let mut stopped = false;
+
+	while !stopped && Instant::now() < deadline {
+		let batch_end = cmp::min(deadline, Instant::now() + BATCH_INTERVAL);
+
+		let mut lines = Vec::new();
+		let mut skipped = 0;
+		loop {
+			match time::timeout_at(batch_end, receiver.recv()).await {
+				Ok(Some(line)) => {
+					if lines.len() < MAX_LINES_PER_MESSAGE {
+						lines.push(line);
+					} else {
+						skipped += 1;
+					}
+				},
+				Ok(None) => {
+					stopped = true;
+					break;
+				},
+				Err(_) => break,
+			}
+		}
+
+		skipped += dropped.swap(0, Ordering::Relaxed);
+		if !lines.is_empty() || skipped > 0 {
+			send_lines(&target, &lines, skipped);
+		}
+	}
+
+	if !stopped {
+		let mut capture = CAPTURE.write().unwrap();
+		if capture
+			.as_ref()
+			.is_some_and(|capture| capture.started == started)
+		{
+			capture.take();
+			drop(capture);
+
+			if let Err(e) = apply_filter() {
+				services()
+					.admin
+					.send_message(RoomMessageEventContent::text_plain(format!(
+						"Failed to restore the log filter after capturing: {e}"
+					)));
+			}
+		}
+	}
+
+	services()
+		.admin
+		.send_message(RoomMessageEventContent::text_plain(format!(
+			"Stopped capturing the logs of {target}."
+		)));
+}
+
+fn send_lines(target: &str, lines: &[String], skipped: usize) {
+	let mut message = format!("Logs of {target}:\n");
+	for line in lines {
+		message.push_str(line);
+		message.push('\n');
+	}
+
+	if skipped > 0 {
+		_ = writeln!(message, "... and {skipped} more lines");
+	}
+
+	services()
+		.admin
+		.send_message(RoomMessageEventContent::text_plain(message));
+}
+
+/// Copies the events of the running capture into its channel. It only sees
+/// the events that pass the log filter.
+pub struct CaptureLayer;
+
+impl<S: Subscriber> Layer<S> for CaptureLayer {
+	fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+		// Skipping events while a capture starts or stops is better than
+		// deadlocking on an event logged with the lock held
+		let Ok(capture) = CAPTURE.try_read() else {
+			return;
+		};
+		let Some(capture) = &*capture else {
+			return;
+		};
+
+		let metadata = event.metadata();
+		if *metadata.level() > capture.level || !is_in_module(metadata.target(), &capture.target) {
+			return;
+		}
+
+		let mut visitor = LineVisitor {
+			line: format!("{} {}:", metadata.level(), metadata.target()),
+		};
+		event.record(&mut visitor);
+
+		// Never blocks the thread that logged the event. The receiver is gone once
+		// the capture ended.
+		if let Err(TrySendError::Full(_)) = capture.sender.try_send(visitor.line) {
+			capture.dropped.fetch_add(1, Ordering::Relaxed);
+		}
+	}
+}
+
+fn is_in_module(target: &str, module: &str) -> bool {
+	target
+		.strip_prefix(module)
+		.is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+}
+
+/// Whether capturing `target` would capture any logs of `module`
+fn overlaps(target: &str, module: &str) -> bool { is_in_module(target, module) || is_in_module(module, target) }
+
+struct LineVisitor {
+	line: String,
+}
+
+impl Visit for LineVisitor {
+	fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+		if field.name() == "message" {
+			_ = write!(self.line, " {value:?}");
+		} else {
+			_ = write!(self.line, " {}={value:?}", field.name());
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{is_in_module, overlaps};
+
+	#[test]
+	fn is_in_module_matches_whole_path_segments() {
+		assert!(is_in_module("conduit::service::sending", "conduit::service"));
+		assert!(is_in_module("conduit::service", "conduit::service"));
+		assert!(!is_in_module("conduit::services", "conduit::service"));
+		assert!(!is_in_module("conduit", "conduit::service"));
+	}
+
+	#[test]
+	fn overlaps_parents_and_submodules() {
+		assert!(overlaps("conduit", "conduit::service::admin"));
+		assert!(overlaps("conduit::service::admin::user", "conduit::service::admin"));
+		assert!(!overlaps("conduit::service::sending", "conduit::service::admin"));
+		assert!(!overlaps("conduit::service::administration", "conduit::service::admin"));
+	}
+}
@@ -1,4 +1,5 @@
 pub(crate) mod error;
+pub(crate) mod log;
 
 use std::{
 	cmp,